@@ -1,17 +1,81 @@
-use std::collections::HashMap;
+use near_contract_standards::non_fungible_token::hash_account_id;
 
-use crate::bid::{Bid, Origins};
+use crate::bid::{Bid, BidListing, Origins};
+use crate::event::{
+    AuctionBidData, AuctionCancelData, AuctionExtendData, NearEvent, PayoutFailData, PurchaseData,
+};
 use crate::fee::calculate_price_with_fees;
-use crate::market_core::AuctionArgs;
+use crate::market_core::{AuctionArgs, DutchAuctionArgs};
+use crate::sealed_auction::SealedBid;
 use crate::sale::{
-    ext_contract, ext_self, Payout, GAS_FOR_FT_TRANSFER, GAS_FOR_NFT_TRANSFER, GAS_FOR_ROYALTIES,
+    ext_contract, ext_self, DELIMETER, GAS_FOR_NFT_TRANSFER, GAS_FOR_ROYALTIES, MAX_LEN_PAYOUT,
     NO_DEPOSIT,
 };
 use crate::*;
 use near_sdk::{near_bindgen, promise_result_as_success};
 // should check calculation
 pub const EXTENSION_DURATION: u64 = 15 * 60 * NANOS_PER_SEC; // 15 minutes
+pub const MIN_DURATION: u64 = 15 * 60 * NANOS_PER_SEC; // 15 minutes
 pub const MAX_DURATION: u64 = 1000 * 60 * 60 * 24 * NANOS_PER_SEC; // 1000 days
+pub const MIN_EXTENSION_DURATION_DEFAULT: u64 = 60 * NANOS_PER_SEC; // 1 minute
+pub const MAX_EXTENSION_DURATION_DEFAULT: u64 = 60 * 60 * 24 * NANOS_PER_SEC; // 1 day
+
+// A bid made less than `window` before the auction end moves the end to `duration` after the bid
+// `max_count` and `max_total` optionally limit the number and the total length of extensions
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AuctionExtension {
+    pub window: U64,
+    pub duration: U64,
+    pub max_count: Option<u32>,
+    pub max_total: Option<U64>,
+}
+
+impl Default for AuctionExtension {
+    fn default() -> Self {
+        Self {
+            window: EXTENSION_DURATION.into(),
+            duration: EXTENSION_DURATION.into(),
+            max_count: None,
+            max_total: None,
+        }
+    }
+}
+
+// Owner-set limits for the extension window and duration of new auctions
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ExtensionBounds {
+    pub min_duration: U64,
+    pub max_duration: U64,
+}
+
+impl Default for ExtensionBounds {
+    fn default() -> Self {
+        Self {
+            min_duration: MIN_EXTENSION_DURATION_DEFAULT.into(),
+            max_duration: MAX_EXTENSION_DURATION_DEFAULT.into(),
+        }
+    }
+}
+
+// English auctions are won by the highest bid,
+// Dutch auctions are won by the first buyer who pays the current decaying price,
+// sealed-bid auctions are won by the highest bid revealed after the end of the auction
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub enum AuctionKind {
+    English,
+    Dutch {
+        floor_price: U128,
+        step: Option<U64>,
+    },
+    Sealed {
+        reveal_duration: U64,
+        second_price: bool,
+        bids: Vec<SealedBid>,
+    },
+}
 
 #[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
@@ -26,11 +90,81 @@ pub struct Auction {
     pub minimal_step: u128,
     pub start_price: u128,
     pub buy_out_price: Option<u128>,
+    pub reserve_price: Option<u128>,
 
     pub start: u64,
     pub end: u64,
 
     pub origins: Origins,
+    pub kind: AuctionKind,
+    // Protocol fee at the moment of listing, it doesn't change with the market fee
+    pub protocol_fee: u32,
+
+    pub extension: AuctionExtension,
+    pub extension_count: u32,
+    pub extension_total: u64,
+}
+
+impl Auction {
+    // Extends the auction after a late bid within the extension limits
+    // Returns `true` if the end has been changed
+    pub fn extend(&mut self) -> bool {
+        let now = env::block_timestamp();
+        if self.end - now >= self.extension.window.0 {
+            return false;
+        }
+        if matches!(self.extension.max_count, Some(max_count) if self.extension_count >= max_count)
+        {
+            return false;
+        }
+        let mut time = (now + self.extension.duration.0).saturating_sub(self.end);
+        if let Some(max_total) = self.extension.max_total {
+            time = time.min(max_total.0.saturating_sub(self.extension_total));
+        }
+        if time == 0 {
+            return false;
+        }
+        self.end += time;
+        self.extension_count += 1;
+        self.extension_total += time;
+        true
+    }
+
+    // Checks that the current bid (not including fees) reaches the reserve price
+    pub fn reserve_met(&self) -> bool {
+        match (self.reserve_price, &self.bid) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(reserve_price), Some(bid)) => {
+                let total_origins = fee::calculate_origins(&bid.origins);
+                fee::calculate_actual_amount(bid.price.0, total_origins, self.protocol_fee)
+                    >= reserve_price
+            }
+        }
+    }
+
+    // Returns the current price of the Dutch auction (not including fees)
+    // or `None` for other kinds of auctions
+    pub fn dutch_price(&self) -> Option<u128> {
+        let (floor_price, step) = match self.kind {
+            AuctionKind::Dutch { floor_price, step } => (floor_price.0, step),
+            _ => return None,
+        };
+        let duration = self.end - self.start;
+        let mut elapsed = env::block_timestamp()
+            .saturating_sub(self.start)
+            .min(duration);
+        if let Some(step) = step {
+            if elapsed < duration {
+                elapsed -= elapsed % step.0;
+            }
+        }
+        // (start_price - floor_price) * elapsed / duration without overflow
+        let (elapsed, duration) = (elapsed as u128, duration as u128);
+        let diff = self.start_price - floor_price;
+        let decay = diff / duration * elapsed + diff % duration * elapsed / duration;
+        Some(self.start_price - decay)
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -45,9 +179,17 @@ pub struct AuctionJson {
     pub minimal_step: U128,
     pub start_price: U128,
     pub buy_out_price: Option<U128>,
+    // `None` if the auction has no reserve price
+    pub reserve_met: Option<bool>,
 
     pub start: U64,
     pub end: U64,
+    pub kind: AuctionKind,
+    pub protocol_fee: u32,
+
+    pub extension: AuctionExtension,
+    pub extension_count: u32,
+    pub extension_total: U64,
 }
 
 #[near_bindgen]
@@ -57,24 +199,48 @@ impl Market {
     pub(crate) fn start_auction(
         &mut self,
         args: AuctionArgs,
+        kind: AuctionKind,
         token_id: TokenId,
         owner_id: AccountId,
         approval_id: u64,
         nft_contract_id: AccountId,
     ) -> (u128, AuctionJson) {
         require!(
-            args.duration.0 >= EXTENSION_DURATION && args.duration.0 <= MAX_DURATION,
-            format!(
-                "Incorrect duration. Should be at least {}",
-                EXTENSION_DURATION
-            )
+            args.duration.0 >= MIN_DURATION && args.duration.0 <= MAX_DURATION,
+            format!("Incorrect duration. Should be at least {}", MIN_DURATION)
         );
+        let extension = args.extension.unwrap_or_default();
+        // Only the bids of English auctions extend them
+        if matches!(kind, AuctionKind::English) {
+            let bounds = &self.market.extension_bounds;
+            for time in [extension.window.0, extension.duration.0] {
+                require!(
+                    bounds.min_duration.0 <= time && time <= bounds.max_duration.0,
+                    format!(
+                        "Extension window and duration should be from {} to {}",
+                        bounds.min_duration.0, bounds.max_duration.0
+                    )
+                );
+            }
+        }
         let ft_token_id = self.token_type_to_ft_token_type(args.token_type);
         let start = args
             .start
             .map(|s| s.into())
             .unwrap_or_else(env::block_timestamp);
         require!(start >= env::block_timestamp(), "incorrect start time");
+        if let Some(reserve_price) = args.reserve_price {
+            require!(
+                reserve_price.0 >= args.start_price.0,
+                "Reserve price should not be less than the start price"
+            );
+            if let Some(buy_out_price) = args.buy_out_price {
+                require!(
+                    buy_out_price.0 >= reserve_price.0,
+                    "Buyout price should not be less than the reserve price"
+                );
+            }
+        }
         let end = start + args.duration.0;
         let auction_id = self.market.next_auction_id;
         let origins = args.origins.unwrap_or_default();
@@ -89,11 +255,17 @@ impl Market {
             minimal_step: args.minimal_step.into(),
             start_price: args.start_price.into(),
             buy_out_price: args.buy_out_price.map(|p| p.into()),
+            reserve_price: args.reserve_price.map(|p| p.into()),
             start,
             end,
             origins,
+            kind,
+            protocol_fee: self.market.protocol_fee,
+            extension,
+            extension_count: 0,
+            extension_total: 0,
         };
-        self.market.auctions.insert(&auction_id, &auction);
+        self.internal_insert_auction(auction_id, &auction);
         self.market.next_auction_id += 1;
 
         let auction_json = self.json_from_auction(auction);
@@ -102,8 +274,52 @@ impl Market {
         (auction_id, auction_json)
     }
 
+    // Called in nft_on_approve to create a new Dutch auction
+    // It is stored as a regular auction without the minimal step and the buyout
+    pub(crate) fn start_dutch_auction(
+        &mut self,
+        args: DutchAuctionArgs,
+        token_id: TokenId,
+        owner_id: AccountId,
+        approval_id: u64,
+        nft_contract_id: AccountId,
+    ) -> (u128, AuctionJson) {
+        require!(
+            args.floor_price.0 < args.start_price.0,
+            "Floor price should be less than the start price"
+        );
+        if let Some(step) = args.step {
+            require!(
+                step.0 > 0 && step.0 <= args.duration.0,
+                "Price step should be positive and not longer than the auction"
+            );
+        }
+        self.start_auction(
+            AuctionArgs {
+                token_type: args.token_type,
+                minimal_step: U128(0),
+                start_price: args.start_price,
+                start: args.start,
+                duration: args.duration,
+                buy_out_price: None,
+                reserve_price: None,
+                extension: None,
+                origins: args.origins,
+            },
+            AuctionKind::Dutch {
+                floor_price: args.floor_price,
+                step: args.step,
+            },
+            token_id,
+            owner_id,
+            approval_id,
+            nft_contract_id,
+        )
+    }
+
     // Adds a bid to the corresponding auction
     // Supports buyout and time extension
+    // Buys the token of a Dutch auction, the change is refunded
     #[payable]
     pub fn auction_add_bid(
         &mut self,
@@ -112,59 +328,25 @@ impl Market {
         origins: Option<Origins>,
     ) {
         let ft_token_id = self.token_type_to_ft_token_type(token_type);
+        // Fungible tokens are bid through `ft_on_transfer`, the attached deposit is always NEAR
         require!(
-            self.market.ft_token_ids.contains(&ft_token_id),
-            "token not supported"
-        );
-        require!(
-            self.check_auction_in_progress(auction_id),
-            "Auction is not in progress"
+            ft_token_id.as_str() == "near",
+            "Fungible tokens should be bid with ft_transfer_call"
         );
-        let mut auction = self
-            .market
-            .auctions
-            .get(&auction_id.into())
-            .unwrap_or_else(|| env::panic_str("auction not active"));
-        require!(
-            auction.owner_id != env::predecessor_account_id(),
-            "Cannot bid on your own auction"
-        );
-            let deposit = env::attached_deposit();
-        let min_deposit =
-            calculate_price_with_fees(self.get_minimal_next_bid(auction_id), origins.as_ref());
-
-        // Check that the bid is not smaller than the minimal allowed bid
-        require!(
-            deposit >= min_deposit,
-            format!("Should bid at least {}", min_deposit)
+        let unused_deposit = self.internal_auction_add_bid(
+            auction_id,
+            ft_token_id,
+            env::predecessor_account_id(),
+            env::attached_deposit(),
+            origins,
         );
-        //Return previous bid
-        if let Some(previous_bid) = auction.bid {
-            self.refund_bid(ft_token_id, previous_bid.owner_id, previous_bid.price);
+        if unused_deposit > 0 {
+            self.internal_send(
+                AccountId::new_unchecked("near".to_owned()),
+                env::predecessor_account_id(),
+                unused_deposit.into(),
+            );
         }
-        // If the price is bigger than the buy_out_price, the auction end is set to the current time
-        let mut bought_out = false;
-        if let Some(buy_out_price) = auction.buy_out_price {
-            if calculate_price_with_fees(buy_out_price.into(), origins.as_ref()) <= deposit {
-                auction.end = env::block_timestamp();
-                bought_out = true;
-            }
-        }
-        // Create a bid
-        let bid = Bid {
-            owner_id: env::predecessor_account_id(),
-            price: deposit.into(),
-            start: env::block_timestamp().into(),
-            end: None,
-            origins: origins.unwrap_or_default(),
-        };
-        // Extend the auction if the bid is added EXTENSION_DURATION (15 min) before the auction end
-        // and the token is not bought out
-        auction.bid = Some(bid);
-        if auction.end - env::block_timestamp() < EXTENSION_DURATION && !bought_out {
-            auction.end = env::block_timestamp() + EXTENSION_DURATION;
-        }
-        self.market.auctions.insert(&auction_id.into(), &auction);
     }
 
     // Cancels the auction if it doesn't have a bid yet
@@ -181,52 +363,68 @@ impl Market {
             auction.owner_id == env::predecessor_account_id(),
             "Only the auction owner can cancel the auction"
         );
+        let has_sealed_bids =
+            matches!(&auction.kind, AuctionKind::Sealed { bids, .. } if !bids.is_empty());
         require!(
-            auction.bid.is_none(),
+            auction.bid.is_none() && !has_sealed_bids,
             "Can't cancel the auction after the first bid is made"
         );
-        self.market.auctions.remove(&auction_id.into());
+        self.internal_remove_auction(auction_id.into());
+        NearEvent::auction_cancel(AuctionCancelData { auction_id }).emit();
     }
 
     // Finishes the auction if it has reached its end
     // Can be called by anyone
-    pub fn finish_auction(&mut self, auction_id: U128) -> Promise {
+    // Returns `None` if the auction is removed without a sale: a sealed-bid auction has no
+    // revealed bids or the reserve price is not met (the bid is refunded)
+    pub fn finish_auction(&mut self, auction_id: U128) -> Option<Promise> {
         let auction = self
-            .market
-            .auctions
-            .remove(&auction_id.into())
+            .internal_remove_auction(auction_id.into())
             .unwrap_or_else(|| env::panic_str("Auction is not active"));
+        if let AuctionKind::Sealed { .. } = auction.kind {
+            return self.finish_sealed_auction(auction_id, auction);
+        }
         require!(
             env::block_timestamp() > auction.end,
             "Auction can be finalized only after the end time"
         );
+        // The token stays with the seller if the reserve price is not reached
+        if let Some(bid) = auction.bid.as_ref().filter(|_| !auction.reserve_met()) {
+            self.refund_bid(auction.ft_token_id.clone(), bid.owner_id.clone(), bid.price);
+            NearEvent::auction_cancel(AuctionCancelData { auction_id }).emit();
+            return None;
+        }
+        Some(self.settle_auction(auction_id, auction))
+    }
+
+    // Transfers the token to the auction winner and pays out in the callback
+    // The auction should be already removed
+    pub(crate) fn settle_auction(&mut self, auction_id: U128, auction: Auction) -> Promise {
         let final_bid = auction
             .bid
+            .clone()
             .unwrap_or_else(|| env::panic_str("Can finalize only if there is a bid"));
-        let mut buyer = final_bid.origins;
-        buyer.insert(env::current_account_id(), PROTOCOL_FEE as u32);
-        let mut seller_fee = HashMap::with_capacity(auction.origins.len() + 1);
-        seller_fee.extend(auction.origins.clone()); // TODO: dodge this clone
-        seller_fee.insert(env::current_account_id(), PROTOCOL_FEE as u32);
-        let fees = fee::Fees {
-            buyer,
-            seller: seller_fee,
-        };
+        let fees = fee::Fees::new(
+            final_bid.origins,
+            &auction.origins,
+            &self.market.treasury_id,
+            auction.protocol_fee,
+        );
         ext_contract::nft_transfer_payout(
             final_bid.owner_id.clone(),
             auction.token_id.clone(),
             auction.approval_id,
-            Some(near_sdk::serde_json::to_string(&fees).expect("Failed to sereailize")),
+            Some(near_sdk::serde_json::to_string(&fees).expect("Failed to serialize")),
             final_bid.price,
-            10,
+            MAX_LEN_PAYOUT,
             auction.nft_contract_id.clone(),
             1,
             GAS_FOR_NFT_TRANSFER,
         )
         .then(ext_self::resolve_finish_auction(
-            auction.ft_token_id,
-            final_bid.owner_id.clone(),
-            final_bid.price,
+            auction_id,
+            auction,
+            fees,
             env::current_account_id(),
             NO_DEPOSIT,
             GAS_FOR_ROYALTIES,
@@ -236,69 +434,57 @@ impl Market {
     // self callback
     // If transfer of token succeded - count fees and transfer payouts
     // If failed - refund price to buyer
+    // The refunds are sent explicitly, so nothing is returned to `ft_resolve_transfer`
     #[private]
     pub fn resolve_finish_auction(
         &mut self,
-        ft_token_id: AccountId,
-        buyer_id: AccountId,
-        price: U128,
+        auction_id: U128,
+        auction: Auction,
+        fees: fee::Fees,
     ) -> U128 {
-        let payout_option = promise_result_as_success().and_then(|value| {
-            near_sdk::serde_json::from_slice::<Payout>(&value)
-                .ok()
-                .and_then(|payout| {
-                    if payout.payout.len() > 10 || payout.payout.is_empty() {
-                        env::log_str("Cannot have more than 10 payouts and sale.bids refunds");
-                        None
-                    } else {
-                        let mut remainder = price.0;
-                        for &value in payout.payout.values() {
-                            remainder = remainder.checked_sub(value.0)?;
-                        }
-                        if remainder <= 1 {
-                            Some(payout)
-                        } else {
-                            None
-                        }
-                    }
+        let ft_token_id = auction.ft_token_id;
+        let owner_id = auction.owner_id;
+        let final_bid = auction
+            .bid
+            .unwrap_or_else(|| env::panic_str("Can finalize only if there is a bid"));
+        let buyer_id = final_bid.owner_id;
+        let price = final_bid.price;
+        // The price is either paid out or refunded to the buyer
+        self.escrow_release(&ft_token_id, price.0);
+        let value = match promise_result_as_success() {
+            Some(value) => value,
+            None => {
+                self.internal_send(ft_token_id.clone(), buyer_id.clone(), price);
+                NearEvent::payout_fail(PayoutFailData {
+                    auction_id: Some(auction_id),
+                    owner_id,
+                    nft_contract_id: auction.nft_contract_id,
+                    token_id: auction.token_id,
+                    buyer_id,
+                    ft_token_id,
+                    price,
                 })
-        });
-        // is payout option valid?
-        let payout = if let Some(payout_option) = payout_option {
-            payout_option
-        } else {
-            if ft_token_id == "near".parse().unwrap() {
-                Promise::new(buyer_id).transfer(u128::from(price));
+                .emit();
+                return U128(0);
             }
-            // leave function and return all FTs in ft_resolve_transfer
-            return price;
         };
-
-        // NEAR payouts
-        if ft_token_id == "near".parse().unwrap() {
-            for (receiver_id, amount) in payout.payout {
-                Promise::new(receiver_id).transfer(amount.0);
-            }
-            // refund all FTs (won't be any)
-            price
-        } else {
-            // FT payouts
-            for (receiver_id, amount) in payout.payout {
-                ext_contract::ft_transfer(
-                    receiver_id,
-                    amount,
-                    None,
-                    ft_token_id.clone(),
-                    1,
-                    GAS_FOR_FT_TRANSFER,
-                );
-            }
-            // keep all FTs (already transferred for payouts)
-            U128(0)
-        }
+        let payout = fees.payout_from_result(&value, price.0, &owner_id);
+        NearEvent::auction_finish(PurchaseData {
+            auction_id: Some(auction_id),
+            owner_id,
+            nft_contract_id: auction.nft_contract_id,
+            token_id: auction.token_id,
+            buyer_id,
+            ft_token_id: ft_token_id.clone(),
+            price,
+            payout: payout.clone(),
+        })
+        .emit();
+        self.internal_pay_out(&ft_token_id, payout);
+        U128(0)
     }
 
-    fn token_type_to_ft_token_type(&self, token_type: TokenType) -> AccountId {
+    pub(crate) fn token_type_to_ft_token_type(&self, token_type: TokenType) -> AccountId {
         let token_type = if let Some(token_type) = token_type {
             AccountId::new_unchecked(token_type)
         } else {
@@ -312,6 +498,7 @@ impl Market {
     }
 
     pub(crate) fn json_from_auction(&self, auction: Auction) -> AuctionJson {
+        let reserve_met = auction.reserve_price.map(|_| auction.reserve_met());
         AuctionJson {
             owner_id: auction.owner_id,
             nft_contract_id: auction.nft_contract_id,
@@ -322,8 +509,438 @@ impl Market {
             minimal_step: auction.minimal_step.into(),
             start_price: auction.start_price.into(),
             buy_out_price: auction.buy_out_price.map(|p| p.into()),
+            reserve_met,
             start: auction.start.into(),
             end: auction.end.into(),
+            kind: auction.kind,
+            protocol_fee: auction.protocol_fee,
+            extension: auction.extension,
+            extension_count: auction.extension_count,
+            extension_total: auction.extension_total.into(),
         }
     }
 }
+
+impl Market {
+    // Returns the part of the deposit which should be refunded to the bidder
+    pub(crate) fn internal_auction_add_bid(
+        &mut self,
+        auction_id: U128,
+        ft_token_id: AccountId,
+        bidder_id: AccountId,
+        deposit: Balance,
+        origins: Option<Origins>,
+    ) -> Balance {
+        require!(
+            self.market.ft_token_ids.contains(&ft_token_id),
+            "token not supported"
+        );
+        require!(
+            self.check_auction_in_progress(auction_id),
+            "Auction is not in progress"
+        );
+        let mut auction = self
+            .market
+            .auctions
+            .get(&auction_id.into())
+            .unwrap_or_else(|| env::panic_str("auction not active"));
+        require!(
+            auction.ft_token_id == ft_token_id,
+            format!("Auction accepts only {}", auction.ft_token_id)
+        );
+        require!(
+            auction.owner_id != bidder_id,
+            "Cannot bid on your own auction"
+        );
+        match auction.kind {
+            AuctionKind::English => {}
+            AuctionKind::Dutch { .. } => {
+                return self.internal_dutch_auction_buy(
+                    auction_id,
+                    auction,
+                    ft_token_id,
+                    bidder_id,
+                    deposit,
+                    origins,
+                );
+            }
+            AuctionKind::Sealed { .. } => {
+                env::panic_str("Sealed bids should be committed with auction_commit_bid")
+            }
+        }
+        let min_deposit = calculate_price_with_fees(
+            self.get_minimal_next_bid(auction_id),
+            origins.as_ref(),
+            auction.protocol_fee,
+        );
+
+        // Check that the bid is not smaller than the minimal allowed bid
+        require!(
+            deposit >= min_deposit,
+            format!("Should bid at least {}", min_deposit)
+        );
+        //Return previous bid
+        let listing = BidListing::Auction { auction_id };
+        if let Some(previous_bid) = auction.bid {
+            self.internal_unindex_bid(&previous_bid.owner_id, &listing);
+            self.refund_bid(ft_token_id.clone(), previous_bid.owner_id, previous_bid.price);
+        }
+        // If the price is bigger than the buy_out_price, the auction end is set to the current time
+        let mut bought_out = false;
+        if let Some(buy_out_price) = auction.buy_out_price {
+            if calculate_price_with_fees(
+                buy_out_price.into(),
+                origins.as_ref(),
+                auction.protocol_fee,
+            ) <= deposit
+            {
+                auction.end = env::block_timestamp();
+                bought_out = true;
+            }
+        }
+        // Create a bid
+        self.escrow_deposit(&ft_token_id, deposit);
+        self.internal_index_bid(&bidder_id, &listing);
+        let bid = Bid {
+            owner_id: bidder_id,
+            price: deposit.into(),
+            start: env::block_timestamp().into(),
+            end: None,
+            origins: origins.unwrap_or_default(),
+        };
+        // Extend the auction if the bid is added within the extension window before the auction end
+        // and the token is not bought out
+        auction.bid = Some(bid.clone());
+        NearEvent::auction_bid(AuctionBidData {
+            auction_id,
+            ft_token_id,
+            bid,
+        })
+        .emit();
+        if !bought_out && auction.extend() {
+            NearEvent::auction_extend(AuctionExtendData {
+                auction_id,
+                end: auction.end.into(),
+            })
+            .emit();
+        }
+        self.market.auctions.insert(&auction_id.into(), &auction);
+        0
+    }
+
+    // Inserts the auction with the indexes for views and storage accounting
+    pub(crate) fn internal_insert_auction(&mut self, auction_id: u128, auction: &Auction) {
+        self.market.auctions.insert(&auction_id, auction);
+        let contract_and_token_id = format!(
+            "{}{}{}",
+            auction.nft_contract_id, DELIMETER, auction.token_id
+        );
+        let listing = TokenListing::Auction { auction_id };
+        self.internal_index_listing(&contract_and_token_id, &listing);
+        let mut by_owner_id = self
+            .market
+            .auctions_by_owner_id
+            .get(&auction.owner_id)
+            .unwrap_or_else(|| {
+                UnorderedSet::new(StorageKey::AuctionsByOwnerIdInner {
+                    account_id_hash: hash_account_id(&auction.owner_id),
+                })
+            });
+        by_owner_id.insert(&auction_id);
+        self.market
+            .auctions_by_owner_id
+            .insert(&auction.owner_id, &by_owner_id);
+
+        let mut by_nft_contract_id = self
+            .market
+            .auctions_by_nft_contract_id
+            .get(&auction.nft_contract_id)
+            .unwrap_or_else(|| {
+                UnorderedSet::new(StorageKey::AuctionsByNFTContractIdInner {
+                    account_id_hash: hash_account_id(&auction.nft_contract_id),
+                })
+            });
+        by_nft_contract_id.insert(&auction_id);
+        self.market
+            .auctions_by_nft_contract_id
+            .insert(&auction.nft_contract_id, &by_nft_contract_id);
+    }
+
+    // Removes the auction with its indexes, returns `None` if there is no such auction
+    pub(crate) fn internal_remove_auction(&mut self, auction_id: u128) -> Option<Auction> {
+        let auction = self.market.auctions.remove(&auction_id)?;
+        let contract_and_token_id = format!(
+            "{}{}{}",
+            auction.nft_contract_id, DELIMETER, auction.token_id
+        );
+        let listing = TokenListing::Auction { auction_id };
+        self.internal_unindex_listing(&contract_and_token_id, &listing);
+        let listing = BidListing::Auction {
+            auction_id: auction_id.into(),
+        };
+        if let Some(bid) = &auction.bid {
+            self.internal_unindex_bid(&bid.owner_id, &listing);
+        }
+        if let AuctionKind::Sealed { bids, .. } = &auction.kind {
+            for bid in bids {
+                self.internal_unindex_bid(&bid.owner_id, &listing);
+            }
+        }
+        let mut by_owner_id = self
+            .market
+            .auctions_by_owner_id
+            .get(&auction.owner_id)
+            .expect("No auction by_owner_id");
+        by_owner_id.remove(&auction_id);
+        if by_owner_id.is_empty() {
+            self.market.auctions_by_owner_id.remove(&auction.owner_id);
+        } else {
+            self.market
+                .auctions_by_owner_id
+                .insert(&auction.owner_id, &by_owner_id);
+        }
+
+        let mut by_nft_contract_id = self
+            .market
+            .auctions_by_nft_contract_id
+            .get(&auction.nft_contract_id)
+            .expect("No auction by_nft_contract_id");
+        by_nft_contract_id.remove(&auction_id);
+        if by_nft_contract_id.is_empty() {
+            self.market
+                .auctions_by_nft_contract_id
+                .remove(&auction.nft_contract_id);
+        } else {
+            self.market
+                .auctions_by_nft_contract_id
+                .insert(&auction.nft_contract_id, &by_nft_contract_id);
+        }
+        Some(auction)
+    }
+
+    // Credits the bid and the sealed deposits of the removed auction to the claimable balances
+    pub(crate) fn credit_auction_bids(&mut self, auction: Auction) {
+        if let Some(bid) = auction.bid {
+            self.internal_credit_claimable(&bid.owner_id, &auction.ft_token_id, bid.price.0);
+        }
+        if let AuctionKind::Sealed { bids, .. } = auction.kind {
+            for bid in bids {
+                self.internal_credit_claimable(&bid.owner_id, &auction.ft_token_id, bid.deposit.0);
+            }
+        }
+    }
+
+    // The first buyer who covers the current price with fees wins the Dutch auction immediately
+    fn internal_dutch_auction_buy(
+        &mut self,
+        auction_id: U128,
+        mut auction: Auction,
+        ft_token_id: AccountId,
+        buyer_id: AccountId,
+        deposit: Balance,
+        origins: Option<Origins>,
+    ) -> Balance {
+        let price = auction.dutch_price().expect("Not a Dutch auction");
+        let price_with_fees =
+            calculate_price_with_fees(price.into(), origins.as_ref(), auction.protocol_fee);
+        require!(
+            deposit >= price_with_fees,
+            format!("Should pay at least {}", price_with_fees)
+        );
+        self.escrow_deposit(&ft_token_id, price_with_fees);
+        let bid = Bid {
+            owner_id: buyer_id,
+            price: price_with_fees.into(),
+            start: env::block_timestamp().into(),
+            end: None,
+            origins: origins.unwrap_or_default(),
+        };
+        auction.bid = Some(bid.clone());
+        auction.end = env::block_timestamp();
+        NearEvent::auction_bid(AuctionBidData {
+            auction_id,
+            ft_token_id,
+            bid,
+        })
+        .emit();
+        self.internal_remove_auction(auction_id.into());
+        self.settle_auction(auction_id, auction);
+        deposit - price_with_fees
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::testing_env;
+
+    use super::*;
+    use crate::market_core::NonFungibleTokenApprovalReceiver;
+    use crate::test_utils::{account, auction, bid, context, new_market};
+
+    fn dutch_auction(step: Option<u64>) -> Auction {
+        Auction {
+            minimal_step: 0,
+            start: 100,
+            end: 1100,
+            kind: AuctionKind::Dutch {
+                floor_price: U128(200),
+                step: step.map(U64),
+            },
+            ..auction(None)
+        }
+    }
+
+    fn price_at(auction: &Auction, timestamp: u64) -> u128 {
+        testing_env!(VMContextBuilder::new().block_timestamp(timestamp).build());
+        auction.dutch_price().unwrap()
+    }
+
+    #[test]
+    fn dutch_price_linear() {
+        let auction = dutch_auction(None);
+        assert_eq!(price_at(&auction, 50), 1000);
+        assert_eq!(price_at(&auction, 100), 1000);
+        assert_eq!(price_at(&auction, 350), 800);
+        assert_eq!(price_at(&auction, 600), 600);
+        assert_eq!(price_at(&auction, 1100), 200);
+        assert_eq!(price_at(&auction, 5000), 200);
+    }
+
+    #[test]
+    fn dutch_price_stepped() {
+        let auction = dutch_auction(Some(300));
+        assert_eq!(price_at(&auction, 399), 1000);
+        assert_eq!(price_at(&auction, 400), 760);
+        assert_eq!(price_at(&auction, 1099), 280);
+        assert_eq!(price_at(&auction, 1100), 200);
+    }
+
+    #[test]
+    fn dutch_price_no_overflow() {
+        let mut auction = dutch_auction(None);
+        auction.start_price = u128::MAX;
+        auction.end = auction.start + MAX_DURATION;
+        let price = price_at(&auction, auction.start + MAX_DURATION / 2);
+        assert!(price > u128::MAX / 2 && price < u128::MAX / 2 + 200);
+    }
+
+    #[test]
+    fn reserve_met() {
+        let mut auction = dutch_auction(None);
+        assert!(auction.reserve_met());
+        auction.reserve_price = Some(1000);
+        assert!(!auction.reserve_met());
+        // 1030 includes 3% protocol fee
+        auction.bid = Some(Bid {
+            owner_id: AccountId::new_unchecked("bob".to_string()),
+            price: U128(1020),
+            start: U64(0),
+            end: None,
+            origins: HashMap::new(),
+        });
+        assert!(!auction.reserve_met());
+        auction.bid.as_mut().unwrap().price = U128(1030);
+        assert!(auction.reserve_met());
+    }
+
+    #[test]
+    fn extend_within_limits() {
+        let mut auction = dutch_auction(None);
+        auction.extension = AuctionExtension {
+            window: U64(100),
+            duration: U64(150),
+            max_count: Some(3),
+            max_total: Some(U64(250)),
+        };
+        testing_env!(VMContextBuilder::new().block_timestamp(900).build());
+        assert!(!auction.extend());
+        testing_env!(VMContextBuilder::new().block_timestamp(1050).build());
+        assert!(auction.extend());
+        assert_eq!(auction.end, 1200);
+        // The total length of extensions is capped
+        testing_env!(VMContextBuilder::new().block_timestamp(1150).build());
+        assert!(auction.extend());
+        assert_eq!(auction.end, 1300);
+        assert_eq!(auction.extension_total, 200);
+        testing_env!(VMContextBuilder::new().block_timestamp(1290).build());
+        assert!(auction.extend());
+        assert_eq!(auction.end, 1350);
+        assert_eq!(auction.extension_count, 3);
+        testing_env!(VMContextBuilder::new().block_timestamp(1340).build());
+        assert!(!auction.extend());
+    }
+
+    #[test]
+    fn english_auction_has_no_dutch_price() {
+        let mut auction = dutch_auction(None);
+        auction.kind = AuctionKind::English;
+        assert!(auction.dutch_price().is_none());
+    }
+
+    #[test]
+    fn cancel_auction_removes_indexes() {
+        let alice = account("alice");
+        let nft = account("nft");
+        let mut market = new_market();
+        market.internal_insert_auction(0, &dutch_auction(None));
+        market.internal_insert_auction(1, &dutch_auction(None));
+        assert_eq!(market.internal_listings_count(&alice), 2);
+        assert_eq!(
+            market.get_auctions_by_nft_contract_id(nft.clone(), U64(0), 10).len(),
+            2
+        );
+
+        context("alice", "alice", 1, 0, vec![]);
+        market.cancel_auction(U128(0));
+        market.cancel_auction(U128(1));
+        assert_eq!(market.internal_listings_count(&alice), 0);
+        assert!(market.get_auctions_by_owner_id(alice, U64(0), 10).is_empty());
+        assert!(market.get_auctions_by_nft_contract_id(nft, U64(0), 10).is_empty());
+    }
+
+    #[test]
+    fn dutch_auction_ignores_extension_bounds() {
+        let mut contract = new_market();
+        // The default extension of 15 minutes is out of these bounds
+        contract.market.extension_bounds = ExtensionBounds {
+            min_duration: U64(NANOS_PER_SEC),
+            max_duration: U64(NANOS_PER_SEC),
+        };
+        let args = DutchAuctionArgs {
+            token_type: None,
+            start_price: U128(1000),
+            floor_price: U128(200),
+            step: None,
+            start: None,
+            duration: U64(MIN_DURATION),
+            origins: None,
+        };
+        let (auction_id, _) = contract.start_dutch_auction(
+            args,
+            "1:1".to_string(),
+            account("alice"),
+            0,
+            account("nft"),
+        );
+        assert!(contract.market.auctions.get(&auction_id).is_some());
+    }
+
+    #[test]
+    fn nft_on_revoke_credits_auction_bid() {
+        let mut contract = new_market();
+        contract.escrow_deposit(&account("near"), 1000);
+        contract.internal_insert_auction(0, &auction(Some(bid("bob", 1000))));
+        context("nft", "nft", 0, 0, vec![]);
+        // The auction was made with the approval 1
+        contract.nft_on_revoke("1:1".to_string(), account("alice"), 0);
+        assert!(contract.market.auctions.get(&0).is_some());
+
+        contract.nft_on_revoke("1:1".to_string(), account("alice"), 1);
+        assert!(contract.market.auctions.get(&0).is_none());
+        assert_eq!(
+            contract.get_claimable(account("bob")),
+            HashMap::from([(account("near"), U128(1000))])
+        );
+    }
+}
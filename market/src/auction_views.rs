@@ -1,3 +1,5 @@
+use std::cmp::min;
+
 use crate::auction::AuctionJson;
 use crate::common::*;
 use crate::*;
@@ -36,15 +38,20 @@ impl Market {
     }
 
     // Returns the minimum amount of the next auction bid (not including fees)
+    // For Dutch auctions it is the current price
     pub fn get_minimal_next_bid(&self, auction_id: U128) -> U128 {
         let auction = self
             .market
             .auctions
             .get(&auction_id.into())
             .unwrap_or_else(|| env::panic_str("Auction does not exist"));
-        let min_deposit = if let Some(ref bid) = auction.bid {
+        let min_deposit = if let Some(price) = auction.dutch_price() {
+            price
+        } else if let Some(ref bid) = auction.bid {
             let total_origins = fee::calculate_origins(&bid.origins);
-            let actual_amount = fee::calculate_actual_amount(bid.price.0, total_origins); // TODO: need more tests here
+            // TODO: need more tests here
+            let actual_amount =
+                fee::calculate_actual_amount(bid.price.0, total_origins, auction.protocol_fee);
             actual_amount + auction.minimal_step
         } else {
             auction.start_price
@@ -52,6 +59,19 @@ impl Market {
         U128(min_deposit)
     }
 
+    // Returns the current price of the Dutch auction (not including fees)
+    pub fn get_current_price(&self, auction_id: U128) -> U128 {
+        let auction = self
+            .market
+            .auctions
+            .get(&auction_id.into())
+            .unwrap_or_else(|| env::panic_str("Auction does not exist"));
+        auction
+            .dutch_price()
+            .unwrap_or_else(|| env::panic_str("Not a Dutch auction"))
+            .into()
+    }
+
     // Returns current bid amount (not including fees)
     pub fn get_current_bid(&self, auction_id: U128) -> Option<U128> {
         let auction = self
@@ -59,10 +79,12 @@ impl Market {
             .auctions
             .get(&auction_id.into())
             .unwrap_or_else(|| env::panic_str("Auction does not exist"));
+        let protocol_fee = auction.protocol_fee;
         auction.bid.map(|bid| {
             {
                 let total_origins = fee::calculate_origins(&bid.origins);
-                let actual_amount = fee::calculate_actual_amount(bid.price.0, total_origins);
+                let actual_amount =
+                    fee::calculate_actual_amount(bid.price.0, total_origins, protocol_fee);
                 actual_amount
             }
             .into()
@@ -81,5 +103,48 @@ impl Market {
             .collect()
     }
 
+    pub fn get_auctions_by_owner_id(
+        &self,
+        account_id: AccountId,
+        from_index: U64,
+        limit: u64,
+    ) -> Vec<AuctionJson> {
+        match self.market.auctions_by_owner_id.get(&account_id) {
+            Some(auction_ids) => self.auctions_from_index(&auction_ids, from_index, limit),
+            None => vec![],
+        }
+    }
+
+    pub fn get_auctions_by_nft_contract_id(
+        &self,
+        nft_contract_id: AccountId,
+        from_index: U64,
+        limit: u64,
+    ) -> Vec<AuctionJson> {
+        match self.market.auctions_by_nft_contract_id.get(&nft_contract_id) {
+            Some(auction_ids) => self.auctions_from_index(&auction_ids, from_index, limit),
+            None => vec![],
+        }
+    }
+
     //pub fn get_bid_total_amount() -> U128;
 }
+
+impl Market {
+    fn auctions_from_index(
+        &self,
+        auction_ids: &UnorderedSet<u128>,
+        from_index: U64,
+        limit: u64,
+    ) -> Vec<AuctionJson> {
+        let keys = auction_ids.as_vector();
+        let start = u64::from(from_index);
+        let end = min(start.saturating_add(limit), keys.len());
+        (start..end)
+            .map(|i| {
+                let auction = self.market.auctions.get(&keys.get(i).unwrap()).unwrap();
+                self.json_from_auction(auction)
+            })
+            .collect()
+    }
+}
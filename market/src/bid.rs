@@ -1,11 +1,12 @@
+use std::cmp::min;
 use std::collections::HashMap;
 
+use near_contract_standards::non_fungible_token::hash_account_id;
 use near_sdk::assert_one_yocto;
 
+use crate::event::{BidData, NearEvent};
 use crate::fee::{calculate_actual_amount, calculate_origins};
-use crate::sale::{
-    ext_contract, ContractAndTokenId, FungibleTokenId, Sale, DELIMETER, GAS_FOR_FT_TRANSFER,
-};
+use crate::sale::{ContractAndTokenId, FungibleTokenId, Sale, DELIMETER};
 use crate::*;
 
 #[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
@@ -37,6 +38,34 @@ impl Bid {
 pub type Bids = HashMap<FungibleTokenId, Vec<Bid>>;
 pub type Origins = HashMap<AccountId, u32>;
 
+// Open bid of the bidder, the bids of a sale are identified by the currency and the price
+// like in `remove_bid`
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum BidListing {
+    Sale {
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        ft_token_id: FungibleTokenId,
+        price: U128,
+    },
+    Auction {
+        auction_id: U128,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BidderBidJson {
+    pub listing: BidListing,
+    pub ft_token_id: FungibleTokenId,
+    #[serde(flatten)]
+    pub bid: Bid,
+    // The bid is the highest one in its currency
+    pub leading: bool,
+}
+
 #[near_bindgen]
 impl Market {
     // Adds a bid if it is higher than the last bid of this ft_token_id
@@ -65,7 +94,7 @@ impl Market {
         };
 
         require!(total_origins < 4_700, "Max origins exceeded"); // TODO: FINDOUT MAX ORIGINS
-        let actual_amount = calculate_actual_amount(amount, total_origins);
+        let actual_amount = calculate_actual_amount(amount, total_origins, sale.protocol_fee);
 
         // store a bid and refund any current bid lower
         let new_bid = Bid {
@@ -82,7 +111,11 @@ impl Market {
             .or_insert_with(Vec::new);
         if let Some(current_bid) = bids_for_token_id.last() {
             let current_origins = calculate_origins(&current_bid.origins);
-            let current_amount = calculate_actual_amount(current_bid.price.0, current_origins);
+            let current_amount = calculate_actual_amount(
+                current_bid.price.0,
+                current_origins,
+                sale.protocol_fee,
+            );
             require!(
                 actual_amount > current_amount,
                 format!(
@@ -92,12 +125,39 @@ impl Market {
             );
         }
 
-        bids_for_token_id.push(new_bid);
+        bids_for_token_id.push(new_bid.clone());
+        self.escrow_deposit(&ft_token_id, amount);
+        self.internal_index_bid(
+            &new_bid.owner_id,
+            &BidListing::sale(&sale.nft_contract_id, &sale.token_id, &ft_token_id, new_bid.price),
+        );
+        NearEvent::bid_add(BidData {
+            nft_contract_id: sale.nft_contract_id.clone(),
+            token_id: sale.token_id.clone(),
+            ft_token_id: ft_token_id.clone(),
+            bid: new_bid,
+        })
+        .emit();
         if bids_for_token_id.len() > self.market.bid_history_length as usize {
             // Need to refund the earliest bid before removing it
-            let early_bid = &bids_for_token_id[0];
-            self.refund_bid(ft_token_id, early_bid.owner_id.clone(), early_bid.price);
-            bids_for_token_id.remove(0);
+            let early_bid = bids_for_token_id.remove(0);
+            self.refund_bid(ft_token_id.clone(), early_bid.owner_id.clone(), early_bid.price);
+            self.internal_unindex_bid(
+                &early_bid.owner_id,
+                &BidListing::sale(
+                    &sale.nft_contract_id,
+                    &sale.token_id,
+                    &ft_token_id,
+                    early_bid.price,
+                ),
+            );
+            NearEvent::bid_refund(BidData {
+                nft_contract_id: sale.nft_contract_id.clone(),
+                token_id: sale.token_id.clone(),
+                ft_token_id,
+                bid: early_bid,
+            })
+            .emit();
         }
 
         self.market.sales.insert(&contract_and_token_id, sale);
@@ -113,8 +173,23 @@ impl Market {
     ) {
         assert_one_yocto();
         let owner_id = env::predecessor_account_id();
-        self.internal_remove_bid(nft_contract_id, &ft_token_id, token_id, &owner_id, price);
-        self.refund_bid(ft_token_id, owner_id, price);
+        let bid = self
+            .internal_remove_bid(
+                nft_contract_id.clone(),
+                &ft_token_id,
+                token_id.clone(),
+                &owner_id,
+                price,
+            )
+            .expect("No such bid");
+        self.refund_bid(ft_token_id.clone(), owner_id, price);
+        NearEvent::bid_remove(BidData {
+            nft_contract_id,
+            token_id,
+            ft_token_id,
+            bid,
+        })
+        .emit();
     }
 
     // Cancels the bid if it has ended
@@ -128,12 +203,25 @@ impl Market {
         price: U128,
     ) {
         let bid = self
-            .internal_remove_bid(nft_contract_id, &ft_token_id, token_id, &owner_id, price)
+            .internal_remove_bid(
+                nft_contract_id.clone(),
+                &ft_token_id,
+                token_id.clone(),
+                &owner_id,
+                price,
+            )
             .expect("No such bid");
         if let Some(end) = bid.end {
             let is_finished = env::block_timestamp() >= end.0;
             require!(is_finished, "The bid hasn't ended yet");
-            self.refund_bid(ft_token_id, owner_id, price);
+            self.refund_bid(ft_token_id.clone(), owner_id, price);
+            NearEvent::bid_cancel(BidData {
+                nft_contract_id,
+                token_id,
+                ft_token_id,
+                bid,
+            })
+            .emit();
         } else {
             panic!("The bid doesn't have an end");
         }
@@ -168,6 +256,22 @@ impl Market {
                         bid_from_vec.owner_id.clone(),
                         bid_from_vec.price,
                     );
+                    self.internal_unindex_bid(
+                        &bid_from_vec.owner_id,
+                        &BidListing::sale(
+                            &nft_contract_id,
+                            &token_id,
+                            &ft_token_id,
+                            bid_from_vec.price,
+                        ),
+                    );
+                    NearEvent::bid_cancel(BidData {
+                        nft_contract_id: nft_contract_id.clone(),
+                        token_id: token_id.clone(),
+                        ft_token_id: ft_token_id.clone(),
+                        bid: bid_from_vec.clone(),
+                    })
+                    .emit();
                     not_finished = false;
                 };
             }
@@ -182,22 +286,182 @@ impl Market {
         };
         self.market.sales.insert(&contract_and_token_id, &sale);
     }
+
+    // Returns the open bids of the account on sales and auctions
+    // The bids of a sale being sold are hidden until the sale is restored or its bids refunded
+    pub fn get_bids_by_bidder(
+        &self,
+        account_id: AccountId,
+        from_index: U64,
+        limit: u64,
+    ) -> Vec<BidderBidJson> {
+        let listings = match self.market.bids_by_bidder.get(&account_id) {
+            Some(listings) => listings,
+            None => return vec![],
+        };
+        let keys = listings.as_vector();
+        let start = u64::from(from_index);
+        let end = min(start.saturating_add(limit), keys.len());
+        (start..end)
+            .filter_map(|i| self.bidder_bid_json(&account_id, keys.get(i).unwrap()))
+            .collect()
+    }
+}
+
+impl BidListing {
+    pub(crate) fn sale(
+        nft_contract_id: &AccountId,
+        token_id: &TokenId,
+        ft_token_id: &FungibleTokenId,
+        price: U128,
+    ) -> Self {
+        BidListing::Sale {
+            nft_contract_id: nft_contract_id.clone(),
+            token_id: token_id.clone(),
+            ft_token_id: ft_token_id.clone(),
+            price,
+        }
+    }
 }
 
 impl Market {
-    pub(crate) fn refund_all_bids(&mut self, bids_map: &Bids) {
+    pub(crate) fn internal_index_bid(&mut self, bidder_id: &AccountId, listing: &BidListing) {
+        let mut listings = self
+            .market
+            .bids_by_bidder
+            .get(bidder_id)
+            .unwrap_or_else(|| {
+                UnorderedSet::new(StorageKey::BidsByBidderInner {
+                    account_id_hash: hash_account_id(bidder_id),
+                })
+            });
+        listings.insert(listing);
+        self.market.bids_by_bidder.insert(bidder_id, &listings);
+    }
+
+    pub(crate) fn internal_unindex_bid(&mut self, bidder_id: &AccountId, listing: &BidListing) {
+        let mut listings = match self.market.bids_by_bidder.get(bidder_id) {
+            Some(listings) => listings,
+            None => return,
+        };
+        listings.remove(listing);
+        if listings.is_empty() {
+            self.market.bids_by_bidder.remove(bidder_id);
+        } else {
+            self.market.bids_by_bidder.insert(bidder_id, &listings);
+        }
+    }
+
+    // Indexes or unindexes every bid of the sale when the sale is listed or removed
+    pub(crate) fn internal_index_sale_bids(&mut self, sale: &Sale, index: bool) {
+        for (ft_token_id, bids) in sale.bids.iter() {
+            for bid in bids {
+                let listing =
+                    BidListing::sale(&sale.nft_contract_id, &sale.token_id, ft_token_id, bid.price);
+                if index {
+                    self.internal_index_bid(&bid.owner_id, &listing);
+                } else {
+                    self.internal_unindex_bid(&bid.owner_id, &listing);
+                }
+            }
+        }
+    }
+
+    fn bidder_bid_json(&self, bidder_id: &AccountId, listing: BidListing) -> Option<BidderBidJson> {
+        match listing {
+            BidListing::Sale {
+                ref nft_contract_id,
+                ref token_id,
+                ref ft_token_id,
+                price,
+            } => {
+                let contract_and_token_id =
+                    format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+                let mut sale = self.market.sales.get(&contract_and_token_id)?;
+                let mut bids = sale.bids.remove(ft_token_id)?;
+                let index = bids
+                    .iter()
+                    .position(|bid| &bid.owner_id == bidder_id && bid.price == price)?;
+                let leading = index + 1 == bids.len();
+                Some(BidderBidJson {
+                    ft_token_id: ft_token_id.clone(),
+                    bid: bids.swap_remove(index),
+                    leading,
+                    listing,
+                })
+            }
+            BidListing::Auction { auction_id } => {
+                let auction = self.market.auctions.get(&auction_id.0)?;
+                // A committed sealed bid shows its deposit, it is never known to be leading
+                if let AuctionKind::Sealed { bids, .. } = auction.kind {
+                    let sealed_bid = bids.into_iter().find(|bid| &bid.owner_id == bidder_id)?;
+                    return Some(BidderBidJson {
+                        listing,
+                        ft_token_id: auction.ft_token_id,
+                        bid: Bid {
+                            owner_id: sealed_bid.owner_id,
+                            price: sealed_bid.deposit,
+                            start: auction.start.into(),
+                            end: None,
+                            origins: sealed_bid.origins,
+                        },
+                        leading: false,
+                    });
+                }
+                let bid = auction.bid.filter(|bid| &bid.owner_id == bidder_id)?;
+                Some(BidderBidJson {
+                    listing,
+                    ft_token_id: auction.ft_token_id,
+                    bid,
+                    leading: true,
+                })
+            }
+        }
+    }
+
+    pub(crate) fn refund_all_bids(
+        &mut self,
+        nft_contract_id: &AccountId,
+        token_id: &TokenId,
+        bids_map: &Bids,
+    ) {
         for (ft, bids) in bids_map {
             for bid in bids {
                 self.refund_bid((*ft).clone(), bid.owner_id.clone(), bid.price);
+                NearEvent::bid_refund(BidData {
+                    nft_contract_id: nft_contract_id.clone(),
+                    token_id: token_id.clone(),
+                    ft_token_id: (*ft).clone(),
+                    bid: bid.clone(),
+                })
+                .emit();
             }
         }
     }
 
-    pub(crate) fn refund_bid(&mut self, bid_ft: FungibleTokenId, owner_id: AccountId, price: U128) {
-        if bid_ft.as_str() == "near" {
-            Promise::new(owner_id).transfer(u128::from(price));
-        } else {
-            ext_contract::ft_transfer(owner_id, price, None, bid_ft, 1, GAS_FOR_FT_TRANSFER);
+    // Like `refund_all_bids`, but the bids stay on the market as claimable balances of the bidders
+    pub(crate) fn credit_all_bids(
+        &mut self,
+        nft_contract_id: &AccountId,
+        token_id: &TokenId,
+        bids_map: &Bids,
+    ) {
+        for (ft, bids) in bids_map {
+            for bid in bids {
+                self.internal_credit_claimable(&bid.owner_id, ft, bid.price.0);
+                NearEvent::bid_refund(BidData {
+                    nft_contract_id: nft_contract_id.clone(),
+                    token_id: token_id.clone(),
+                    ft_token_id: (*ft).clone(),
+                    bid: bid.clone(),
+                })
+                .emit();
+            }
         }
     }
+
+    pub(crate) fn refund_bid(&mut self, bid_ft: FungibleTokenId, owner_id: AccountId, price: U128) {
+        self.escrow_release(&bid_ft, price.0);
+        self.internal_send(bid_ft, owner_id, price);
+    }
 }
@@ -0,0 +1,998 @@
+use near_contract_standards::non_fungible_token::hash_account_id;
+use near_sdk::{Gas, PromiseResult};
+use std::cmp::min;
+use std::collections::{HashMap, HashSet};
+
+use crate::bid::{Bid, Origins};
+use crate::claimable::{
+    ClaimableToken, GAS_FOR_MARKET_NFT_TRANSFER, GAS_FOR_RESOLVE_NFT_TRANSFERS, GAS_PER_SEND,
+};
+use crate::event::{
+    BundleBidData, BundlePayoutFailData, BundlePurchaseData, BundleRemoveData, NearEvent,
+};
+use crate::fee::{calculate_actual_amount, calculate_origins, calculate_price_with_fees};
+use crate::market_core::{BundleArgs, BundleTokenArgs};
+use crate::sale::{
+    ext_contract, ext_self, FungibleTokenId, DELIMETER, GAS_FOR_NFT_TOKEN, MAX_LEN_PAYOUT,
+    NO_DEPOSIT,
+};
+use crate::*;
+
+pub const MAX_BUNDLE_TOKENS: usize = 4;
+// Unlike `GAS_FOR_NFT_TRANSFER` doesn't pay for the revoke notifications to fit in the gas limit
+// with every token, the other approved accounts are notified only if the gas is left
+pub const GAS_FOR_BUNDLE_NFT_TRANSFER: Gas = Gas(30_000_000_000_000);
+pub const GAS_FOR_RESOLVE_CREATE_BUNDLE: Gas = Gas(20_000_000_000_000);
+// The purchase pays out at most this number of transfers, the other receivers are credited,
+// so that it fits in the gas limit with the transfers of every token
+pub const MAX_BUNDLE_PAY_OUT: u32 = 4;
+// Sends the tokens held by the market to the buyer or back to the owner and pays out
+pub const GAS_FOR_RESOLVE_BUNDLE: Gas = Gas(15_000_000_000_000
+    + GAS_FOR_MARKET_NFT_TRANSFER.0 * MAX_BUNDLE_TOKENS as u64
+    + GAS_FOR_RESOLVE_NFT_TRANSFERS.0
+    + GAS_PER_SEND.0 * MAX_BUNDLE_PAY_OUT as u64);
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BundleToken {
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub approval_id: u64,
+}
+
+// Several tokens sold or auctioned as one lot
+// The price is split equally between the tokens, each share is paid out with the royalties
+// of its token
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Bundle {
+    pub owner_id: AccountId,
+    pub tokens: Vec<BundleToken>,
+    // The price per currency, or the start price of the auction
+    pub sale_conditions: SaleConditions,
+    // Set only for auctions
+    pub minimal_step: Option<U128>,
+    pub bid: Option<Bid>,
+    pub created_at: U64,
+
+    pub start: U64,
+    pub end: Option<U64>,
+
+    pub origins: Origins,
+    pub protocol_fee: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BundleJson {
+    pub bundle_id: U128,
+    #[serde(flatten)]
+    pub bundle: Bundle,
+}
+
+impl Bundle {
+    pub fn in_limits(&self) -> bool {
+        let now = env::block_timestamp();
+        self.start.0 <= now && !matches!(self.end, Some(end) if now >= end.0)
+    }
+}
+
+// Shares of the tokens in the price, the first token takes the remainder
+pub(crate) fn split_price(price: u128, tokens: usize) -> Vec<u128> {
+    let share = price / tokens as u128;
+    let mut shares = vec![share; tokens];
+    shares[0] += price - share * tokens as u128;
+    shares
+}
+
+// Approval id of the market for the token returned by `nft_token` in the promise result,
+// `None` if the token doesn't belong to `owner_id` or is not approved for the market
+pub(crate) fn market_approval_id(result_index: u64, owner_id: &AccountId) -> Option<u64> {
+    let token = match env::promise_result(result_index) {
+        PromiseResult::Successful(value) => {
+            near_sdk::serde_json::from_slice::<Option<Token>>(&value)
+                .ok()
+                .flatten()
+        }
+        _ => None,
+    };
+    token
+        .filter(|token| &token.owner_id == owner_id)
+        .and_then(|token| token.approved_account_ids)
+        .and_then(|ids| ids.get(&env::current_account_id()).copied())
+}
+
+#[near_bindgen]
+impl Market {
+    // Lists the approved tokens as one lot, the storage is taken like for one sale
+    // The owner and the approvals of the tokens are checked with `nft_token`
+    // Returns the id of the bundle
+    pub fn create_bundle(&mut self, args: BundleArgs) -> Promise {
+        let owner_id = env::predecessor_account_id();
+        require!(
+            args.tokens.len() >= 2 && args.tokens.len() <= MAX_BUNDLE_TOKENS,
+            format!("A bundle should have from 2 to {} tokens", MAX_BUNDLE_TOKENS)
+        );
+        let mut unique = HashSet::new();
+        for token in args.tokens.iter() {
+            require!(
+                unique.insert((&token.nft_contract_id, &token.token_id)),
+                "The tokens of the bundle should be different"
+            );
+            require!(
+                self.non_fungible_token_account_ids
+                    .contains(&token.nft_contract_id),
+                format!(
+                    "NFT contract {} is not supported by this market",
+                    token.nft_contract_id
+                )
+            );
+            self.assert_not_listed(&token.nft_contract_id, &token.token_id);
+        }
+        require!(
+            !args.sale_conditions.is_empty(),
+            "The bundle should have a price"
+        );
+        for ft_token_id in args.sale_conditions.keys() {
+            require!(
+                self.market.ft_token_ids.contains(ft_token_id),
+                format!("Token {} not supported by this market", ft_token_id)
+            );
+        }
+        if args.minimal_step.is_some() {
+            require!(
+                args.sale_conditions.len() == 1,
+                "The bundle auction accepts only one currency"
+            );
+            require!(
+                args.duration.is_some(),
+                "The bundle auction should have a duration"
+            );
+        }
+        require!(
+            self.storage_covers_new_sale(&owner_id, &owner_id),
+            "User has more sales than storage paid"
+        );
+        args.tokens
+            .iter()
+            .map(|token| {
+                ext_contract::nft_token(
+                    token.token_id.clone(),
+                    token.nft_contract_id.clone(),
+                    NO_DEPOSIT,
+                    GAS_FOR_NFT_TOKEN,
+                )
+            })
+            .reduce(Promise::and)
+            .unwrap()
+            .then(ext_self::resolve_create_bundle(
+                owner_id,
+                args,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_RESOLVE_CREATE_BUNDLE,
+            ))
+    }
+
+    // Buys the bundle at the price with fees
+    #[payable]
+    pub fn buy_bundle(
+        &mut self,
+        bundle_id: U128,
+        ft_token_id: FungibleTokenId,
+        origins: Option<Origins>,
+    ) -> Promise {
+        // Fungible tokens are paid through `ft_on_transfer`, the attached deposit is always NEAR
+        require!(
+            ft_token_id.as_str() == "near",
+            "Fungible tokens should be paid with ft_transfer_call"
+        );
+        self.internal_buy_bundle(
+            bundle_id,
+            ft_token_id,
+            env::predecessor_account_id(),
+            env::attached_deposit(),
+            origins,
+        )
+    }
+
+    // Bids on the bundle auction, the previous bid is refunded
+    #[payable]
+    pub fn bundle_add_bid(&mut self, bundle_id: U128, origins: Option<Origins>) {
+        self.internal_bundle_add_bid(
+            bundle_id,
+            AccountId::new_unchecked("near".to_owned()),
+            env::predecessor_account_id(),
+            env::attached_deposit(),
+            origins,
+        );
+    }
+
+    // Sells the bundle to the last bid after the auction end, can be called by anyone
+    // Without bids the bundle is removed and `None` is returned
+    pub fn finish_bundle_auction(&mut self, bundle_id: U128) -> Option<Promise> {
+        let bundle = self
+            .market
+            .bundles
+            .get(&bundle_id.0)
+            .unwrap_or_else(|| env::panic_str("No bundle"));
+        require!(
+            bundle.minimal_step.is_some(),
+            "The bundle is not auctioned"
+        );
+        require!(
+            matches!(bundle.end, Some(end) if env::block_timestamp() > end.0),
+            "Bundle auction can be finalized only after the end time"
+        );
+        let bundle = self.internal_remove_bundle(bundle_id.0);
+        let bid = bundle.bid.clone()?;
+        let ft_token_id = bundle.sale_conditions.keys().next().unwrap().clone();
+        Some(self.settle_bundle(
+            bundle_id,
+            bundle,
+            ft_token_id,
+            bid.owner_id,
+            bid.price,
+            bid.origins,
+        ))
+    }
+
+    // Removes the bundle, an auction can't be removed after the first bid
+    #[payable]
+    pub fn remove_bundle(&mut self, bundle_id: U128) {
+        assert_one_yocto();
+        let bundle = self.internal_remove_bundle(bundle_id.0);
+        require!(
+            env::predecessor_account_id() == bundle.owner_id,
+            "Only the bundle owner can remove the bundle"
+        );
+        require!(
+            bundle.bid.is_none(),
+            "Can't remove the bundle after the first bid is made"
+        );
+    }
+
+    pub fn get_bundle(&self, bundle_id: U128) -> Option<BundleJson> {
+        self.market
+            .bundles
+            .get(&bundle_id.0)
+            .map(|bundle| BundleJson { bundle_id, bundle })
+    }
+
+    pub fn get_bundles(&self, from_index: Option<U64>, limit: Option<u64>) -> Vec<BundleJson> {
+        self.market
+            .bundles
+            .iter()
+            .skip(from_index.map(u64::from).unwrap_or_default() as usize)
+            .take(limit.map(|limit| limit as usize).unwrap_or(usize::MAX))
+            .map(|(bundle_id, bundle)| BundleJson {
+                bundle_id: bundle_id.into(),
+                bundle,
+            })
+            .collect()
+    }
+
+    pub fn get_bundles_by_owner_id(
+        &self,
+        account_id: AccountId,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<BundleJson> {
+        let by_owner_id = match self.market.bundles_by_owner_id.get(&account_id) {
+            Some(by_owner_id) => by_owner_id,
+            None => return vec![],
+        };
+        let ids = by_owner_id.as_vector();
+        let start = from_index.map(u64::from).unwrap_or_default();
+        let end = min(start.saturating_add(limit.unwrap_or(u64::MAX)), ids.len());
+        (start..end)
+            .filter_map(|i| self.get_bundle(ids.get(i).unwrap().into()))
+            .collect()
+    }
+
+    // self callback
+    // Creates the bundle if every token belongs to the owner and is approved for the market
+    #[private]
+    pub fn resolve_create_bundle(&mut self, owner_id: AccountId, args: BundleArgs) -> U128 {
+        let BundleArgs {
+            tokens,
+            sale_conditions,
+            minimal_step,
+            start,
+            duration,
+            origins,
+        } = args;
+        let tokens = tokens
+            .into_iter()
+            .enumerate()
+            .map(|(i, BundleTokenArgs { nft_contract_id, token_id })| {
+                // The token could have been listed while it was requested
+                self.assert_not_listed(&nft_contract_id, &token_id);
+                let approval_id =
+                    market_approval_id(i as u64, &owner_id).unwrap_or_else(|| {
+                        env::panic_str(&format!(
+                            "Token {} of {} is not approved for the market by the owner",
+                            token_id, nft_contract_id
+                        ))
+                    });
+                BundleToken {
+                    nft_contract_id,
+                    token_id,
+                    approval_id,
+                }
+            })
+            .collect();
+        // The storage could have been withdrawn while the tokens were requested
+        require!(
+            self.storage_covers_new_sale(&owner_id, &owner_id),
+            "User has more sales than storage paid"
+        );
+        let start = start.unwrap_or_else(|| env::block_timestamp().into());
+        let bundle = Bundle {
+            owner_id,
+            tokens,
+            sale_conditions,
+            minimal_step,
+            bid: None,
+            created_at: env::block_timestamp().into(),
+            start,
+            end: duration.map(|duration| U64(start.0 + duration.0)),
+            origins: origins.unwrap_or_default(),
+            protocol_fee: self.market.protocol_fee,
+        };
+        let bundle_id = self.market.next_bundle_id;
+        self.market.next_bundle_id += 1;
+        self.internal_insert_bundle(bundle_id, &bundle);
+        NearEvent::bundle_create(BundleJson {
+            bundle_id: bundle_id.into(),
+            bundle,
+        })
+        .emit();
+        bundle_id.into()
+    }
+
+    // self callback
+    // The tokens are transferred to the market first: if all of them are transferred,
+    // they are sent to the buyer and the price is paid out with the royalties of every token
+    // Otherwise the transferred tokens are returned to the owner and the price is refunded,
+    // the bundle is listed again if none of its tokens left the owner
+    // A token which fails to be sent from the market can be claimed, see `claim_token`
+    #[private]
+    pub fn resolve_bundle_purchase(
+        &mut self,
+        bundle_id: U128,
+        bundle: Bundle,
+        ft_token_id: FungibleTokenId,
+        buyer_id: AccountId,
+        price: U128,
+        fees: fee::Fees,
+    ) {
+        // The price is either paid out or refunded to the buyer
+        self.escrow_release(&ft_token_id, price.0);
+        let mut payout: HashMap<AccountId, U128> = HashMap::new();
+        let mut transferred = Vec::with_capacity(bundle.tokens.len());
+        for (i, share) in split_price(price.0, bundle.tokens.len())
+            .into_iter()
+            .enumerate()
+        {
+            let value = match env::promise_result(i as u64) {
+                PromiseResult::Successful(value) => value,
+                _ => {
+                    transferred.push(false);
+                    continue;
+                }
+            };
+            transferred.push(true);
+            let token_payout = fees.payout_from_result(&value, share, &bundle.owner_id);
+            for (receiver_id, amount) in token_payout {
+                payout.entry(receiver_id).or_insert(U128(0)).0 += amount.0;
+            }
+        }
+
+        let sold = transferred.iter().all(|&transferred| transferred);
+        let receiver_id = if sold { &buyer_id } else { &bundle.owner_id };
+        let transfers = bundle
+            .tokens
+            .iter()
+            .zip(&transferred)
+            .filter(|(_, transferred)| **transferred)
+            .map(|(token, _)| {
+                let token = ClaimableToken {
+                    nft_contract_id: token.nft_contract_id.clone(),
+                    token_id: token.token_id.clone(),
+                };
+                (receiver_id.clone(), token)
+            })
+            .collect();
+        self.internal_send_tokens(transfers);
+        if !sold {
+            self.internal_send(ft_token_id.clone(), buyer_id.clone(), price);
+            NearEvent::bundle_payout_fail(BundlePayoutFailData {
+                bundle_id,
+                owner_id: bundle.owner_id.clone(),
+                buyer_id,
+                ft_token_id,
+                price,
+            })
+            .emit();
+            // The approvals of the transferred tokens are gone
+            if !transferred.contains(&true) {
+                self.internal_restore_bundle(bundle_id, bundle);
+            }
+            return;
+        }
+        NearEvent::bundle_purchase(BundlePurchaseData {
+            bundle_id,
+            owner_id: bundle.owner_id,
+            buyer_id,
+            ft_token_id: ft_token_id.clone(),
+            price,
+            payout: payout.clone(),
+        })
+        .emit();
+        self.internal_pay_out_with_limit(&ft_token_id, payout, MAX_BUNDLE_PAY_OUT);
+    }
+}
+
+impl Market {
+    pub(crate) fn internal_buy_bundle(
+        &mut self,
+        bundle_id: U128,
+        ft_token_id: FungibleTokenId,
+        buyer_id: AccountId,
+        deposit: Balance,
+        origins: Option<Origins>,
+    ) -> Promise {
+        let bundle = self
+            .market
+            .bundles
+            .get(&bundle_id.0)
+            .unwrap_or_else(|| env::panic_str("No bundle"));
+        require!(
+            bundle.minimal_step.is_none(),
+            "The bundle is auctioned, bids are added with bundle_add_bid"
+        );
+        require!(
+            bundle.in_limits(),
+            "Either the sale is finished or it hasn't started yet"
+        );
+        require!(bundle.owner_id != buyer_id, "Cannot buy your own bundle");
+        let price = *bundle
+            .sale_conditions
+            .get(&ft_token_id)
+            .unwrap_or_else(|| env::panic_str("Not supported ft"));
+        // The token could have been removed from the market after the bundle was created
+        require!(
+            self.market.ft_token_ids.contains(&ft_token_id),
+            format!("Token {} not supported by this market", ft_token_id)
+        );
+        let price_with_fees =
+            calculate_price_with_fees(price, origins.as_ref(), bundle.protocol_fee);
+        require!(
+            deposit == price_with_fees,
+            format!("Deposit should be equal to the price with fees {}", price_with_fees)
+        );
+        let bundle = self.internal_remove_bundle(bundle_id.0);
+        self.escrow_deposit(&ft_token_id, deposit);
+        self.settle_bundle(
+            bundle_id,
+            bundle,
+            ft_token_id,
+            buyer_id,
+            U128(deposit),
+            origins.unwrap_or_default(),
+        )
+    }
+
+    pub(crate) fn internal_bundle_add_bid(
+        &mut self,
+        bundle_id: U128,
+        ft_token_id: FungibleTokenId,
+        bidder_id: AccountId,
+        deposit: Balance,
+        origins: Option<Origins>,
+    ) {
+        let mut bundle = self
+            .market
+            .bundles
+            .get(&bundle_id.0)
+            .unwrap_or_else(|| env::panic_str("No bundle"));
+        let minimal_step = bundle
+            .minimal_step
+            .unwrap_or_else(|| env::panic_str("The bundle is sold at a fixed price"));
+        require!(
+            bundle.in_limits(),
+            "Either the auction is finished or it hasn't started yet"
+        );
+        require!(bundle.owner_id != bidder_id, "Cannot bid on your own bundle");
+        let start_price = *bundle
+            .sale_conditions
+            .get(&ft_token_id)
+            .unwrap_or_else(|| env::panic_str("Not supported ft"));
+        // The token could have been removed from the market after the bundle was created
+        require!(
+            self.market.ft_token_ids.contains(&ft_token_id),
+            format!("Token {} not supported by this market", ft_token_id)
+        );
+        let min_price = match bundle.bid {
+            Some(ref bid) => {
+                let total_origins = calculate_origins(&bid.origins);
+                calculate_actual_amount(bid.price.0, total_origins, bundle.protocol_fee)
+                    + minimal_step.0
+            }
+            None => start_price.0,
+        };
+        let min_deposit =
+            calculate_price_with_fees(U128(min_price), origins.as_ref(), bundle.protocol_fee);
+        require!(
+            deposit >= min_deposit,
+            format!("Should bid at least {}", min_deposit)
+        );
+        if let Some(previous_bid) = bundle.bid.take() {
+            self.refund_bid(ft_token_id.clone(), previous_bid.owner_id, previous_bid.price);
+        }
+        self.escrow_deposit(&ft_token_id, deposit);
+        let bid = Bid {
+            owner_id: bidder_id,
+            price: deposit.into(),
+            start: env::block_timestamp().into(),
+            end: None,
+            origins: origins.unwrap_or_default(),
+        };
+        bundle.bid = Some(bid.clone());
+        self.market.bundles.insert(&bundle_id.0, &bundle);
+        NearEvent::bundle_bid(BundleBidData {
+            bundle_id,
+            ft_token_id,
+            bid,
+        })
+        .emit();
+    }
+
+    // Transfers every token of the removed bundle to the market with its share of the price
+    pub(crate) fn settle_bundle(
+        &mut self,
+        bundle_id: U128,
+        bundle: Bundle,
+        ft_token_id: FungibleTokenId,
+        buyer_id: AccountId,
+        price: U128,
+        origins: Origins,
+    ) -> Promise {
+        let fees = fee::Fees::new(
+            origins,
+            &bundle.origins,
+            &self.market.treasury_id,
+            bundle.protocol_fee,
+        );
+        let memo = near_sdk::serde_json::to_string(&fees).expect("Failed to serialize");
+        bundle
+            .tokens
+            .iter()
+            .zip(split_price(price.0, bundle.tokens.len()))
+            .map(|(token, share)| {
+                ext_contract::nft_transfer_payout(
+                    env::current_account_id(),
+                    token.token_id.clone(),
+                    token.approval_id,
+                    Some(memo.clone()),
+                    U128(share),
+                    MAX_LEN_PAYOUT,
+                    token.nft_contract_id.clone(),
+                    1,
+                    GAS_FOR_BUNDLE_NFT_TRANSFER,
+                )
+            })
+            .reduce(Promise::and)
+            .unwrap()
+            .then(ext_self::resolve_bundle_purchase(
+                bundle_id,
+                bundle,
+                ft_token_id,
+                buyer_id,
+                price,
+                fees,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_RESOLVE_BUNDLE,
+            ))
+    }
+
+    // Lists the bundle again after a failed purchase, unless it is auctioned, a token
+    // has been listed again or the storage of the owner doesn't cover it anymore
+    fn internal_restore_bundle(&mut self, bundle_id: U128, bundle: Bundle) {
+        if bundle.minimal_step.is_some()
+            || bundle
+                .tokens
+                .iter()
+                .any(|token| self.is_listed(&token.nft_contract_id, &token.token_id))
+            || !self.storage_covers_new_sale(&bundle.owner_id, &bundle.owner_id)
+        {
+            return;
+        }
+        self.internal_insert_bundle(bundle_id.0, &bundle);
+        NearEvent::bundle_restore(BundleJson { bundle_id, bundle }).emit();
+    }
+
+    // A token is listed in one sale, auction or bundle, see `TokenListing`
+    fn is_listed(&self, nft_contract_id: &AccountId, token_id: &TokenId) -> bool {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        self.market
+            .listings_by_token
+            .contains_key(&contract_and_token_id)
+    }
+
+    fn assert_not_listed(&self, nft_contract_id: &AccountId, token_id: &TokenId) {
+        require!(
+            !self.is_listed(nft_contract_id, token_id),
+            format!("Token {} of {} is already listed", token_id, nft_contract_id)
+        );
+    }
+
+    pub(crate) fn internal_insert_bundle(&mut self, bundle_id: u128, bundle: &Bundle) {
+        self.market.bundles.insert(&bundle_id, bundle);
+        let listing = TokenListing::Bundle { bundle_id };
+        for token in bundle.tokens.iter() {
+            let contract_and_token_id =
+                format!("{}{}{}", token.nft_contract_id, DELIMETER, token.token_id);
+            self.internal_index_listing(&contract_and_token_id, &listing);
+        }
+        let mut by_owner_id = self
+            .market
+            .bundles_by_owner_id
+            .get(&bundle.owner_id)
+            .unwrap_or_else(|| {
+                UnorderedSet::new(StorageKey::BundlesByOwnerIdInner {
+                    account_id_hash: hash_account_id(&bundle.owner_id),
+                })
+            });
+        by_owner_id.insert(&bundle_id);
+        self.market
+            .bundles_by_owner_id
+            .insert(&bundle.owner_id, &by_owner_id);
+    }
+
+    pub(crate) fn internal_remove_bundle(&mut self, bundle_id: u128) -> Bundle {
+        let bundle = self
+            .market
+            .bundles
+            .remove(&bundle_id)
+            .unwrap_or_else(|| env::panic_str("No bundle"));
+        let listing = TokenListing::Bundle { bundle_id };
+        for token in bundle.tokens.iter() {
+            let contract_and_token_id =
+                format!("{}{}{}", token.nft_contract_id, DELIMETER, token.token_id);
+            self.internal_unindex_listing(&contract_and_token_id, &listing);
+        }
+        let mut by_owner_id = self
+            .market
+            .bundles_by_owner_id
+            .get(&bundle.owner_id)
+            .expect("No bundle by_owner_id");
+        by_owner_id.remove(&bundle_id);
+        if by_owner_id.is_empty() {
+            self.market.bundles_by_owner_id.remove(&bundle.owner_id);
+        } else {
+            self.market
+                .bundles_by_owner_id
+                .insert(&bundle.owner_id, &by_owner_id);
+        }
+        NearEvent::bundle_remove(BundleRemoveData {
+            bundle_id: bundle_id.into(),
+            owner_id: bundle.owner_id.clone(),
+        })
+        .emit();
+        bundle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listing::TokenListingJson;
+    use crate::market_core::NonFungibleTokenApprovalReceiver;
+    use crate::test_utils::{
+        account, context, function_calls, near_transfers, new_market, nft_transfers, token,
+        with_storage,
+    };
+
+    fn args(minimal_step: Option<U128>) -> BundleArgs {
+        BundleArgs {
+            tokens: ["1:1", "1:2"]
+                .iter()
+                .map(|token_id| BundleTokenArgs {
+                    nft_contract_id: account("nft"),
+                    token_id: token_id.to_string(),
+                })
+                .collect(),
+            sale_conditions: HashMap::from([(account("near"), U128(1000))]),
+            minimal_step,
+            start: None,
+            duration: Some(U64(100)),
+            origins: None,
+        }
+    }
+
+    // Market with the bundle 0 of alice listed at 10
+    fn market(minimal_step: Option<U128>) -> Market {
+        let mut contract = new_market();
+        with_storage(&mut contract, "alice");
+        let results = vec![token("1:1", true), token("1:2", true)];
+        context("market", "market", 0, 10, results);
+        contract.resolve_create_bundle(account("alice"), args(minimal_step));
+        contract
+    }
+
+    fn payout(owner_share: u128) -> PromiseResult {
+        PromiseResult::Successful(
+            near_sdk::serde_json::json!({
+                "payout": { "alice": owner_share.to_string(), "market": "30" }
+            })
+            .to_string()
+            .into_bytes(),
+        )
+    }
+
+    #[test]
+    fn bundle_purchase_fits_in_gas_limit() {
+        // `buy_bundle` itself takes the rest of 300 Tgas
+        let transfers = GAS_FOR_BUNDLE_NFT_TRANSFER.0 * MAX_BUNDLE_TOKENS as u64;
+        assert!(transfers + GAS_FOR_RESOLVE_BUNDLE.0 <= 280_000_000_000_000);
+    }
+
+    #[test]
+    fn split_price_between_tokens() {
+        assert_eq!(split_price(1030, 4), vec![259, 257, 257, 257]);
+    }
+
+    #[test]
+    fn buy_bundle_transfers_tokens_to_market() {
+        let mut contract = market(None);
+        let bundle = contract.get_bundle(U128(0)).unwrap().bundle;
+        assert_eq!(bundle.tokens[1].approval_id, 3);
+        assert_eq!(
+            contract.get_bundles_by_owner_id(account("alice"), None, None).len(),
+            1
+        );
+
+        context("bob", "bob", 1030, 20, vec![]);
+        contract.buy_bundle(U128(0), account("near"), None);
+        let transfers: Vec<_> = function_calls()
+            .into_iter()
+            .filter(|(_, function_name, _)| function_name == "nft_transfer_payout")
+            .map(|(_, _, args)| (args["receiver_id"].clone(), args["balance"].clone()))
+            .collect();
+        assert_eq!(
+            transfers,
+            vec![
+                ("market".into(), "515".into()),
+                ("market".into(), "515".into())
+            ]
+        );
+        assert!(contract.get_bundle(U128(0)).is_none());
+        assert_eq!(
+            contract.get_escrow_summary().escrow[&account("near")],
+            U128(1030)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Deposit should be equal to the price with fees 1030")]
+    fn buy_bundle_with_wrong_deposit() {
+        let mut contract = market(None);
+        context("bob", "bob", 1000, 20, vec![]);
+        contract.buy_bundle(U128(0), account("near"), None);
+    }
+
+    #[test]
+    fn bundle_purchase_sends_tokens_to_buyer() {
+        let mut contract = market(None);
+        let bundle = contract.internal_remove_bundle(0);
+        // Taken by `buy_bundle`
+        contract.escrow_deposit(&account("near"), 1030);
+        context("market", "market", 0, 20, vec![payout(485), payout(485)]);
+        contract.resolve_bundle_purchase(
+            U128(0),
+            bundle,
+            account("near"),
+            account("bob"),
+            U128(1030),
+            fee::Fees::new(Origins::new(), &Origins::new(), &account("market"), 300),
+        );
+        assert_eq!(
+            nft_transfers(),
+            vec![
+                ("bob".to_string(), "1:1".to_string()),
+                ("bob".to_string(), "1:2".to_string())
+            ]
+        );
+        assert_eq!(near_transfers(), vec![("alice".to_string(), 970)]);
+        assert_eq!(contract.get_protocol_fees()[&account("near")], U128(60));
+    }
+
+    #[test]
+    fn bundle_purchase_rolls_back() {
+        let mut contract = market(None);
+        let bundle = contract.internal_remove_bundle(0);
+        // Taken by `buy_bundle`
+        contract.escrow_deposit(&account("near"), 1030);
+        let results = vec![payout(485), PromiseResult::Failed];
+        context("market", "market", 0, 20, results);
+        contract.resolve_bundle_purchase(
+            U128(0),
+            bundle,
+            account("near"),
+            account("bob"),
+            U128(1030),
+            fee::Fees::new(Origins::new(), &Origins::new(), &account("market"), 300),
+        );
+        assert_eq!(
+            nft_transfers(),
+            vec![("alice".to_string(), "1:1".to_string())]
+        );
+        assert_eq!(near_transfers(), vec![("bob".to_string(), 1030)]);
+        assert!(contract.get_protocol_fees().is_empty());
+        // The approval of the returned token is gone
+        assert!(contract.get_bundle(U128(0)).is_none());
+    }
+
+    #[test]
+    fn bundle_purchase_relists_untransferred_bundle() {
+        let mut contract = market(None);
+        let bundle = contract.internal_remove_bundle(0);
+        // Taken by `buy_bundle`
+        contract.escrow_deposit(&account("near"), 1030);
+        let results = vec![PromiseResult::Failed, PromiseResult::Failed];
+        context("market", "market", 0, 20, results);
+        contract.resolve_bundle_purchase(
+            U128(0),
+            bundle,
+            account("near"),
+            account("bob"),
+            U128(1030),
+            fee::Fees::new(Origins::new(), &Origins::new(), &account("market"), 300),
+        );
+        assert!(nft_transfers().is_empty());
+        assert_eq!(near_transfers(), vec![("bob".to_string(), 1030)]);
+        assert_eq!(
+            contract.get_bundles_by_owner_id(account("alice"), None, None)[0].bundle_id,
+            U128(0)
+        );
+    }
+
+    #[test]
+    fn failed_token_transfer_can_be_claimed() {
+        let mut contract = market(None);
+        let transfers: Vec<_> = ["1:1", "1:2"]
+            .iter()
+            .map(|token_id| {
+                let token = ClaimableToken {
+                    nft_contract_id: account("nft"),
+                    token_id: token_id.to_string(),
+                };
+                (account("bob"), token)
+            })
+            .collect();
+        let results = vec![PromiseResult::Successful(vec![]), PromiseResult::Failed];
+        context("market", "market", 0, 20, results);
+        contract.resolve_nft_transfers(transfers.clone());
+        assert_eq!(
+            contract.get_claimable_tokens(account("bob")),
+            vec![transfers[1].1.clone()]
+        );
+
+        context("bob", "bob", 1, 30, vec![]);
+        contract.claim_token(account("nft"), "1:2".to_string());
+        assert_eq!(
+            nft_transfers(),
+            vec![("bob".to_string(), "1:2".to_string())]
+        );
+        assert!(contract.get_claimable_tokens(account("bob")).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Nothing to claim")]
+    fn claim_token_of_other_account() {
+        let mut contract = market(None);
+        context("market", "market", 0, 20, vec![PromiseResult::Failed]);
+        let token = ClaimableToken {
+            nft_contract_id: account("nft"),
+            token_id: "1:1".to_string(),
+        };
+        contract.resolve_nft_transfers(vec![(account("bob"), token)]);
+        context("carol", "carol", 1, 30, vec![]);
+        contract.claim_token(account("nft"), "1:1".to_string());
+    }
+
+    #[test]
+    fn buy_bundle_at_start() {
+        let mut contract = market(None);
+        // The bundle starts at its creation time
+        context("bob", "bob", 1030, 10, vec![]);
+        contract.buy_bundle(U128(0), account("near"), None);
+        assert!(contract.get_bundle(U128(0)).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Token near not supported by this market")]
+    fn bundle_bid_in_removed_currency() {
+        let mut contract = market(Some(U128(100)));
+        contract.market.ft_token_ids.remove(&account("near"));
+        context("bob", "bob", 1030, 20, vec![]);
+        contract.bundle_add_bid(U128(0), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Should bid at least 1133")]
+    fn bundle_bid_should_grow_by_step() {
+        let mut contract = market(Some(U128(100)));
+        context("bob", "bob", 1030, 20, vec![]);
+        contract.bundle_add_bid(U128(0), None);
+        context("carol", "carol", 1132, 30, vec![]);
+        contract.bundle_add_bid(U128(0), None);
+    }
+
+    #[test]
+    fn finish_bundle_auction_without_bids() {
+        let mut contract = market(Some(U128(100)));
+        context("bob", "bob", 0, 200, vec![]);
+        assert!(contract.finish_bundle_auction(U128(0)).is_none());
+        assert!(contract.get_bundle(U128(0)).is_none());
+        assert_eq!(
+            contract.get_supply_by_owner_id(account("alice")),
+            U64(0)
+        );
+    }
+
+    #[test]
+    fn bundle_tokens_are_listed() {
+        let mut contract = market(None);
+        assert!(matches!(
+            contract.get_listing_for_token(account("nft"), "1:2".to_string()),
+            Some(TokenListingJson::Bundle(_))
+        ));
+
+        // A new listing of a token replaces the bundle
+        contract.internal_replace_listing(&account("nft"), &"1:2".to_string(), &account("alice"));
+        assert!(contract.get_bundle(U128(0)).is_none());
+        assert!(contract
+            .get_listing_for_token(account("nft"), "1:1".to_string())
+            .is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Token 1:1 of nft is already listed")]
+    fn bundle_of_listed_token() {
+        let mut contract = market(None);
+        context("market", "market", 0, 20, vec![token("1:1", true), token("1:2", true)]);
+        contract.resolve_create_bundle(account("alice"), args(None));
+    }
+
+    #[test]
+    fn accepted_offer_credits_bundle_bid() {
+        let mut contract = market(Some(U128(100)));
+        context("bob", "bob", 1030, 20, vec![]);
+        contract.bundle_add_bid(U128(0), None);
+
+        // The token was transferred to carol, who accepts an offer
+        contract.internal_clear_listing(&account("nft"), &"1:1".to_string(), &account("carol"));
+        assert!(contract.get_bundle(U128(0)).is_none());
+        assert!(near_transfers().is_empty());
+        assert_eq!(
+            contract.get_claimable(account("bob"))[&account("near")],
+            U128(1030)
+        );
+    }
+
+    #[test]
+    fn revoked_token_removes_bundle() {
+        let mut contract = market(None);
+        context("nft", "nft", 0, 20, vec![]);
+        contract.nft_on_revoke("1:2".to_string(), account("alice"), 3);
+        assert!(contract.get_bundle(U128(0)).is_none());
+        assert_eq!(
+            contract.get_supply_by_owner_id(account("alice")),
+            U64(0)
+        );
+    }
+}
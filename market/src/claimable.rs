@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+
+use near_sdk::{is_promise_success, Gas, PromiseResult};
+
+use crate::sale::{
+    ext_contract, ext_self, FungibleTokenId, GAS_FOR_FT_TRANSFER, MAX_LEN_PAYOUT, NO_DEPOSIT,
+};
+use crate::*;
+
+pub const GAS_FOR_RESOLVE_REFUND: Gas = Gas(5_000_000_000_000);
+// One `internal_send` of NEAR: `resolve_refund` and the creation of the promises
+pub const GAS_PER_NEAR_SEND: Gas = Gas(GAS_FOR_RESOLVE_REFUND.0 + 2_000_000_000_000);
+// Fungible tokens also attach the gas for `ft_transfer`
+pub const GAS_PER_SEND: Gas = Gas(GAS_PER_NEAR_SEND.0 + GAS_FOR_FT_TRANSFER.0);
+// `internal_pay_out` sends at most `MAX_LEN_PAYOUT` transfers
+pub const GAS_FOR_PAY_OUT: Gas = Gas(GAS_PER_SEND.0 * MAX_LEN_PAYOUT as u64);
+pub const GAS_FOR_NEAR_PAY_OUT: Gas = Gas(GAS_PER_NEAR_SEND.0 * MAX_LEN_PAYOUT as u64);
+// Transfer of a token held by the market, it has no approvals to revoke
+pub const GAS_FOR_MARKET_NFT_TRANSFER: Gas = Gas(15_000_000_000_000);
+pub const GAS_FOR_RESOLVE_NFT_TRANSFERS: Gas = Gas(10_000_000_000_000);
+
+// Token held by the market after its transfer failed, see `claim_token`
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ClaimableToken {
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+}
+
+#[near_bindgen]
+impl Market {
+    // Returns the refunds which couldn't be transferred to the account
+    pub fn get_claimable(&self, account_id: AccountId) -> HashMap<FungibleTokenId, U128> {
+        self.market
+            .claimable
+            .get(&account_id)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(ft_token_id, amount)| (ft_token_id, amount.into()))
+            .collect()
+    }
+
+    // Transfers the whole claimable balance of the caller in the given currency
+    // If the transfer fails again, the balance can be claimed later
+    #[payable]
+    pub fn claim(&mut self, ft_token_id: FungibleTokenId) -> Promise {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let mut balances = self.market.claimable.get(&account_id).unwrap_or_default();
+        let amount = balances
+            .remove(&ft_token_id)
+            .unwrap_or_else(|| env::panic_str("Nothing to claim"));
+        if balances.is_empty() {
+            self.market.claimable.remove(&account_id);
+        } else {
+            self.market.claimable.insert(&account_id, &balances);
+        }
+        self.escrow_release(&ft_token_id, amount);
+        self.internal_send(ft_token_id, account_id, amount.into())
+    }
+
+    // Returns the tokens held by the market for the account after their transfers failed
+    pub fn get_claimable_tokens(&self, account_id: AccountId) -> Vec<ClaimableToken> {
+        self.market
+            .claimable_tokens
+            .get(&account_id)
+            .unwrap_or_default()
+    }
+
+    // Transfers the token held by the market for the caller
+    // If the transfer fails again, the token can be claimed later
+    #[payable]
+    pub fn claim_token(&mut self, nft_contract_id: AccountId, token_id: TokenId) -> Promise {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let token = ClaimableToken {
+            nft_contract_id,
+            token_id,
+        };
+        let mut tokens = self
+            .market
+            .claimable_tokens
+            .get(&account_id)
+            .unwrap_or_default();
+        let index = tokens
+            .iter()
+            .position(|claimable| claimable == &token)
+            .expect("Nothing to claim");
+        tokens.swap_remove(index);
+        if tokens.is_empty() {
+            self.market.claimable_tokens.remove(&account_id);
+        } else {
+            self.market.claimable_tokens.insert(&account_id, &tokens);
+        }
+        self.internal_send_tokens(vec![(account_id, token)])
+            .unwrap()
+    }
+
+    // self callback
+    // The tokens of the failed transfers stay on the market and are credited to their receivers
+    #[private]
+    pub fn resolve_nft_transfers(&mut self, transfers: Vec<(AccountId, ClaimableToken)>) {
+        for (i, (receiver_id, token)) in transfers.into_iter().enumerate() {
+            if !matches!(env::promise_result(i as u64), PromiseResult::Successful(_)) {
+                let mut tokens = self
+                    .market
+                    .claimable_tokens
+                    .get(&receiver_id)
+                    .unwrap_or_default();
+                tokens.push(token);
+                self.market.claimable_tokens.insert(&receiver_id, &tokens);
+            }
+        }
+    }
+
+    // self callback
+    // The failed transfer is returned to the market and credited to the receiver
+    #[private]
+    pub fn resolve_refund(
+        &mut self,
+        ft_token_id: FungibleTokenId,
+        receiver_id: AccountId,
+        amount: U128,
+    ) {
+        if is_promise_success() {
+            return;
+        }
+        self.escrow_deposit(&ft_token_id, amount.0);
+        self.internal_credit_claimable(&receiver_id, &ft_token_id, amount.0);
+    }
+}
+
+impl Market {
+    // The amount should be already counted in the escrow
+    pub(crate) fn internal_credit_claimable(
+        &mut self,
+        account_id: &AccountId,
+        ft_token_id: &FungibleTokenId,
+        amount: Balance,
+    ) {
+        let mut balances = self.market.claimable.get(account_id).unwrap_or_default();
+        *balances.entry(ft_token_id.clone()).or_insert(0) += amount;
+        self.market.claimable.insert(account_id, &balances);
+    }
+
+    // Transfers NEAR or fungible tokens to the user, see `resolve_refund`
+    pub(crate) fn internal_send(
+        &self,
+        ft_token_id: FungibleTokenId,
+        receiver_id: AccountId,
+        amount: U128,
+    ) -> Promise {
+        let transfer = if ft_token_id.as_str() == "near" {
+            Promise::new(receiver_id.clone()).transfer(amount.0)
+        } else {
+            ext_contract::ft_transfer(
+                receiver_id.clone(),
+                amount,
+                None,
+                ft_token_id.clone(),
+                1,
+                GAS_FOR_FT_TRANSFER,
+            )
+        };
+        transfer.then(ext_self::resolve_refund(
+            ft_token_id,
+            receiver_id,
+            amount,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_RESOLVE_REFUND,
+        ))
+    }
+    // Transfers the tokens held by the market, see `resolve_nft_transfers`
+    // Returns `None` if there is nothing to transfer
+    pub(crate) fn internal_send_tokens(
+        &self,
+        transfers: Vec<(AccountId, ClaimableToken)>,
+    ) -> Option<Promise> {
+        let transfer = transfers
+            .iter()
+            .map(|(receiver_id, token)| {
+                ext_contract::nft_transfer(
+                    receiver_id.clone(),
+                    token.token_id.clone(),
+                    None,
+                    None,
+                    token.nft_contract_id.clone(),
+                    1,
+                    GAS_FOR_MARKET_NFT_TRANSFER,
+                )
+            })
+            .reduce(Promise::and)?;
+        Some(transfer.then(ext_self::resolve_nft_transfers(
+            transfers,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_RESOLVE_NFT_TRANSFERS,
+        )))
+    }
+}
@@ -0,0 +1,524 @@
+#![allow(clippy::too_many_arguments)]
+use near_contract_standards::non_fungible_token::hash_account_id;
+use near_sdk::promise_result_as_success;
+
+use crate::bid::Origins;
+use crate::event::{CollectionOfferRemoveData, NearEvent, PayoutFailData, PurchaseData};
+use crate::fee::calculate_origins;
+use crate::sale::{
+    ext_contract, ext_self, FungibleTokenId, GAS_FOR_NFT_TRANSFER, GAS_FOR_ROYALTIES,
+    MAX_LEN_PAYOUT, NO_DEPOSIT,
+};
+use crate::token::TOKEN_DELIMETER;
+use crate::*;
+
+// Offer to buy any token of the NFT contract or only a token of the series `token_type`
+// The price includes the fees like the price of a bid, it stays in the escrow until the offer
+// is accepted or removed
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug, PartialEq))]
+#[serde(crate = "near_sdk::serde")]
+pub struct CollectionOffer {
+    pub owner_id: AccountId,
+    pub nft_contract_id: AccountId,
+    pub token_type: TokenType,
+    pub ft_token_id: FungibleTokenId,
+    pub price: U128,
+    pub created_at: U64,
+
+    pub start: U64,
+    pub end: Option<U64>,
+
+    pub origins: Origins,
+    // Protocol fee at the moment of the offer, it doesn't change with the market fee
+    pub protocol_fee: u32,
+}
+
+impl CollectionOffer {
+    pub fn in_limits(&self) -> bool {
+        let now = env::block_timestamp();
+        self.start.0 <= now && !matches!(self.end, Some(end) if now >= end.0)
+    }
+
+    // Whether the token can be sold to this offer
+    pub fn matches_token(&self, nft_contract_id: &AccountId, token_id: &str) -> bool {
+        if &self.nft_contract_id != nft_contract_id {
+            return false;
+        }
+        match &self.token_type {
+            Some(token_type) => {
+                token_id.split(TOKEN_DELIMETER).next() == Some(token_type.as_str())
+            }
+            None => true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CollectionOfferJson {
+    pub offer_id: U128,
+    #[serde(flatten)]
+    pub offer: CollectionOffer,
+}
+
+#[near_bindgen]
+impl Market {
+    // Offers the attached NEAR for any token of the NFT contract (or of the series `token_type`),
+    // fungible tokens are offered with `ft_transfer_call`
+    // The deposit should include the fees, like the deposit of `offer`
+    // Each offer takes the storage of a sale, see `storage_deposit`
+    // Returns the id of the offer
+    #[payable]
+    pub fn collection_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_type: TokenType,
+        start: Option<U64>,
+        duration: Option<U64>,
+        origins: Option<Origins>,
+    ) -> U128 {
+        self.internal_collection_offer(
+            nft_contract_id,
+            token_type,
+            AccountId::new_unchecked("near".to_owned()),
+            env::predecessor_account_id(),
+            env::attached_deposit(),
+            start,
+            duration,
+            origins,
+        )
+    }
+
+    // Removes the offer and refunds it
+    // Until the offer has ended, only its owner can remove it
+    #[payable]
+    pub fn remove_collection_offer(&mut self, offer_id: U128) {
+        assert_one_yocto();
+        let offer = self.internal_remove_collection_offer(offer_id.0);
+        let has_ended = matches!(offer.end, Some(end) if env::block_timestamp() >= end.0);
+        require!(
+            has_ended || env::predecessor_account_id() == offer.owner_id,
+            "Until the offer has ended, it can only be removed by its owner"
+        );
+        self.refund_bid(offer.ft_token_id, offer.owner_id, offer.price);
+    }
+
+    pub fn get_collection_offer(&self, offer_id: U128) -> Option<CollectionOfferJson> {
+        self.market
+            .collection_offers
+            .get(&offer_id.0)
+            .map(|offer| CollectionOfferJson { offer_id, offer })
+    }
+
+    // Returns the offers on the NFT contract,
+    // with `token_type` only the offers which a token of this series can accept
+    pub fn get_collection_offers(
+        &self,
+        nft_contract_id: AccountId,
+        token_type: TokenType,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<CollectionOfferJson> {
+        let offer_ids = match self
+            .market
+            .collection_offers_by_nft_contract_id
+            .get(&nft_contract_id)
+        {
+            Some(offer_ids) => offer_ids,
+            None => return vec![],
+        };
+        let start_index: u64 = from_index.map(From::from).unwrap_or_default();
+        let limit = limit.map(|v| v as usize).unwrap_or(usize::MAX);
+        offer_ids
+            .iter()
+            .filter_map(|offer_id| {
+                let offer = self.market.collection_offers.get(&offer_id)?;
+                let matches = match (&token_type, &offer.token_type) {
+                    (Some(token_type), Some(offer_token_type)) => token_type == offer_token_type,
+                    _ => true,
+                };
+                if matches {
+                    Some(CollectionOfferJson {
+                        offer_id: offer_id.into(),
+                        offer,
+                    })
+                } else {
+                    None
+                }
+            })
+            .skip(start_index as usize)
+            .take(limit)
+            .collect()
+    }
+
+    // self callback
+    // If the token is transferred - pays out the offer
+    // If failed - the offer is put back, it stays in the escrow,
+    // unless the storage of the owner doesn't cover it anymore, then it is refunded
+    #[private]
+    pub fn resolve_collection_offer(
+        &mut self,
+        offer_id: U128,
+        offer: CollectionOffer,
+        token_id: TokenId,
+        owner_id: AccountId,
+        fees: fee::Fees,
+    ) {
+        let value = match promise_result_as_success() {
+            Some(value) => value,
+            None => {
+                NearEvent::payout_fail(PayoutFailData {
+                    auction_id: None,
+                    owner_id,
+                    nft_contract_id: offer.nft_contract_id.clone(),
+                    token_id,
+                    buyer_id: offer.owner_id.clone(),
+                    ft_token_id: offer.ft_token_id.clone(),
+                    price: offer.price,
+                })
+                .emit();
+                // The owner could have withdrawn the storage while the token was transferred
+                if !self.storage_covers_new_sale(&offer.owner_id, &offer.owner_id) {
+                    self.escrow_release(&offer.ft_token_id, offer.price.0);
+                    self.internal_send(offer.ft_token_id, offer.owner_id, offer.price);
+                    return;
+                }
+                self.internal_insert_collection_offer(offer_id.0, &offer);
+                NearEvent::collection_offer_add(CollectionOfferJson { offer_id, offer }).emit();
+                return;
+            }
+        };
+        let price = offer.price;
+        self.escrow_release(&offer.ft_token_id, price.0);
+        let payout = fees.payout_from_result(&value, price.0, &owner_id);
+        NearEvent::purchase(PurchaseData {
+            auction_id: None,
+            owner_id,
+            nft_contract_id: offer.nft_contract_id,
+            token_id,
+            buyer_id: offer.owner_id,
+            ft_token_id: offer.ft_token_id.clone(),
+            price,
+            payout: payout.clone(),
+        })
+        .emit();
+        self.internal_pay_out(&offer.ft_token_id, payout);
+    }
+}
+
+impl Market {
+    pub(crate) fn internal_collection_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_type: TokenType,
+        ft_token_id: FungibleTokenId,
+        owner_id: AccountId,
+        deposit: Balance,
+        start: Option<U64>,
+        duration: Option<U64>,
+        origins: Option<Origins>,
+    ) -> U128 {
+        require!(
+            self.non_fungible_token_account_ids
+                .contains(&nft_contract_id),
+            format!(
+                "NFT contract {} is not supported by this market",
+                nft_contract_id
+            )
+        );
+        require!(
+            self.market.ft_token_ids.contains(&ft_token_id),
+            format!("Token {} not supported by this market", ft_token_id)
+        );
+        require!(deposit > 0, "Attached deposit must be greater than 0");
+        let origins = origins.unwrap_or_default();
+        require!(calculate_origins(&origins) < 4_700, "Max origins exceeded");
+        require!(
+            self.storage_covers_new_sale(&owner_id, &owner_id),
+            "User has more sales than storage paid"
+        );
+
+        let start = start.unwrap_or(env::block_timestamp().into());
+        let offer = CollectionOffer {
+            owner_id,
+            nft_contract_id,
+            token_type,
+            ft_token_id,
+            price: deposit.into(),
+            created_at: env::block_timestamp().into(),
+            start,
+            end: duration.map(|d| U64(d.0 + start.0)),
+            origins,
+            protocol_fee: self.market.protocol_fee,
+        };
+        let offer_id = self.market.next_collection_offer_id;
+        self.market.next_collection_offer_id += 1;
+        self.internal_insert_collection_offer(offer_id, &offer);
+        self.escrow_deposit(&offer.ft_token_id, deposit);
+        NearEvent::collection_offer_add(CollectionOfferJson {
+            offer_id: offer_id.into(),
+            offer,
+        })
+        .emit();
+        offer_id.into()
+    }
+
+    // Called in nft_on_approve to sell the token to the offer
+    pub(crate) fn accept_collection_offer(
+        &mut self,
+        offer_id: U128,
+        token_id: TokenId,
+        owner_id: AccountId,
+        approval_id: u64,
+        nft_contract_id: AccountId,
+    ) -> Promise {
+        let offer = self.internal_remove_collection_offer(offer_id.0);
+        require!(
+            offer.matches_token(&nft_contract_id, &token_id),
+            "The offer doesn't match the token"
+        );
+        require!(
+            offer.in_limits(),
+            "Either the offer has ended or it hasn't started yet"
+        );
+        require!(offer.owner_id != owner_id, "Cannot accept your own offer");
+        // The offer is not bound to the token, so the seller has no origins
+        let fees = fee::Fees::new(
+            offer.origins.clone(),
+            &Origins::new(),
+            &self.market.treasury_id,
+            offer.protocol_fee,
+        );
+        ext_contract::nft_transfer_payout(
+            offer.owner_id.clone(),
+            token_id.clone(),
+            approval_id,
+            Some(near_sdk::serde_json::to_string(&fees).expect("Failed to serialize")),
+            offer.price,
+            MAX_LEN_PAYOUT,
+            nft_contract_id,
+            1,
+            GAS_FOR_NFT_TRANSFER,
+        )
+        .then(ext_self::resolve_collection_offer(
+            offer_id,
+            offer,
+            token_id,
+            owner_id,
+            fees,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_ROYALTIES,
+        ))
+    }
+
+    pub(crate) fn internal_insert_collection_offer(
+        &mut self,
+        offer_id: u128,
+        offer: &CollectionOffer,
+    ) {
+        self.market.collection_offers.insert(&offer_id, offer);
+        let mut by_nft_contract_id = self
+            .market
+            .collection_offers_by_nft_contract_id
+            .get(&offer.nft_contract_id)
+            .unwrap_or_else(|| {
+                UnorderedSet::new(
+                    StorageKey::CollectionOffersByNFTContractIdInner {
+                        account_id_hash: hash_account_id(&offer.nft_contract_id),
+                    }
+                    .try_to_vec()
+                    .unwrap(),
+                )
+            });
+        by_nft_contract_id.insert(&offer_id);
+        self.market
+            .collection_offers_by_nft_contract_id
+            .insert(&offer.nft_contract_id, &by_nft_contract_id);
+
+        let mut by_owner_id = self
+            .market
+            .collection_offers_by_owner_id
+            .get(&offer.owner_id)
+            .unwrap_or_else(|| {
+                UnorderedSet::new(StorageKey::CollectionOffersByOwnerIdInner {
+                    account_id_hash: hash_account_id(&offer.owner_id),
+                })
+            });
+        by_owner_id.insert(&offer_id);
+        self.market
+            .collection_offers_by_owner_id
+            .insert(&offer.owner_id, &by_owner_id);
+    }
+
+    pub(crate) fn internal_remove_collection_offer(&mut self, offer_id: u128) -> CollectionOffer {
+        let offer = self
+            .market
+            .collection_offers
+            .remove(&offer_id)
+            .unwrap_or_else(|| env::panic_str("No collection offer"));
+        let mut by_nft_contract_id = self
+            .market
+            .collection_offers_by_nft_contract_id
+            .get(&offer.nft_contract_id)
+            .expect("No collection offer by nft_contract_id");
+        by_nft_contract_id.remove(&offer_id);
+        if by_nft_contract_id.is_empty() {
+            self.market
+                .collection_offers_by_nft_contract_id
+                .remove(&offer.nft_contract_id);
+        } else {
+            self.market
+                .collection_offers_by_nft_contract_id
+                .insert(&offer.nft_contract_id, &by_nft_contract_id);
+        }
+        let mut by_owner_id = self
+            .market
+            .collection_offers_by_owner_id
+            .get(&offer.owner_id)
+            .expect("No collection offer by owner_id");
+        by_owner_id.remove(&offer_id);
+        if by_owner_id.is_empty() {
+            self.market
+                .collection_offers_by_owner_id
+                .remove(&offer.owner_id);
+        } else {
+            self.market
+                .collection_offers_by_owner_id
+                .insert(&offer.owner_id, &by_owner_id);
+        }
+        NearEvent::collection_offer_remove(CollectionOfferRemoveData {
+            offer_id: offer_id.into(),
+            owner_id: offer.owner_id.clone(),
+        })
+        .emit();
+        offer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::serde_json::from_value;
+    use near_sdk::PromiseResult;
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::market_core::NonFungibleTokenApprovalReceiver;
+    use crate::test_utils::{
+        account, context, function_calls, near_transfers, new_market, with_storage,
+    };
+
+    fn accept_msg(offer_id: u128) -> String {
+        near_sdk::serde_json::to_string(&ArgsKind::AcceptCollectionOffer(
+            AcceptCollectionOfferArgs {
+                offer_id: U128(offer_id),
+            },
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn matches_token() {
+        let offer = CollectionOffer {
+            owner_id: account("bob"),
+            nft_contract_id: account("nft"),
+            token_type: Some("1".to_string()),
+            ft_token_id: account("near"),
+            price: U128(1000),
+            created_at: U64(0),
+            start: U64(0),
+            end: None,
+            origins: HashMap::new(),
+            protocol_fee: 300,
+        };
+        assert!(offer.matches_token(&account("nft"), "1:5"));
+        assert!(!offer.matches_token(&account("nft"), "10:1"));
+        assert!(!offer.matches_token(&account("nft2"), "1:5"));
+        let offer = CollectionOffer {
+            token_type: None,
+            ..offer
+        };
+        assert!(offer.matches_token(&account("nft"), "10:1"));
+        assert_eq!(TOKEN_DELIMETER, nft_contract::TOKEN_DELIMETER);
+    }
+
+    // Market with the offer of bob for the series "1"
+    fn market() -> (Market, U128) {
+        let mut contract = new_market();
+        with_storage(&mut contract, "bob");
+        context("bob", "bob", 1000, 10, vec![]);
+        let offer_id =
+            contract.collection_offer(account("nft"), Some("1".to_string()), None, None, None);
+        (contract, offer_id)
+    }
+
+    #[test]
+    #[should_panic(expected = "User has more sales than storage paid")]
+    fn offer_takes_storage() {
+        let (mut contract, _) = market();
+        context("bob", "bob", 1000, 10, vec![]);
+        contract.collection_offer(account("nft"), None, None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "The offer doesn't match the token")]
+    fn accept_other_series() {
+        let (mut contract, _) = market();
+        context("nft", "alice", 0, 10, vec![]);
+        contract.nft_on_approve("2:1".to_string(), account("alice"), 0, accept_msg(0));
+    }
+
+    #[test]
+    fn accept_and_restore() {
+        let (mut contract, offer_id) = market();
+        let offers =
+            contract.get_collection_offers(account("nft"), Some("2".to_string()), None, None);
+        assert!(offers.is_empty());
+
+        context("nft", "alice", 0, 10, vec![]);
+        contract.nft_on_approve("1:1".to_string(), account("alice"), 3, accept_msg(0));
+        assert!(contract.get_collection_offer(offer_id).is_none());
+        let calls = function_calls();
+        assert_eq!(calls[0].1, "nft_transfer_payout");
+        assert_eq!(calls[1].1, "resolve_collection_offer");
+        let args = &calls[0].2;
+        assert_eq!(args["receiver_id"], "bob");
+        assert_eq!(args["approval_id"], 3);
+
+        // The offer is put back if the token isn't transferred
+        let args = &calls[1].2;
+        let offer: CollectionOffer = from_value(args["offer"].clone()).unwrap();
+        let fees: fee::Fees = from_value(args["fees"].clone()).unwrap();
+        context("market", "market", 0, 10, vec![PromiseResult::Failed]);
+        contract.resolve_collection_offer(
+            offer_id,
+            offer.clone(),
+            "1:1".to_string(),
+            account("alice"),
+            fees,
+        );
+        assert_eq!(contract.get_collection_offer(offer_id).unwrap().offer, offer);
+        assert_eq!(contract.get_escrow_summary().escrow[&account("near")], U128(1000));
+    }
+    #[test]
+    fn refund_without_storage() {
+        let (mut contract, offer_id) = market();
+        let offer = contract.internal_remove_collection_offer(offer_id.0);
+        // The storage is withdrawn while the token is transferred
+        context("bob", "bob", 1, 10, vec![]);
+        contract.storage_withdraw();
+        let fees = fee::Fees::new(HashMap::new(), &HashMap::new(), &account("market"), 300);
+        context("market", "market", 0, 10, vec![PromiseResult::Failed]);
+        contract.resolve_collection_offer(
+            offer_id,
+            offer,
+            "1:1".to_string(),
+            account("alice"),
+            fees,
+        );
+        assert!(contract.get_collection_offer(offer_id).is_none());
+        assert_eq!(near_transfers(), vec![("bob".to_string(), 1000)]);
+        assert!(contract.get_escrow_summary().escrow.is_empty());
+    }
+}
@@ -0,0 +1,786 @@
+use std::collections::HashMap;
+
+use crate::sale::FungibleTokenId;
+use crate::*;
+
+// Funds held by the market, the NEAR balance should cover the NEAR part of all of them
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct EscrowSummary {
+    // Paid by the sellers for the storage of their sales
+    pub storage_deposits: U128,
+    // Deposits of the sale and auction bids and of the purchases waiting for the payout
+    pub escrow: HashMap<FungibleTokenId, U128>,
+    // Accrued protocol fees, see `withdraw_protocol_fees`
+    pub protocol_fees: HashMap<FungibleTokenId, U128>,
+}
+
+#[near_bindgen]
+impl Market {
+    pub fn get_escrow_summary(&self) -> EscrowSummary {
+        EscrowSummary {
+            storage_deposits: self.market.storage_deposits_total.into(),
+            escrow: self
+                .market
+                .escrow
+                .iter()
+                .map(|(ft_token_id, amount)| (ft_token_id, amount.into()))
+                .collect(),
+            protocol_fees: self.get_protocol_fees(),
+        }
+    }
+}
+
+impl Market {
+    // Called when the market takes a bid or a purchase deposit
+    pub(crate) fn escrow_deposit(&mut self, ft_token_id: &FungibleTokenId, amount: Balance) {
+        let escrow = self.market.escrow.get(ft_token_id).unwrap_or(0);
+        self.market.escrow.insert(ft_token_id, &(escrow + amount));
+    }
+
+    // Called when the deposit is refunded or paid out
+    // Releasing more than was deposited is a bug in the accounting
+    pub(crate) fn escrow_release(&mut self, ft_token_id: &FungibleTokenId, amount: Balance) {
+        let escrow = self.market.escrow.get(ft_token_id).unwrap_or(0);
+        let escrow = escrow.checked_sub(amount).expect("Escrow underflow");
+        if escrow == 0 {
+            self.market.escrow.remove(ft_token_id);
+        } else {
+            self.market.escrow.insert(ft_token_id, &escrow);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::mock::VmAction;
+    use near_sdk::test_utils::{get_created_receipts, VMContextBuilder};
+    use near_sdk::{testing_env, PromiseResult, RuntimeFeesConfig, VMConfig};
+
+    use super::*;
+    use crate::auction::{Auction, MIN_DURATION};
+    use crate::bid::Bid;
+    use crate::collection_offer::CollectionOffer;
+    use crate::token_offer::TokenOffer;
+    use crate::fee::{calculate_price_with_fees, Fees};
+    use crate::market_core::NonFungibleTokenApprovalReceiver;
+    use crate::sale::{Payout, Sale, DELIMETER};
+    use crate::test_utils::{account, new_market};
+
+    const ONE_NEAR: Balance = 10u128.pow(24);
+    const SELLER: &str = "alice";
+    const BIDDERS: [&str; 3] = ["bob", "carol", "dave"];
+
+    fn near() -> FungibleTokenId {
+        account("near")
+    }
+
+    #[derive(Deserialize)]
+    #[serde(crate = "near_sdk::serde")]
+    struct ResolvePurchaseArgs {
+        ft_token_id: FungibleTokenId,
+        buyer_id: AccountId,
+        sale: Sale,
+        price: U128,
+        fees: Fees,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(crate = "near_sdk::serde")]
+    struct ResolveFinishAuctionArgs {
+        auction_id: U128,
+        auction: Auction,
+        fees: Fees,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(crate = "near_sdk::serde")]
+    struct ResolveRefundArgs {
+        ft_token_id: FungibleTokenId,
+        receiver_id: AccountId,
+        amount: U128,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(crate = "near_sdk::serde")]
+    struct ResolveRestoreSaleArgs {
+        sale: Sale,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(crate = "near_sdk::serde")]
+    struct ResolveCollectionOfferArgs {
+        offer_id: U128,
+        offer: CollectionOffer,
+        token_id: TokenId,
+        owner_id: AccountId,
+        fees: Fees,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(crate = "near_sdk::serde")]
+    struct ResolveTokenOfferArgs {
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        ft_token_id: FungibleTokenId,
+        offer: TokenOffer,
+        owner_id: AccountId,
+        fees: Fees,
+    }
+
+    // Purchases waiting for `nft_transfer_payout` and sales waiting for `nft_is_approved`
+    enum Pending {
+        Purchase(ResolvePurchaseArgs),
+        Auction(ResolveFinishAuctionArgs),
+        Restore(ResolveRestoreSaleArgs),
+        CollectionOffer(ResolveCollectionOfferArgs),
+        TokenOffer(ResolveTokenOfferArgs),
+    }
+
+    impl Pending {
+        fn price(&self) -> Balance {
+            match self {
+                Pending::Purchase(args) => args.price.0,
+                Pending::Auction(args) => args.auction.bid.as_ref().unwrap().price.0,
+                Pending::Restore(_) => 0,
+                Pending::CollectionOffer(args) => args.offer.price.0,
+                Pending::TokenOffer(args) => args.offer.bid.price.0,
+            }
+        }
+
+        // Other bids of the sale, they are refunded after the purchase
+        fn sale_bids(&self) -> Balance {
+            let sale = match self {
+                Pending::Purchase(args) => &args.sale,
+                Pending::Restore(args) => &args.sale,
+                Pending::Auction(_) | Pending::CollectionOffer(_) | Pending::TokenOffer(_) => {
+                    return 0
+                }
+            };
+            sale.bids.values().flatten().map(|bid| bid.price.0).sum()
+        }
+    }
+
+    // Runs the contract calls one by one keeping the NEAR balance of the market between them
+    struct Sim {
+        contract: Market,
+        balance: Balance,
+        now: u64,
+        seed: u64,
+        next_token: u64,
+        pending: Vec<Pending>,
+        // Refunds waiting for the transfer result
+        refunds: Vec<ResolveRefundArgs>,
+    }
+
+    impl Sim {
+        fn new(seed: u64) -> Self {
+            // Every simulation starts with empty storage
+            Sim {
+                contract: new_market(),
+                balance: 10 * ONE_NEAR,
+                now: 1,
+                seed,
+                next_token: 1,
+                pending: Vec::new(),
+                refunds: Vec::new(),
+            }
+        }
+
+        // xorshift, the test should be reproducible
+        fn rand(&mut self, n: u64) -> u64 {
+            self.seed ^= self.seed << 13;
+            self.seed ^= self.seed >> 7;
+            self.seed ^= self.seed << 17;
+            self.seed % n
+        }
+
+        fn pick<T>(&mut self, items: Vec<T>) -> Option<T> {
+            if items.is_empty() {
+                return None;
+            }
+            let index = self.rand(items.len() as u64) as usize;
+            items.into_iter().nth(index)
+        }
+
+        fn call<R>(
+            &mut self,
+            predecessor: &str,
+            deposit: Balance,
+            promise_results: Vec<PromiseResult>,
+            f: impl FnOnce(&mut Market) -> R,
+        ) -> R {
+            let signer = if predecessor == "nft" { SELLER } else { predecessor };
+            testing_env!(
+                VMContextBuilder::new()
+                    .current_account_id(account("market"))
+                    .predecessor_account_id(account(predecessor))
+                    .signer_account_id(account(signer))
+                    .attached_deposit(deposit)
+                    .account_balance(self.balance + deposit)
+                    .block_timestamp(self.now)
+                    .build(),
+                VMConfig::test(),
+                RuntimeFeesConfig::test(),
+                HashMap::default(),
+                promise_results,
+            );
+            let result = f(&mut self.contract);
+            self.balance = env::account_balance();
+            for receipt in get_created_receipts() {
+                for action in receipt.actions {
+                    if let VmAction::FunctionCall {
+                        function_name,
+                        args,
+                        ..
+                    } = action
+                    {
+                        match function_name.as_str() {
+                            "resolve_purchase" => self.pending.push(Pending::Purchase(
+                                near_sdk::serde_json::from_slice(&args).unwrap(),
+                            )),
+                            "resolve_finish_auction" => self.pending.push(Pending::Auction(
+                                near_sdk::serde_json::from_slice(&args).unwrap(),
+                            )),
+                            "resolve_restore_sale" => self.pending.push(Pending::Restore(
+                                near_sdk::serde_json::from_slice(&args).unwrap(),
+                            )),
+                            "resolve_token_offer" => self.pending.push(Pending::TokenOffer(
+                                near_sdk::serde_json::from_slice(&args).unwrap(),
+                            )),
+                            "resolve_collection_offer" => {
+                                self.pending.push(Pending::CollectionOffer(
+                                    near_sdk::serde_json::from_slice(&args).unwrap(),
+                                ))
+                            }
+                            "resolve_refund" => self
+                                .refunds
+                                .push(near_sdk::serde_json::from_slice(&args).unwrap()),
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            result
+        }
+
+        fn sales(&self) -> Vec<Sale> {
+            self.contract.market.sales.values().collect()
+        }
+
+        fn auctions(&self) -> Vec<(u128, Auction)> {
+            self.contract.market.auctions.iter().collect()
+        }
+
+        fn collection_offers(&self) -> Vec<(u128, CollectionOffer)> {
+            self.contract.market.collection_offers.iter().collect()
+        }
+
+        fn storage_deposit(&mut self) {
+            let user = self.pick(vec![SELLER, BIDDERS[0]]).unwrap();
+            let deposit = STORAGE_PER_SALE * (1 + self.rand(3) as u128);
+            self.call(user, deposit, vec![], |market| {
+                market.storage_deposit(None);
+            });
+        }
+
+        fn storage_withdraw(&mut self) {
+            let user = self.pick(vec![SELLER, BIDDERS[0]]).unwrap();
+            self.call(user, 1, vec![], |market| market.storage_withdraw());
+        }
+
+        fn list(&mut self, auction: bool) {
+            let paid = self
+                .contract
+                .market
+                .storage_deposits
+                .get(&account(SELLER))
+                .unwrap_or(0);
+            let listings = self.contract.internal_listings_count(&account(SELLER)) as u128;
+            if paid <= (listings + 1) * STORAGE_PER_SALE {
+                return self.storage_deposit();
+            }
+            let price = U128(ONE_NEAR * (1 + self.rand(5) as u128));
+            let msg = if auction {
+                let reserve_price = if self.rand(2) == 0 {
+                    Some(U128(price.0 * 2))
+                } else {
+                    None
+                };
+                ArgsKind::Auction(AuctionArgs {
+                    token_type: None,
+                    minimal_step: U128(1000),
+                    start_price: price,
+                    start: None,
+                    duration: U64(MIN_DURATION),
+                    buy_out_price: None,
+                    reserve_price,
+                    extension: None,
+                    origins: None,
+                })
+            } else {
+                ArgsKind::Sale(SaleArgs {
+                    sale_conditions: HashMap::from([(near(), price)]),
+                    token_type: None,
+                    start: None,
+                    end: None,
+                    origins: None,
+                })
+            };
+            let token_id = format!("1:{}", self.next_token);
+            self.next_token += 1;
+            let msg = near_sdk::serde_json::to_string(&msg).unwrap();
+            self.call("nft", 0, vec![], |market| {
+                market.nft_on_approve(token_id, account(SELLER), 0, msg)
+            });
+        }
+
+        fn offer(&mut self) {
+            let sale = match self.pick(self.sales()) {
+                Some(sale) => sale,
+                None => return,
+            };
+            let bidder = self.pick(BIDDERS.to_vec()).unwrap();
+            let deposit = if self.rand(4) == 0 {
+                calculate_price_with_fees(sale.sale_conditions[&near()], None, sale.protocol_fee)
+            } else {
+                match sale.bids.get(&near()).and_then(|bids| bids.last()) {
+                    Some(bid) => bid.price.0 + 1000 + self.rand(1000) as u128,
+                    None => ONE_NEAR / 100 * (1 + self.rand(10) as u128),
+                }
+            };
+            self.call(bidder, deposit, vec![], |market| {
+                market.offer(sale.nft_contract_id, sale.token_id, near(), None, None, None)
+            });
+        }
+
+        // Only the first bidder pays for the storage of the offers
+        fn storage_covers_new_offer(&self, bidder: &str) -> bool {
+            self.contract
+                .storage_covers_new_sale(&account(bidder), &account(bidder))
+        }
+
+        fn collection_offer(&mut self) {
+            let bidder = BIDDERS[0];
+            if !self.storage_covers_new_offer(bidder) {
+                return self.storage_deposit();
+            }
+            let token_type = if self.rand(2) == 0 {
+                Some("1".to_string())
+            } else {
+                None
+            };
+            let deposit = ONE_NEAR / 100 * (1 + self.rand(10) as u128);
+            self.call(bidder, deposit, vec![], |market| {
+                market.collection_offer(account("nft"), token_type, None, None, None)
+            });
+        }
+
+        // The seller either sells a new token to the offer or the bidder removes it
+        fn accept_collection_offer(&mut self, accept: bool) {
+            let (offer_id, offer) = match self.pick(self.collection_offers()) {
+                Some(offer) => offer,
+                None => return,
+            };
+            if !accept {
+                return self.call(offer.owner_id.as_str(), 1, vec![], |market| {
+                    market.remove_collection_offer(U128(offer_id))
+                });
+            }
+            let token_id = format!("1:{}", self.next_token);
+            self.next_token += 1;
+            let msg = near_sdk::serde_json::to_string(&ArgsKind::AcceptCollectionOffer(
+                AcceptCollectionOfferArgs {
+                    offer_id: U128(offer_id),
+                },
+            ))
+            .unwrap();
+            self.call("nft", 0, vec![], |market| {
+                market.nft_on_approve(token_id, account(SELLER), 0, msg)
+            });
+        }
+
+        fn token_offers(&self) -> Vec<(TokenId, TokenOffer)> {
+            self.contract
+                .market
+                .token_offers
+                .iter()
+                .flat_map(|(contract_and_token_id, offers)| {
+                    let token_id = contract_and_token_id.split(DELIMETER).nth(1).unwrap();
+                    let token_id = token_id.to_string();
+                    let offers = offers.get(&near()).cloned().unwrap_or_default();
+                    offers.into_iter().map(move |offer| (token_id.clone(), offer))
+                })
+                .collect()
+        }
+
+        // Offers on any token, listed or not
+        fn token_offer(&mut self) {
+            let bidder = BIDDERS[0];
+            if !self.storage_covers_new_offer(bidder) {
+                return self.storage_deposit();
+            }
+            let token_id = format!("1:{}", 1 + self.rand(self.next_token));
+            let offers = self.contract.get_token_offers(account("nft"), token_id.clone());
+            let deposit = match offers.get(&near()).and_then(|offers| offers.last()) {
+                Some(offer) => offer.bid.price.0 + 1000 + self.rand(1000) as u128,
+                None => ONE_NEAR / 100 * (1 + self.rand(10) as u128),
+            };
+            self.call(bidder, deposit, vec![], |market| {
+                market.token_offer(account("nft"), token_id, near(), None, None, None)
+            });
+        }
+
+        // The seller sells the token to its last offer or the bidder removes an offer
+        fn accept_token_offer(&mut self, accept: bool) {
+            let (token_id, TokenOffer { bid, .. }) = match self.pick(self.token_offers()) {
+                Some(offer) => offer,
+                None => return,
+            };
+            if !accept {
+                return self.call(bid.owner_id.as_str(), 1, vec![], |market| {
+                    market.remove_token_offer(
+                        account("nft"),
+                        token_id,
+                        near(),
+                        bid.owner_id.clone(),
+                        bid.price,
+                    )
+                });
+            }
+            // The token auctioned with bids can't be sold before the auction is finished
+            let auctioned = self
+                .auctions()
+                .iter()
+                .any(|(_, auction)| auction.token_id == token_id && auction.bid.is_some());
+            if auctioned {
+                return;
+            }
+            let msg = near_sdk::serde_json::to_string(&ArgsKind::AcceptOffer(AcceptOfferArgs {
+                ft_token_id: near(),
+                bid_owner_id: None,
+                bid_price: None,
+            }))
+            .unwrap();
+            self.call("nft", 0, vec![], |market| {
+                market.nft_on_approve(token_id, account(SELLER), 0, msg)
+            });
+        }
+
+        fn remove_bid(&mut self) {
+            let bids: Vec<(AccountId, TokenId, Bid)> = self
+                .sales()
+                .into_iter()
+                .flat_map(|sale| {
+                    let bids = sale.bids.get(&near()).cloned().unwrap_or_default();
+                    let (nft_contract_id, token_id) = (sale.nft_contract_id, sale.token_id);
+                    bids.into_iter()
+                        .map(move |bid| (nft_contract_id.clone(), token_id.clone(), bid))
+                })
+                .collect();
+            if let Some((nft_contract_id, token_id, bid)) = self.pick(bids) {
+                self.call(bid.owner_id.as_str(), 1, vec![], |market| {
+                    market.remove_bid(nft_contract_id, token_id, near(), bid.price)
+                });
+            }
+        }
+
+        fn accept_offer(&mut self) {
+            let sales: Vec<Sale> = self
+                .sales()
+                .into_iter()
+                .filter(|sale| sale.bids.contains_key(&near()))
+                .collect();
+            if let Some(sale) = self.pick(sales) {
+                self.call(SELLER, 0, vec![], |market| {
+                    market.accept_offer(
+                        sale.nft_contract_id,
+                        sale.token_id,
+                        Some(near()),
+                        None,
+                        None,
+                    )
+                });
+            }
+        }
+
+        fn auction_add_bid(&mut self) {
+            let now = self.now;
+            let auctions: Vec<(u128, Auction)> = self
+                .auctions()
+                .into_iter()
+                .filter(|(_, auction)| auction.start < now && now <= auction.end)
+                .collect();
+            if let Some((auction_id, auction)) = self.pick(auctions) {
+                let bidder = self.pick(BIDDERS.to_vec()).unwrap();
+                let minimal_bid = self.contract.get_minimal_next_bid(U128(auction_id));
+                let deposit = calculate_price_with_fees(minimal_bid, None, auction.protocol_fee)
+                    + self.rand(1000) as u128;
+                self.call(bidder, deposit, vec![], |market| {
+                    market.auction_add_bid(U128(auction_id), None, None)
+                });
+            }
+        }
+
+        fn finish_auction(&mut self) {
+            let now = self.now;
+            let auctions: Vec<(u128, Auction)> = self
+                .auctions()
+                .into_iter()
+                .filter(|(_, auction)| auction.end < now)
+                .collect();
+            if let Some((auction_id, auction)) = self.pick(auctions) {
+                if auction.bid.is_some() {
+                    self.call(BIDDERS[0], 0, vec![], |market| {
+                        market.finish_auction(U128(auction_id));
+                    });
+                } else {
+                    self.call(SELLER, 1, vec![], |market| {
+                        market.cancel_auction(U128(auction_id))
+                    });
+                }
+            }
+        }
+
+        // Completes a purchase with a valid payout, a bad payout or a failed NFT transfer
+        fn resolve(&mut self) {
+            if self.pending.is_empty() {
+                return;
+            }
+            let index = self.rand(self.pending.len() as u64) as usize;
+            let pending = self.pending.remove(index);
+            if let Pending::Restore(args) = pending {
+                return self.resolve_restore_sale(args.sale);
+            }
+            let (owner_id, price, protocol_fee) = match &pending {
+                Pending::TokenOffer(args) => {
+                    (args.owner_id.clone(), pending.price(), args.offer.protocol_fee)
+                }
+                Pending::CollectionOffer(args) => {
+                    (args.owner_id.clone(), pending.price(), args.offer.protocol_fee)
+                }
+                Pending::Purchase(args) => {
+                    (args.sale.owner_id.clone(), args.price.0, args.sale.protocol_fee)
+                }
+                Pending::Auction(args) => (
+                    args.auction.owner_id.clone(),
+                    pending.price(),
+                    args.auction.protocol_fee,
+                ),
+                Pending::Restore(_) => unreachable!(),
+            };
+            let result = match self.rand(5) {
+                0 => PromiseResult::Failed,
+                // The token is transferred, but the NFT contract returns a bad payout
+                1 => PromiseResult::Successful(b"{}".to_vec()),
+                _ => {
+                    let fees = price * 2 * protocol_fee as u128
+                        / (PAYOUT_TOTAL_VALUE + protocol_fee as u128);
+                    let payout = Payout {
+                        payout: HashMap::from([
+                            (owner_id, U128(price - fees)),
+                            (account("market"), U128(fees)),
+                        ]),
+                    };
+                    PromiseResult::Successful(near_sdk::serde_json::to_vec(&payout).unwrap())
+                }
+            };
+            self.call("market", 0, vec![result], |market| match pending {
+                Pending::Purchase(args) => {
+                    market.resolve_purchase(
+                        args.ft_token_id,
+                        args.buyer_id,
+                        args.sale,
+                        args.price,
+                        args.fees,
+                    );
+                }
+                Pending::Auction(args) => {
+                    market.resolve_finish_auction(args.auction_id, args.auction, args.fees);
+                }
+                Pending::CollectionOffer(args) => {
+                    market.resolve_collection_offer(
+                        args.offer_id,
+                        args.offer,
+                        args.token_id,
+                        args.owner_id,
+                        args.fees,
+                    );
+                }
+                Pending::TokenOffer(args) => {
+                    market.resolve_token_offer(
+                        args.nft_contract_id,
+                        args.token_id,
+                        args.ft_token_id,
+                        args.offer,
+                        args.owner_id,
+                        args.fees,
+                    );
+                }
+                Pending::Restore(_) => unreachable!(),
+            });
+        }
+
+        // Restores the sale after a failed purchase if the token is still approved
+        fn resolve_restore_sale(&mut self, sale: Sale) {
+            let result = match self.rand(3) {
+                0 => PromiseResult::Failed,
+                1 => PromiseResult::Successful(b"false".to_vec()),
+                _ => PromiseResult::Successful(b"true".to_vec()),
+            };
+            self.call("market", 0, vec![result], |market| {
+                market.resolve_restore_sale(sale)
+            });
+        }
+
+        // Completes a refund, the failed transfer returns the amount to the market
+        fn resolve_refund(&mut self) {
+            if self.refunds.is_empty() {
+                return;
+            }
+            let index = self.rand(self.refunds.len() as u64) as usize;
+            let args = self.refunds.remove(index);
+            let result = if self.rand(2) == 0 {
+                self.balance += args.amount.0;
+                PromiseResult::Failed
+            } else {
+                PromiseResult::Successful(vec![])
+            };
+            self.call("market", 0, vec![result], |market| {
+                market.resolve_refund(args.ft_token_id, args.receiver_id, args.amount)
+            });
+        }
+
+        fn claim(&mut self) {
+            // The failed payouts are credited to the seller
+            let claimants: Vec<&str> = [SELLER]
+                .iter()
+                .chain(BIDDERS.iter())
+                .copied()
+                .filter(|bidder| {
+                    self.contract
+                        .get_claimable(account(bidder))
+                        .contains_key(&near())
+                })
+                .collect();
+            if let Some(bidder) = self.pick(claimants) {
+                self.call(bidder, 1, vec![], |market| {
+                    market.claim(near());
+                });
+            }
+        }
+
+        // The seller moves the token or revokes the approval
+        fn nft_on_revoke(&mut self) {
+            if let Some(sale) = self.pick(self.sales()) {
+                self.call("nft", 0, vec![], |market| {
+                    market.nft_on_revoke(sale.token_id, sale.owner_id, sale.approval_id)
+                });
+            }
+        }
+
+        fn withdraw_protocol_fees(&mut self) {
+            let accrued = self.contract.market.protocol_fees.get(&near()).unwrap_or(0);
+            if accrued == 0 {
+                return;
+            }
+            let amount = 1 + self.rand(u64::MAX) as u128 % accrued;
+            self.call("owner", 1, vec![], |market| {
+                market.withdraw_protocol_fees(near(), U128(amount), account("owner"));
+            });
+        }
+
+        fn check_invariant(&self) {
+            let summary = self.contract.get_escrow_summary();
+            let escrow = summary.escrow.get(&near()).map(|a| a.0).unwrap_or(0);
+            let protocol_fees = summary.protocol_fees.get(&near()).map(|a| a.0).unwrap_or(0);
+            assert!(self.balance >= summary.storage_deposits.0 + escrow + protocol_fees);
+
+            let storage_deposits: Balance = [SELLER, BIDDERS[0]]
+                .iter()
+                .filter_map(|user| self.contract.market.storage_deposits.get(&account(user)))
+                .sum();
+            assert_eq!(summary.storage_deposits.0, storage_deposits);
+
+            let sale_bids: Balance = self
+                .sales()
+                .iter()
+                .flat_map(|sale| sale.bids.get(&near()).cloned().unwrap_or_default())
+                .map(|bid| bid.price.0)
+                .sum();
+            let auction_bids: Balance = self
+                .auctions()
+                .iter()
+                .filter_map(|(_, auction)| auction.bid.as_ref().map(|bid| bid.price.0))
+                .sum();
+            let pending: Balance = self
+                .pending
+                .iter()
+                .map(|pending| pending.price() + pending.sale_bids())
+                .sum();
+            let collection_offers: Balance = self
+                .collection_offers()
+                .iter()
+                .map(|(_, offer)| offer.price.0)
+                .sum();
+            let token_offers: Balance = self
+                .token_offers()
+                .iter()
+                .map(|(_, offer)| offer.bid.price.0)
+                .sum();
+            let claimable: Balance = [SELLER]
+                .iter()
+                .chain(BIDDERS.iter())
+                .filter_map(|bidder| {
+                    let claimable = self.contract.get_claimable(account(bidder));
+                    claimable.get(&near()).map(|amount| amount.0)
+                })
+                .sum();
+            assert_eq!(
+                escrow,
+                sale_bids + auction_bids + collection_offers + token_offers + pending + claimable
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Escrow underflow")]
+    fn escrow_release_more_than_deposited() {
+        let mut contract = new_market();
+        contract.escrow_deposit(&near(), 100);
+        contract.escrow_release(&near(), 101);
+    }
+
+    #[test]
+    fn escrow_invariant_random_operations() {
+        for seed in 1..=20u64 {
+            let mut sim = Sim::new(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15));
+            for _ in 0..200 {
+                sim.now += NANOS_PER_SEC + sim.rand(60 * NANOS_PER_SEC);
+                match sim.rand(23) {
+                    0 => sim.storage_deposit(),
+                    1 => sim.storage_withdraw(),
+                    2 => sim.list(false),
+                    3 => sim.list(true),
+                    4 | 5 => sim.offer(),
+                    6 => sim.remove_bid(),
+                    7 => sim.accept_offer(),
+                    8 => sim.auction_add_bid(),
+                    9 => sim.finish_auction(),
+                    10 | 11 => sim.resolve(),
+                    12 | 13 => sim.resolve_refund(),
+                    14 => sim.claim(),
+                    15 => sim.nft_on_revoke(),
+                    16 => sim.collection_offer(),
+                    17 => sim.accept_collection_offer(true),
+                    18 => sim.accept_collection_offer(false),
+                    19 => sim.token_offer(),
+                    20 => sim.accept_token_offer(true),
+                    21 => sim.accept_token_offer(false),
+                    _ => sim.withdraw_protocol_fees(),
+                }
+                sim.check_invariant();
+            }
+        }
+    }
+}
@@ -0,0 +1,493 @@
+use std::collections::HashMap;
+
+use crate::auction::AuctionJson;
+use crate::bid::Bid;
+use crate::bundle::BundleJson;
+use crate::collection_offer::CollectionOfferJson;
+use crate::common::*;
+use crate::sale::{FungibleTokenId, SaleJson, SeriesSale, TokenSeriesId};
+use crate::swap::SwapOfferJson;
+
+pub const MARKET_STANDARD_VERSION: &str = "1.0.0";
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "standard")]
+#[serde(rename_all = "snake_case")]
+pub enum NearEvent {
+    NftBidMarket(MarketEvent),
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MarketEvent {
+    pub version: &'static str,
+    #[serde(flatten)]
+    pub event_kind: MarketEventKind,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "event", content = "data")]
+#[serde(rename_all = "snake_case")]
+pub enum MarketEventKind {
+    SaleCreate(Vec<SaleJson>),
+    SaleUpdate(Vec<SaleUpdateData>),
+    SaleRemove(Vec<SaleRemoveData>),
+    SaleRestore(Vec<SaleJson>),
+    BidAdd(Vec<BidData>),
+    BidRemove(Vec<BidData>),
+    BidCancel(Vec<BidData>),
+    BidRefund(Vec<BidData>),
+    AuctionStart(Vec<AuctionStartData>),
+    AuctionBid(Vec<AuctionBidData>),
+    AuctionExtend(Vec<AuctionExtendData>),
+    AuctionCancel(Vec<AuctionCancelData>),
+    AuctionCommit(Vec<AuctionCommitData>),
+    AuctionReveal(Vec<AuctionRevealData>),
+    AuctionFinish(Vec<PurchaseData>),
+    Purchase(Vec<PurchaseData>),
+    PayoutFail(Vec<PayoutFailData>),
+    CollectionOfferAdd(Vec<CollectionOfferJson>),
+    CollectionOfferRemove(Vec<CollectionOfferRemoveData>),
+    TokenOfferAdd(Vec<BidData>),
+    TokenOfferRemove(Vec<BidData>),
+    SeriesSaleCreate(Vec<SeriesSale>),
+    SeriesSaleRemove(Vec<SeriesSaleRemoveData>),
+    BundleCreate(Vec<BundleJson>),
+    BundleRemove(Vec<BundleRemoveData>),
+    BundleBid(Vec<BundleBidData>),
+    BundlePurchase(Vec<BundlePurchaseData>),
+    BundlePayoutFail(Vec<BundlePayoutFailData>),
+    BundleRestore(Vec<BundleJson>),
+    SwapOfferCreate(Vec<SwapOfferJson>),
+    SwapOfferRemove(Vec<SwapOfferRemoveData>),
+    Swap(Vec<SwapData>),
+    SwapFail(Vec<SwapData>),
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SaleUpdateData {
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub ft_token_id: FungibleTokenId,
+    pub price: U128,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SaleRemoveData {
+    pub owner_id: AccountId,
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BidData {
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub ft_token_id: FungibleTokenId,
+    #[serde(flatten)]
+    pub bid: Bid,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AuctionStartData {
+    pub auction_id: U128,
+    #[serde(flatten)]
+    pub auction: AuctionJson,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AuctionBidData {
+    pub auction_id: U128,
+    pub ft_token_id: FungibleTokenId,
+    #[serde(flatten)]
+    pub bid: Bid,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AuctionExtendData {
+    pub auction_id: U128,
+    pub end: U64,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AuctionCancelData {
+    pub auction_id: U128,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AuctionCommitData {
+    pub auction_id: U128,
+    pub owner_id: AccountId,
+    pub ft_token_id: FungibleTokenId,
+    pub deposit: U128,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AuctionRevealData {
+    pub auction_id: U128,
+    pub owner_id: AccountId,
+    pub price: U128,
+}
+
+// Settled purchase of the sale or the auction (`auction_id` is set only for auctions)
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PurchaseData {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auction_id: Option<U128>,
+    pub owner_id: AccountId,
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub buyer_id: AccountId,
+    pub ft_token_id: FungibleTokenId,
+    pub price: U128,
+    pub payout: HashMap<AccountId, U128>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PayoutFailData {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auction_id: Option<U128>,
+    pub owner_id: AccountId,
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub buyer_id: AccountId,
+    pub ft_token_id: FungibleTokenId,
+    pub price: U128,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CollectionOfferRemoveData {
+    pub offer_id: U128,
+    pub owner_id: AccountId,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SeriesSaleRemoveData {
+    pub owner_id: AccountId,
+    pub nft_contract_id: AccountId,
+    pub series_id: TokenSeriesId,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BundleRemoveData {
+    pub bundle_id: U128,
+    pub owner_id: AccountId,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BundleBidData {
+    pub bundle_id: U128,
+    pub ft_token_id: FungibleTokenId,
+    #[serde(flatten)]
+    pub bid: Bid,
+}
+
+// Every token of the bundle is transferred to the buyer, the payout includes all the royalties
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BundlePurchaseData {
+    pub bundle_id: U128,
+    pub owner_id: AccountId,
+    pub buyer_id: AccountId,
+    pub ft_token_id: FungibleTokenId,
+    pub price: U128,
+    pub payout: HashMap<AccountId, U128>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BundlePayoutFailData {
+    pub bundle_id: U128,
+    pub owner_id: AccountId,
+    pub buyer_id: AccountId,
+    pub ft_token_id: FungibleTokenId,
+    pub price: U128,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapOfferRemoveData {
+    pub swap_id: U128,
+    pub owner_id: AccountId,
+}
+
+// `owner_id` made the offer for the token of `receiver_id`
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapData {
+    pub swap_id: U128,
+    pub owner_id: AccountId,
+    pub receiver_id: AccountId,
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub ft_token_id: FungibleTokenId,
+    pub top_up: U128,
+}
+
+impl NearEvent {
+    pub fn new_market(version: &'static str, event_kind: MarketEventKind) -> Self {
+        NearEvent::NftBidMarket(MarketEvent {
+            version,
+            event_kind,
+        })
+    }
+
+    pub fn new_market_v1(event_kind: MarketEventKind) -> Self {
+        NearEvent::new_market(MARKET_STANDARD_VERSION, event_kind)
+    }
+
+    #[must_use = "don't forget to .emit() the event"]
+    pub fn sale_create(data: SaleJson) -> Self {
+        NearEvent::new_market_v1(MarketEventKind::SaleCreate(vec![data]))
+    }
+
+    #[must_use = "don't forget to .emit() the event"]
+    pub fn sale_update(data: SaleUpdateData) -> Self {
+        NearEvent::new_market_v1(MarketEventKind::SaleUpdate(vec![data]))
+    }
+
+    #[must_use = "don't forget to .emit() the event"]
+    pub fn sale_remove(data: SaleRemoveData) -> Self {
+        NearEvent::new_market_v1(MarketEventKind::SaleRemove(vec![data]))
+    }
+
+    #[must_use = "don't forget to .emit() the event"]
+    pub fn sale_restore(data: SaleJson) -> Self {
+        NearEvent::new_market_v1(MarketEventKind::SaleRestore(vec![data]))
+    }
+
+    #[must_use = "don't forget to .emit() the event"]
+    pub fn bid_add(data: BidData) -> Self {
+        NearEvent::new_market_v1(MarketEventKind::BidAdd(vec![data]))
+    }
+
+    #[must_use = "don't forget to .emit() the event"]
+    pub fn bid_remove(data: BidData) -> Self {
+        NearEvent::new_market_v1(MarketEventKind::BidRemove(vec![data]))
+    }
+
+    #[must_use = "don't forget to .emit() the event"]
+    pub fn bid_cancel(data: BidData) -> Self {
+        NearEvent::new_market_v1(MarketEventKind::BidCancel(vec![data]))
+    }
+
+    #[must_use = "don't forget to .emit() the event"]
+    pub fn bid_refund(data: BidData) -> Self {
+        NearEvent::new_market_v1(MarketEventKind::BidRefund(vec![data]))
+    }
+
+    #[must_use = "don't forget to .emit() the event"]
+    pub fn auction_start(data: AuctionStartData) -> Self {
+        NearEvent::new_market_v1(MarketEventKind::AuctionStart(vec![data]))
+    }
+
+    #[must_use = "don't forget to .emit() the event"]
+    pub fn auction_bid(data: AuctionBidData) -> Self {
+        NearEvent::new_market_v1(MarketEventKind::AuctionBid(vec![data]))
+    }
+
+    #[must_use = "don't forget to .emit() the event"]
+    pub fn auction_extend(data: AuctionExtendData) -> Self {
+        NearEvent::new_market_v1(MarketEventKind::AuctionExtend(vec![data]))
+    }
+
+    #[must_use = "don't forget to .emit() the event"]
+    pub fn auction_cancel(data: AuctionCancelData) -> Self {
+        NearEvent::new_market_v1(MarketEventKind::AuctionCancel(vec![data]))
+    }
+
+    #[must_use = "don't forget to .emit() the event"]
+    pub fn auction_commit(data: AuctionCommitData) -> Self {
+        NearEvent::new_market_v1(MarketEventKind::AuctionCommit(vec![data]))
+    }
+
+    #[must_use = "don't forget to .emit() the event"]
+    pub fn auction_reveal(data: AuctionRevealData) -> Self {
+        NearEvent::new_market_v1(MarketEventKind::AuctionReveal(vec![data]))
+    }
+
+    #[must_use = "don't forget to .emit() the event"]
+    pub fn auction_finish(data: PurchaseData) -> Self {
+        NearEvent::new_market_v1(MarketEventKind::AuctionFinish(vec![data]))
+    }
+
+    #[must_use = "don't forget to .emit() the event"]
+    pub fn purchase(data: PurchaseData) -> Self {
+        NearEvent::new_market_v1(MarketEventKind::Purchase(vec![data]))
+    }
+
+    #[must_use = "don't forget to .emit() the event"]
+    pub fn payout_fail(data: PayoutFailData) -> Self {
+        NearEvent::new_market_v1(MarketEventKind::PayoutFail(vec![data]))
+    }
+
+    #[must_use = "don't forget to .emit() the event"]
+    pub fn collection_offer_add(data: CollectionOfferJson) -> Self {
+        NearEvent::new_market_v1(MarketEventKind::CollectionOfferAdd(vec![data]))
+    }
+
+    #[must_use = "don't forget to .emit() the event"]
+    pub fn collection_offer_remove(data: CollectionOfferRemoveData) -> Self {
+        NearEvent::new_market_v1(MarketEventKind::CollectionOfferRemove(vec![data]))
+    }
+
+    #[must_use = "don't forget to .emit() the event"]
+    pub fn token_offer_add(data: BidData) -> Self {
+        NearEvent::new_market_v1(MarketEventKind::TokenOfferAdd(vec![data]))
+    }
+
+    #[must_use = "don't forget to .emit() the event"]
+    pub fn token_offer_remove(data: BidData) -> Self {
+        NearEvent::new_market_v1(MarketEventKind::TokenOfferRemove(vec![data]))
+    }
+
+    #[must_use = "don't forget to .emit() the event"]
+    pub fn series_sale_create(data: SeriesSale) -> Self {
+        NearEvent::new_market_v1(MarketEventKind::SeriesSaleCreate(vec![data]))
+    }
+
+    #[must_use = "don't forget to .emit() the event"]
+    pub fn series_sale_remove(data: SeriesSaleRemoveData) -> Self {
+        NearEvent::new_market_v1(MarketEventKind::SeriesSaleRemove(vec![data]))
+    }
+
+    #[must_use = "don't forget to .emit() the event"]
+    pub fn bundle_create(data: BundleJson) -> Self {
+        NearEvent::new_market_v1(MarketEventKind::BundleCreate(vec![data]))
+    }
+
+    #[must_use = "don't forget to .emit() the event"]
+    pub fn bundle_remove(data: BundleRemoveData) -> Self {
+        NearEvent::new_market_v1(MarketEventKind::BundleRemove(vec![data]))
+    }
+
+    #[must_use = "don't forget to .emit() the event"]
+    pub fn bundle_bid(data: BundleBidData) -> Self {
+        NearEvent::new_market_v1(MarketEventKind::BundleBid(vec![data]))
+    }
+
+    #[must_use = "don't forget to .emit() the event"]
+    pub fn bundle_purchase(data: BundlePurchaseData) -> Self {
+        NearEvent::new_market_v1(MarketEventKind::BundlePurchase(vec![data]))
+    }
+
+    #[must_use = "don't forget to .emit() the event"]
+    pub fn bundle_payout_fail(data: BundlePayoutFailData) -> Self {
+        NearEvent::new_market_v1(MarketEventKind::BundlePayoutFail(vec![data]))
+    }
+
+    #[must_use = "don't forget to .emit() the event"]
+    pub fn bundle_restore(data: BundleJson) -> Self {
+        NearEvent::new_market_v1(MarketEventKind::BundleRestore(vec![data]))
+    }
+
+    #[must_use = "don't forget to .emit() the event"]
+    pub fn swap_offer_create(data: SwapOfferJson) -> Self {
+        NearEvent::new_market_v1(MarketEventKind::SwapOfferCreate(vec![data]))
+    }
+
+    #[must_use = "don't forget to .emit() the event"]
+    pub fn swap_offer_remove(data: SwapOfferRemoveData) -> Self {
+        NearEvent::new_market_v1(MarketEventKind::SwapOfferRemove(vec![data]))
+    }
+
+    #[must_use = "don't forget to .emit() the event"]
+    pub fn swap(data: SwapData) -> Self {
+        NearEvent::new_market_v1(MarketEventKind::Swap(vec![data]))
+    }
+
+    #[must_use = "don't forget to .emit() the event"]
+    pub fn swap_fail(data: SwapData) -> Self {
+        NearEvent::new_market_v1(MarketEventKind::SwapFail(vec![data]))
+    }
+
+    pub(crate) fn to_json_string(&self) -> String {
+        near_sdk::serde_json::to_string(self).unwrap()
+    }
+
+    pub fn to_json_event_string(&self) -> String {
+        format!("EVENT_JSON:{}", self.to_json_string())
+    }
+
+    /// Logs the event to the host. This is required to ensure that the event is triggered
+    /// and to consume the event.
+    pub fn emit(self) {
+        near_sdk::env::log_str(&self.to_json_event_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::account;
+
+    #[test]
+    fn sale_remove() {
+        let log = NearEvent::sale_remove(SaleRemoveData {
+            owner_id: account("bob"),
+            nft_contract_id: account("nft"),
+            token_id: "1:1".to_string(),
+        })
+        .to_json_string();
+        assert_eq!(
+            log,
+            r#"{"standard":"nft_bid_market","version":"1.0.0","event":"sale_remove","data":[{"owner_id":"bob","nft_contract_id":"nft","token_id":"1:1"}]}"#
+        );
+    }
+
+    #[test]
+    fn bid_add() {
+        let log = NearEvent::bid_add(BidData {
+            nft_contract_id: account("nft"),
+            token_id: "1:1".to_string(),
+            ft_token_id: account("near"),
+            bid: Bid {
+                owner_id: account("bob"),
+                price: U128(1000),
+                start: U64(1),
+                end: None,
+                origins: HashMap::new(),
+            },
+        })
+        .to_json_string();
+        assert_eq!(
+            log,
+            r#"{"standard":"nft_bid_market","version":"1.0.0","event":"bid_add","data":[{"nft_contract_id":"nft","token_id":"1:1","ft_token_id":"near","owner_id":"bob","price":"1000","start":"1","end":null,"origins":{}}]}"#
+        );
+    }
+
+    #[test]
+    fn payout_fail() {
+        let log = NearEvent::payout_fail(PayoutFailData {
+            auction_id: Some(U128(3)),
+            owner_id: account("bob"),
+            nft_contract_id: account("nft"),
+            token_id: "1:1".to_string(),
+            buyer_id: AccountId::new_unchecked("alice".to_string()),
+            ft_token_id: account("near"),
+            price: U128(1000),
+        })
+        .to_json_event_string();
+        assert_eq!(
+            log,
+            r#"EVENT_JSON:{"standard":"nft_bid_market","version":"1.0.0","event":"payout_fail","data":[{"auction_id":"3","owner_id":"bob","nft_contract_id":"nft","token_id":"1:1","buyer_id":"alice","ft_token_id":"near","price":"1000"}]}"#
+        );
+    }
+}
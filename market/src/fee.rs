@@ -1,17 +1,78 @@
 use crate::*;
+use crate::sale::{
+    ext_contract, ext_self, parse_payout, FungibleTokenId, GAS_FOR_FT_TRANSFER, MAX_LEN_PAYOUT,
+    NO_DEPOSIT,
+};
 use crate::{bid::Origins, common::*};
+use near_sdk::{is_promise_success, Gas};
 use std::collections::HashMap;
 
 pub const PAYOUT_TOTAL_VALUE: u128 = 10_000;
 pub const PROTOCOL_FEE: u128 = 300; // 10_000 is 100%, so 300 is 3%
+pub const MAX_PROTOCOL_FEE: u32 = 1_000;
+pub const GAS_FOR_RESOLVE_WITHDRAW: Gas = Gas(5_000_000_000_000);
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct Fees {
     pub buyer: HashMap<AccountId, u32>,
     pub seller: HashMap<AccountId, u32>,
 }
 
+impl Fees {
+    // Both the buyer and the seller pay the protocol fee to the treasury
+    pub(crate) fn new(
+        buyer: Origins,
+        seller: &Origins,
+        treasury_id: &AccountId,
+        protocol_fee: u32,
+    ) -> Self {
+        let mut buyer = buyer;
+        *buyer.entry(treasury_id.clone()).or_insert(0) += protocol_fee;
+        let mut seller = seller.clone();
+        *seller.entry(treasury_id.clone()).or_insert(0) += protocol_fee;
+        Self { buyer, seller }
+    }
+
+    // Payout of the token sold for `price` by the result of `nft_transfer_payout`
+    // The token is already transferred, so a bad payout from bad NFT contract can't be refunded,
+    // the fees are paid and the rest goes to the owner instead
+    pub(crate) fn payout_from_result(
+        &self,
+        value: &[u8],
+        price: u128,
+        owner_id: &AccountId,
+    ) -> HashMap<AccountId, U128> {
+        parse_payout(value, price).map_or_else(
+            || {
+                env::log_str("Invalid payout, paying the fees and the rest to the owner");
+                self.payout(price, owner_id)
+            },
+            |payout| payout.payout,
+        )
+    }
+
+    // Payout without royalties, used when the payout of the NFT contract can't be trusted
+    // Mirrors `nft_transfer_payout`: the fees are taken from the price without the buyer fees,
+    // the rest goes to the owner
+    pub(crate) fn payout(&self, price: u128, owner_id: &AccountId) -> HashMap<AccountId, U128> {
+        let buyer_fee = calculate_origins(&self.buyer) as u128;
+        let initial_price = price - price * buyer_fee / (PAYOUT_TOTAL_VALUE + buyer_fee);
+        let mut payout: HashMap<AccountId, u128> = HashMap::new();
+        let mut paid = 0;
+        for (account_id, &fee) in self.buyer.iter().chain(self.seller.iter()) {
+            let amount = initial_price * fee as u128 / PAYOUT_TOTAL_VALUE;
+            *payout.entry(account_id.clone()).or_insert(0) += amount;
+            paid += amount;
+        }
+        *payout.entry(owner_id.clone()).or_insert(0) += price.saturating_sub(paid);
+        payout
+            .into_iter()
+            .map(|(account_id, amount)| (account_id, amount.into()))
+            .collect()
+    }
+}
+
 pub fn calculate_origins(origins: &Origins) -> u32 {
     let mut total: u32 = 0;
     for val in origins.values() {
@@ -20,25 +81,131 @@ pub fn calculate_origins(origins: &Origins) -> u32 {
     total
 }
 
-pub fn calculate_actual_amount(amount: u128, total_origins: u32) -> u128 {
-    let origin_fee = amount * (total_origins as u128 + PROTOCOL_FEE)
-        / (PAYOUT_TOTAL_VALUE + total_origins as u128 + PROTOCOL_FEE);
+pub fn calculate_actual_amount(amount: u128, total_origins: u32, protocol_fee: u32) -> u128 {
+    let total_fee = total_origins as u128 + protocol_fee as u128;
+    let origin_fee = amount * total_fee / (PAYOUT_TOTAL_VALUE + total_fee);
     amount - origin_fee
 }
 
-pub fn calculate_price_with_fees(price: U128, origins: Option<&Origins>) -> u128 {
+pub fn calculate_price_with_fees(
+    price: U128,
+    origins: Option<&Origins>,
+    protocol_fee: u32,
+) -> u128 {
     let total_origins = if let Some(origins) = origins {
         calculate_origins(origins)
     } else {
         0
     };
-    price.0 * (PAYOUT_TOTAL_VALUE + PROTOCOL_FEE + total_origins as u128) / PAYOUT_TOTAL_VALUE
+    price.0 * (PAYOUT_TOTAL_VALUE + protocol_fee as u128 + total_origins as u128)
+        / PAYOUT_TOTAL_VALUE
 }
 
 #[near_bindgen]
 impl Market {
+    // Uses the current protocol fee, existing sales and auctions keep the fee they were listed with
     pub fn price_with_fees(&self, price: U128, origins: Option<Origins>) -> U128 {
-        calculate_price_with_fees(price, origins.as_ref()).into()
+        calculate_price_with_fees(price, origins.as_ref(), self.market.protocol_fee).into()
+    }
+
+    // Returns the protocol fees accrued on the market account which can be withdrawn
+    pub fn get_protocol_fees(&self) -> HashMap<FungibleTokenId, U128> {
+        self.market
+            .protocol_fees
+            .iter()
+            .map(|(ft_token_id, amount)| (ft_token_id, amount.into()))
+            .collect()
+    }
+
+    // Sends the accrued protocol fees in NEAR or a fungible token
+    // Escrowed bids and storage deposits can't be withdrawn this way
+    #[payable]
+    pub fn withdraw_protocol_fees(
+        &mut self,
+        ft_token_id: FungibleTokenId,
+        amount: U128,
+        receiver_id: AccountId,
+    ) -> Promise {
+        assert_one_yocto();
+        self.assert_owner();
+        let accrued = self.market.protocol_fees.get(&ft_token_id).unwrap_or(0);
+        require!(
+            amount.0 > 0 && amount.0 <= accrued,
+            format!("Can withdraw from 1 to {} of {}", accrued, ft_token_id)
+        );
+        self.internal_update_protocol_fees(&ft_token_id, accrued - amount.0);
+        let transfer = if ft_token_id.as_str() == "near" {
+            Promise::new(receiver_id).transfer(amount.0)
+        } else {
+            ext_contract::ft_transfer(
+                receiver_id,
+                amount,
+                None,
+                ft_token_id.clone(),
+                1,
+                GAS_FOR_FT_TRANSFER,
+            )
+        };
+        transfer.then(ext_self::resolve_withdraw_protocol_fees(
+            ft_token_id,
+            amount,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_RESOLVE_WITHDRAW,
+        ))
+    }
+
+    // self callback
+    // Returns the fees back to the accrued ones if the transfer failed
+    #[private]
+    pub fn resolve_withdraw_protocol_fees(&mut self, ft_token_id: FungibleTokenId, amount: U128) {
+        if !is_promise_success() {
+            let accrued = self.market.protocol_fees.get(&ft_token_id).unwrap_or(0);
+            self.internal_update_protocol_fees(&ft_token_id, accrued + amount.0);
+        }
+    }
+}
+
+impl Market {
+    // Pays out the purchase, the part going to the market account is accrued as protocol fees
+    // A failed transfer is credited to the receiver, see `resolve_refund`
+    // The callbacks have gas for `MAX_LEN_PAYOUT` transfers, the other receivers are credited
+    pub(crate) fn internal_pay_out(
+        &mut self,
+        ft_token_id: &FungibleTokenId,
+        payout: HashMap<AccountId, U128>,
+    ) {
+        self.internal_pay_out_with_limit(ft_token_id, payout, MAX_LEN_PAYOUT);
+    }
+
+    // Same with at most `max_transfers` transfers
+    pub(crate) fn internal_pay_out_with_limit(
+        &mut self,
+        ft_token_id: &FungibleTokenId,
+        payout: HashMap<AccountId, U128>,
+        max_transfers: u32,
+    ) {
+        let mut transfers = 0;
+        for (receiver_id, amount) in payout {
+            if receiver_id == env::current_account_id() {
+                let accrued = self.market.protocol_fees.get(ft_token_id).unwrap_or(0);
+                self.internal_update_protocol_fees(ft_token_id, accrued + amount.0);
+            } else if transfers < max_transfers {
+                self.internal_send(ft_token_id.clone(), receiver_id, amount);
+                transfers += 1;
+            } else {
+                self.escrow_deposit(ft_token_id, amount.0);
+                self.internal_credit_claimable(&receiver_id, ft_token_id, amount.0);
+            }
+        }
+    }
+
+    fn internal_update_protocol_fees(&mut self, ft_token_id: &FungibleTokenId, amount: Balance) {
+        if amount == 0 {
+            self.market.protocol_fees.remove(ft_token_id);
+        } else {
+            self.market.protocol_fees.insert(ft_token_id, &amount);
+        }
     }
 }
 
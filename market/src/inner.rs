@@ -1,9 +1,143 @@
-use crate::bid::Bid;
+use near_contract_standards::non_fungible_token::hash_account_id;
+
+use crate::bid::{Bid, BidListing};
 use crate::common::*;
+use crate::event::{NearEvent, SaleRemoveData};
 use crate::sale::{Sale, DELIMETER};
-use crate::Market;
+use crate::{Market, StorageKey, TokenListing, STORAGE_PER_SALE};
 
 impl Market {
+    // Whether the storage paid by `account_id` covers one more sale of `owner_id`,
+    // the same check as in `nft_on_approve`
+    pub(crate) fn storage_covers_new_sale(
+        &self,
+        account_id: &AccountId,
+        owner_id: &AccountId,
+    ) -> bool {
+        let paid_storage = self.market.storage_deposits.get(account_id).unwrap_or(0);
+        paid_storage >= u128::from(self.internal_listings_count(owner_id) + 1) * STORAGE_PER_SALE
+    }
+
+    // Number of the sales, the auctions, the series sales, the bundles, the swap offers,
+    // the collection offers and the token offers of `owner_id`, each one takes `STORAGE_PER_SALE`
+    pub(crate) fn internal_listings_count(&self, owner_id: &AccountId) -> u64 {
+        let sales = self
+            .market
+            .by_owner_id
+            .get(owner_id)
+            .map(|sales| sales.len())
+            .unwrap_or(0);
+        // The migrated auctions are free
+        let first_charged_auction_id = self.market.first_charged_auction_id;
+        let auctions = self
+            .market
+            .auctions_by_owner_id
+            .get(owner_id)
+            .map(|auctions| {
+                auctions
+                    .iter()
+                    .filter(|&auction_id| auction_id >= first_charged_auction_id)
+                    .count() as u64
+            })
+            .unwrap_or(0);
+        let series_sales = self
+            .market
+            .series_sales_by_owner_id
+            .get(owner_id)
+            .map(|sales| sales.len())
+            .unwrap_or(0);
+        let bundles = self
+            .market
+            .bundles_by_owner_id
+            .get(owner_id)
+            .map(|bundles| bundles.len())
+            .unwrap_or(0);
+        let swap_offers = self
+            .market
+            .swap_offers_by_owner_id
+            .get(owner_id)
+            .map(|offers| offers.len())
+            .unwrap_or(0);
+        let collection_offers = self
+            .market
+            .collection_offers_by_owner_id
+            .get(owner_id)
+            .map(|offers| offers.len())
+            .unwrap_or(0);
+        let token_offers = self
+            .market
+            .token_offers_by_owner_id
+            .get(owner_id)
+            .unwrap_or(0);
+        sales + auctions + series_sales + bundles + swap_offers + collection_offers + token_offers
+    }
+
+    // Inserts the sale with the indexes for views
+    pub(crate) fn internal_insert_sale(&mut self, sale: &Sale) {
+        let contract_and_token_id =
+            format!("{}{}{}", sale.nft_contract_id, DELIMETER, sale.token_id);
+        self.market.sales.insert(&contract_and_token_id, sale);
+        self.internal_index_listing(&contract_and_token_id, &TokenListing::Sale);
+        // A restored sale keeps its bids
+        self.internal_index_sale_bids(sale, true);
+
+        let mut by_owner_id = self
+            .market
+            .by_owner_id
+            .get(&sale.owner_id)
+            .unwrap_or_else(|| {
+                UnorderedSet::new(
+                    StorageKey::ByOwnerIdInner {
+                        account_id_hash: hash_account_id(&sale.owner_id),
+                    }
+                    .try_to_vec()
+                    .unwrap(),
+                )
+            });
+        by_owner_id.insert(&contract_and_token_id);
+        self.market.by_owner_id.insert(&sale.owner_id, &by_owner_id);
+
+        let mut by_nft_contract_id = self
+            .market
+            .by_nft_contract_id
+            .get(&sale.nft_contract_id)
+            .unwrap_or_else(|| {
+                UnorderedSet::new(
+                    StorageKey::ByNFTContractIdInner {
+                        account_id_hash: hash_account_id(&sale.nft_contract_id),
+                    }
+                    .try_to_vec()
+                    .unwrap(),
+                )
+            });
+        by_nft_contract_id.insert(&sale.token_id);
+        self.market
+            .by_nft_contract_id
+            .insert(&sale.nft_contract_id, &by_nft_contract_id);
+
+        if let Some(token_type) = &sale.token_type {
+            let mut by_nft_token_type = self
+                .market
+                .by_nft_token_type
+                .get(token_type)
+                .unwrap_or_else(|| {
+                    UnorderedSet::new(
+                        StorageKey::ByNFTTokenTypeInner {
+                            token_type_hash: hash_account_id(&AccountId::new_unchecked(
+                                token_type.clone(),
+                            )),
+                        }
+                        .try_to_vec()
+                        .unwrap(),
+                    )
+                });
+            by_nft_token_type.insert(&contract_and_token_id);
+            self.market
+                .by_nft_token_type
+                .insert(token_type, &by_nft_token_type);
+        }
+    }
+
     pub(crate) fn internal_remove_sale(
         &mut self,
         nft_contract_id: AccountId,
@@ -15,6 +149,8 @@ impl Market {
             .sales
             .remove(&contract_and_token_id)
             .expect("No sale");
+        self.internal_unindex_listing(&contract_and_token_id, &TokenListing::Sale);
+        self.internal_index_sale_bids(&sale, false);
 
         let mut by_owner_id = self
             .market
@@ -59,6 +195,12 @@ impl Market {
             }
         }
 
+        NearEvent::sale_remove(SaleRemoveData {
+            owner_id: sale.owner_id.clone(),
+            nft_contract_id,
+            token_id,
+        })
+        .emit();
         sale
     }
 
@@ -96,6 +238,10 @@ impl Market {
                         .remove(index);
                 };
                 self.market.sales.insert(&contract_and_token_id, &sale);
+                self.internal_unindex_bid(
+                    owner_id,
+                    &BidListing::sale(&nft_contract_id, &token_id, ft_token_id, price),
+                );
                 //break; // shouldn't allow bids with equal price 
                 return Some((*bid_from_vec).clone());
             };
@@ -1,25 +1,62 @@
 mod auction;
 mod auction_views;
 mod bid;
+mod bundle;
+mod claimable;
+mod collection_offer;
 mod common;
+mod escrow;
+pub mod event;
 mod fee;
 mod inner;
+mod listing;
 mod market_core;
+mod migration;
+mod oracle;
+mod owner;
 mod sale;
 mod sale_views;
+mod sealed_auction;
+mod series_sale;
+mod swap;
+#[cfg(test)]
+mod test_utils;
 mod token;
+mod token_offer;
 
 mod hack; // TODO: remove
 
+use std::cmp::min;
+use std::collections::HashMap;
+
 use common::*;
 
 use crate::sale::{Sale, SaleConditions, TokenType,
     ContractAndTokenId, FungibleTokenId};
 use crate::auction::Auction;
-pub use crate::sale::{SaleJson, BID_HISTORY_LENGTH_DEFAULT};
-pub use crate::market_core::{ArgsKind, SaleArgs, AuctionArgs};
-pub use crate::auction::{AuctionJson, EXTENSION_DURATION};
-pub use crate::fee::{Fees, PAYOUT_TOTAL_VALUE, PROTOCOL_FEE};
+pub use crate::sale::{
+    SaleJson, SeriesSale, PurchaseArgs, BID_HISTORY_LENGTH_DEFAULT, MAX_LEN_PAYOUT,
+};
+pub use crate::market_core::{
+    ArgsKind, SaleArgs, AuctionArgs, DutchAuctionArgs, SealedAuctionArgs, FtArgsKind, OfferArgs,
+    AuctionBidArgs, AuctionCommitBidArgs, CollectionOfferArgs, AcceptCollectionOfferArgs,
+    AcceptOfferArgs, BundleArgs, BundleTokenArgs, BundlePurchaseArgs, AcceptSwapArgs, SwapOfferArgs,
+};
+pub use crate::bundle::{Bundle, BundleJson, BundleToken, MAX_BUNDLE_TOKENS};
+pub use crate::claimable::ClaimableToken;
+pub use crate::collection_offer::{CollectionOffer, CollectionOfferJson};
+pub use crate::auction::{
+    AuctionExtension, AuctionJson, AuctionKind, ExtensionBounds, EXTENSION_DURATION, MIN_DURATION,
+};
+pub use crate::escrow::EscrowSummary;
+pub use crate::fee::{Fees, MAX_PROTOCOL_FEE, PAYOUT_TOTAL_VALUE, PROTOCOL_FEE};
+pub use crate::oracle::{BestOfferJson, OraclePrice};
+pub use crate::sealed_auction::SealedBid;
+pub use crate::series_sale::STORAGE_FOR_MINT;
+pub use crate::swap::{SwapOffer, SwapOfferJson, MAX_SWAP_TOKENS};
+pub use crate::token_offer::{TokenOffer, TokenOffers};
+pub use crate::bid::{BidListing, BidderBidJson};
+pub use crate::listing::{TokenListing, TokenListingJson};
 
 const STORAGE_PER_SALE: u128 = 1000 * STORAGE_PRICE_PER_BYTE;
 
@@ -38,6 +75,37 @@ pub enum StorageKey {
     OriginFees,
     Auctions,
     AuctionId,
+    NFTTokenIds,
+    ProtocolFees,
+    Escrow,
+    Claimable,
+    CollectionOffers,
+    CollectionOffersByNFTContractId,
+    CollectionOffersByNFTContractIdInner { account_id_hash: CryptoHash },
+    TokenOffers,
+    SeriesSales,
+    SeriesSalesByOwnerId,
+    SeriesSalesByOwnerIdInner { account_id_hash: CryptoHash },
+    Bundles,
+    BundlesByOwnerId,
+    BundlesByOwnerIdInner { account_id_hash: CryptoHash },
+    SwapOffers,
+    SwapOffersByOwnerId,
+    SwapOffersByOwnerIdInner { account_id_hash: CryptoHash },
+    SwapOffersByToken,
+    SwapOffersByTokenInner { token_hash: CryptoHash },
+    AuctionsByOwnerId,
+    AuctionsByOwnerIdInner { account_id_hash: CryptoHash },
+    AuctionsByNFTContractId,
+    AuctionsByNFTContractIdInner { account_id_hash: CryptoHash },
+    BidsByBidder,
+    BidsByBidderInner { account_id_hash: CryptoHash },
+    ListingsByToken,
+    OraclePrices,
+    CollectionOffersByOwnerId,
+    CollectionOffersByOwnerIdInner { account_id_hash: CryptoHash },
+    TokenOffersByOwnerId,
+    ClaimableTokens,
 }
 
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
@@ -50,15 +118,114 @@ pub struct MarketSales {
     pub ft_token_ids: UnorderedSet<FungibleTokenId>,
     pub storage_deposits: LookupMap<AccountId, Balance>,
     pub bid_history_length: u8,
+    // Open bids on sales and auctions per bidder, see `get_bids_by_bidder`
+    pub bids_by_bidder: LookupMap<AccountId, UnorderedSet<BidListing>>,
+    // The sale or the auction of each listed token, see `get_listing_for_token`
+    pub listings_by_token: LookupMap<ContractAndTokenId, TokenListing>,
 
     pub auctions: UnorderedMap<u128, Auction>,
     pub next_auction_id: u128,
+    pub auctions_by_owner_id: LookupMap<AccountId, UnorderedSet<u128>>,
+    pub auctions_by_nft_contract_id: LookupMap<AccountId, UnorderedSet<u128>>,
+    // The auctions with smaller ids were migrated from the state before the auctions took
+    // the storage deposit, they stay free for their owners, see `migrate`
+    pub first_charged_auction_id: u128,
+
+    pub price_oracle_id: Option<AccountId>,
+    // The last prices received from the oracle, see `get_best_offer`
+    pub prices: UnorderedMap<FungibleTokenId, OraclePrice>,
+    pub extension_bounds: ExtensionBounds,
+
+    // Protocol fee of new sales and auctions, see `fee.rs`
+    pub protocol_fee: u32,
+    pub treasury_id: AccountId,
+    // Protocol fees accrued on the market account per currency
+    pub protocol_fees: UnorderedMap<FungibleTokenId, Balance>,
+
+    // Funds held for the users, see `escrow.rs`
+    pub storage_deposits_total: Balance,
+    pub escrow: UnorderedMap<FungibleTokenId, Balance>,
+    // Failed refunds, see `claim`
+    pub claimable: LookupMap<AccountId, HashMap<FungibleTokenId, Balance>>,
+    // Tokens held by the market after a failed transfer, see `claim_token`
+    pub claimable_tokens: LookupMap<AccountId, Vec<ClaimableToken>>,
+
+    // Offers on any token of the NFT contract or of its series, see `collection_offer.rs`
+    pub collection_offers: UnorderedMap<u128, CollectionOffer>,
+    pub next_collection_offer_id: u128,
+    pub collection_offers_by_nft_contract_id: LookupMap<AccountId, UnorderedSet<u128>>,
+    pub collection_offers_by_owner_id: LookupMap<AccountId, UnorderedSet<u128>>,
+    // Offers on the tokens which don't have to be listed, see `token_offer.rs`
+    pub token_offers: UnorderedMap<ContractAndTokenId, TokenOffers>,
+    pub token_offers_by_owner_id: LookupMap<AccountId, u64>,
+    // Copies of the series minted on purchase, keyed by the NFT contract and the series id
+    pub series_sales: UnorderedMap<ContractAndTokenId, SeriesSale>,
+    pub series_sales_by_owner_id: LookupMap<AccountId, UnorderedSet<ContractAndTokenId>>,
+    // Lots of several tokens, see `bundle.rs`
+    pub bundles: UnorderedMap<u128, Bundle>,
+    pub next_bundle_id: u128,
+    pub bundles_by_owner_id: LookupMap<AccountId, UnorderedSet<u128>>,
+    // Tokens offered in exchange for a token, see `swap.rs`
+    pub swap_offers: UnorderedMap<u128, SwapOffer>,
+    pub next_swap_offer_id: u128,
+    pub swap_offers_by_owner_id: LookupMap<AccountId, UnorderedSet<u128>>,
+    pub swap_offers_by_token: LookupMap<ContractAndTokenId, UnorderedSet<u128>>,
+}
+
+impl MarketSales {
+    // Empty collections besides the supported fungible tokens
+    pub(crate) fn new(owner_id: AccountId, ft_token_ids: UnorderedSet<FungibleTokenId>) -> Self {
+        MarketSales {
+            owner_id,
+            sales: UnorderedMap::new(StorageKey::Sales),
+            by_owner_id: LookupMap::new(StorageKey::ByOwnerId),
+            by_nft_contract_id: LookupMap::new(StorageKey::ByNFTContractId),
+            by_nft_token_type: LookupMap::new(StorageKey::ByNFTTokenType),
+            ft_token_ids,
+            storage_deposits: LookupMap::new(StorageKey::StorageDeposits),
+            bid_history_length: BID_HISTORY_LENGTH_DEFAULT,
+            bids_by_bidder: LookupMap::new(StorageKey::BidsByBidder),
+            listings_by_token: LookupMap::new(StorageKey::ListingsByToken),
+            auctions: UnorderedMap::new(StorageKey::Auctions),
+            next_auction_id: 0,
+            auctions_by_owner_id: LookupMap::new(StorageKey::AuctionsByOwnerId),
+            auctions_by_nft_contract_id: LookupMap::new(StorageKey::AuctionsByNFTContractId),
+            first_charged_auction_id: 0,
+            price_oracle_id: None,
+            prices: UnorderedMap::new(StorageKey::OraclePrices),
+            extension_bounds: ExtensionBounds::default(),
+            protocol_fee: PROTOCOL_FEE as u32,
+            treasury_id: env::current_account_id(),
+            protocol_fees: UnorderedMap::new(StorageKey::ProtocolFees),
+            storage_deposits_total: 0,
+            escrow: UnorderedMap::new(StorageKey::Escrow),
+            claimable: LookupMap::new(StorageKey::Claimable),
+            claimable_tokens: LookupMap::new(StorageKey::ClaimableTokens),
+            collection_offers: UnorderedMap::new(StorageKey::CollectionOffers),
+            next_collection_offer_id: 0,
+            collection_offers_by_nft_contract_id: LookupMap::new(
+                StorageKey::CollectionOffersByNFTContractId,
+            ),
+            collection_offers_by_owner_id: LookupMap::new(StorageKey::CollectionOffersByOwnerId),
+            token_offers: UnorderedMap::new(StorageKey::TokenOffers),
+            token_offers_by_owner_id: LookupMap::new(StorageKey::TokenOffersByOwnerId),
+            series_sales: UnorderedMap::new(StorageKey::SeriesSales),
+            series_sales_by_owner_id: LookupMap::new(StorageKey::SeriesSalesByOwnerId),
+            bundles: UnorderedMap::new(StorageKey::Bundles),
+            next_bundle_id: 0,
+            bundles_by_owner_id: LookupMap::new(StorageKey::BundlesByOwnerId),
+            swap_offers: UnorderedMap::new(StorageKey::SwapOffers),
+            next_swap_offer_id: 0,
+            swap_offers_by_owner_id: LookupMap::new(StorageKey::SwapOffersByOwnerId),
+            swap_offers_by_token: LookupMap::new(StorageKey::SwapOffersByToken),
+        }
+    }
 }
 
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct Market {
-    non_fungible_token_account_ids: LookupSet<AccountId>,
+    non_fungible_token_account_ids: UnorderedSet<AccountId>,
     market: MarketSales,
 }
 
@@ -66,22 +233,11 @@ pub struct Market {
 impl Market {
     #[init]
     pub fn new(nft_ids: Vec<AccountId>, owner_id: AccountId) -> Self {
-        let mut non_fungible_token_account_ids = LookupSet::new(b"n");
+        let mut non_fungible_token_account_ids = UnorderedSet::new(StorageKey::NFTTokenIds);
         non_fungible_token_account_ids.extend(nft_ids);
         let mut tokens = UnorderedSet::new(StorageKey::FTTokenIds);
         tokens.insert(&AccountId::new_unchecked("near".to_owned()));
-        let market = MarketSales {
-            owner_id,
-            sales: UnorderedMap::new(StorageKey::Sales),
-            by_owner_id: LookupMap::new(StorageKey::ByOwnerId),
-            by_nft_contract_id: LookupMap::new(StorageKey::ByNFTContractId),
-            by_nft_token_type: LookupMap::new(StorageKey::ByNFTTokenType),
-            ft_token_ids: tokens,
-            storage_deposits: LookupMap::new(StorageKey::StorageDeposits),
-            bid_history_length: BID_HISTORY_LENGTH_DEFAULT,
-            auctions: UnorderedMap::new(StorageKey::Auctions),
-            next_auction_id: 0,
-        };
+        let market = MarketSales::new(owner_id, tokens);
         Self {
             non_fungible_token_account_ids,
             market,
@@ -92,16 +248,20 @@ impl Market {
     pub fn storage_withdraw(&mut self) {
         assert_one_yocto();
         let owner_id = env::predecessor_account_id();
-        let mut amount = self.market.storage_deposits.remove(&owner_id).unwrap_or(0);
-        let sales = self.market.by_owner_id.get(&owner_id);
-        let len = sales.map(|s| s.len()).unwrap_or_default();
-        let diff = u128::from(len) * STORAGE_PER_SALE;
-        amount -= diff;
+        let paid = self.market.storage_deposits.remove(&owner_id).unwrap_or(0);
+        // The listings shouldn't take more than paid, otherwise the whole deposit stays
+        let diff = u128::from(self.internal_listings_count(&owner_id)) * STORAGE_PER_SALE;
+        let amount = paid.saturating_sub(diff);
+        // The deposits which weren't counted by `migrate` are missing from the total
+        // until `reconcile_storage_deposits`
+        self.market.storage_deposits_total =
+            self.market.storage_deposits_total.saturating_sub(amount);
         if amount > 0 {
             Promise::new(owner_id.clone()).transfer(amount);
         }
-        if diff > 0 {
-            self.market.storage_deposits.insert(&owner_id, &diff);
+        let kept = min(diff, paid);
+        if kept > 0 {
+            self.market.storage_deposits.insert(&owner_id, &kept);
         }
     }
 
@@ -120,6 +280,7 @@ impl Market {
             .get(&storage_account_id)
             .unwrap_or(0);
         balance += deposit;
+        self.market.storage_deposits_total += deposit;
         self.market
             .storage_deposits
             .insert(&storage_account_id, &balance);
@@ -0,0 +1,256 @@
+use crate::auction::AuctionKind;
+use crate::event::{AuctionCancelData, NearEvent};
+use crate::sale::{ContractAndTokenId, DELIMETER};
+use crate::*;
+
+// The sale, the auction or the bundle of the token, a token has at most one of them
+#[derive(BorshDeserialize, BorshSerialize, PartialEq)]
+pub enum TokenListing {
+    Sale,
+    Auction { auction_id: u128 },
+    Bundle { bundle_id: u128 },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum TokenListingJson {
+    Sale(SaleJson),
+    Auction {
+        auction_id: U128,
+        #[serde(flatten)]
+        auction: AuctionJson,
+    },
+    Bundle(BundleJson),
+}
+
+#[near_bindgen]
+impl Market {
+    // Returns the sale, the auction or the bundle of the token
+    pub fn get_listing_for_token(
+        &self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+    ) -> Option<TokenListingJson> {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        match self.market.listings_by_token.get(&contract_and_token_id)? {
+            TokenListing::Sale => {
+                let sale = self.market.sales.get(&contract_and_token_id)?;
+                Some(TokenListingJson::Sale(self.json_from_sale(sale)))
+            }
+            TokenListing::Auction { auction_id } => {
+                let auction = self.market.auctions.get(&auction_id)?;
+                Some(TokenListingJson::Auction {
+                    auction_id: auction_id.into(),
+                    auction: self.json_from_auction(auction),
+                })
+            }
+            TokenListing::Bundle { bundle_id } => self
+                .get_bundle(bundle_id.into())
+                .map(TokenListingJson::Bundle),
+        }
+    }
+}
+
+impl Market {
+    pub(crate) fn internal_index_listing(
+        &mut self,
+        contract_and_token_id: &ContractAndTokenId,
+        listing: &TokenListing,
+    ) {
+        self.market
+            .listings_by_token
+            .insert(contract_and_token_id, listing);
+    }
+
+    // The token could have been listed again before the previous listing is removed
+    pub(crate) fn internal_unindex_listing(
+        &mut self,
+        contract_and_token_id: &ContractAndTokenId,
+        listing: &TokenListing,
+    ) {
+        if self
+            .market
+            .listings_by_token
+            .get(contract_and_token_id)
+            .as_ref()
+            == Some(listing)
+        {
+            self.market.listings_by_token.remove(contract_and_token_id);
+        }
+    }
+
+    // A new approval of the listed token makes the approval id of its listing stale,
+    // so the new sale or auction replaces the previous one and its bids are refunded
+    // The owner can't replace an own auction with bids, like with `cancel_auction`
+    pub(crate) fn internal_replace_listing(
+        &mut self,
+        nft_contract_id: &AccountId,
+        token_id: &TokenId,
+        owner_id: &AccountId,
+    ) {
+        self.internal_remove_listing(nft_contract_id, token_id, owner_id, false);
+    }
+
+    // Same before the token is sold to an offer or swapped, the bids are credited to
+    // the bidders so that the gas of the transfer doesn't depend on their number
+    pub(crate) fn internal_clear_listing(
+        &mut self,
+        nft_contract_id: &AccountId,
+        token_id: &TokenId,
+        owner_id: &AccountId,
+    ) {
+        self.internal_remove_listing(nft_contract_id, token_id, owner_id, true);
+    }
+
+    fn internal_remove_listing(
+        &mut self,
+        nft_contract_id: &AccountId,
+        token_id: &TokenId,
+        owner_id: &AccountId,
+        credit_bids: bool,
+    ) {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        match self.market.listings_by_token.get(&contract_and_token_id) {
+            None => {}
+            Some(TokenListing::Sale) => {
+                let sale = self.internal_remove_sale(nft_contract_id.clone(), token_id.clone());
+                if credit_bids {
+                    self.credit_all_bids(&sale.nft_contract_id, &sale.token_id, &sale.bids);
+                } else {
+                    self.refund_all_bids(&sale.nft_contract_id, &sale.token_id, &sale.bids);
+                }
+            }
+            Some(TokenListing::Auction { auction_id }) => {
+                let auction = self
+                    .internal_remove_auction(auction_id)
+                    .expect("No auction for the listing");
+                let sealed_bids =
+                    matches!(&auction.kind, AuctionKind::Sealed { bids, .. } if !bids.is_empty());
+                require!(
+                    &auction.owner_id != owner_id || (auction.bid.is_none() && !sealed_bids),
+                    "The token is auctioned with bids, the auction should be finished first"
+                );
+                if credit_bids {
+                    self.credit_auction_bids(auction);
+                } else {
+                    if let Some(bid) = auction.bid {
+                        self.refund_bid(auction.ft_token_id.clone(), bid.owner_id, bid.price);
+                    }
+                    if let AuctionKind::Sealed { bids, .. } = auction.kind {
+                        for bid in bids {
+                            self.refund_bid(auction.ft_token_id.clone(), bid.owner_id, bid.deposit);
+                        }
+                    }
+                }
+                NearEvent::auction_cancel(AuctionCancelData {
+                    auction_id: auction_id.into(),
+                })
+                .emit();
+            }
+            Some(TokenListing::Bundle { bundle_id }) => {
+                let bundle = self.internal_remove_bundle(bundle_id);
+                require!(
+                    &bundle.owner_id != owner_id || bundle.bid.is_none(),
+                    "The token is auctioned in a bundle with bids"
+                );
+                if let Some(bid) = bundle.bid {
+                    // The bundle auction accepts only one currency
+                    let ft_token_id = bundle.sale_conditions.keys().next().unwrap().clone();
+                    if credit_bids {
+                        self.internal_credit_claimable(&bid.owner_id, &ft_token_id, bid.price.0);
+                    } else {
+                        self.refund_bid(ft_token_id, bid.owner_id, bid.price);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{account, auction, bid, near_transfers, new_market, sale};
+
+    #[test]
+    fn new_listing_replaces_sale() {
+        let mut contract = new_market();
+        contract.internal_insert_sale(&sale(vec![("near", vec![bid("carol", 500)])]));
+        contract.escrow_deposit(&account("near"), 500);
+        assert!(matches!(
+            contract.get_listing_for_token(account("nft"), "1:1".to_string()),
+            Some(TokenListingJson::Sale(_))
+        ));
+
+        contract.internal_replace_listing(&account("nft"), &"1:1".to_string(), &account("alice"));
+        assert_eq!(contract.get_supply_sales(), U64(0));
+        assert!(contract
+            .get_listing_for_token(account("nft"), "1:1".to_string())
+            .is_none());
+        assert_eq!(near_transfers(), vec![("carol".to_string(), 500)]);
+    }
+
+    #[test]
+    fn accepted_offer_credits_sale_bids() {
+        let mut contract = new_market();
+        contract.internal_insert_sale(&sale(vec![("near", vec![bid("carol", 500)])]));
+        contract.escrow_deposit(&account("near"), 500);
+
+        contract.internal_clear_listing(&account("nft"), &"1:1".to_string(), &account("alice"));
+        assert_eq!(contract.get_supply_sales(), U64(0));
+        assert!(near_transfers().is_empty());
+        assert_eq!(
+            contract.get_claimable(account("carol"))[&account("near")],
+            U128(500)
+        );
+    }
+
+    #[test]
+    fn new_listing_replaces_auction_of_previous_owner() {
+        let mut contract = new_market();
+        contract.internal_insert_auction(7, &auction(Some(bid("carol", 1030))));
+        contract.escrow_deposit(&account("near"), 1030);
+        match contract.get_listing_for_token(account("nft"), "1:1".to_string()) {
+            Some(TokenListingJson::Auction { auction_id, .. }) => assert_eq!(auction_id, U128(7)),
+            _ => panic!("The token should be auctioned"),
+        }
+
+        contract.internal_replace_listing(&account("nft"), &"1:1".to_string(), &account("bob"));
+        assert!(contract.market.auctions.get(&7).is_none());
+        assert!(contract
+            .get_listing_for_token(account("nft"), "1:1".to_string())
+            .is_none());
+        assert_eq!(near_transfers(), vec![("carol".to_string(), 1030)]);
+    }
+
+    #[test]
+    fn new_listing_replaces_auction_without_bids() {
+        let mut contract = new_market();
+        contract.internal_insert_auction(0, &auction(None));
+        contract.internal_replace_listing(&account("nft"), &"1:1".to_string(), &account("alice"));
+        assert!(contract.market.auctions.get(&0).is_none());
+        assert_eq!(contract.internal_listings_count(&account("alice")), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "The token is auctioned with bids")]
+    fn own_auction_with_bids_is_not_replaced() {
+        let mut contract = new_market();
+        contract.internal_insert_auction(0, &auction(Some(bid("carol", 1030))));
+        contract.internal_replace_listing(&account("nft"), &"1:1".to_string(), &account("alice"));
+    }
+
+    #[test]
+    fn stale_listing_is_not_unindexed() {
+        let mut contract = new_market();
+        contract.internal_insert_auction(0, &auction(None));
+        contract.internal_insert_auction(1, &auction(None));
+        // Removing the replaced auction keeps the index of the new one
+        contract.internal_remove_auction(0);
+        match contract.get_listing_for_token(account("nft"), "1:1".to_string()) {
+            Some(TokenListingJson::Auction { auction_id, .. }) => assert_eq!(auction_id, U128(1)),
+            _ => panic!("The token should be auctioned"),
+        }
+    }
+}
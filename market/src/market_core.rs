@@ -1,6 +1,12 @@
-use near_sdk::serde_json::json;
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_sdk::json_types::Base64VecU8;
+use near_sdk::PromiseOrValue;
 use crate::*;
 use crate::bid::Origins;
+use crate::auction::{AuctionExtension, AuctionKind};
+use crate::event::{AuctionCancelData, AuctionStartData, NearEvent};
+use crate::sale::{PurchaseArgs, DELIMETER};
+use crate::token::TokenSeriesSale;
 
 
 pub trait NonFungibleTokenApprovalReceiver {
@@ -11,6 +17,10 @@ pub trait NonFungibleTokenApprovalReceiver {
         approval_id: u64,
         msg: String,
     );
+
+    fn nft_on_revoke(&mut self, token_id: TokenId, owner_id: AccountId, approval_id: u64);
+
+    fn nft_on_series_approve(&mut self, token_series: TokenSeriesSale);
 }
 
 #[derive(Serialize, Deserialize)]
@@ -35,15 +45,178 @@ pub struct AuctionArgs {
     pub start: Option<U64>,
     pub duration: U64,
     pub buy_out_price: Option<U128>,
+    // Hidden minimal price to sell the token, views only show whether it is met
+    pub reserve_price: Option<U128>,
+    // 15 minutes window and duration without the limits by default
+    pub extension: Option<AuctionExtension>,
+
+    pub origins: Option<Origins>,
+}
+
+// The price decays from `start_price` to `floor_price` over the `duration`:
+// linearly if `step` is not given, otherwise it drops once per `step` nanoseconds
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DutchAuctionArgs {
+    pub token_type: TokenType,
+    pub start_price: U128,
+    pub floor_price: U128,
+    pub step: Option<U64>,
+
+    pub start: Option<U64>,
+    pub duration: U64,
+
+    pub origins: Option<Origins>,
+}
+
+// Bids are committed as hashes until the auction end and revealed during `reveal_duration`
+// The winner pays either its own price or the second highest revealed price (`second_price`)
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SealedAuctionArgs {
+    pub token_type: TokenType,
+    pub start_price: U128,
+    pub second_price: bool,
+
+    pub start: Option<U64>,
+    pub duration: U64,
+    pub reveal_duration: U64,
+
+    pub origins: Option<Origins>,
+}
+
+// Sells the approved token to the collection offer, see `collection_offer.rs`
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AcceptCollectionOfferArgs {
+    pub offer_id: U128,
+}
+
+// Sells the approved token to the offer of `bid_owner_id` with `bid_price`
+// or to the last offer in `ft_token_id`, see `token_offer.rs`
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AcceptOfferArgs {
+    pub ft_token_id: FungibleTokenId,
+    pub bid_owner_id: Option<AccountId>,
+    pub bid_price: Option<U128>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BundleTokenArgs {
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+}
+
+// The tokens are sold as one lot at the price of `sale_conditions`, see `bundle.rs`
+// With `minimal_step` the lot is auctioned for `duration` starting from the price
+// in the only currency of `sale_conditions`
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BundleArgs {
+    pub tokens: Vec<BundleTokenArgs>,
+    pub sale_conditions: SaleConditions,
+    pub minimal_step: Option<U128>,
+
+    pub start: Option<U64>,
+    pub duration: Option<U64>,
 
     pub origins: Option<Origins>,
 }
 
+// Swaps the approved token for the swap offer, see `swap.rs`
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AcceptSwapArgs {
+    pub swap_id: U128,
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
 pub enum ArgsKind {
     Sale(SaleArgs),
     Auction(AuctionArgs),
+    DutchAuction(DutchAuctionArgs),
+    SealedAuction(SealedAuctionArgs),
+    AcceptCollectionOffer(AcceptCollectionOfferArgs),
+    AcceptOffer(AcceptOfferArgs),
+    Swap(AcceptSwapArgs),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct OfferArgs {
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+
+    pub start: Option<U64>,
+    pub duration: Option<U64>,
+
+    pub origins: Option<Origins>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AuctionBidArgs {
+    pub auction_id: U128,
+
+    pub origins: Option<Origins>,
+}
+
+// Arguments passed in `msg` of `ft_transfer_call`
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AuctionCommitBidArgs {
+    pub auction_id: U128,
+    pub hash: Base64VecU8,
+    pub origins: Option<Origins>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CollectionOfferArgs {
+    pub nft_contract_id: AccountId,
+    pub token_type: TokenType,
+
+    pub start: Option<U64>,
+    pub duration: Option<U64>,
+
+    pub origins: Option<Origins>,
+}
+
+// Buys the bundle or bids on the bundle auction
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BundlePurchaseArgs {
+    pub bundle_id: U128,
+    pub origins: Option<Origins>,
+}
+
+// The approved `tokens` and the top-up are offered for the token, see `swap.rs`
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapOfferArgs {
+    pub tokens: Vec<BundleTokenArgs>,
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+
+    pub start: Option<U64>,
+    pub duration: Option<U64>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub enum FtArgsKind {
+    Offer(OfferArgs),
+    Purchase(PurchaseArgs),
+    AuctionAddBid(AuctionBidArgs),
+    AuctionCommitBid(AuctionCommitBidArgs),
+    CollectionOffer(CollectionOfferArgs),
+    TokenOffer(OfferArgs),
+    BundlePurchase(BundlePurchaseArgs),
+    BundleAddBid(BundlePurchaseArgs),
+    SwapOffer(SwapOfferArgs),
 }
 
 #[near_bindgen]
@@ -65,24 +238,44 @@ impl NonFungibleTokenApprovalReceiver for Market {
             "nft_on_approve should only be called via cross-contract call"
         );
         require!(owner_id == signer_id, "owner_id should be signer_id");
-
-        // check that the signer's storage is enough to cover one more sale
-
-        let storage_amount = self.storage_amount().0;
-        let owner_paid_storage = self.market.storage_deposits.get(&signer_id).unwrap_or(0);
-        let signer_storage_required =
-            (self.get_supply_by_owner_id(signer_id).0 + 1) as u128 * storage_amount;
-        assert!(
-            owner_paid_storage >= signer_storage_required,
-            "Insufficient storage paid: {}, for {} sales at {} rate of per sale",
-            owner_paid_storage,
-            signer_storage_required / STORAGE_PER_SALE,
-            STORAGE_PER_SALE
+        require!(
+            self.non_fungible_token_account_ids
+                .contains(&nft_contract_id),
+            format!(
+                "NFT contract {} is not supported by this market",
+                nft_contract_id
+            )
         );
 
         // Parse the msg to find Sale or Auction arguments
 
         let args: ArgsKind = near_sdk::serde_json::from_str(&msg).expect("Not valid args");
+
+        // check that the signer's storage is enough to cover one more sale
+        // (accepting an offer or a swap doesn't list the token)
+        // the new listing replaces the current sale, auction or bundle of the token,
+        // the token sold to an offer or swapped is delisted
+
+        if matches!(
+            args,
+            ArgsKind::AcceptCollectionOffer(_) | ArgsKind::AcceptOffer(_) | ArgsKind::Swap(_)
+        ) {
+            self.internal_clear_listing(&nft_contract_id, &token_id, &owner_id);
+        } else {
+            self.internal_replace_listing(&nft_contract_id, &token_id, &owner_id);
+            let storage_amount = self.storage_amount().0;
+            let owner_paid_storage = self.market.storage_deposits.get(&signer_id).unwrap_or(0);
+            let signer_storage_required =
+                (self.internal_listings_count(&signer_id) + 1) as u128 * storage_amount;
+            assert!(
+                owner_paid_storage >= signer_storage_required,
+                "Insufficient storage paid: {}, for {} sales at {} rate of per sale",
+                owner_paid_storage,
+                signer_storage_required / STORAGE_PER_SALE,
+                STORAGE_PER_SALE
+            );
+        }
+
         match args {
             ArgsKind::Sale(sale_args) => {
                 let sale_json = self.start_sale(
@@ -92,98 +285,314 @@ impl NonFungibleTokenApprovalReceiver for Market {
                     approval_id,
                     nft_contract_id,
                 );
-                env::log_str(&near_sdk::serde_json::to_string(&sale_json).unwrap());
+                NearEvent::sale_create(sale_json).emit();
             }
             ArgsKind::Auction(auction_args) => {
                 let (id, auction_json) = self.start_auction(
                     auction_args,
+                    AuctionKind::English,
                     token_id,
                     owner_id,
                     approval_id,
                     nft_contract_id,
                 );
-                env::log_str(&json!({
-                    "auction_id": U128(id),
-                    "auction_json": auction_json
-                }).to_string())
+                NearEvent::auction_start(AuctionStartData {
+                    auction_id: U128(id),
+                    auction: auction_json,
+                })
+                .emit();
+            }
+            ArgsKind::DutchAuction(dutch_auction_args) => {
+                let (id, auction_json) = self.start_dutch_auction(
+                    dutch_auction_args,
+                    token_id,
+                    owner_id,
+                    approval_id,
+                    nft_contract_id,
+                );
+                NearEvent::auction_start(AuctionStartData {
+                    auction_id: U128(id),
+                    auction: auction_json,
+                })
+                .emit();
+            }
+            ArgsKind::SealedAuction(sealed_auction_args) => {
+                let (id, auction_json) = self.start_sealed_auction(
+                    sealed_auction_args,
+                    token_id,
+                    owner_id,
+                    approval_id,
+                    nft_contract_id,
+                );
+                NearEvent::auction_start(AuctionStartData {
+                    auction_id: U128(id),
+                    auction: auction_json,
+                })
+                .emit();
+            }
+            ArgsKind::AcceptCollectionOffer(AcceptCollectionOfferArgs { offer_id }) => {
+                self.accept_collection_offer(
+                    offer_id,
+                    token_id,
+                    owner_id,
+                    approval_id,
+                    nft_contract_id,
+                );
+            }
+            ArgsKind::AcceptOffer(AcceptOfferArgs {
+                ft_token_id,
+                bid_owner_id,
+                bid_price,
+            }) => {
+                self.accept_token_offer(
+                    ft_token_id,
+                    bid_owner_id,
+                    bid_price,
+                    token_id,
+                    owner_id,
+                    approval_id,
+                    nft_contract_id,
+                );
+            }
+            ArgsKind::Swap(AcceptSwapArgs { swap_id }) => {
+                self.accept_swap_offer(swap_id, token_id, owner_id, approval_id, nft_contract_id);
+            }
+        }
+    }
+
+    // nft_on_revoke is called by the NFT contract when the market loses the approval
+    // (`nft_revoke`, `nft_revoke_all` or a transfer of the token), the listing made with
+    // the revoked `approval_id` is removed
+    // The gas is limited, so the bids are credited to the claimable balances, see `claim`
+    fn nft_on_revoke(&mut self, token_id: TokenId, owner_id: AccountId, approval_id: u64) {
+        let nft_contract_id = env::predecessor_account_id();
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        // The token could have been listed again with a new approval
+        match self.market.listings_by_token.get(&contract_and_token_id) {
+            Some(TokenListing::Sale) => {
+                let listed = matches!(
+                    self.market.sales.get(&contract_and_token_id),
+                    Some(sale) if sale.owner_id == owner_id && sale.approval_id == approval_id
+                );
+                if listed {
+                    let sale = self.internal_remove_sale(nft_contract_id, token_id);
+                    self.credit_all_bids(&sale.nft_contract_id, &sale.token_id, &sale.bids);
+                }
             }
+            Some(TokenListing::Auction { auction_id }) => {
+                let listed = matches!(
+                    self.market.auctions.get(&auction_id),
+                    Some(auction) if auction.owner_id == owner_id
+                        && auction.approval_id == approval_id
+                );
+                if listed {
+                    let auction = self
+                        .internal_remove_auction(auction_id)
+                        .expect("No auction for the listing");
+                    self.credit_auction_bids(auction);
+                    NearEvent::auction_cancel(AuctionCancelData {
+                        auction_id: auction_id.into(),
+                    })
+                    .emit();
+                }
+            }
+            Some(TokenListing::Bundle { bundle_id }) => {
+                let listed = match self.market.bundles.get(&bundle_id) {
+                    Some(bundle) => {
+                        bundle.owner_id == owner_id
+                            && bundle.tokens.iter().any(|token| {
+                                token.nft_contract_id == nft_contract_id
+                                    && token.token_id == token_id
+                                    && token.approval_id == approval_id
+                            })
+                    }
+                    None => false,
+                };
+                if listed {
+                    let bundle = self.internal_remove_bundle(bundle_id);
+                    if let Some(bid) = bundle.bid {
+                        // The bundle auction accepts only one currency
+                        let ft_token_id = bundle.sale_conditions.keys().next().unwrap();
+                        self.internal_credit_claimable(&bid.owner_id, ft_token_id, bid.price.0);
+                    }
+                }
+            }
+            None => {}
         }
     }
 
-    /*
+    // nft_on_series_approve is called by `nft_series_market_approve` of the NFT contract
+    // in order to sell `copies` of the series, the market mints them for the buyers
+    // Approving the listed series again replaces its price and copies
     fn nft_on_series_approve(&mut self, token_series: TokenSeriesSale) {
         let nft_contract_id = env::predecessor_account_id();
         let signer_id = env::signer_account_id();
         require!(
             nft_contract_id != signer_id,
-            "nft_on_approve should only be called via cross-contract call"
+            "nft_on_series_approve should only be called via cross-contract call"
         );
         require!(
             token_series.owner_id == signer_id,
             "owner_id should be signer_id"
         );
+        require!(
+            self.non_fungible_token_account_ids
+                .contains(&nft_contract_id),
+            format!(
+                "NFT contract {} is not supported by this market",
+                nft_contract_id
+            )
+        );
+        let series_sale = self.start_series_sale(token_series, nft_contract_id);
+        NearEvent::series_sale_create(series_sale).emit();
+    }
+}
 
-        let storage_amount = self.storage_amount().0;
-        let owner_paid_storage = self.market.storage_deposits.get(&signer_id).unwrap_or(0);
-        let signer_storage_required =
-            (self.get_supply_by_owner_id(signer_id).0 + 1) as u128 * storage_amount;
-        assert!(
-            owner_paid_storage >= signer_storage_required,
-            "Insufficient storage paid: {}, for {} sales at {} rate of per sale",
-            owner_paid_storage,
-            signer_storage_required / STORAGE_PER_SALE,
-            STORAGE_PER_SALE
+#[near_bindgen]
+impl FungibleTokenReceiver for Market {
+    // ft_on_transfer is called via cross-contract call from `ft_transfer_call`
+    // of a supported FT contract
+    // Depending on `msg` makes an offer, buys the nft or adds a bid to the auction
+    // Returns the amount of tokens which should be refunded to `sender_id` in `ft_resolve_transfer`
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        let ft_token_id = env::predecessor_account_id();
+        require!(
+            ft_token_id.as_str() != "near" && self.market.ft_token_ids.contains(&ft_token_id),
+            format!("Token {} not supported by this market", ft_token_id)
         );
 
-        for (ft_token_id, _price) in token_series.sale_conditions.clone() {
-            if !self.market.ft_token_ids.contains(&ft_token_id) {
-                env::panic_str(&format!(
-                    "Token {} not supported by this market",
-                    ft_token_id
-                ));
+        let args: FtArgsKind = near_sdk::serde_json::from_str(&msg).expect("Not valid args");
+        match args {
+            FtArgsKind::Offer(OfferArgs {
+                nft_contract_id,
+                token_id,
+                start,
+                duration,
+                origins,
+            }) => {
+                // If the nft is bought, `resolve_purchase` refunds the amount itself on a failure
+                match self.internal_offer(
+                    nft_contract_id,
+                    token_id,
+                    ft_token_id,
+                    sender_id,
+                    amount.0,
+                    start,
+                    duration,
+                    origins,
+                ) {
+                    Some(purchase) => PromiseOrValue::Promise(purchase),
+                    None => PromiseOrValue::Value(U128(0)),
+                }
+            }
+            FtArgsKind::Purchase(PurchaseArgs {
+                nft_contract_id,
+                token_id,
+                origins,
+            }) => {
+                // Panic reverts the bid added by `internal_offer` and refunds the whole amount
+                let purchase = self
+                    .internal_offer(
+                        nft_contract_id,
+                        token_id,
+                        ft_token_id,
+                        sender_id,
+                        amount.0,
+                        None,
+                        None,
+                        origins,
+                    )
+                    .unwrap_or_else(|| {
+                        env::panic_str("Amount should be equal to the price with fees")
+                    });
+                PromiseOrValue::Promise(purchase)
+            }
+            FtArgsKind::AuctionAddBid(AuctionBidArgs {
+                auction_id,
+                origins,
+            }) => {
+                // The change of a Dutch auction purchase is returned to the sender
+                let unused_amount = self.internal_auction_add_bid(
+                    auction_id,
+                    ft_token_id,
+                    sender_id,
+                    amount.0,
+                    origins,
+                );
+                PromiseOrValue::Value(U128(unused_amount))
+            }
+            FtArgsKind::AuctionCommitBid(AuctionCommitBidArgs {
+                auction_id,
+                hash,
+                origins,
+            }) => {
+                self.internal_auction_commit_bid(
+                    auction_id,
+                    ft_token_id,
+                    sender_id,
+                    amount.0,
+                    hash,
+                    origins,
+                );
+                PromiseOrValue::Value(U128(0))
+            }
+            FtArgsKind::CollectionOffer(CollectionOfferArgs {
+                nft_contract_id,
+                token_type,
+                start,
+                duration,
+                origins,
+            }) => {
+                self.internal_collection_offer(
+                    nft_contract_id,
+                    token_type,
+                    ft_token_id,
+                    sender_id,
+                    amount.0,
+                    start,
+                    duration,
+                    origins,
+                );
+                PromiseOrValue::Value(U128(0))
+            }
+            FtArgsKind::TokenOffer(OfferArgs {
+                nft_contract_id,
+                token_id,
+                start,
+                duration,
+                origins,
+            }) => {
+                self.internal_token_offer(
+                    nft_contract_id,
+                    token_id,
+                    ft_token_id,
+                    sender_id,
+                    amount.0,
+                    start,
+                    duration,
+                    origins,
+                );
+                PromiseOrValue::Value(U128(0))
+            }
+            FtArgsKind::BundlePurchase(BundlePurchaseArgs { bundle_id, origins }) => {
+                // `resolve_bundle_purchase` refunds the amount itself on a failure
+                self.internal_buy_bundle(bundle_id, ft_token_id, sender_id, amount.0, origins);
+                PromiseOrValue::Value(U128(0))
+            }
+            FtArgsKind::BundleAddBid(BundlePurchaseArgs { bundle_id, origins }) => {
+                self.internal_bundle_add_bid(bundle_id, ft_token_id, sender_id, amount.0, origins);
+                PromiseOrValue::Value(U128(0))
+            }
+            FtArgsKind::SwapOffer(swap_offer_args) => {
+                // `resolve_create_swap_offer` refunds the amount itself if the offer is not created
+                self.internal_swap_offer(swap_offer_args, ft_token_id, sender_id, amount.0);
+                PromiseOrValue::Value(U128(0))
             }
         }
-
-        let contract_and_series_id =
-            format!("{}{}{}", nft_contract_id, DELIMETER, token_series.series_id);
-
-        // extra for views
-
-        let mut by_owner_id = self
-            .market
-            .by_owner_id
-            .get(&token_series.owner_id)
-            .unwrap_or_else(|| {
-                UnorderedSet::new(
-                    StorageKey::ByOwnerIdInner {
-                        account_id_hash: hash_account_id(&token_series.owner_id),
-                    }
-                    .try_to_vec()
-                    .unwrap(),
-                )
-            });
-
-        let owner_occupied_storage = u128::from(by_owner_id.len()) * STORAGE_PER_SALE;
-        require!(
-            owner_paid_storage > owner_occupied_storage,
-            "User has more sales than storage paid"
-        );
-        by_owner_id.insert(&contract_and_series_id);
-        self.market
-            .by_owner_id
-            .insert(&token_series.owner_id, &by_owner_id);
-
-        self.market.series_sales.insert(
-            &contract_and_series_id,
-            &SeriesSale {
-                owner_id: token_series.owner_id,
-                nft_contract_id: env::predecessor_account_id(),
-                series_id: token_series.series_id,
-                sale_conditions: token_series.sale_conditions,
-                created_at: env::block_timestamp(),
-                copies: token_series.copies,
-            },
-        );
     }
-    */
 }
@@ -0,0 +1,363 @@
+use std::collections::HashSet;
+
+use crate::auction::{Auction, AuctionExtension, AuctionKind};
+use crate::bid::{Bid, BidListing, Bids, Origins};
+use crate::sale::{ContractAndTokenId, FungibleTokenId, Sale, SaleConditions, TokenType};
+use crate::*;
+
+// Frozen layouts of the state before the upgrade, they must not follow the changes of
+// `MarketSales`, `Sale` and `Auction`
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub(crate) struct OldSale {
+    pub owner_id: AccountId,
+    pub approval_id: u64,
+    pub nft_contract_id: AccountId,
+    pub token_id: String,
+    pub sale_conditions: SaleConditions,
+    pub bids: Bids,
+    pub created_at: u64,
+    pub token_type: TokenType,
+
+    pub start: Option<u64>,
+    pub end: Option<u64>,
+
+    pub origins: Origins,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub(crate) struct OldAuction {
+    pub owner_id: AccountId,
+    pub approval_id: u64,
+    pub nft_contract_id: AccountId,
+    pub token_id: String,
+    pub bid: Option<Bid>,
+    pub created_at: u64,
+    pub ft_token_id: AccountId,
+    pub minimal_step: u128,
+    pub start_price: u128,
+    pub buy_out_price: Option<u128>,
+
+    pub start: u64,
+    pub end: u64,
+
+    pub origins: Origins,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub(crate) struct OldMarketSales {
+    pub owner_id: AccountId,
+    pub sales: UnorderedMap<ContractAndTokenId, OldSale>,
+    pub by_owner_id: LookupMap<AccountId, UnorderedSet<ContractAndTokenId>>,
+    pub by_nft_contract_id: LookupMap<AccountId, UnorderedSet<TokenId>>,
+    pub by_nft_token_type: LookupMap<String, UnorderedSet<ContractAndTokenId>>,
+    pub ft_token_ids: UnorderedSet<FungibleTokenId>,
+    pub storage_deposits: LookupMap<AccountId, Balance>,
+    pub bid_history_length: u8,
+
+    pub auctions: UnorderedMap<u128, OldAuction>,
+    pub next_auction_id: u128,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub(crate) struct OldMarket {
+    pub non_fungible_token_account_ids: LookupSet<AccountId>,
+    pub market: OldMarketSales,
+}
+
+// The listings created before the upgrade keep the fee and the extension of that time
+impl From<OldSale> for Sale {
+    fn from(sale: OldSale) -> Self {
+        Sale {
+            owner_id: sale.owner_id,
+            approval_id: sale.approval_id,
+            nft_contract_id: sale.nft_contract_id,
+            token_id: sale.token_id,
+            sale_conditions: sale.sale_conditions,
+            bids: sale.bids,
+            created_at: sale.created_at,
+            token_type: sale.token_type,
+            start: sale.start,
+            end: sale.end,
+            origins: sale.origins,
+            protocol_fee: PROTOCOL_FEE as u32,
+        }
+    }
+}
+
+impl From<OldAuction> for Auction {
+    fn from(auction: OldAuction) -> Self {
+        Auction {
+            owner_id: auction.owner_id,
+            approval_id: auction.approval_id,
+            nft_contract_id: auction.nft_contract_id,
+            token_id: auction.token_id,
+            bid: auction.bid,
+            created_at: auction.created_at,
+            ft_token_id: auction.ft_token_id,
+            minimal_step: auction.minimal_step,
+            start_price: auction.start_price,
+            buy_out_price: auction.buy_out_price,
+            reserve_price: None,
+            start: auction.start,
+            end: auction.end,
+            origins: auction.origins,
+            kind: AuctionKind::English,
+            protocol_fee: PROTOCOL_FEE as u32,
+            extension: AuctionExtension::default(),
+            extension_count: 0,
+            extension_total: 0,
+        }
+    }
+}
+
+#[near_bindgen]
+impl Market {
+    // Upgrades the state to the current layout
+    // `LookupSet` can't be enumerated, so the allowlisted `nft_ids` should be passed explicitly,
+    // contracts which weren't in the old allowlist are ignored
+    // The total of the storage deposits is counted for the owners of the listings
+    // and for `storage_account_ids`, which should include the other accounts with deposits
+    // Sales and auctions are rewritten under the same prefixes with their indexes and escrow,
+    // so the gas limits the number of the listings which can be migrated
+    // The migrated auctions didn't take the storage deposit and stay free for their owners
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate(nft_ids: Vec<AccountId>, storage_account_ids: Vec<AccountId>) -> Self {
+        let OldMarket {
+            non_fungible_token_account_ids: mut old_ids,
+            market: old,
+        } = env::state_read().expect("Old state doesn't exist");
+        let mut non_fungible_token_account_ids = UnorderedSet::new(StorageKey::NFTTokenIds);
+        for nft_id in nft_ids {
+            if old_ids.remove(&nft_id) {
+                non_fungible_token_account_ids.insert(&nft_id);
+            }
+        }
+
+        let mut old_sales = old.sales;
+        let sales = old_sales.to_vec();
+        old_sales.clear();
+        let mut old_auctions = old.auctions;
+        let auctions = old_auctions.to_vec();
+        old_auctions.clear();
+
+        let mut market = MarketSales::new(old.owner_id, old.ft_token_ids);
+        market.bid_history_length = old.bid_history_length;
+        market.next_auction_id = old.next_auction_id;
+        market.first_charged_auction_id = old.next_auction_id;
+        let mut contract = Self {
+            non_fungible_token_account_ids,
+            market,
+        };
+
+        let mut storage_account_ids: HashSet<AccountId> = storage_account_ids.into_iter().collect();
+        for (_, sale) in sales {
+            let sale = Sale::from(sale);
+            for (ft_token_id, bids) in sale.bids.iter() {
+                for bid in bids {
+                    contract.escrow_deposit(ft_token_id, bid.price.0);
+                }
+            }
+            storage_account_ids.insert(sale.owner_id.clone());
+            contract.internal_insert_sale(&sale);
+        }
+        for (auction_id, auction) in auctions {
+            let auction = Auction::from(auction);
+            if let Some(bid) = &auction.bid {
+                contract.escrow_deposit(&auction.ft_token_id, bid.price.0);
+                let listing = BidListing::Auction {
+                    auction_id: auction_id.into(),
+                };
+                contract.internal_index_bid(&bid.owner_id, &listing);
+            }
+            storage_account_ids.insert(auction.owner_id.clone());
+            contract.internal_insert_auction(auction_id, &auction);
+        }
+        // The deposits stay under the same prefix
+        let storage_deposits = &contract.market.storage_deposits;
+        contract.market.storage_deposits_total = storage_account_ids
+            .iter()
+            .map(|account_id| storage_deposits.get(account_id).unwrap_or(0))
+            .sum();
+        contract
+    }
+
+    // Adds the deposits of the accounts which were missing from `storage_account_ids`
+    // of `migrate` to the total, an account shouldn't be counted twice
+    // Returns the total of the storage deposits
+    #[private]
+    pub fn reconcile_storage_deposits(&mut self, account_ids: Vec<AccountId>) -> U128 {
+        let account_ids: HashSet<AccountId> = account_ids.into_iter().collect();
+        for account_id in account_ids {
+            let deposit = self.market.storage_deposits.get(&account_id).unwrap_or(0);
+            self.market.storage_deposits_total += deposit;
+        }
+        self.market.storage_deposits_total.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::sale::DELIMETER;
+    use crate::test_utils::{account, bid, context, near_transfers};
+
+    // The state written by the market before the upgrade
+    fn write_baseline_state() {
+        let mut non_fungible_token_account_ids = LookupSet::new(b"n".to_vec());
+        non_fungible_token_account_ids.insert(&account("nft"));
+        non_fungible_token_account_ids.insert(&account("old_nft"));
+        let mut ft_token_ids = UnorderedSet::new(StorageKey::FTTokenIds);
+        ft_token_ids.insert(&account("near"));
+        let mut sales = UnorderedMap::new(StorageKey::Sales);
+        sales.insert(
+            &format!("nft{}1:1", DELIMETER),
+            &OldSale {
+                owner_id: account("alice"),
+                approval_id: 0,
+                nft_contract_id: account("nft"),
+                token_id: "1:1".to_string(),
+                sale_conditions: HashMap::from([(account("near"), U128(1000))]),
+                bids: HashMap::from([(account("near"), vec![bid("bob", 500)])]),
+                created_at: 0,
+                token_type: None,
+                start: Some(0),
+                end: None,
+                origins: HashMap::new(),
+            },
+        );
+        let mut auctions = UnorderedMap::new(StorageKey::Auctions);
+        auctions.insert(
+            &0,
+            &OldAuction {
+                owner_id: account("alice"),
+                approval_id: 1,
+                nft_contract_id: account("nft"),
+                token_id: "1:2".to_string(),
+                bid: Some(bid("carol", 1100)),
+                created_at: 0,
+                ft_token_id: account("near"),
+                minimal_step: 100,
+                start_price: 1000,
+                buy_out_price: None,
+                start: 0,
+                end: 1000,
+                origins: HashMap::new(),
+            },
+        );
+        let mut storage_deposits = LookupMap::new(StorageKey::StorageDeposits);
+        storage_deposits.insert(&account("alice"), &(2 * STORAGE_PER_SALE));
+        storage_deposits.insert(&account("dave"), &STORAGE_PER_SALE);
+        env::state_write(&OldMarket {
+            non_fungible_token_account_ids,
+            market: OldMarketSales {
+                owner_id: account("owner"),
+                sales,
+                by_owner_id: LookupMap::new(StorageKey::ByOwnerId),
+                by_nft_contract_id: LookupMap::new(StorageKey::ByNFTContractId),
+                by_nft_token_type: LookupMap::new(StorageKey::ByNFTTokenType),
+                ft_token_ids,
+                storage_deposits,
+                bid_history_length: 3,
+                auctions,
+                next_auction_id: 1,
+            },
+        });
+    }
+
+    #[test]
+    fn migrate_baseline_state() {
+        context("market", "market", 0, 0, vec![]);
+        write_baseline_state();
+        let contract = Market::migrate(
+            vec![account("nft"), account("new_nft")],
+            vec![account("dave")],
+        );
+
+        assert_eq!(contract.get_supported_nft_contracts(None, None), vec![account("nft")]);
+        assert_eq!(contract.get_supported_ft_tokens(), vec![account("near")]);
+        assert_eq!(contract.market.bid_history_length, 3);
+        assert_eq!(contract.market.next_auction_id, 1);
+
+        let sale = contract
+            .get_sale(account("nft"), "1:1".to_string())
+            .unwrap();
+        assert_eq!(sale.bids[&account("near")][0].owner_id, account("bob"));
+        assert_eq!(contract.get_supply_by_owner_id(account("alice")), U64(1));
+        let auction = contract.get_auction(U128(0));
+        assert_eq!(auction.bid.unwrap().price, U128(1100));
+        assert_eq!(contract.get_auctions_by_owner_id(account("alice"), U64(0), 10).len(), 1);
+        assert!(contract
+            .get_listing_for_token(account("nft"), "1:2".to_string())
+            .is_some());
+        assert_eq!(contract.get_bids_by_bidder(account("bob"), U64(0), 10).len(), 1);
+        assert_eq!(contract.get_bids_by_bidder(account("carol"), U64(0), 10).len(), 1);
+
+        let summary = contract.get_escrow_summary();
+        assert_eq!(summary.escrow, HashMap::from([(account("near"), U128(1600))]));
+        assert_eq!(summary.storage_deposits, U128(3 * STORAGE_PER_SALE));
+    }
+
+    #[test]
+    fn migrated_auctions_are_free() {
+        context("market", "market", 0, 0, vec![]);
+        write_baseline_state();
+        let mut contract = Market::migrate(vec![account("nft")], vec![account("dave")]);
+        assert_eq!(contract.internal_listings_count(&account("alice")), 1);
+
+        // The sale keeps its deposit, the rest is withdrawn
+        context("alice", "alice", 1, 0, vec![]);
+        contract.storage_withdraw();
+        assert_eq!(near_transfers(), vec![("alice".to_string(), STORAGE_PER_SALE)]);
+        assert_eq!(
+            contract.market.storage_deposits.get(&account("alice")),
+            Some(STORAGE_PER_SALE)
+        );
+        assert_eq!(
+            contract.get_escrow_summary().storage_deposits,
+            U128(2 * STORAGE_PER_SALE)
+        );
+    }
+
+    #[test]
+    fn reconcile_missing_storage_deposit() {
+        context("market", "market", 0, 0, vec![]);
+        write_baseline_state();
+        // dave isn't in `storage_account_ids`
+        let mut contract = Market::migrate(vec![account("nft")], vec![]);
+        assert_eq!(
+            contract.get_escrow_summary().storage_deposits,
+            U128(2 * STORAGE_PER_SALE)
+        );
+        let total = contract.reconcile_storage_deposits(vec![account("dave"), account("dave")]);
+        assert_eq!(total, U128(3 * STORAGE_PER_SALE));
+
+        context("dave", "dave", 1, 0, vec![]);
+        contract.storage_withdraw();
+        assert_eq!(near_transfers(), vec![("dave".to_string(), STORAGE_PER_SALE)]);
+        assert_eq!(
+            contract.get_escrow_summary().storage_deposits,
+            U128(2 * STORAGE_PER_SALE)
+        );
+    }
+
+    #[test]
+    fn withdraw_missing_storage_deposit() {
+        context("market", "market", 0, 0, vec![]);
+        write_baseline_state();
+        let mut contract = Market::migrate(vec![account("nft")], vec![account("dave")]);
+        // erin deposited before the upgrade, but isn't in `storage_account_ids`
+        contract
+            .market
+            .storage_deposits
+            .insert(&account("erin"), &(4 * STORAGE_PER_SALE));
+
+        context("erin", "erin", 1, 0, vec![]);
+        contract.storage_withdraw();
+        assert_eq!(near_transfers(), vec![("erin".to_string(), 4 * STORAGE_PER_SALE)]);
+        assert_eq!(contract.get_escrow_summary().storage_deposits, U128(0));
+    }
+}
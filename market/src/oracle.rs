@@ -0,0 +1,314 @@
+use std::collections::HashMap;
+
+use near_sdk::{ext_contract, Gas, PromiseResult};
+
+use crate::bid::Bid;
+use crate::fee::{calculate_actual_amount, calculate_origins};
+use crate::sale::{
+    ext_self, FungibleTokenId, Sale, DELIMETER, GAS_FOR_NFT_TRANSFER, GAS_FOR_ROYALTIES, NO_DEPOSIT,
+};
+use crate::*;
+
+pub const GAS_FOR_GET_PRICE: Gas = Gas(5_000_000_000_000);
+pub const GAS_FOR_RESOLVE_UPDATE_PRICES: Gas = Gas(10_000_000_000_000);
+pub const GAS_FOR_ACCEPT_BEST_OFFER: Gas =
+    Gas(GAS_FOR_NFT_TRANSFER.0 + GAS_FOR_ROYALTIES.0 + 20_000_000_000_000);
+
+// Price oracle used to compare bids in different currencies
+// `get_price` returns the price of the smallest unit of `ft_token_id` ("near" for yoctoNEAR),
+// the prices of all the tokens should be given in the same quote unit
+#[ext_contract(ext_oracle)]
+trait PriceOracle {
+    fn get_price(&self, ft_token_id: FungibleTokenId) -> U128;
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BestOfferJson {
+    pub ft_token_id: FungibleTokenId,
+    #[serde(flatten)]
+    pub bid: Bid,
+    // What the seller gets for the bid (without the fees) in the oracle quote units
+    pub value: U128,
+}
+
+// The last price of the token received from the oracle
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct OraclePrice {
+    pub price: U128,
+    pub updated_at: U64,
+}
+
+#[near_bindgen]
+impl Market {
+    // Returns the highest active bid of the sale across all the currencies
+    // The bids are compared by the cached prices, see `update_prices`,
+    // the currencies without a cached price are skipped
+    pub fn get_best_offer(
+        &self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+    ) -> Option<BestOfferJson> {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let sale = self
+            .market
+            .sales
+            .get(&contract_and_token_id)
+            .expect("No sale");
+        let prices = best_offer_candidates(&sale)
+            .into_iter()
+            .filter_map(|ft_token_id| {
+                let price = self.market.prices.get(&ft_token_id)?;
+                Some((ft_token_id, price.price.0))
+            })
+            .collect();
+        best_offer(&sale, prices)
+    }
+
+    // Requests the prices of all the supported tokens from the oracle and caches them
+    // Can be called by anyone, the prices are also cached when the best offer is accepted
+    pub fn update_prices(&mut self) -> Promise {
+        let ft_token_ids = self.market.ft_token_ids.to_vec();
+        self.query_prices(&ft_token_ids)
+            .then(ext_self::resolve_update_prices(
+                ft_token_ids,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_RESOLVE_UPDATE_PRICES,
+            ))
+    }
+
+    pub fn get_prices(&self) -> HashMap<FungibleTokenId, OraclePrice> {
+        self.market.prices.iter().collect()
+    }
+
+    // self callback
+    // Prices are returned in the order of `ft_token_ids`
+    #[private]
+    pub fn resolve_update_prices(&mut self, ft_token_ids: Vec<FungibleTokenId>) {
+        let prices = read_prices(ft_token_ids);
+        self.internal_cache_prices(&prices);
+    }
+
+    // self callback
+    // Accepts the best bid if the sale still belongs to `owner_id`, who called `accept_offer`
+    #[private]
+    pub fn resolve_accept_best_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        owner_id: AccountId,
+        ft_token_ids: Vec<FungibleTokenId>,
+    ) {
+        let prices = read_prices(ft_token_ids);
+        self.internal_cache_prices(&prices);
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let sale = self
+            .market
+            .sales
+            .get(&contract_and_token_id)
+            .expect("No sale");
+        require!(
+            sale.owner_id == owner_id,
+            "Only the sale owner can accept an offer"
+        );
+        require!(
+            sale.in_limits(),
+            "Either the sale is finished or it hasn't started yet"
+        );
+        let best = best_offer(&sale, prices).expect("No bids");
+        self.internal_accept_offer(
+            nft_contract_id,
+            token_id,
+            best.ft_token_id,
+            best.bid.owner_id,
+            best.bid.price,
+        );
+    }
+}
+
+impl Market {
+    // Requests the prices of the bid currencies and accepts the best bid in the callback
+    pub(crate) fn accept_best_offer(
+        &self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        owner_id: AccountId,
+        ft_token_ids: Vec<FungibleTokenId>,
+    ) -> Promise {
+        self.query_prices(&ft_token_ids)
+            .then(ext_self::resolve_accept_best_offer(
+                nft_contract_id,
+                token_id,
+                owner_id,
+                ft_token_ids,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_ACCEPT_BEST_OFFER,
+            ))
+    }
+
+    fn internal_cache_prices(&mut self, prices: &[(FungibleTokenId, u128)]) {
+        let updated_at = U64(env::block_timestamp());
+        for (ft_token_id, price) in prices {
+            let price = OraclePrice {
+                price: U128(*price),
+                updated_at,
+            };
+            self.market.prices.insert(ft_token_id, &price);
+        }
+    }
+
+    fn query_prices(&self, ft_token_ids: &[FungibleTokenId]) -> Promise {
+        let price_oracle_id = self
+            .market
+            .price_oracle_id
+            .clone()
+            .unwrap_or_else(|| env::panic_str("Price oracle is not set"));
+        ft_token_ids
+            .iter()
+            .map(|ft_token_id| {
+                ext_oracle::get_price(
+                    ft_token_id.clone(),
+                    price_oracle_id.clone(),
+                    NO_DEPOSIT,
+                    GAS_FOR_GET_PRICE,
+                )
+            })
+            .reduce(|prices, price| prices.and(price))
+            .expect("No bids")
+    }
+}
+
+// Currencies where the highest (last) bid is active, sorted to make the choice deterministic
+pub(crate) fn best_offer_candidates(sale: &Sale) -> Vec<FungibleTokenId> {
+    let mut ft_token_ids: Vec<FungibleTokenId> = sale
+        .bids
+        .iter()
+        .filter(|(_, bids)| matches!(bids.last(), Some(bid) if bid.in_limits()))
+        .map(|(ft_token_id, _)| ft_token_id.clone())
+        .collect();
+    ft_token_ids.sort();
+    ft_token_ids
+}
+
+// Value of the bid for the seller: the bid price without the buyer fees, converted by `price`
+pub(crate) fn bid_value(bid: &Bid, protocol_fee: u32, price: u128) -> u128 {
+    calculate_actual_amount(bid.price.0, calculate_origins(&bid.origins), protocol_fee)
+        .checked_mul(price)
+        .unwrap_or_else(|| env::panic_str("Bid value overflow"))
+}
+
+// Picks the highest bid among the currencies in `prices`, on a tie the first currency wins
+pub(crate) fn best_offer(
+    sale: &Sale,
+    prices: Vec<(FungibleTokenId, u128)>,
+) -> Option<BestOfferJson> {
+    let mut best: Option<BestOfferJson> = None;
+    for (ft_token_id, price) in prices {
+        let bid = match sale.bids.get(&ft_token_id).and_then(|bids| bids.last()) {
+            Some(bid) if bid.in_limits() => bid,
+            _ => continue,
+        };
+        let value = bid_value(bid, sale.protocol_fee, price);
+        if matches!(&best, Some(best) if best.value.0 >= value) {
+            continue;
+        }
+        best = Some(BestOfferJson {
+            ft_token_id,
+            bid: bid.clone(),
+            value: U128(value),
+        });
+    }
+    best
+}
+
+fn read_prices(ft_token_ids: Vec<FungibleTokenId>) -> Vec<(FungibleTokenId, u128)> {
+    require!(
+        env::promise_results_count() == ft_token_ids.len() as u64,
+        "Unexpected number of prices"
+    );
+    ft_token_ids
+        .into_iter()
+        .enumerate()
+        .map(|(i, ft_token_id)| match env::promise_result(i as u64) {
+            PromiseResult::Successful(value) => {
+                let price = near_sdk::serde_json::from_slice::<U128>(&value)
+                    .unwrap_or_else(|_| env::panic_str("Invalid price"));
+                (ft_token_id, price.0)
+            }
+            _ => env::panic_str(&format!("Failed to get the price of {}", ft_token_id)),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::testing_env;
+
+    use super::*;
+    use crate::test_utils::{account, bid, context, new_market, sale};
+
+    #[test]
+    fn best_offer_across_currencies() {
+        testing_env!(VMContextBuilder::new().block_timestamp(10).build());
+        let sale = sale(vec![
+            ("near", vec![bid("bob", 500), bid("carol", 1030)]),
+            ("usdc", vec![bid("dave", 2060)]),
+        ]);
+        assert_eq!(
+            best_offer_candidates(&sale),
+            vec![account("near"), account("usdc")]
+        );
+
+        // One usdc unit costs two near units
+        let best = best_offer(&sale, vec![(account("near"), 2), (account("usdc"), 1)]).unwrap();
+        assert_eq!(best.ft_token_id, account("near"));
+        assert_eq!(best.bid.owner_id, account("carol"));
+        assert_eq!(best.value, U128(2000));
+
+        let best = best_offer(&sale, vec![(account("near"), 1), (account("usdc"), 1)]).unwrap();
+        assert_eq!(best.ft_token_id, account("usdc"));
+        assert_eq!(best.bid.owner_id, account("dave"));
+    }
+
+    #[test]
+    fn best_offer_skips_inactive_bids() {
+        testing_env!(VMContextBuilder::new().block_timestamp(10).build());
+        let mut expired = bid("carol", 10_000);
+        expired.end = Some(U64(5));
+        let sale = sale(vec![
+            ("near", vec![bid("bob", 100), expired]),
+            ("usdc", vec![bid("dave", 200)]),
+        ]);
+        assert_eq!(best_offer_candidates(&sale), vec![account("usdc")]);
+        let best = best_offer(&sale, vec![(account("near"), 1), (account("usdc"), 1)]).unwrap();
+        assert_eq!(best.ft_token_id, account("usdc"));
+        assert!(best_offer(&sale, vec![(account("near"), 1)]).is_none());
+    }
+
+    #[test]
+    fn best_offer_by_cached_prices() {
+        let mut contract = new_market();
+        context("market", "market", 0, 10, vec![]);
+        contract.internal_insert_sale(&sale(vec![
+            ("near", vec![bid("carol", 1030)]),
+            ("usdc", vec![bid("dave", 2060)]),
+        ]));
+        // Only the near price is cached, so the usdc bid is skipped
+        contract.internal_cache_prices(&[(account("near"), 2)]);
+        let best = contract
+            .get_best_offer(account("nft"), "1:1".to_string())
+            .unwrap();
+        assert_eq!(best.bid.owner_id, account("carol"));
+        assert_eq!(contract.get_prices()[&account("near")].updated_at, U64(10));
+
+        contract.internal_cache_prices(&[(account("usdc"), 2)]);
+        let best = contract
+            .get_best_offer(account("nft"), "1:1".to_string())
+            .unwrap();
+        assert_eq!(best.bid.owner_id, account("dave"));
+    }
+}
@@ -0,0 +1,135 @@
+use crate::*;
+
+#[near_bindgen]
+impl Market {
+    // Adds a fungible token which can be used in sales and auctions
+    // Returns `true` if the token wasn't supported before
+    #[payable]
+    pub fn add_ft_token(&mut self, ft_token_id: FungibleTokenId) -> bool {
+        assert_one_yocto();
+        self.assert_owner();
+        self.market.ft_token_ids.insert(&ft_token_id)
+    }
+
+    // Removes a fungible token from the supported list
+    // Live sales and auctions in this token can't get new bids or purchases,
+    // but they still can be settled, cancelled and their bids refunded
+    // Returns `true` if the token was supported
+    #[payable]
+    pub fn remove_ft_token(&mut self, ft_token_id: FungibleTokenId) -> bool {
+        assert_one_yocto();
+        self.assert_owner();
+        self.market.ft_token_ids.remove(&ft_token_id)
+    }
+
+    pub fn get_supported_ft_tokens(&self) -> Vec<FungibleTokenId> {
+        self.market.ft_token_ids.to_vec()
+    }
+
+    // Allows tokens of the NFT contract to be listed on the market
+    // Returns `true` if the contract wasn't allowed before
+    #[payable]
+    pub fn add_nft_contract(&mut self, nft_contract_id: AccountId) -> bool {
+        assert_one_yocto();
+        self.assert_owner();
+        self.non_fungible_token_account_ids.insert(&nft_contract_id)
+    }
+
+    // Forbids new listings of the NFT contract tokens
+    // Existing sales and auctions are kept and can be finished as usual
+    // Returns `true` if the contract was allowed
+    #[payable]
+    pub fn remove_nft_contract(&mut self, nft_contract_id: AccountId) -> bool {
+        assert_one_yocto();
+        self.assert_owner();
+        self.non_fungible_token_account_ids.remove(&nft_contract_id)
+    }
+
+    pub fn get_supported_nft_contracts(
+        &self,
+        from_index: Option<U128>,
+        limit: Option<u64>,
+    ) -> Vec<AccountId> {
+        let start_index: u128 = from_index.map(From::from).unwrap_or_default();
+        let limit = limit.map(|v| v as usize).unwrap_or(usize::MAX);
+        self.non_fungible_token_account_ids
+            .iter()
+            .skip(start_index as usize)
+            .take(limit)
+            .collect()
+    }
+
+    // Sets the contract used to compare bids in different currencies, `None` disables it
+    // The oracle should implement `get_price(ft_token_id) -> U128` for every supported token
+    // including "near", see `oracle.rs`
+    // The prices cached from the previous oracle are dropped
+    #[payable]
+    pub fn set_price_oracle(&mut self, price_oracle_id: Option<AccountId>) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.market.price_oracle_id = price_oracle_id;
+        self.market.prices.clear();
+    }
+
+    pub fn get_price_oracle(&self) -> Option<AccountId> {
+        self.market.price_oracle_id.clone()
+    }
+
+    // Sets the limits for the extension window and duration of new auctions
+    // Existing auctions keep their extension settings
+    #[payable]
+    pub fn set_extension_bounds(&mut self, min_duration: U64, max_duration: U64) {
+        assert_one_yocto();
+        self.assert_owner();
+        require!(
+            min_duration.0 <= max_duration.0,
+            "min_duration should not be bigger than max_duration"
+        );
+        self.market.extension_bounds = ExtensionBounds {
+            min_duration,
+            max_duration,
+        };
+    }
+
+    pub fn get_extension_bounds(&self) -> ExtensionBounds {
+        self.market.extension_bounds.clone()
+    }
+
+    // Sets the protocol fee of new sales and auctions, 10_000 is 100%
+    // Existing sales and auctions keep the fee they were listed with
+    #[payable]
+    pub fn set_protocol_fee(&mut self, protocol_fee: u32) {
+        assert_one_yocto();
+        self.assert_owner();
+        require!(
+            protocol_fee <= MAX_PROTOCOL_FEE,
+            format!("Protocol fee should not be bigger than {}", MAX_PROTOCOL_FEE)
+        );
+        self.market.protocol_fee = protocol_fee;
+    }
+
+    pub fn get_protocol_fee(&self) -> u32 {
+        self.market.protocol_fee
+    }
+
+    // Sets the account which receives the protocol fee of all the following purchases
+    #[payable]
+    pub fn set_treasury(&mut self, treasury_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.market.treasury_id = treasury_id;
+    }
+
+    pub fn get_treasury(&self) -> AccountId {
+        self.market.treasury_id.clone()
+    }
+}
+
+impl Market {
+    pub(crate) fn assert_owner(&self) {
+        require!(
+            env::predecessor_account_id() == self.market.owner_id,
+            "Only the market owner can call this method"
+        );
+    }
+}
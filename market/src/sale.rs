@@ -2,22 +2,32 @@
 use std::collections::HashMap;
 
 use near_sdk::ext_contract;
-use near_sdk::serde_json::json;
 use near_sdk::{promise_result_as_success, Gas};
 
+use crate::claimable::GAS_FOR_PAY_OUT;
+use crate::event::{NearEvent, PayoutFailData, PurchaseData, SaleUpdateData};
 use crate::fee::calculate_price_with_fees;
 use crate::market_core::SaleArgs;
 use crate::*;
 use common::*;
-use near_contract_standards::non_fungible_token::hash_account_id;
 
 use bid::{Bids, Origins};
 pub type TokenSeriesId = String;
 
 pub const GAS_FOR_FT_TRANSFER: Gas = Gas(5_000_000_000_000);
-pub const GAS_FOR_ROYALTIES: Gas = Gas(115_000_000_000_000);
-pub const GAS_FOR_NFT_TRANSFER: Gas = Gas(30_000_000_000_000);
-// pub const GAS_FOR_MINT: Gas = Gas(20_000_000_000_000);
+// Settlement callbacks: the payout and either crediting the bids or restoring the sale
+pub const GAS_FOR_ROYALTIES: Gas = Gas(15_000_000_000_000 + GAS_FOR_PAY_OUT.0);
+// `nft_transfer` notifies the other approved accounts of the token with `nft_on_revoke`
+// as long as the gas left is enough, the market pays for a few of these notifications
+pub const GAS_FOR_NFT_ON_REVOKE: Gas = Gas(10_000_000_000_000);
+pub const REVOKE_NOTIFICATIONS_PER_TRANSFER: u64 = 2;
+pub const GAS_FOR_NFT_TRANSFER: Gas =
+    Gas(30_000_000_000_000 + GAS_FOR_NFT_ON_REVOKE.0 * REVOKE_NOTIFICATIONS_PER_TRANSFER);
+pub const GAS_FOR_NFT_IS_APPROVED: Gas = Gas(10_000_000_000_000);
+pub const GAS_FOR_RESOLVE_RESTORE_SALE: Gas = Gas(60_000_000_000_000);
+pub const GAS_FOR_NFT_TOKEN: Gas = Gas(10_000_000_000_000);
+pub const GAS_FOR_RESOLVE_PRUNE: Gas = Gas(60_000_000_000_000);
+pub const MAX_LEN_PAYOUT: u32 = 10;
 pub const BID_HISTORY_LENGTH_DEFAULT: u8 = 5;
 pub(crate) const NO_DEPOSIT: Balance = 0;
 pub static DELIMETER: &str = "||";
@@ -30,6 +40,23 @@ pub struct Payout {
     pub payout: HashMap<AccountId, U128>,
 }
 
+// Parses the payout of `nft_transfer_payout`, None if it doesn't add up to the price
+pub(crate) fn parse_payout(value: &[u8], price: u128) -> Option<Payout> {
+    let payout = near_sdk::serde_json::from_slice::<Payout>(value).ok()?;
+    if payout.payout.is_empty() || payout.payout.len() > MAX_LEN_PAYOUT as usize {
+        return None;
+    }
+    let mut remainder = price;
+    for value in payout.payout.values() {
+        remainder = remainder.checked_sub(value.0)?;
+    }
+    if remainder <= 1 {
+        Some(payout)
+    } else {
+        None
+    }
+}
+
 pub type ContractAndTokenId = String;
 pub type FungibleTokenId = AccountId;
 pub type TokenType = Option<String>;
@@ -50,6 +77,8 @@ pub struct Sale {
     pub end: Option<u64>,
 
     pub origins: Origins,
+    // Protocol fee at the moment of listing, it doesn't change with the market fee
+    pub protocol_fee: u32,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -66,16 +95,21 @@ pub struct SaleJson {
     pub start: Option<U64>,
     pub end: Option<U64>,
     pub origins: Origins,
+    pub protocol_fee: u32,
 }
 
-#[derive(BorshDeserialize, BorshSerialize)]
+// Copies of the series which the market mints for the buyers, see `series_sale.rs`
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
 pub struct SeriesSale {
     pub owner_id: AccountId,
     pub nft_contract_id: AccountId,
-    pub series_id: String,
+    pub series_id: TokenSeriesId,
     pub sale_conditions: SaleConditions,
-    pub created_at: u64,
+    pub created_at: U64,
+    // Copies left to sell, the sale is removed when the last one is bought
     pub copies: u64,
+    pub protocol_fee: u32,
 }
 
 impl Sale {
@@ -106,6 +140,7 @@ impl Sale {
 pub struct PurchaseArgs {
     pub nft_contract_id: AccountId,
     pub token_id: TokenId,
+    pub origins: Option<Origins>,
 }
 
 #[near_bindgen]
@@ -141,12 +176,11 @@ impl Market {
         // Create a new sale with given arguments and empty list of bids
 
         let bids = HashMap::new();
-        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
         let start = start.map(|s| s.into()).unwrap_or_else(env::block_timestamp);
         let sale = Sale {
             owner_id: owner_id.clone(),
             approval_id,
-            nft_contract_id: nft_contract_id.clone(),
+            nft_contract_id,
             token_id: token_id.clone(),
             sale_conditions,
             bids,
@@ -155,78 +189,21 @@ impl Market {
             start: Some(start),
             end: end.map(|e| e.into()),
             origins: origins.unwrap_or_default(),
+            protocol_fee: self.market.protocol_fee,
         };
-        self.market.sales.insert(&contract_and_token_id, &sale);
-
-        // extra for views
-
-        let mut by_owner_id = self.market.by_owner_id.get(&owner_id).unwrap_or_else(|| {
-            UnorderedSet::new(
-                StorageKey::ByOwnerIdInner {
-                    account_id_hash: hash_account_id(&owner_id),
-                }
-                .try_to_vec()
-                .unwrap(),
-            )
-        });
 
         // Check that the paid storage amount is enough
-        let owner_paid_storage = self
-            .market
-            .storage_deposits
-            .get(&env::signer_account_id())
-            .unwrap_or(0);
-        let owner_occupied_storage = u128::from(by_owner_id.len()) * STORAGE_PER_SALE;
         assert!(
-            owner_paid_storage > owner_occupied_storage,
+            self.storage_covers_new_sale(&env::signer_account_id(), &owner_id),
             "User has more sales than storage paid"
         );
-        by_owner_id.insert(&contract_and_token_id);
-        self.market.by_owner_id.insert(&owner_id, &by_owner_id);
-
-        let mut by_nft_contract_id = self
-            .market
-            .by_nft_contract_id
-            .get(&nft_contract_id)
-            .unwrap_or_else(|| {
-                UnorderedSet::new(
-                    StorageKey::ByNFTContractIdInner {
-                        account_id_hash: hash_account_id(&nft_contract_id),
-                    }
-                    .try_to_vec()
-                    .unwrap(),
-                )
-            });
-        by_nft_contract_id.insert(&token_id);
-        self.market
-            .by_nft_contract_id
-            .insert(&nft_contract_id, &by_nft_contract_id);
-
         if let Some(token_type) = token_type {
             assert!(
                 token_id.contains(token_type.as_str()),
                 "TokenType should be substr of TokenId"
             );
-            let mut by_nft_token_type = self
-                .market
-                .by_nft_token_type
-                .get(&token_type)
-                .unwrap_or_else(|| {
-                    UnorderedSet::new(
-                        StorageKey::ByNFTTokenTypeInner {
-                            token_type_hash: hash_account_id(&AccountId::new_unchecked(
-                                token_type.clone(),
-                            )),
-                        }
-                        .try_to_vec()
-                        .unwrap(),
-                    )
-                });
-            by_nft_token_type.insert(&contract_and_token_id);
-            self.market
-                .by_nft_token_type
-                .insert(&token_type, &by_nft_token_type);
         }
+        self.internal_insert_sale(&sale);
 
         self.json_from_sale(sale)
     }
@@ -243,7 +220,34 @@ impl Market {
                 "Until the sale is finished, it can only be removed by the sale owner"
             );
         };
-        self.refund_all_bids(&sale.bids);
+        self.refund_all_bids(&sale.nft_contract_id, &sale.token_id, &sale.bids);
+    }
+
+    // Removes the sale and refunds its bids if the sale owner doesn't own the token anymore
+    // or the market isn't approved to transfer it, can be called by anyone
+    pub fn prune_stale_listing(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+    ) -> Promise {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        require!(
+            self.market.sales.get(&contract_and_token_id).is_some(),
+            "No sale"
+        );
+        ext_contract::nft_token(
+            token_id.clone(),
+            nft_contract_id.clone(),
+            NO_DEPOSIT,
+            GAS_FOR_NFT_TOKEN,
+        )
+        .then(ext_self::resolve_prune_stale_listing(
+            nft_contract_id,
+            token_id,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_RESOLVE_PRUNE,
+        ))
     }
 
     #[payable]
@@ -273,8 +277,15 @@ impl Market {
                 ft_token_id
             ));
         }
-        sale.sale_conditions.insert(ft_token_id, price);
+        sale.sale_conditions.insert(ft_token_id.clone(), price);
         self.market.sales.insert(&contract_and_token_id, &sale);
+        NearEvent::sale_update(SaleUpdateData {
+            nft_contract_id: sale.nft_contract_id,
+            token_id: sale.token_id,
+            ft_token_id,
+            price,
+        })
+        .emit();
     }
 
     // Offer to buy the nft
@@ -289,86 +300,78 @@ impl Market {
         duration: Option<U64>,
         origins: Option<Origins>,
     ) {
-        let contract_id: AccountId = nft_contract_id;
-        let contract_and_token_id = format!("{}{}{}", contract_id, DELIMETER, token_id);
-        let mut sale = self
-            .market
-            .sales
-            .get(&contract_and_token_id)
-            .expect("No sale");
-        // Check that the sale is in progress
+        // Fungible tokens are offered through `ft_on_transfer`, the attached deposit is always NEAR
         require!(
-            sale.in_limits(),
-            "Either the sale is finished or it hasn't started yet"
+            ft_token_id.as_str() == "near",
+            "Fungible tokens should be offered with ft_transfer_call"
+        );
+        self.internal_offer(
+            nft_contract_id,
+            token_id,
+            ft_token_id,
+            env::predecessor_account_id(),
+            env::attached_deposit(),
+            start,
+            duration,
+            origins,
         );
-
-        let buyer_id = env::predecessor_account_id();
-        require!(sale.owner_id != buyer_id, "Cannot bid on your own sale.");
-        let price = *sale
-            .sale_conditions
-            .get(&ft_token_id)
-            .unwrap_or_else(|| env::panic_str("Not supported ft"));
-
-        let deposit = env::attached_deposit();
-        require!(deposit > 0, "Attached deposit must be greater than 0");
-
-        if deposit == calculate_price_with_fees(price, origins.as_ref()) {
-            self.process_purchase(
-                contract_id,
-                token_id,
-                ft_token_id,
-                U128(deposit),
-                buyer_id,
-                origins.unwrap_or_default(),
-            );
-        } else {
-            let start = start.unwrap_or(env::block_timestamp().into());
-            let end = duration.map(|d| U64(d.0 + start.0));
-            self.add_bid(
-                contract_and_token_id,
-                deposit,
-                ft_token_id,
-                buyer_id,
-                &mut sale,
-                start,
-                end,
-                origins,
-            );
-        }
     }
 
-    // Accepts the last (highest) offer
+    // Accepts the offer of `bid_owner_id` with `bid_price`
+    // or the last (highest) offer if the bid is not specified
+    // Without `ft_token_id` the best offer across all the currencies is accepted,
+    // bids in different currencies are compared by the price oracle
+    // Other bids stay in the sale and are refunded after the purchase
     pub fn accept_offer(
         &mut self,
         nft_contract_id: AccountId,
         token_id: String,
-        ft_token_id: AccountId,
+        ft_token_id: Option<FungibleTokenId>,
+        bid_owner_id: Option<AccountId>,
+        bid_price: Option<U128>,
     ) {
         let contract_id: AccountId = nft_contract_id;
         let contract_and_token_id = format!("{}{}{}", contract_id, DELIMETER, token_id);
         // Check that the sale is in progress and remove bid before proceeding to process purchase
-        let mut sale = self
+        let sale = self
             .market
             .sales
             .get(&contract_and_token_id)
             .expect("No sale");
+        require!(
+            sale.owner_id == env::predecessor_account_id(),
+            "Only the sale owner can accept an offer"
+        );
         require!(
             sale.in_limits(),
             "Either the sale is finished or it hasn't started yet"
         );
-        let bids_for_token_id = sale.bids.remove(&ft_token_id).expect("No bids");
-        let bid = &bids_for_token_id[bids_for_token_id.len() - 1];
-        require!(bid.in_limits(), "Out of time limit of the bid");
-        self.market.sales.insert(&contract_and_token_id, &sale);
-        // panics at `self.internal_remove_sale` and reverts above if predecessor is not sale.owner_id
-        self.process_purchase(
-            contract_id,
-            token_id,
-            ft_token_id,
-            bid.price,
-            bid.owner_id.clone(),
-            bid.origins.clone(),
-        );
+        let ft_token_id = match ft_token_id {
+            Some(ft_token_id) => ft_token_id,
+            None => {
+                require!(
+                    bid_owner_id.is_none() && bid_price.is_none(),
+                    "ft_token_id should be specified to accept a specific bid"
+                );
+                let mut ft_token_ids = oracle::best_offer_candidates(&sale);
+                // No need to compare prices when all the bids are in one currency
+                if ft_token_ids.len() > 1 {
+                    self.accept_best_offer(contract_id, token_id, sale.owner_id, ft_token_ids);
+                    return;
+                }
+                ft_token_ids.pop().expect("No bids")
+            }
+        };
+        let bids_for_token_id = sale.bids.get(&ft_token_id).expect("No bids");
+        let (bid_owner_id, bid_price) = match (bid_owner_id, bid_price) {
+            (Some(bid_owner_id), Some(bid_price)) => (bid_owner_id, bid_price),
+            (None, None) => {
+                let bid = bids_for_token_id.last().expect("No bids");
+                (bid.owner_id.clone(), bid.price)
+            }
+            _ => env::panic_str("Both bid_owner_id and bid_price should be specified"),
+        };
+        self.internal_accept_offer(contract_id, token_id, ft_token_id, bid_owner_id, bid_price);
     }
 
     #[private]
@@ -382,22 +385,19 @@ impl Market {
         origins: Origins,
     ) -> Promise {
         let sale = self.internal_remove_sale(nft_contract_id.clone(), token_id.clone());
-        let mut buyer = origins;
-        buyer.insert(env::current_account_id(), PROTOCOL_FEE as u32);
-        let mut seller_fee = HashMap::with_capacity(sale.origins.len() + 1);
-        seller_fee.extend(sale.origins.clone()); // TODO: dodge this clone
-        seller_fee.insert(env::current_account_id(), PROTOCOL_FEE as u32);
-        let fees = fee::Fees {
-            buyer,
-            seller: seller_fee,
-        };
+        let fees = fee::Fees::new(
+            origins,
+            &sale.origins,
+            &self.market.treasury_id,
+            sale.protocol_fee,
+        );
         ext_contract::nft_transfer_payout(
             buyer_id.clone(),
             token_id,
             sale.approval_id,
-            Some(near_sdk::serde_json::to_string(&fees).expect("Failed to sereailize")),
+            Some(near_sdk::serde_json::to_string(&fees).expect("Failed to serialize")),
             price,
-            10,
+            MAX_LEN_PAYOUT,
             nft_contract_id,
             1,
             GAS_FOR_NFT_TRANSFER,
@@ -407,6 +407,7 @@ impl Market {
             buyer_id,
             sale,
             price,
+            fees,
             env::current_account_id(),
             NO_DEPOSIT,
             GAS_FOR_ROYALTIES,
@@ -415,7 +416,8 @@ impl Market {
 
     // self callback
     // If transfer of token succeded - count fees and transfer payouts
-    // If failed - refund price to buyer
+    // If failed - refund price to buyer and restore the sale, see `resolve_restore_sale`
+    // The refunds are sent explicitly, so nothing is returned to `ft_resolve_transfer`
     #[private]
     pub fn resolve_purchase(
         &mut self,
@@ -423,109 +425,211 @@ impl Market {
         buyer_id: AccountId,
         sale: Sale,
         price: U128,
+        fees: fee::Fees,
     ) -> U128 {
-        // checking for payout information
-        let payout_option = promise_result_as_success().and_then(|value| {
-            // None means a bad payout from bad NFT contract
-            near_sdk::serde_json::from_slice::<Payout>(&value)
-                .ok()
-                .and_then(|payout| {
-                    // gas to do 10 FT transfers (and definitely 10 NEAR transfers)
-                    if payout.payout.len() + sale.bids.len() > 10 || payout.payout.is_empty() {
-                        env::log_str("Cannot have more than 10 royalties and sale.bids refunds");
-                        None
-                    } else {
-                        let mut remainder = price.0;
-                        for &value in payout.payout.values() {
-                            remainder = remainder.checked_sub(value.0)?;
-                        }
-                        if remainder <= 1 {
-                            Some(payout)
-                        } else {
-                            None
-                        }
-                    }
+        // The price is either paid out or refunded to the buyer
+        self.escrow_release(&ft_token_id, price.0);
+        let value = match promise_result_as_success() {
+            Some(value) => value,
+            None => {
+                self.internal_send(ft_token_id.clone(), buyer_id.clone(), price);
+                NearEvent::payout_fail(PayoutFailData {
+                    auction_id: None,
+                    owner_id: sale.owner_id.clone(),
+                    nft_contract_id: sale.nft_contract_id.clone(),
+                    token_id: sale.token_id.clone(),
+                    buyer_id,
+                    ft_token_id,
+                    price,
                 })
-        });
-        // is payout option valid?
-        let payout = if let Some(payout_option) = payout_option {
-            payout_option
-        } else {
-            if ft_token_id == "near".parse().unwrap() {
-                Promise::new(buyer_id.clone()).transfer(u128::from(price));
+                .emit();
+                ext_contract::nft_is_approved(
+                    sale.token_id.clone(),
+                    env::current_account_id(),
+                    Some(sale.approval_id),
+                    sale.nft_contract_id.clone(),
+                    NO_DEPOSIT,
+                    GAS_FOR_NFT_IS_APPROVED,
+                )
+                .then(ext_self::resolve_restore_sale(
+                    sale,
+                    env::current_account_id(),
+                    NO_DEPOSIT,
+                    GAS_FOR_RESOLVE_RESTORE_SALE,
+                ));
+                return U128(0);
             }
-            // leave function and return all FTs in ft_resolve_transfer
-            env::log_str(
-                &json!({
-                    "type": "resolve_purchase_fail",
-                    "params": {
-                        "owner_id": sale.owner_id,
-                        "nft_contract_id": sale.nft_contract_id,
-                        "token_id": sale.token_id,
-                        "ft_token_id": ft_token_id,
-                        "price": price,
-                        "buyer_id": buyer_id,
-                    }
-                })
-                .to_string(),
-            );
-            return price;
         };
+        let payout = fees.payout_from_result(&value, price.0, &sale.owner_id);
         // Going to payout everyone, first return all outstanding bids (accepted offer bid was already removed)
-        self.refund_all_bids(&sale.bids); // TODO: maybe should do this outside of this call, to lower gas for this call
+        // The callback gas doesn't depend on the number of bids, so they are credited to be claimed
+        self.credit_all_bids(&sale.nft_contract_id, &sale.token_id, &sale.bids);
+        NearEvent::purchase(PurchaseData {
+            auction_id: None,
+            owner_id: sale.owner_id,
+            nft_contract_id: sale.nft_contract_id,
+            token_id: sale.token_id,
+            buyer_id,
+            ft_token_id: ft_token_id.clone(),
+            price,
+            payout: payout.clone(),
+        })
+        .emit();
+        self.internal_pay_out(&ft_token_id, payout);
+        U128(0)
+    }
 
-        // NEAR payouts
-        if ft_token_id == "near".parse().unwrap() {
-            for (receiver_id, amount) in payout.payout {
-                Promise::new(receiver_id).transfer(amount.0);
-            }
-            price
+    // self callback
+    // Puts the sale back after a failed purchase if the market can still transfer the token,
+    // otherwise the remaining bids are credited to the bidders, see `claim`
+    #[private]
+    pub fn resolve_restore_sale(&mut self, sale: Sale) {
+        let approved = promise_result_as_success()
+            .and_then(|value| near_sdk::serde_json::from_slice::<bool>(&value).ok())
+            .unwrap_or(false);
+        let contract_and_token_id =
+            format!("{}{}{}", sale.nft_contract_id, DELIMETER, sale.token_id);
+        // The token could have been listed again, the sale owner could have withdrawn the storage
+        if approved
+            && self.market.listings_by_token.get(&contract_and_token_id).is_none()
+            && self.storage_covers_new_sale(&sale.owner_id, &sale.owner_id)
+        {
+            self.internal_insert_sale(&sale);
+            NearEvent::sale_restore(self.json_from_sale(sale)).emit();
         } else {
-            // FT payouts
-            for (receiver_id, amount) in payout.payout {
-                ext_contract::ft_transfer(
-                    receiver_id,
-                    amount,
-                    None,
-                    ft_token_id.clone(),
-                    1,
-                    GAS_FOR_FT_TRANSFER,
-                );
-            }
-            // keep all FTs (already transferred for payouts)
-            U128(0)
+            self.credit_all_bids(&sale.nft_contract_id, &sale.token_id, &sale.bids);
         }
     }
 
-    // For lazy-mint situations easier resolver
+    // self callback
+    // Returns true if the sale was removed, its bids are credited to the bidders, see `claim`
     #[private]
-    pub fn resolve_token_buy(&mut self, buyer_id: AccountId, deposit: U128, price: U128) -> U128 {
-        let payout_option = promise_result_as_success().and_then(|value| {
-            // None means a bad payout from bad NFT contract
-            near_sdk::serde_json::from_slice::<Payout>(&value)
-                .ok()
-                .and_then(|payout| {
-                    let mut remainder = price.0;
-                    for &value in payout.payout.values() {
-                        remainder = remainder.checked_sub(value.0)?;
-                    }
-                    if remainder <= 1 {
-                        Some(payout)
-                    } else {
-                        None
-                    }
-                })
-        });
-        let payout = if let Some(payout_option) = payout_option {
-            payout_option
-        } else {
-            Promise::new(buyer_id).transfer(u128::from(deposit));
-            return price;
+    pub fn resolve_prune_stale_listing(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+    ) -> bool {
+        let token = promise_result_as_success()
+            .and_then(|value| near_sdk::serde_json::from_slice::<Option<Token>>(&value).ok())
+            .unwrap_or_else(|| env::panic_str("Failed to get the token"));
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        // The sale could be removed while the token was requested
+        let sale = match self.market.sales.get(&contract_and_token_id) {
+            Some(sale) => sale,
+            None => return false,
         };
-        for (receiver_id, amount) in payout.payout {
-            Promise::new(receiver_id).transfer(amount.0);
+        let listed = match token {
+            Some(token) => {
+                let approval_id = token
+                    .approved_account_ids
+                    .and_then(|ids| ids.get(&env::current_account_id()).copied());
+                token.owner_id == sale.owner_id && approval_id == Some(sale.approval_id)
+            }
+            None => false,
+        };
+        if listed {
+            return false;
+        }
+        let sale = self.internal_remove_sale(nft_contract_id, token_id);
+        self.credit_all_bids(&sale.nft_contract_id, &sale.token_id, &sale.bids);
+        true
+    }
+}
+
+impl Market {
+    // Removes the bid and sells the token to its owner
+    pub(crate) fn internal_accept_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: String,
+        ft_token_id: FungibleTokenId,
+        bid_owner_id: AccountId,
+        bid_price: U128,
+    ) {
+        let bid = self
+            .internal_remove_bid(
+                nft_contract_id.clone(),
+                &ft_token_id,
+                token_id.clone(),
+                &bid_owner_id,
+                bid_price,
+            )
+            .expect("No such bid");
+        require!(bid.in_limits(), "Out of time limit of the bid");
+        self.process_purchase(
+            nft_contract_id,
+            token_id,
+            ft_token_id,
+            bid.price,
+            bid.owner_id,
+            bid.origins,
+        );
+    }
+
+    // Buys the nft if `deposit` is equal to the price with fees, otherwise adds a bid
+    // Returns the purchase promise if the nft is being bought
+    pub(crate) fn internal_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: String,
+        ft_token_id: AccountId,
+        buyer_id: AccountId,
+        deposit: Balance,
+        start: Option<U64>,
+        duration: Option<U64>,
+        origins: Option<Origins>,
+    ) -> Option<Promise> {
+        let contract_id: AccountId = nft_contract_id;
+        let contract_and_token_id = format!("{}{}{}", contract_id, DELIMETER, token_id);
+        let mut sale = self
+            .market
+            .sales
+            .get(&contract_and_token_id)
+            .expect("No sale");
+        // Check that the sale is in progress
+        require!(
+            sale.in_limits(),
+            "Either the sale is finished or it hasn't started yet"
+        );
+
+        require!(sale.owner_id != buyer_id, "Cannot bid on your own sale.");
+        let price = *sale
+            .sale_conditions
+            .get(&ft_token_id)
+            .unwrap_or_else(|| env::panic_str("Not supported ft"));
+        // The token could have been removed from the market after the sale was created
+        require!(
+            self.market.ft_token_ids.contains(&ft_token_id),
+            format!("Token {} not supported by this market", ft_token_id)
+        );
+
+        require!(deposit > 0, "Attached deposit must be greater than 0");
+
+        if deposit == calculate_price_with_fees(price, origins.as_ref(), sale.protocol_fee) {
+            self.escrow_deposit(&ft_token_id, deposit);
+            Some(self.process_purchase(
+                contract_id,
+                token_id,
+                ft_token_id,
+                U128(deposit),
+                buyer_id,
+                origins.unwrap_or_default(),
+            ))
+        } else {
+            let start = start.unwrap_or(env::block_timestamp().into());
+            let end = duration.map(|d| U64(d.0 + start.0));
+            self.add_bid(
+                contract_and_token_id,
+                deposit,
+                ft_token_id,
+                buyer_id,
+                &mut sale,
+                start,
+                end,
+                origins,
+            );
+            None
         }
-        price
     }
 }
 
@@ -539,19 +643,95 @@ trait ExtSelf {
         buyer_id: AccountId,
         sale: Sale,
         price: U128,
+        fees: fee::Fees,
     ) -> Promise;
 
-    fn resolve_finish_auction(&mut self, ft_token_id: AccountId, buyer_id: AccountId, price: U128);
+    fn resolve_restore_sale(&mut self, sale: Sale);
+
+    fn resolve_prune_stale_listing(&mut self, nft_contract_id: AccountId, token_id: TokenId);
+
+    fn resolve_collection_offer(
+        &mut self,
+        offer_id: U128,
+        offer: CollectionOffer,
+        token_id: TokenId,
+        owner_id: AccountId,
+        fees: fee::Fees,
+    );
+
+    fn resolve_token_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        ft_token_id: FungibleTokenId,
+        offer: TokenOffer,
+        owner_id: AccountId,
+        fees: fee::Fees,
+    );
+
+    fn resolve_create_bundle(&mut self, owner_id: AccountId, args: BundleArgs) -> U128;
+
+    fn resolve_bundle_purchase(
+        &mut self,
+        bundle_id: U128,
+        bundle: Bundle,
+        ft_token_id: FungibleTokenId,
+        buyer_id: AccountId,
+        price: U128,
+        fees: fee::Fees,
+    );
+
+    fn resolve_create_swap_offer(
+        &mut self,
+        owner_id: AccountId,
+        args: SwapOfferArgs,
+        ft_token_id: FungibleTokenId,
+        top_up: U128,
+    ) -> Option<U128>;
+
+    fn resolve_swap(&mut self, swap_id: U128, offer: SwapOffer, receiver_id: AccountId);
+
+    fn resolve_finish_auction(&mut self, auction_id: U128, auction: Auction, fees: fee::Fees);
+
+    fn resolve_nft_transfers(&mut self, transfers: Vec<(AccountId, ClaimableToken)>);
+
+    fn resolve_refund(
+        &mut self,
+        ft_token_id: FungibleTokenId,
+        receiver_id: AccountId,
+        amount: U128,
+    );
+
+    fn resolve_withdraw_protocol_fees(&mut self, ft_token_id: FungibleTokenId, amount: U128);
 
     fn resolve_mint(
         &mut self,
         nft_contract_id: AccountId,
+        owner_id: AccountId,
         buyer_id: AccountId,
         deposit: U128,
-        price: U128,
+        fees: fee::Fees,
     ) -> Promise;
 
-    fn resolve_token_buy(&mut self, buyer_id: AccountId, deposit: U128, price: U128) -> Promise;
+    fn resolve_token_buy(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        owner_id: AccountId,
+        buyer_id: AccountId,
+        deposit: U128,
+        fees: fee::Fees,
+    );
+
+    fn resolve_update_prices(&mut self, ft_token_ids: Vec<FungibleTokenId>);
+
+    fn resolve_accept_best_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        owner_id: AccountId,
+        ft_token_ids: Vec<FungibleTokenId>,
+    );
 }
 
 /// external contract calls
@@ -567,7 +747,306 @@ trait ExtContract {
         balance: U128,
         max_len_payout: u32,
     ) -> Promise;
+    fn nft_transfer(
+        &mut self,
+        receiver_id: AccountId,
+        token_id: TokenId,
+        approval_id: Option<u64>,
+        memo: Option<String>,
+    );
     fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
-    fn nft_mint(&mut self, token_series_id: TokenSeriesId, receiver_id: AccountId);
+    fn nft_token(&self, token_id: TokenId) -> Option<Token>;
+    fn nft_is_approved(
+        &self,
+        token_id: TokenId,
+        approved_account_id: AccountId,
+        approval_id: Option<u64>,
+    ) -> bool;
+    fn nft_mint(
+        &mut self,
+        token_series_id: TokenSeriesId,
+        receiver_id: AccountId,
+        refund_id: Option<AccountId>,
+    ) -> TokenId;
     fn nft_payout(&self, token_id: String, balance: U128, max_len_payout: u32) -> Payout;
 }
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::PromiseResult;
+
+    use super::*;
+    use crate::market_core::NonFungibleTokenApprovalReceiver;
+    use crate::test_utils::{
+        account, bid, callback_context, context, function_calls, near_transfers, new_market, sale,
+        with_storage,
+    };
+
+    #[test]
+    fn fees_payout_without_royalties() {
+        let fees = fee::Fees::new(
+            HashMap::from([(account("origin"), 100)]),
+            &HashMap::new(),
+            &account("market"),
+            300,
+        );
+        // 10400 with 4% of the buyer fees is 10000 for the seller
+        assert_eq!(
+            fees.payout(10400, &account("alice")),
+            HashMap::from([
+                (account("origin"), U128(100)),
+                (account("market"), U128(600)),
+                (account("alice"), U128(9700)),
+            ])
+        );
+    }
+
+    #[test]
+    fn revoke_gas_of_nft_contract() {
+        assert_eq!(GAS_FOR_NFT_ON_REVOKE, nft_contract::GAS_FOR_NFT_ON_REVOKE);
+    }
+
+    #[test]
+    fn parse_payout_checks_price() {
+        let payout = |payout: Vec<(&str, u128)>| {
+            let payout: HashMap<AccountId, U128> = payout
+                .into_iter()
+                .map(|(account_id, amount)| (account(account_id), U128(amount)))
+                .collect();
+            near_sdk::serde_json::to_vec(&Payout { payout }).unwrap()
+        };
+        assert!(parse_payout(&payout(vec![("alice", 700), ("market", 300)]), 1000).is_some());
+        // The remainder of 1 is allowed for the rounding
+        assert!(parse_payout(&payout(vec![("alice", 999)]), 1000).is_some());
+        assert!(parse_payout(&payout(vec![("alice", 998)]), 1000).is_none());
+        assert!(parse_payout(&payout(vec![("alice", 1001)]), 1000).is_none());
+        assert!(parse_payout(&payout(vec![]), 1000).is_none());
+        let receivers: Vec<String> = (0..=MAX_LEN_PAYOUT).map(|i| format!("r{}", i)).collect();
+        let too_many = receivers.iter().map(|id| (id.as_str(), 1)).collect();
+        assert!(parse_payout(&payout(too_many), 11).is_none());
+        assert!(parse_payout(b"{}", 1000).is_none());
+    }
+
+    fn usdc_sale() -> Sale {
+        sale(vec![("usdc", vec![bid("carol", 500)])])
+    }
+
+    // Lists `usdc_sale` with the bid of carol held in escrow
+    fn insert_usdc_sale(contract: &mut Market) {
+        contract.internal_insert_sale(&usdc_sale());
+        contract.escrow_deposit(&account("usdc"), 500);
+    }
+
+    // (ft_token_id, receiver_id, amount) of the created `ft_transfer` calls
+    fn ft_transfers() -> Vec<(String, String, String)> {
+        function_calls()
+            .into_iter()
+            .filter(|(_, function_name, _)| function_name == "ft_transfer")
+            .map(|(receiver_id, _, args)| {
+                let amount = args["amount"].as_str().unwrap().to_string();
+                (receiver_id, args["receiver_id"].as_str().unwrap().to_string(), amount)
+            })
+            .collect()
+    }
+
+    #[test]
+    #[should_panic(expected = "Fungible tokens should be offered with ft_transfer_call")]
+    fn offer_in_unsupported_ft() {
+        let mut contract = new_market();
+        contract.internal_insert_sale(&usdc_sale());
+        context("bob", "bob", 1030, 0, vec![]);
+        contract.offer(account("nft"), "1:1".to_string(), account("usdc"), None, None, None);
+    }
+
+    #[test]
+    fn resolve_purchase_refunds_ft() {
+        let mut contract = new_market();
+        contract.escrow_deposit(&account("usdc"), 1030);
+        callback_context(PromiseResult::Failed);
+        let fees = fee::Fees::new(HashMap::new(), &HashMap::new(), &account("market"), 300);
+        let unused = contract.resolve_purchase(
+            account("usdc"),
+            account("bob"),
+            usdc_sale(),
+            U128(1030),
+            fees,
+        );
+        // Nothing is left for `ft_resolve_transfer`, the buyer is refunded
+        assert_eq!(unused, U128(0));
+        assert_eq!(
+            ft_transfers(),
+            vec![("usdc".to_string(), "bob".to_string(), "1030".to_string())]
+        );
+        // The bids wait for the approval check
+        let calls: Vec<(String, String)> = function_calls()
+            .into_iter()
+            .map(|(receiver_id, function_name, _)| (receiver_id, function_name))
+            .collect();
+        assert!(calls.contains(&("nft".to_string(), "nft_is_approved".to_string())));
+        assert!(calls.contains(&("market".to_string(), "resolve_restore_sale".to_string())));
+    }
+
+    #[test]
+    fn resolve_purchase_credits_bids() {
+        let mut contract = new_market();
+        contract.escrow_deposit(&account("usdc"), 1030 + 500);
+        let payout = Payout {
+            payout: HashMap::from([(account("alice"), U128(1000)), (account("market"), U128(30))]),
+        };
+        callback_context(PromiseResult::Successful(
+            near_sdk::serde_json::to_vec(&payout).unwrap(),
+        ));
+        let fees = fee::Fees::new(HashMap::new(), &HashMap::new(), &account("market"), 300);
+        contract.resolve_purchase(account("usdc"), account("bob"), usdc_sale(), U128(1030), fees);
+        // The bid of carol waits for `claim`, the payout is sent with the refund callback
+        assert_eq!(
+            contract.get_claimable(account("carol")),
+            HashMap::from([(account("usdc"), U128(500))])
+        );
+        assert_eq!(
+            ft_transfers(),
+            vec![("usdc".to_string(), "alice".to_string(), "1000".to_string())]
+        );
+        let calls: Vec<String> = function_calls()
+            .into_iter()
+            .map(|(_, function_name, _)| function_name)
+            .collect();
+        assert_eq!(calls, vec!["ft_transfer", "resolve_refund"]);
+        assert_eq!(contract.get_protocol_fees()[&account("usdc")], U128(30));
+    }
+
+    #[test]
+    fn pay_out_credits_receivers_over_the_limit() {
+        let mut contract = new_market();
+        let payout = (0..MAX_LEN_PAYOUT + 2)
+            .map(|i| (account(&format!("r{}", i)), U128(100)))
+            .collect();
+        contract.internal_pay_out(&account("near"), payout);
+        assert_eq!(near_transfers().len(), MAX_LEN_PAYOUT as usize);
+        let credited: Vec<_> = (0..MAX_LEN_PAYOUT + 2)
+            .filter(|i| !contract.get_claimable(account(&format!("r{}", i))).is_empty())
+            .collect();
+        assert_eq!(credited.len(), 2);
+        assert_eq!(contract.get_escrow_summary().escrow[&account("near")], U128(200));
+    }
+
+    #[test]
+    fn resolve_restore_sale() {
+        let contract_and_token_id = format!("nft{}1:1", DELIMETER);
+        let approved = || PromiseResult::Successful(b"true".to_vec());
+
+        // The token isn't approved anymore
+        let mut contract = new_market();
+        contract.escrow_deposit(&account("usdc"), 500);
+        callback_context(PromiseResult::Successful(b"false".to_vec()));
+        contract.resolve_restore_sale(usdc_sale());
+        assert!(contract.market.sales.get(&contract_and_token_id).is_none());
+        // The bids are credited, the callback doesn't send a transfer per bid
+        assert_eq!(
+            contract.get_claimable(account("carol")),
+            HashMap::from([(account("usdc"), U128(500))])
+        );
+        assert!(ft_transfers().is_empty());
+
+        // The storage of the sale owner is withdrawn
+        let mut contract = new_market();
+        contract.escrow_deposit(&account("usdc"), 500);
+        callback_context(approved());
+        contract.resolve_restore_sale(usdc_sale());
+        assert!(contract.market.sales.get(&contract_and_token_id).is_none());
+        assert_eq!(contract.get_claimable(account("carol")).len(), 1);
+
+        let mut contract = new_market();
+        contract.escrow_deposit(&account("usdc"), 500);
+        with_storage(&mut contract, "alice");
+        callback_context(approved());
+        contract.resolve_restore_sale(usdc_sale());
+        let sale = contract.market.sales.get(&contract_and_token_id).unwrap();
+        assert_eq!(sale.bids[&account("usdc")][0].owner_id, account("carol"));
+        assert_eq!(contract.get_supply_by_owner_id(account("alice")), U64(1));
+        assert!(ft_transfers().is_empty());
+    }
+
+    #[test]
+    fn bids_by_bidder_follow_the_sale() {
+        let mut contract = new_market();
+        let mut sale = usdc_sale();
+        sale.bids
+            .get_mut(&account("usdc"))
+            .unwrap()
+            .push(bid("dave", 600));
+        contract.internal_insert_sale(&sale);
+        let bids = contract.get_bids_by_bidder(account("carol"), U64(0), 10);
+        assert_eq!(bids.len(), 1);
+        assert_eq!(bids[0].bid.price, U128(500));
+        assert!(!bids[0].leading);
+        assert!(contract.get_bids_by_bidder(account("dave"), U64(0), 10)[0].leading);
+
+        contract.internal_remove_bid(
+            account("nft"),
+            &account("usdc"),
+            "1:1".to_string(),
+            &account("dave"),
+            U128(600),
+        );
+        assert!(contract.get_bids_by_bidder(account("dave"), U64(0), 10).is_empty());
+        assert!(contract.get_bids_by_bidder(account("carol"), U64(0), 10)[0].leading);
+
+        contract.internal_remove_sale(account("nft"), "1:1".to_string());
+        assert!(contract.market.bids_by_bidder.get(&account("carol")).is_none());
+    }
+
+    #[test]
+    fn nft_on_revoke_credits_bids() {
+        let mut contract = new_market();
+        insert_usdc_sale(&mut contract);
+        context("nft", "nft", 0, 0, vec![]);
+        // Another owner or a revoke of another approval can't remove the sale
+        contract.nft_on_revoke("1:1".to_string(), account("bob"), 0);
+        contract.nft_on_revoke("1:1".to_string(), account("alice"), 1);
+        assert_eq!(contract.get_supply_sales(), U64(1));
+
+        contract.nft_on_revoke("1:1".to_string(), account("alice"), 0);
+        assert_eq!(contract.get_supply_sales(), U64(0));
+        assert_eq!(
+            contract.get_claimable(account("carol")),
+            HashMap::from([(account("usdc"), U128(500))])
+        );
+        assert!(function_calls().is_empty());
+    }
+
+    #[test]
+    fn resolve_prune_stale_listing() {
+        let token = |owner_id: &str, approval_id: u64| {
+            let token = Token {
+                token_id: "1:1".to_string(),
+                owner_id: account(owner_id),
+                metadata: None,
+                approved_account_ids: Some(HashMap::from([(account("market"), approval_id)])),
+            };
+            PromiseResult::Successful(near_sdk::serde_json::to_vec(&Some(token)).unwrap())
+        };
+        let mut contract = new_market();
+        insert_usdc_sale(&mut contract);
+
+        callback_context(token("alice", 0));
+        assert!(!contract.resolve_prune_stale_listing(account("nft"), "1:1".to_string()));
+        assert_eq!(contract.get_supply_sales(), U64(1));
+
+        // The market was approved again for another sale
+        callback_context(token("alice", 1));
+        assert!(contract.resolve_prune_stale_listing(account("nft"), "1:1".to_string()));
+        assert_eq!(contract.get_supply_sales(), U64(0));
+        assert_eq!(
+            contract.get_claimable(account("carol")),
+            HashMap::from([(account("usdc"), U128(500))])
+        );
+        assert!(ft_transfers().is_empty());
+
+        insert_usdc_sale(&mut contract);
+        callback_context(token("bob", 0));
+        assert!(contract.resolve_prune_stale_listing(account("nft"), "1:1".to_string()));
+        callback_context(PromiseResult::Successful(b"null".to_vec()));
+        assert!(!contract.resolve_prune_stale_listing(account("nft"), "1:1".to_string()));
+    }
+}
@@ -155,6 +155,7 @@ impl Market {
             start: sale.start.map(|s| s.into()),
             end: sale.end.map(|e| e.into()),
             origins: sale.origins,
+            protocol_fee: sale.protocol_fee,
         }
     }
 }
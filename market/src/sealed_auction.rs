@@ -0,0 +1,383 @@
+use near_sdk::json_types::Base64VecU8;
+
+use crate::auction::{Auction, AuctionJson, AuctionKind, MAX_DURATION, MIN_DURATION};
+use crate::bid::{Bid, BidListing, Origins};
+use crate::event::{AuctionCancelData, AuctionCommitData, AuctionRevealData, NearEvent};
+use crate::fee::calculate_price_with_fees;
+use crate::market_core::{AuctionArgs, SealedAuctionArgs};
+use crate::*;
+
+pub const SEALED_BIDS_MAX: usize = 50;
+
+// Committed bid of a sealed-bid auction
+// The deposit should cover the price with fees, the change is refunded after the auction
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SealedBid {
+    pub owner_id: AccountId,
+    // sha256 of "{owner_id}:{price}:{salt}", see `get_sealed_bid_hash`
+    pub hash: Base64VecU8,
+    pub deposit: U128,
+    pub origins: Origins,
+    // Set when the bid is revealed
+    pub price: Option<U128>,
+}
+
+pub(crate) fn sealed_bid_hash(owner_id: &AccountId, price: U128, salt: &str) -> Vec<u8> {
+    env::sha256(format!("{}:{}:{}", owner_id, price.0, salt).as_bytes())
+}
+
+#[near_bindgen]
+impl Market {
+    // Called in nft_on_approve to create a new sealed-bid auction
+    pub(crate) fn start_sealed_auction(
+        &mut self,
+        args: SealedAuctionArgs,
+        token_id: TokenId,
+        owner_id: AccountId,
+        approval_id: u64,
+        nft_contract_id: AccountId,
+    ) -> (u128, AuctionJson) {
+        require!(
+            args.reveal_duration.0 >= MIN_DURATION && args.reveal_duration.0 <= MAX_DURATION,
+            format!(
+                "Incorrect reveal duration. Should be at least {}",
+                MIN_DURATION
+            )
+        );
+        self.start_auction(
+            AuctionArgs {
+                token_type: args.token_type,
+                minimal_step: U128(0),
+                start_price: args.start_price,
+                start: args.start,
+                duration: args.duration,
+                buy_out_price: None,
+                reserve_price: None,
+                extension: None,
+                origins: args.origins,
+            },
+            AuctionKind::Sealed {
+                reveal_duration: args.reveal_duration,
+                second_price: args.second_price,
+                bids: Vec::new(),
+            },
+            token_id,
+            owner_id,
+            approval_id,
+            nft_contract_id,
+        )
+    }
+
+    // Commits a hidden bid to the sealed-bid auction, one bid per account
+    // The deposit should cover the price with fees and can't be less than the start price with fees
+    #[payable]
+    pub fn auction_commit_bid(
+        &mut self,
+        auction_id: U128,
+        hash: Base64VecU8,
+        token_type: TokenType,
+        origins: Option<Origins>,
+    ) {
+        let ft_token_id = self.token_type_to_ft_token_type(token_type);
+        require!(
+            ft_token_id.as_str() == "near",
+            "Fungible tokens should be bid with ft_transfer_call"
+        );
+        self.internal_auction_commit_bid(
+            auction_id,
+            ft_token_id,
+            env::predecessor_account_id(),
+            env::attached_deposit(),
+            hash,
+            origins,
+        );
+    }
+
+    // Reveals the committed bid, can be called after the auction end during the reveal phase
+    pub fn auction_reveal_bid(&mut self, auction_id: U128, price: U128, salt: String) {
+        let bidder_id = env::predecessor_account_id();
+        let mut auction = self
+            .market
+            .auctions
+            .get(&auction_id.into())
+            .unwrap_or_else(|| env::panic_str("Auction is not active"));
+        let (reveal_duration, bids) = match auction.kind {
+            AuctionKind::Sealed {
+                reveal_duration,
+                ref mut bids,
+                ..
+            } => (reveal_duration.0, bids),
+            _ => env::panic_str("Not a sealed-bid auction"),
+        };
+        let now = env::block_timestamp();
+        require!(
+            auction.end < now && now <= auction.end + reveal_duration,
+            "Bids can be revealed only during the reveal phase"
+        );
+        let bid = bids
+            .iter_mut()
+            .find(|bid| bid.owner_id == bidder_id)
+            .unwrap_or_else(|| env::panic_str("No such bid"));
+        require!(bid.price.is_none(), "The bid is already revealed");
+        require!(
+            bid.hash.0 == sealed_bid_hash(&bidder_id, price, &salt),
+            "The price and the salt don't match the committed hash"
+        );
+        require!(
+            price.0 >= auction.start_price,
+            format!("Should bid at least {}", auction.start_price)
+        );
+        let price_with_fees =
+            calculate_price_with_fees(price, Some(&bid.origins), auction.protocol_fee);
+        require!(
+            bid.deposit.0 >= price_with_fees,
+            format!("The deposit doesn't cover {}", price_with_fees)
+        );
+        bid.price = Some(price);
+        NearEvent::auction_reveal(AuctionRevealData {
+            auction_id,
+            owner_id: bidder_id,
+            price,
+        })
+        .emit();
+        self.market.auctions.insert(&auction_id.into(), &auction);
+    }
+
+    // Returns the hash which should be committed for the given price and salt
+    pub fn get_sealed_bid_hash(
+        &self,
+        owner_id: AccountId,
+        price: U128,
+        salt: String,
+    ) -> Base64VecU8 {
+        Base64VecU8(sealed_bid_hash(&owner_id, price, &salt))
+    }
+}
+
+impl Market {
+    pub(crate) fn internal_auction_commit_bid(
+        &mut self,
+        auction_id: U128,
+        ft_token_id: AccountId,
+        bidder_id: AccountId,
+        deposit: Balance,
+        hash: Base64VecU8,
+        origins: Option<Origins>,
+    ) {
+        require!(
+            self.market.ft_token_ids.contains(&ft_token_id),
+            "token not supported"
+        );
+        require!(
+            self.check_auction_in_progress(auction_id),
+            "Auction is not in progress"
+        );
+        let mut auction = self
+            .market
+            .auctions
+            .get(&auction_id.into())
+            .unwrap_or_else(|| env::panic_str("auction not active"));
+        require!(
+            auction.ft_token_id == ft_token_id,
+            format!("Auction accepts only {}", auction.ft_token_id)
+        );
+        require!(
+            auction.owner_id != bidder_id,
+            "Cannot bid on your own auction"
+        );
+        require!(hash.0.len() == 32, "Hash should be 32 bytes long");
+        let origins = origins.unwrap_or_default();
+        let min_deposit = calculate_price_with_fees(
+            auction.start_price.into(),
+            Some(&origins),
+            auction.protocol_fee,
+        );
+        require!(
+            deposit >= min_deposit,
+            format!("Should bid at least {}", min_deposit)
+        );
+        let bids = match auction.kind {
+            AuctionKind::Sealed { ref mut bids, .. } => bids,
+            _ => env::panic_str("Not a sealed-bid auction"),
+        };
+        require!(
+            bids.iter().all(|bid| bid.owner_id != bidder_id),
+            "The bid is already committed"
+        );
+        require!(bids.len() < SEALED_BIDS_MAX, "Too many bids");
+        self.escrow_deposit(&ft_token_id, deposit);
+        bids.push(SealedBid {
+            owner_id: bidder_id.clone(),
+            hash,
+            deposit: deposit.into(),
+            origins,
+            price: None,
+        });
+        self.internal_index_bid(&bidder_id, &BidListing::Auction { auction_id });
+        NearEvent::auction_commit(AuctionCommitData {
+            auction_id,
+            owner_id: bidder_id,
+            ft_token_id,
+            deposit: deposit.into(),
+        })
+        .emit();
+        self.market.auctions.insert(&auction_id.into(), &auction);
+    }
+
+    // Called in finish_auction after the reveal phase, the auction should be already removed
+    // Refunds all the deposits except the price paid by the winner,
+    // the auction without revealed bids is closed like a cancelled one
+    pub(crate) fn finish_sealed_auction(
+        &mut self,
+        auction_id: U128,
+        mut auction: Auction,
+    ) -> Option<Promise> {
+        let (reveal_duration, second_price, bids) = match auction.kind {
+            AuctionKind::Sealed {
+                reveal_duration,
+                second_price,
+                ref bids,
+            } => (reveal_duration.0, second_price, bids.clone()),
+            _ => env::panic_str("Not a sealed-bid auction"),
+        };
+        require!(
+            env::block_timestamp() > auction.end + reveal_duration,
+            "Auction can be finalized only after the reveal phase"
+        );
+        let winner = sealed_auction_winner(&bids, auction.start_price, second_price);
+        for (index, bid) in bids.into_iter().enumerate() {
+            let refund = match winner {
+                Some((winner_index, price)) if winner_index == index => {
+                    let price_with_fees = calculate_price_with_fees(
+                        price.into(),
+                        Some(&bid.origins),
+                        auction.protocol_fee,
+                    );
+                    auction.bid = Some(Bid {
+                        owner_id: bid.owner_id.clone(),
+                        price: price_with_fees.into(),
+                        start: env::block_timestamp().into(),
+                        end: None,
+                        origins: bid.origins,
+                    });
+                    bid.deposit.0 - price_with_fees
+                }
+                _ => bid.deposit.0,
+            };
+            if refund > 0 {
+                self.refund_bid(auction.ft_token_id.clone(), bid.owner_id, refund.into());
+            }
+        }
+        if winner.is_none() {
+            NearEvent::auction_cancel(AuctionCancelData { auction_id }).emit();
+            return None;
+        }
+        Some(self.settle_auction(auction_id, auction))
+    }
+}
+
+// Returns the index of the winning bid and the price (without fees) it pays
+// The earliest of equal bids wins, the second price can't be less than the start price
+pub(crate) fn sealed_auction_winner(
+    bids: &[SealedBid],
+    start_price: u128,
+    second_price: bool,
+) -> Option<(usize, u128)> {
+    let mut winner: Option<(usize, u128)> = None;
+    let mut runner_up = start_price;
+    for (index, bid) in bids.iter().enumerate() {
+        let price = match bid.price {
+            Some(price) => price.0,
+            None => continue,
+        };
+        match winner {
+            Some((_, best)) if price <= best => runner_up = runner_up.max(price),
+            _ => {
+                if let Some((_, best)) = winner {
+                    runner_up = runner_up.max(best);
+                }
+                winner = Some((index, price));
+            }
+        }
+    }
+    winner.map(|(index, price)| (index, if second_price { runner_up } else { price }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::test_utils::{account, auction, context, near_transfers, new_market};
+
+    fn sealed_bid(owner_id: &str, price: Option<u128>) -> SealedBid {
+        SealedBid {
+            owner_id: account(owner_id),
+            hash: Base64VecU8(vec![0; 32]),
+            deposit: U128(10_000),
+            origins: HashMap::new(),
+            price: price.map(U128),
+        }
+    }
+
+    #[test]
+    fn sealed_auction_first_price() {
+        let bids = vec![
+            sealed_bid("bob", Some(300)),
+            sealed_bid("carol", None),
+            sealed_bid("dave", Some(500)),
+            sealed_bid("eve", Some(500)),
+        ];
+        assert_eq!(sealed_auction_winner(&bids, 100, false), Some((2, 500)));
+    }
+
+    #[test]
+    fn sealed_auction_second_price() {
+        let bids = vec![
+            sealed_bid("bob", Some(300)),
+            sealed_bid("carol", Some(700)),
+            sealed_bid("dave", Some(500)),
+        ];
+        assert_eq!(sealed_auction_winner(&bids, 100, true), Some((1, 500)));
+        // A single bid pays the start price
+        assert_eq!(
+            sealed_auction_winner(&bids[1..2], 100, true),
+            Some((0, 100))
+        );
+        // Equal bids pay their price
+        let bids = vec![sealed_bid("bob", Some(300)), sealed_bid("carol", Some(300))];
+        assert_eq!(sealed_auction_winner(&bids, 100, true), Some((0, 300)));
+    }
+
+    #[test]
+    fn sealed_auction_without_reveals() {
+        let bids = vec![sealed_bid("bob", None)];
+        assert_eq!(sealed_auction_winner(&bids, 100, true), None);
+        assert_eq!(sealed_auction_winner(&[], 100, false), None);
+    }
+    #[test]
+    fn sealed_bids_by_bidder() {
+        let mut contract = new_market();
+        let mut sealed_auction = auction(None);
+        sealed_auction.kind = AuctionKind::Sealed {
+            reveal_duration: U64(100),
+            second_price: false,
+            bids: Vec::new(),
+        };
+        contract.internal_insert_auction(0, &sealed_auction);
+        context("bob", "bob", 2000, 10, vec![]);
+        let hash = Base64VecU8(sealed_bid_hash(&account("bob"), U128(1500), "salt"));
+        contract.auction_commit_bid(U128(0), hash, None, None);
+        let bids = contract.get_bids_by_bidder(account("bob"), U64(0), 10);
+        assert_eq!(bids.len(), 1);
+        assert_eq!(bids[0].bid.price, U128(2000));
+        assert!(!bids[0].leading);
+
+        // Without reveals the deposit is refunded
+        context("carol", "carol", 0, 1200, vec![]);
+        assert!(contract.finish_auction(U128(0)).is_none());
+        assert_eq!(near_transfers(), vec![("bob".to_string(), 2000)]);
+        assert!(contract.get_bids_by_bidder(account("bob"), U64(0), 10).is_empty());
+    }
+}
@@ -0,0 +1,486 @@
+use near_contract_standards::non_fungible_token::hash_account_id;
+use near_sdk::{promise_result_as_success, Gas};
+use std::cmp::min;
+use std::collections::HashMap;
+
+use crate::bid::Origins;
+use crate::claimable::GAS_FOR_NEAR_PAY_OUT;
+use crate::event::{NearEvent, PurchaseData, SeriesSaleRemoveData};
+use crate::fee::calculate_price_with_fees;
+use crate::sale::{
+    ext_contract, ext_self, parse_payout, SeriesSale, TokenSeriesId, DELIMETER, MAX_LEN_PAYOUT,
+    NO_DEPOSIT,
+};
+use crate::token::TokenSeriesSale;
+use crate::*;
+
+// Attached to `nft_mint` for the storage of the token, the rest is refunded to the buyer
+pub const STORAGE_FOR_MINT: Balance = 10_000_000_000_000_000_000_000;
+pub const GAS_FOR_MINT: Gas = Gas(20_000_000_000_000);
+pub const GAS_FOR_NFT_PAYOUT: Gas = Gas(10_000_000_000_000);
+pub const GAS_FOR_RESOLVE_TOKEN_BUY: Gas = Gas(10_000_000_000_000 + GAS_FOR_NEAR_PAY_OUT.0);
+pub const GAS_FOR_RESOLVE_MINT: Gas =
+    Gas(GAS_FOR_NFT_PAYOUT.0 + GAS_FOR_RESOLVE_TOKEN_BUY.0 + 10_000_000_000_000);
+// Gas required by `buy_series` for every copy
+pub const GAS_PER_COPY: Gas = Gas(GAS_FOR_MINT.0 + GAS_FOR_RESOLVE_MINT.0 + 5_000_000_000_000);
+
+#[near_bindgen]
+impl Market {
+    // Buys `copies` of the series, one by default
+    // The attached deposit should be equal to the price with fees plus `STORAGE_FOR_MINT`
+    // for every copy, each copy is minted for the buyer and paid out separately
+    #[payable]
+    pub fn buy_series(
+        &mut self,
+        nft_contract_id: AccountId,
+        series_id: TokenSeriesId,
+        copies: Option<u64>,
+        origins: Option<Origins>,
+    ) {
+        let copies = copies.unwrap_or(1);
+        require!(copies > 0, "Should buy at least one copy");
+        let buyer_id = env::predecessor_account_id();
+        let contract_and_series_id = format!("{}{}{}", nft_contract_id, DELIMETER, series_id);
+        let mut series_sale = self
+            .market
+            .series_sales
+            .get(&contract_and_series_id)
+            .expect("No series sale");
+        require!(
+            series_sale.owner_id != buyer_id,
+            "Cannot buy your own series sale"
+        );
+        require!(
+            series_sale.copies >= copies,
+            format!("Only {} copies left", series_sale.copies)
+        );
+        let near = AccountId::new_unchecked("near".to_owned());
+        let price = *series_sale
+            .sale_conditions
+            .get(&near)
+            .unwrap_or_else(|| env::panic_str("Not supported ft"));
+        let deposit = calculate_price_with_fees(price, origins.as_ref(), series_sale.protocol_fee);
+        let total = (deposit + STORAGE_FOR_MINT) * copies as u128;
+        require!(
+            env::attached_deposit() == total,
+            format!("Attached deposit should be {}", total)
+        );
+        require!(
+            env::prepaid_gas() - env::used_gas() >= Gas(GAS_PER_COPY.0 * copies),
+            "Not enough gas to mint the copies"
+        );
+
+        series_sale.copies -= copies;
+        if series_sale.copies == 0 {
+            self.internal_remove_series_sale(&nft_contract_id, &series_id);
+        } else {
+            self.market
+                .series_sales
+                .insert(&contract_and_series_id, &series_sale);
+        }
+        self.escrow_deposit(&near, deposit * copies as u128);
+
+        let origins = origins.unwrap_or_default();
+        for _ in 0..copies {
+            // Only the protocol fee is taken from the series owner
+            let fees = fee::Fees::new(
+                origins.clone(),
+                &Origins::new(),
+                &self.market.treasury_id,
+                series_sale.protocol_fee,
+            );
+            ext_contract::nft_mint(
+                series_id.clone(),
+                buyer_id.clone(),
+                Some(buyer_id.clone()),
+                nft_contract_id.clone(),
+                STORAGE_FOR_MINT,
+                GAS_FOR_MINT,
+            )
+            .then(ext_self::resolve_mint(
+                nft_contract_id.clone(),
+                series_sale.owner_id.clone(),
+                buyer_id.clone(),
+                U128(deposit),
+                fees,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_RESOLVE_MINT,
+            ));
+        }
+    }
+
+    // Removes the series sale, the copies which are being minted are still paid out
+    #[payable]
+    pub fn remove_series_sale(&mut self, nft_contract_id: AccountId, series_id: TokenSeriesId) {
+        assert_one_yocto();
+        let series_sale = self.internal_remove_series_sale(&nft_contract_id, &series_id);
+        require!(
+            env::predecessor_account_id() == series_sale.owner_id,
+            "Only the sale owner can remove the series sale"
+        );
+    }
+
+    pub fn get_series_sale(
+        &self,
+        nft_contract_id: AccountId,
+        series_id: TokenSeriesId,
+    ) -> Option<SeriesSale> {
+        let contract_and_series_id = format!("{}{}{}", nft_contract_id, DELIMETER, series_id);
+        self.market.series_sales.get(&contract_and_series_id)
+    }
+
+    pub fn get_series_sales_by_owner_id(
+        &self,
+        account_id: AccountId,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<SeriesSale> {
+        let by_owner_id = match self.market.series_sales_by_owner_id.get(&account_id) {
+            Some(by_owner_id) => by_owner_id,
+            None => return vec![],
+        };
+        let keys = by_owner_id.as_vector();
+        let start = from_index.map(u64::from).unwrap_or_default();
+        let end = min(start.saturating_add(limit.unwrap_or(u64::MAX)), keys.len());
+        (start..end)
+            .filter_map(|i| self.market.series_sales.get(&keys.get(i).unwrap()))
+            .collect()
+    }
+
+    // self callback
+    // If the copy is minted - requests the royalties for the payout in `resolve_token_buy`
+    // If failed - refunds the price and the storage deposit, the copy isn't put back on sale
+    #[private]
+    pub fn resolve_mint(
+        &mut self,
+        nft_contract_id: AccountId,
+        owner_id: AccountId,
+        buyer_id: AccountId,
+        deposit: U128,
+        fees: fee::Fees,
+    ) {
+        let token_id = promise_result_as_success()
+            .and_then(|value| near_sdk::serde_json::from_slice::<TokenId>(&value).ok());
+        let token_id = match token_id {
+            Some(token_id) => token_id,
+            None => {
+                let near = AccountId::new_unchecked("near".to_owned());
+                self.escrow_release(&near, deposit.0);
+                // The storage deposit of the failed mint is returned to the market
+                self.internal_send(near, buyer_id, U128(deposit.0 + STORAGE_FOR_MINT));
+                return;
+            }
+        };
+        let price = fees.payout(deposit.0, &owner_id)[&owner_id];
+        ext_contract::nft_payout(
+            token_id.clone(),
+            price,
+            MAX_LEN_PAYOUT,
+            nft_contract_id.clone(),
+            NO_DEPOSIT,
+            GAS_FOR_NFT_PAYOUT,
+        )
+        .then(ext_self::resolve_token_buy(
+            nft_contract_id,
+            token_id,
+            owner_id,
+            buyer_id,
+            deposit,
+            fees,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_RESOLVE_TOKEN_BUY,
+        ));
+    }
+
+    // self callback
+    // Pays out the minted copy: the fees are taken from the deposit, the share of the series
+    // owner is split by the royalties of the series
+    // Without a valid payout the whole share goes to the series owner
+    #[private]
+    pub fn resolve_token_buy(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        owner_id: AccountId,
+        buyer_id: AccountId,
+        deposit: U128,
+        fees: fee::Fees,
+    ) {
+        let near = AccountId::new_unchecked("near".to_owned());
+        self.escrow_release(&near, deposit.0);
+        let mut payout = fees.payout(deposit.0, &owner_id);
+        let price = payout.remove(&owner_id).unwrap_or(U128(0));
+        let royalties = promise_result_as_success()
+            .and_then(|value| parse_payout(&value, price.0))
+            .map_or_else(
+                || {
+                    env::log_str("Invalid payout, paying the rest to the owner");
+                    HashMap::from([(owner_id.clone(), price)])
+                },
+                |payout| payout.payout,
+            );
+        for (receiver_id, amount) in royalties {
+            // The buyer already owns the minted token, its share belongs to the series owner
+            let receiver_id = if receiver_id == buyer_id {
+                owner_id.clone()
+            } else {
+                receiver_id
+            };
+            payout.entry(receiver_id).or_insert(U128(0)).0 += amount.0;
+        }
+        NearEvent::purchase(PurchaseData {
+            auction_id: None,
+            owner_id,
+            nft_contract_id,
+            token_id,
+            buyer_id,
+            ft_token_id: near.clone(),
+            price: deposit,
+            payout: payout.clone(),
+        })
+        .emit();
+        self.internal_pay_out(&near, payout);
+    }
+}
+
+impl Market {
+    // Lists the copies of the series approved by its owner
+    pub(crate) fn start_series_sale(
+        &mut self,
+        token_series: TokenSeriesSale,
+        nft_contract_id: AccountId,
+    ) -> SeriesSale {
+        let TokenSeriesSale {
+            sale_conditions,
+            series_id,
+            owner_id,
+            copies,
+        } = token_series;
+        require!(copies > 0, "Should list at least one copy");
+        // The market attaches the storage deposit of the mint, so the copies are sold for NEAR
+        let near = AccountId::new_unchecked("near".to_owned());
+        require!(
+            sale_conditions.len() == 1 && sale_conditions.contains_key(&near),
+            "Series copies can only be sold for NEAR"
+        );
+        // Listing the series again replaces its sale
+        let contract_and_series_id = format!("{}{}{}", nft_contract_id, DELIMETER, series_id);
+        if self
+            .market
+            .series_sales
+            .get(&contract_and_series_id)
+            .is_some()
+        {
+            self.internal_remove_series_sale(&nft_contract_id, &series_id);
+        }
+        require!(
+            self.storage_covers_new_sale(&owner_id, &owner_id),
+            "User has more sales than storage paid"
+        );
+
+        let series_sale = SeriesSale {
+            owner_id: owner_id.clone(),
+            nft_contract_id,
+            series_id,
+            sale_conditions,
+            created_at: env::block_timestamp().into(),
+            copies,
+            protocol_fee: self.market.protocol_fee,
+        };
+        self.market
+            .series_sales
+            .insert(&contract_and_series_id, &series_sale);
+        let mut by_owner_id = self
+            .market
+            .series_sales_by_owner_id
+            .get(&owner_id)
+            .unwrap_or_else(|| {
+                UnorderedSet::new(StorageKey::SeriesSalesByOwnerIdInner {
+                    account_id_hash: hash_account_id(&owner_id),
+                })
+            });
+        by_owner_id.insert(&contract_and_series_id);
+        self.market
+            .series_sales_by_owner_id
+            .insert(&owner_id, &by_owner_id);
+        series_sale
+    }
+
+    pub(crate) fn internal_remove_series_sale(
+        &mut self,
+        nft_contract_id: &AccountId,
+        series_id: &TokenSeriesId,
+    ) -> SeriesSale {
+        let contract_and_series_id = format!("{}{}{}", nft_contract_id, DELIMETER, series_id);
+        let series_sale = self
+            .market
+            .series_sales
+            .remove(&contract_and_series_id)
+            .expect("No series sale");
+        let mut by_owner_id = self
+            .market
+            .series_sales_by_owner_id
+            .get(&series_sale.owner_id)
+            .expect("No series sale by_owner_id");
+        by_owner_id.remove(&contract_and_series_id);
+        if by_owner_id.is_empty() {
+            self.market
+                .series_sales_by_owner_id
+                .remove(&series_sale.owner_id);
+        } else {
+            self.market
+                .series_sales_by_owner_id
+                .insert(&series_sale.owner_id, &by_owner_id);
+        }
+        NearEvent::series_sale_remove(SeriesSaleRemoveData {
+            owner_id: series_sale.owner_id.clone(),
+            nft_contract_id: series_sale.nft_contract_id.clone(),
+            series_id: series_sale.series_id.clone(),
+        })
+        .emit();
+        series_sale
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::PromiseResult;
+
+    use super::*;
+    use crate::market_core::NonFungibleTokenApprovalReceiver;
+    use crate::sale::Payout;
+    use crate::test_utils::{
+        account, callback_context, context, function_calls, near_transfers, new_market,
+        with_storage,
+    };
+
+    // Market with 3 copies of the series "1" of alice for 1000 yoctoNEAR
+    fn market() -> Market {
+        let mut contract = new_market();
+        with_storage(&mut contract, "alice");
+        context("nft", "alice", 0, 0, vec![]);
+        contract.nft_on_series_approve(TokenSeriesSale {
+            sale_conditions: HashMap::from([(account("near"), U128(1000))]),
+            series_id: "1".to_string(),
+            owner_id: account("alice"),
+            copies: 3,
+        });
+        contract
+    }
+
+    // Price with the protocol fee and the storage deposit of the mint
+    fn deposit(copies: u128) -> Balance {
+        (1030 + STORAGE_FOR_MINT) * copies
+    }
+
+    fn created_calls() -> Vec<String> {
+        function_calls()
+            .into_iter()
+            .map(|(_, function_name, _)| function_name)
+            .collect()
+    }
+
+    fn fees() -> fee::Fees {
+        fee::Fees::new(Origins::new(), &Origins::new(), &account("market"), 300)
+    }
+
+    #[test]
+    fn buy_copies() {
+        let mut contract = market();
+        context("bob", "bob", deposit(2), 0, vec![]);
+        contract.buy_series(account("nft"), "1".to_string(), Some(2), None);
+        let series_sale = contract
+            .get_series_sale(account("nft"), "1".to_string())
+            .unwrap();
+        assert_eq!(series_sale.copies, 1);
+        assert_eq!(
+            created_calls(),
+            vec!["nft_mint", "resolve_mint", "nft_mint", "resolve_mint"]
+        );
+        assert_eq!(
+            contract.get_escrow_summary().escrow[&account("near")],
+            U128(2060)
+        );
+
+        context("carol", "carol", deposit(1), 0, vec![]);
+        contract.buy_series(account("nft"), "1".to_string(), None, None);
+        assert!(contract
+            .get_series_sale(account("nft"), "1".to_string())
+            .is_none());
+        assert!(contract
+            .get_series_sales_by_owner_id(account("alice"), None, None)
+            .is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Only 3 copies left")]
+    fn buy_too_many_copies() {
+        let mut contract = market();
+        context("bob", "bob", deposit(4), 0, vec![]);
+        contract.buy_series(account("nft"), "1".to_string(), Some(4), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "User has more sales than storage paid")]
+    fn series_sale_takes_storage() {
+        let mut contract = market();
+        context("nft", "alice", 0, 0, vec![]);
+        contract.nft_on_series_approve(TokenSeriesSale {
+            sale_conditions: HashMap::from([(account("near"), U128(1000))]),
+            series_id: "2".to_string(),
+            owner_id: account("alice"),
+            copies: 1,
+        });
+    }
+
+    #[test]
+    fn failed_mint_refunds() {
+        let mut contract = market();
+        // Taken by `buy_series`
+        contract.escrow_deposit(&account("near"), 1030);
+        callback_context(PromiseResult::Failed);
+        contract.resolve_mint(
+            account("nft"),
+            account("alice"),
+            account("bob"),
+            U128(1030),
+            fees(),
+        );
+        assert_eq!(
+            near_transfers(),
+            vec![("bob".to_string(), 1030 + STORAGE_FOR_MINT)]
+        );
+    }
+
+    #[test]
+    fn token_buy_pays_the_series_owner() {
+        let mut contract = market();
+        // Taken by `buy_series`
+        contract.escrow_deposit(&account("near"), 1030);
+        // 10% royalty of carol, the rest is the share of bob as the owner of the minted token
+        let payout = Payout {
+            payout: HashMap::from([
+                (account("carol"), U128(97)),
+                (account("bob"), U128(873)),
+            ]),
+        };
+        callback_context(PromiseResult::Successful(
+            near_sdk::serde_json::to_vec(&payout).unwrap(),
+        ));
+        contract.resolve_token_buy(
+            account("nft"),
+            "1:1".to_string(),
+            account("alice"),
+            account("bob"),
+            U128(1030),
+            fees(),
+        );
+        assert_eq!(
+            near_transfers(),
+            vec![("alice".to_string(), 873), ("carol".to_string(), 97)]
+        );
+        assert_eq!(contract.get_protocol_fees()[&account("near")], U128(60));
+    }
+}
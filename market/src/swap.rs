@@ -0,0 +1,620 @@
+use near_contract_standards::non_fungible_token::hash_account_id;
+use near_sdk::{Gas, PromiseResult};
+use std::cmp::min;
+use std::collections::HashSet;
+
+use crate::bundle::{market_approval_id, BundleToken};
+use crate::claimable::{
+    ClaimableToken, GAS_FOR_MARKET_NFT_TRANSFER, GAS_FOR_RESOLVE_NFT_TRANSFERS,
+};
+use crate::event::{NearEvent, SwapData, SwapOfferRemoveData};
+use crate::market_core::SwapOfferArgs;
+use crate::sale::{
+    ext_contract, ext_self, FungibleTokenId, DELIMETER, GAS_FOR_NFT_ON_REVOKE, GAS_FOR_NFT_TOKEN,
+    NO_DEPOSIT, REVOKE_NOTIFICATIONS_PER_TRANSFER,
+};
+use crate::*;
+
+// The wanted token and the offered tokens are transferred in one swap
+pub const MAX_SWAP_TOKENS: usize = 3;
+pub const GAS_FOR_RESOLVE_CREATE_SWAP_OFFER: Gas = Gas(20_000_000_000_000);
+// Transfer of an approved token of the swap to the market with the revoke notifications
+pub const GAS_FOR_SWAP_NFT_TRANSFER: Gas =
+    Gas(15_000_000_000_000 + GAS_FOR_NFT_ON_REVOKE.0 * REVOKE_NOTIFICATIONS_PER_TRANSFER);
+// Forwards every token of the swap to its new owner or back to the previous one
+pub const GAS_FOR_RESOLVE_SWAP: Gas = Gas(25_000_000_000_000
+    + GAS_FOR_MARKET_NFT_TRANSFER.0 * (MAX_SWAP_TOKENS as u64 + 1)
+    + GAS_FOR_RESOLVE_NFT_TRANSFERS.0);
+
+// Approved tokens and an optional top-up offered in exchange for one token
+// The top-up is escrowed until the offer is accepted or removed
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapOffer {
+    pub owner_id: AccountId,
+    pub tokens: Vec<BundleToken>,
+    // The wanted token
+    pub nft_contract_id: AccountId,
+    pub token_id: TokenId,
+    pub ft_token_id: FungibleTokenId,
+    pub top_up: U128,
+    pub created_at: U64,
+
+    pub start: U64,
+    pub end: Option<U64>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapOfferJson {
+    pub swap_id: U128,
+    #[serde(flatten)]
+    pub offer: SwapOffer,
+}
+
+impl SwapOffer {
+    pub fn in_limits(&self) -> bool {
+        let now = env::block_timestamp();
+        self.start.0 < now && !matches!(self.end, Some(end) if now >= end.0)
+    }
+}
+
+#[near_bindgen]
+impl Market {
+    // Offers the approved tokens and the attached deposit as the top-up for the token
+    // The owner of the token accepts the offer with `nft_approve`, the storage is taken
+    // like for one sale
+    // Returns the id of the offer
+    #[payable]
+    pub fn swap_offer(&mut self, args: SwapOfferArgs) -> Promise {
+        self.internal_swap_offer(
+            args,
+            AccountId::new_unchecked("near".to_owned()),
+            env::predecessor_account_id(),
+            env::attached_deposit(),
+        )
+    }
+
+    // Removes the offer and refunds the top-up
+    // Until the offer has ended, only its owner can remove it
+    #[payable]
+    pub fn remove_swap_offer(&mut self, swap_id: U128) {
+        assert_one_yocto();
+        let offer = self.internal_remove_swap_offer(swap_id.0);
+        let has_ended = matches!(offer.end, Some(end) if env::block_timestamp() >= end.0);
+        require!(
+            has_ended || env::predecessor_account_id() == offer.owner_id,
+            "Until the offer has ended, it can only be removed by its owner"
+        );
+        self.refund_swap_top_up(offer.ft_token_id, offer.owner_id, offer.top_up);
+    }
+
+    pub fn get_swap_offer(&self, swap_id: U128) -> Option<SwapOfferJson> {
+        self.market
+            .swap_offers
+            .get(&swap_id.0)
+            .map(|offer| SwapOfferJson { swap_id, offer })
+    }
+
+    // Returns the offers for the token
+    pub fn get_swap_offers_for_token(
+        &self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+    ) -> Vec<SwapOfferJson> {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        match self.market.swap_offers_by_token.get(&contract_and_token_id) {
+            Some(swap_ids) => swap_ids
+                .iter()
+                .filter_map(|swap_id| self.get_swap_offer(swap_id.into()))
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    pub fn get_swap_offers_by_owner_id(
+        &self,
+        account_id: AccountId,
+        from_index: Option<U64>,
+        limit: Option<u64>,
+    ) -> Vec<SwapOfferJson> {
+        let by_owner_id = match self.market.swap_offers_by_owner_id.get(&account_id) {
+            Some(by_owner_id) => by_owner_id,
+            None => return vec![],
+        };
+        let ids = by_owner_id.as_vector();
+        let start = from_index.map(u64::from).unwrap_or_default();
+        let end = min(start.saturating_add(limit.unwrap_or(u64::MAX)), ids.len());
+        (start..end)
+            .filter_map(|i| self.get_swap_offer(ids.get(i).unwrap().into()))
+            .collect()
+    }
+
+    // self callback
+    // Creates the offer if every offered token belongs to the owner and is approved for the market
+    // Otherwise the top-up is refunded and `None` is returned
+    #[private]
+    pub fn resolve_create_swap_offer(
+        &mut self,
+        owner_id: AccountId,
+        args: SwapOfferArgs,
+        ft_token_id: FungibleTokenId,
+        top_up: U128,
+    ) -> Option<U128> {
+        let SwapOfferArgs {
+            tokens,
+            nft_contract_id,
+            token_id,
+            start,
+            duration,
+        } = args;
+        let mut offered = Vec::with_capacity(tokens.len());
+        for (i, token) in tokens.into_iter().enumerate() {
+            match market_approval_id(i as u64, &owner_id) {
+                Some(approval_id) => offered.push(BundleToken {
+                    nft_contract_id: token.nft_contract_id,
+                    token_id: token.token_id,
+                    approval_id,
+                }),
+                None => {
+                    env::log_str(&format!(
+                        "Token {} of {} is not approved for the market by the owner",
+                        token.token_id, token.nft_contract_id
+                    ));
+                    self.refund_swap_top_up(ft_token_id, owner_id, top_up);
+                    return None;
+                }
+            }
+        }
+        // The storage could have been withdrawn while the tokens were requested
+        if !self.storage_covers_new_sale(&owner_id, &owner_id) {
+            env::log_str("User has more sales than storage paid");
+            self.refund_swap_top_up(ft_token_id, owner_id, top_up);
+            return None;
+        }
+        let start = start.unwrap_or_else(|| env::block_timestamp().into());
+        let offer = SwapOffer {
+            owner_id,
+            tokens: offered,
+            nft_contract_id,
+            token_id,
+            ft_token_id,
+            top_up,
+            created_at: env::block_timestamp().into(),
+            start,
+            end: duration.map(|duration| U64(start.0 + duration.0)),
+        };
+        let swap_id = self.market.next_swap_offer_id;
+        self.market.next_swap_offer_id += 1;
+        self.internal_insert_swap_offer(swap_id, &offer);
+        NearEvent::swap_offer_create(SwapOfferJson {
+            swap_id: swap_id.into(),
+            offer,
+        })
+        .emit();
+        Some(swap_id.into())
+    }
+
+    // self callback
+    // Every token of the swap is transferred to the market first: if all of them are transferred,
+    // the wanted token is sent to the owner of the offer, the offered tokens and the top-up
+    // to `receiver_id`
+    // Otherwise the transferred tokens are returned and the top-up is refunded
+    // A token which fails to be sent from the market can be claimed, see `claim_token`
+    #[private]
+    pub fn resolve_swap(&mut self, swap_id: U128, offer: SwapOffer, receiver_id: AccountId) {
+        let transferred: Vec<bool> = (0..=offer.tokens.len())
+            .map(|i| matches!(env::promise_result(i as u64), PromiseResult::Successful(_)))
+            .collect();
+        let swapped = transferred.iter().all(|&transferred| transferred);
+        // The wanted token of `receiver_id` goes first
+        let wanted = (
+            &offer.nft_contract_id,
+            &offer.token_id,
+            if swapped { &offer.owner_id } else { &receiver_id },
+        );
+        let offered = offer.tokens.iter().map(|token| {
+            (
+                &token.nft_contract_id,
+                &token.token_id,
+                if swapped { &receiver_id } else { &offer.owner_id },
+            )
+        });
+        let transfers = std::iter::once(wanted)
+            .chain(offered)
+            .zip(transferred)
+            .filter(|(_, transferred)| *transferred)
+            .map(|((nft_contract_id, token_id, new_owner_id), _)| {
+                let token = ClaimableToken {
+                    nft_contract_id: nft_contract_id.clone(),
+                    token_id: token_id.clone(),
+                };
+                (new_owner_id.clone(), token)
+            })
+            .collect();
+        self.internal_send_tokens(transfers);
+        let data = SwapData {
+            swap_id,
+            owner_id: offer.owner_id.clone(),
+            receiver_id: receiver_id.clone(),
+            nft_contract_id: offer.nft_contract_id,
+            token_id: offer.token_id,
+            ft_token_id: offer.ft_token_id.clone(),
+            top_up: offer.top_up,
+        };
+        if swapped {
+            NearEvent::swap(data).emit();
+            self.refund_swap_top_up(offer.ft_token_id, receiver_id, offer.top_up);
+        } else {
+            NearEvent::swap_fail(data).emit();
+            self.refund_swap_top_up(offer.ft_token_id, offer.owner_id, offer.top_up);
+        }
+    }
+}
+
+impl Market {
+    pub(crate) fn internal_swap_offer(
+        &mut self,
+        args: SwapOfferArgs,
+        ft_token_id: FungibleTokenId,
+        owner_id: AccountId,
+        top_up: Balance,
+    ) -> Promise {
+        require!(
+            !args.tokens.is_empty() && args.tokens.len() <= MAX_SWAP_TOKENS,
+            format!("A swap offer should have from 1 to {} tokens", MAX_SWAP_TOKENS)
+        );
+        let mut unique = HashSet::new();
+        for (nft_contract_id, token_id) in args
+            .tokens
+            .iter()
+            .map(|token| (&token.nft_contract_id, &token.token_id))
+            .chain(std::iter::once((&args.nft_contract_id, &args.token_id)))
+        {
+            require!(
+                unique.insert((nft_contract_id, token_id)),
+                "The tokens of the swap should be different"
+            );
+            require!(
+                self.non_fungible_token_account_ids
+                    .contains(nft_contract_id),
+                format!(
+                    "NFT contract {} is not supported by this market",
+                    nft_contract_id
+                )
+            );
+        }
+        require!(
+            self.market.ft_token_ids.contains(&ft_token_id),
+            format!("Token {} not supported by this market", ft_token_id)
+        );
+        require!(
+            self.storage_covers_new_sale(&owner_id, &owner_id),
+            "User has more sales than storage paid"
+        );
+        // The top-up is refunded by `resolve_create_swap_offer` if the offer is not created
+        self.escrow_deposit(&ft_token_id, top_up);
+        args.tokens
+            .iter()
+            .map(|token| {
+                ext_contract::nft_token(
+                    token.token_id.clone(),
+                    token.nft_contract_id.clone(),
+                    NO_DEPOSIT,
+                    GAS_FOR_NFT_TOKEN,
+                )
+            })
+            .reduce(Promise::and)
+            .unwrap()
+            .then(ext_self::resolve_create_swap_offer(
+                owner_id,
+                args,
+                ft_token_id,
+                U128(top_up),
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_RESOLVE_CREATE_SWAP_OFFER,
+            ))
+    }
+
+    // Called in nft_on_approve to swap the token for the offer `swap_id`
+    pub(crate) fn accept_swap_offer(
+        &mut self,
+        swap_id: U128,
+        token_id: TokenId,
+        owner_id: AccountId,
+        approval_id: u64,
+        nft_contract_id: AccountId,
+    ) -> Promise {
+        let offer = self.internal_remove_swap_offer(swap_id.0);
+        require!(
+            offer.nft_contract_id == nft_contract_id && offer.token_id == token_id,
+            "The offer doesn't match the token"
+        );
+        require!(offer.in_limits(), "Out of time limit of the offer");
+        require!(offer.owner_id != owner_id, "Cannot accept your own offer");
+        std::iter::once((&nft_contract_id, &token_id, approval_id))
+            .chain(
+                offer
+                    .tokens
+                    .iter()
+                    .map(|token| (&token.nft_contract_id, &token.token_id, token.approval_id)),
+            )
+            .map(|(nft_contract_id, token_id, approval_id)| {
+                ext_contract::nft_transfer(
+                    env::current_account_id(),
+                    token_id.clone(),
+                    Some(approval_id),
+                    None,
+                    nft_contract_id.clone(),
+                    1,
+                    GAS_FOR_SWAP_NFT_TRANSFER,
+                )
+            })
+            .reduce(Promise::and)
+            .unwrap()
+            .then(ext_self::resolve_swap(
+                swap_id,
+                offer,
+                owner_id,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_RESOLVE_SWAP,
+            ))
+    }
+
+    fn refund_swap_top_up(
+        &mut self,
+        ft_token_id: FungibleTokenId,
+        receiver_id: AccountId,
+        top_up: U128,
+    ) {
+        if top_up.0 > 0 {
+            self.refund_bid(ft_token_id, receiver_id, top_up);
+        }
+    }
+
+    pub(crate) fn internal_insert_swap_offer(&mut self, swap_id: u128, offer: &SwapOffer) {
+        self.market.swap_offers.insert(&swap_id, offer);
+        let mut by_owner_id = self
+            .market
+            .swap_offers_by_owner_id
+            .get(&offer.owner_id)
+            .unwrap_or_else(|| {
+                UnorderedSet::new(StorageKey::SwapOffersByOwnerIdInner {
+                    account_id_hash: hash_account_id(&offer.owner_id),
+                })
+            });
+        by_owner_id.insert(&swap_id);
+        self.market
+            .swap_offers_by_owner_id
+            .insert(&offer.owner_id, &by_owner_id);
+
+        let contract_and_token_id =
+            format!("{}{}{}", offer.nft_contract_id, DELIMETER, offer.token_id);
+        let mut by_token = self
+            .market
+            .swap_offers_by_token
+            .get(&contract_and_token_id)
+            .unwrap_or_else(|| {
+                // The token id is not a valid account id to use `hash_account_id`
+                let mut token_hash = CryptoHash::default();
+                token_hash.copy_from_slice(&env::sha256(contract_and_token_id.as_bytes()));
+                UnorderedSet::new(StorageKey::SwapOffersByTokenInner { token_hash })
+            });
+        by_token.insert(&swap_id);
+        self.market
+            .swap_offers_by_token
+            .insert(&contract_and_token_id, &by_token);
+    }
+
+    pub(crate) fn internal_remove_swap_offer(&mut self, swap_id: u128) -> SwapOffer {
+        let offer = self
+            .market
+            .swap_offers
+            .remove(&swap_id)
+            .unwrap_or_else(|| env::panic_str("No swap offer"));
+        let mut by_owner_id = self
+            .market
+            .swap_offers_by_owner_id
+            .get(&offer.owner_id)
+            .expect("No swap offer by_owner_id");
+        by_owner_id.remove(&swap_id);
+        if by_owner_id.is_empty() {
+            self.market.swap_offers_by_owner_id.remove(&offer.owner_id);
+        } else {
+            self.market
+                .swap_offers_by_owner_id
+                .insert(&offer.owner_id, &by_owner_id);
+        }
+
+        let contract_and_token_id =
+            format!("{}{}{}", offer.nft_contract_id, DELIMETER, offer.token_id);
+        let mut by_token = self
+            .market
+            .swap_offers_by_token
+            .get(&contract_and_token_id)
+            .expect("No swap offer by_token");
+        by_token.remove(&swap_id);
+        if by_token.is_empty() {
+            self.market.swap_offers_by_token.remove(&contract_and_token_id);
+        } else {
+            self.market
+                .swap_offers_by_token
+                .insert(&contract_and_token_id, &by_token);
+        }
+        NearEvent::swap_offer_remove(SwapOfferRemoveData {
+            swap_id: swap_id.into(),
+            owner_id: offer.owner_id.clone(),
+        })
+        .emit();
+        offer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::market_core::{AcceptSwapArgs, BundleTokenArgs, NonFungibleTokenApprovalReceiver};
+    use crate::test_utils::{
+        account, context, function_calls, near_transfers, new_market, nft_transfers, token,
+        with_storage,
+    };
+
+    fn args() -> SwapOfferArgs {
+        SwapOfferArgs {
+            tokens: ["1:1", "1:2"]
+                .iter()
+                .map(|token_id| BundleTokenArgs {
+                    nft_contract_id: account("nft"),
+                    token_id: token_id.to_string(),
+                })
+                .collect(),
+            nft_contract_id: account("nft"),
+            token_id: "2:1".to_string(),
+            start: None,
+            duration: None,
+        }
+    }
+
+    // Market where alice offers "1:1" and "1:2" of `results` with 100 yoctoNEAR for "2:1" of bob
+    fn market(results: Vec<PromiseResult>) -> (Market, Option<U128>) {
+        let mut contract = new_market();
+        with_storage(&mut contract, "alice");
+        context("alice", "alice", 100, 10, vec![]);
+        contract.swap_offer(args());
+        context("market", "market", 0, 10, results);
+        let swap_id = contract.resolve_create_swap_offer(
+            account("alice"),
+            args(),
+            account("near"),
+            U128(100),
+        );
+        (contract, swap_id)
+    }
+
+    fn accept_msg(swap_id: u128) -> String {
+        near_sdk::serde_json::to_string(&ArgsKind::Swap(AcceptSwapArgs {
+            swap_id: U128(swap_id),
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn create_swap_offer() {
+        let (contract, swap_id) = market(vec![token("1:1", true), token("1:2", true)]);
+        assert_eq!(swap_id, Some(U128(0)));
+        let offers = contract.get_swap_offers_for_token(account("nft"), "2:1".to_string());
+        assert_eq!(offers.len(), 1);
+        assert_eq!(offers[0].offer.tokens[1].approval_id, 3);
+        assert_eq!(
+            contract.get_swap_offers_by_owner_id(account("alice"), None, None).len(),
+            1
+        );
+        assert_eq!(
+            contract.get_escrow_summary().escrow[&account("near")],
+            U128(100)
+        );
+    }
+
+    #[test]
+    fn create_swap_offer_of_unapproved_token() {
+        let (contract, swap_id) = market(vec![token("1:1", true), token("1:2", false)]);
+        assert!(swap_id.is_none());
+        assert!(contract.get_swap_offer(U128(0)).is_none());
+        assert_eq!(near_transfers(), vec![("alice".to_string(), 100)]);
+        assert!(contract.get_escrow_summary().escrow.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "The offer doesn't match the token")]
+    fn accept_swap_offer_for_other_token() {
+        let (mut contract, _) = market(vec![token("1:1", true), token("1:2", true)]);
+        context("nft", "bob", 0, 20, vec![]);
+        contract.nft_on_approve("2:2".to_string(), account("bob"), 1, accept_msg(0));
+    }
+
+    #[test]
+    fn accept_swap_offer_transfers_tokens_to_market() {
+        let (mut contract, _) = market(vec![token("1:1", true), token("1:2", true)]);
+        context("nft", "bob", 0, 20, vec![]);
+        contract.nft_on_approve("2:1".to_string(), account("bob"), 1, accept_msg(0));
+        assert_eq!(
+            nft_transfers(),
+            vec![
+                ("market".to_string(), "2:1".to_string()),
+                ("market".to_string(), "1:1".to_string()),
+                ("market".to_string(), "1:2".to_string())
+            ]
+        );
+        assert!(contract.get_swap_offer(U128(0)).is_none());
+    }
+
+    #[test]
+    fn resolve_swap_exchanges_tokens() {
+        let (mut contract, _) = market(vec![token("1:1", true), token("1:2", true)]);
+        let offer = contract.internal_remove_swap_offer(0);
+        let results = (0..3).map(|_| PromiseResult::Successful(vec![])).collect();
+        context("market", "market", 0, 20, results);
+        contract.resolve_swap(U128(0), offer, account("bob"));
+        assert_eq!(
+            nft_transfers(),
+            vec![
+                ("alice".to_string(), "2:1".to_string()),
+                ("bob".to_string(), "1:1".to_string()),
+                ("bob".to_string(), "1:2".to_string())
+            ]
+        );
+        assert_eq!(near_transfers(), vec![("bob".to_string(), 100)]);
+        assert!(contract.get_escrow_summary().escrow.is_empty());
+    }
+
+    #[test]
+    fn resolve_swap_rolls_back() {
+        let (mut contract, _) = market(vec![token("1:1", true), token("1:2", true)]);
+        let offer = contract.internal_remove_swap_offer(0);
+        let results = vec![
+            PromiseResult::Successful(vec![]),
+            PromiseResult::Failed,
+            PromiseResult::Successful(vec![]),
+        ];
+        context("market", "market", 0, 20, results);
+        contract.resolve_swap(U128(0), offer, account("bob"));
+        assert_eq!(
+            nft_transfers(),
+            vec![
+                ("bob".to_string(), "2:1".to_string()),
+                ("alice".to_string(), "1:2".to_string())
+            ]
+        );
+        assert_eq!(near_transfers(), vec![("alice".to_string(), 100)]);
+    }
+    #[test]
+    fn resolve_swap_keeps_stranded_token() {
+        let (mut contract, _) = market(vec![token("1:1", true), token("1:2", true)]);
+        let offer = contract.internal_remove_swap_offer(0);
+        let results = (0..3).map(|_| PromiseResult::Successful(vec![])).collect();
+        context("market", "market", 0, 20, results);
+        contract.resolve_swap(U128(0), offer, account("bob"));
+        let (_, _, args) = function_calls()
+            .into_iter()
+            .find(|(_, function_name, _)| function_name == "resolve_nft_transfers")
+            .unwrap();
+        let transfers: Vec<(AccountId, ClaimableToken)> =
+            near_sdk::serde_json::from_value(args["transfers"].clone()).unwrap();
+        assert_eq!(transfers.len(), 3);
+
+        // The wanted token fails to reach alice
+        let results = vec![
+            PromiseResult::Failed,
+            PromiseResult::Successful(vec![]),
+            PromiseResult::Successful(vec![]),
+        ];
+        context("market", "market", 0, 30, results);
+        contract.resolve_nft_transfers(transfers);
+        let claimable = contract.get_claimable_tokens(account("alice"));
+        assert_eq!(claimable.len(), 1);
+        assert_eq!(claimable[0].token_id, "2:1");
+        assert!(contract.get_claimable_tokens(account("bob")).is_empty());
+    }
+}
@@ -0,0 +1,191 @@
+// Helpers shared by the unit tests
+use near_sdk::mock::VmAction;
+use near_sdk::serde_json::Value;
+use near_sdk::test_utils::{get_created_receipts, VMContextBuilder};
+use near_sdk::{testing_env, PromiseResult, RuntimeFeesConfig, VMConfig};
+
+use crate::auction::{Auction, AuctionExtension, AuctionKind};
+use crate::bid::Bid;
+use crate::sale::Sale;
+use crate::*;
+
+pub fn account(id: &str) -> AccountId {
+    AccountId::new_unchecked(id.to_string())
+}
+
+// Call of the market with the `results` of the previous promises
+pub fn context(
+    predecessor: &str,
+    signer: &str,
+    deposit: Balance,
+    timestamp: u64,
+    results: Vec<PromiseResult>,
+) {
+    testing_env!(
+        VMContextBuilder::new()
+            .current_account_id(account("market"))
+            .predecessor_account_id(account(predecessor))
+            .signer_account_id(account(signer))
+            .attached_deposit(deposit)
+            .block_timestamp(timestamp)
+            .build(),
+        VMConfig::test(),
+        RuntimeFeesConfig::test(),
+        HashMap::default(),
+        results,
+    );
+}
+
+// Self callback with the result of one promise
+pub fn callback_context(result: PromiseResult) {
+    context("market", "market", 0, 0, vec![result]);
+}
+
+// Market on empty storage which allows "nft"
+pub fn new_market() -> Market {
+    near_sdk::mock::with_mocked_blockchain(|b| b.take_storage());
+    context("market", "market", 0, 0, vec![]);
+    Market::new(vec![account("nft")], account("owner"))
+}
+
+// Storage deposit of `account_id` for one sale, made with `storage_deposit`
+pub fn with_storage(contract: &mut Market, account_id: &str) {
+    context(account_id, account_id, STORAGE_PER_SALE, 0, vec![]);
+    contract.storage_deposit(None);
+}
+
+pub fn bid(owner_id: &str, price: u128) -> Bid {
+    Bid {
+        owner_id: account(owner_id),
+        price: U128(price),
+        start: U64(0),
+        end: None,
+        origins: HashMap::new(),
+    }
+}
+
+// Sale of the token "1:1" of alice for 1000 in every currency of `bids`
+pub fn sale(bids: Vec<(&str, Vec<Bid>)>) -> Sale {
+    Sale {
+        owner_id: account("alice"),
+        approval_id: 0,
+        nft_contract_id: account("nft"),
+        token_id: "1:1".to_string(),
+        sale_conditions: bids
+            .iter()
+            .map(|(ft_token_id, _)| (account(ft_token_id), U128(1000)))
+            .collect(),
+        bids: bids
+            .into_iter()
+            .map(|(ft_token_id, bids)| (account(ft_token_id), bids))
+            .collect(),
+        created_at: 0,
+        token_type: None,
+        start: None,
+        end: None,
+        origins: HashMap::new(),
+        protocol_fee: 300,
+    }
+}
+
+// English auction of the token "1:1" of alice in NEAR from 0 to 1000
+pub fn auction(bid: Option<Bid>) -> Auction {
+    Auction {
+        owner_id: account("alice"),
+        approval_id: 1,
+        nft_contract_id: account("nft"),
+        token_id: "1:1".to_string(),
+        bid,
+        created_at: 0,
+        ft_token_id: account("near"),
+        minimal_step: 100,
+        start_price: 1000,
+        buy_out_price: None,
+        reserve_price: None,
+        start: 0,
+        end: 1000,
+        origins: HashMap::new(),
+        kind: AuctionKind::English,
+        protocol_fee: 300,
+        extension: AuctionExtension::default(),
+        extension_count: 0,
+        extension_total: 0,
+    }
+}
+
+// Result of `nft_token` for the token of alice
+pub fn token(token_id: &str, approved: bool) -> PromiseResult {
+    let approved_account_ids = if approved {
+        near_sdk::serde_json::json!({ "market": 3 })
+    } else {
+        near_sdk::serde_json::json!({})
+    };
+    PromiseResult::Successful(
+        near_sdk::serde_json::json!({
+            "token_id": token_id,
+            "owner_id": "alice",
+            "metadata": null,
+            "approved_account_ids": approved_account_ids,
+        })
+        .to_string()
+        .into_bytes(),
+    )
+}
+
+// (receiver_id, function_name, args) of the created function calls
+pub fn function_calls() -> Vec<(String, String, Value)> {
+    get_created_receipts()
+        .into_iter()
+        .flat_map(|receipt| {
+            let receiver_id = receipt.receiver_id;
+            receipt
+                .actions
+                .into_iter()
+                .filter_map(move |action| match action {
+                    VmAction::FunctionCall {
+                        function_name,
+                        args,
+                        ..
+                    } => Some((
+                        receiver_id.to_string(),
+                        function_name,
+                        near_sdk::serde_json::from_slice(&args).unwrap_or(Value::Null),
+                    )),
+                    _ => None,
+                })
+        })
+        .collect()
+}
+
+// (receiver_id of the token, token_id) of the created `nft_transfer` calls
+pub fn nft_transfers() -> Vec<(String, String)> {
+    function_calls()
+        .into_iter()
+        .filter(|(_, function_name, _)| function_name == "nft_transfer")
+        .map(|(_, _, args)| {
+            (
+                args["receiver_id"].as_str().unwrap().to_string(),
+                args["token_id"].as_str().unwrap().to_string(),
+            )
+        })
+        .collect()
+}
+
+// (receiver_id, amount) of the created NEAR transfers, sorted
+pub fn near_transfers() -> Vec<(String, Balance)> {
+    let mut transfers: Vec<(String, Balance)> = get_created_receipts()
+        .into_iter()
+        .flat_map(|receipt| {
+            let receiver_id = receipt.receiver_id;
+            receipt
+                .actions
+                .into_iter()
+                .filter_map(move |action| match action {
+                    VmAction::Transfer { deposit } => Some((receiver_id.to_string(), deposit)),
+                    _ => None,
+                })
+        })
+        .collect();
+    transfers.sort();
+    transfers
+}
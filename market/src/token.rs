@@ -9,6 +9,9 @@ use crate::sale::{SaleConditions, TokenSeriesId};
 /// Note that token IDs for NFTs are strings on NEAR. It's still fine to use autoincrementing numbers as unique IDs if desired, but they should be stringified. This is to make IDs more future-proof as chain-agnostic conventions and standards arise, and allows for more flexibility with considerations like bridging NFTs across chains, etc.
 pub type TokenId = String;
 
+// Token ids of the series are "{token_type}:{edition}", the same as `nft::TOKEN_DELIMETER`
+pub const TOKEN_DELIMETER: char = ':';
+
 /// In this implementation, the Token struct takes two extensions standards (metadata and approval) as optional fields, as they are frequently used in modern NFTs.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(crate = "near_sdk::serde")]
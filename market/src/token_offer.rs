@@ -0,0 +1,494 @@
+#![allow(clippy::too_many_arguments)]
+use near_sdk::promise_result_as_success;
+
+use std::collections::HashMap;
+
+use crate::bid::{Bid, Origins};
+use crate::event::{BidData, NearEvent, PayoutFailData, PurchaseData};
+use crate::fee::{calculate_actual_amount, calculate_origins};
+use crate::sale::{
+    ext_contract, ext_self, FungibleTokenId, DELIMETER, GAS_FOR_NFT_TRANSFER, GAS_FOR_ROYALTIES,
+    MAX_LEN_PAYOUT, NO_DEPOSIT,
+};
+use crate::*;
+
+// Offer on the token, the price includes the fees like the price of a bid
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug, PartialEq))]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenOffer {
+    #[serde(flatten)]
+    pub bid: Bid,
+    // Protocol fee at the moment of the offer, it doesn't change with the market fee
+    pub protocol_fee: u32,
+}
+
+pub type TokenOffers = HashMap<FungibleTokenId, Vec<TokenOffer>>;
+
+#[near_bindgen]
+impl Market {
+    // Offers the attached deposit for the token whether it is listed or not
+    // The deposit should include the fees, the owner accepts the offer with `nft_approve`
+    // Each offer takes the storage of a sale, see `storage_deposit`
+    // Like the bids of a sale, a new offer should be higher than the last one in this currency
+    #[payable]
+    pub fn token_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        ft_token_id: FungibleTokenId,
+        start: Option<U64>,
+        duration: Option<U64>,
+        origins: Option<Origins>,
+    ) {
+        // Fungible tokens are offered through `ft_on_transfer`, the attached deposit is always NEAR
+        require!(
+            ft_token_id.as_str() == "near",
+            "Fungible tokens should be offered with ft_transfer_call"
+        );
+        self.internal_token_offer(
+            nft_contract_id,
+            token_id,
+            ft_token_id,
+            env::predecessor_account_id(),
+            env::attached_deposit(),
+            start,
+            duration,
+            origins,
+        );
+    }
+
+    // Removes the offer and refunds it
+    // Until the offer has ended, only its owner can remove it
+    #[payable]
+    pub fn remove_token_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        ft_token_id: FungibleTokenId,
+        owner_id: AccountId,
+        price: U128,
+    ) {
+        assert_one_yocto();
+        let TokenOffer { bid, .. } = self
+            .internal_remove_token_offer(
+                &nft_contract_id,
+                &token_id,
+                &ft_token_id,
+                &owner_id,
+                price,
+            )
+            .expect("No such offer");
+        let has_ended = matches!(bid.end, Some(end) if env::block_timestamp() >= end.0);
+        require!(
+            has_ended || env::predecessor_account_id() == bid.owner_id,
+            "Until the offer has ended, it can only be removed by its owner"
+        );
+        self.refund_bid(ft_token_id, bid.owner_id, bid.price);
+    }
+
+    // Returns the offers on the token per currency
+    pub fn get_token_offers(&self, nft_contract_id: AccountId, token_id: TokenId) -> TokenOffers {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        self.market
+            .token_offers
+            .get(&contract_and_token_id)
+            .unwrap_or_default()
+    }
+
+    // self callback
+    // If the token is transferred - pays out the offer
+    // If failed - refunds the offer
+    #[private]
+    pub fn resolve_token_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        ft_token_id: FungibleTokenId,
+        offer: TokenOffer,
+        owner_id: AccountId,
+        fees: fee::Fees,
+    ) {
+        let bid = offer.bid;
+        let price = bid.price;
+        // The price is either paid out or refunded to the buyer
+        self.escrow_release(&ft_token_id, price.0);
+        let value = match promise_result_as_success() {
+            Some(value) => value,
+            None => {
+                self.internal_send(ft_token_id.clone(), bid.owner_id.clone(), price);
+                NearEvent::payout_fail(PayoutFailData {
+                    auction_id: None,
+                    owner_id,
+                    nft_contract_id,
+                    token_id,
+                    buyer_id: bid.owner_id,
+                    ft_token_id,
+                    price,
+                })
+                .emit();
+                return;
+            }
+        };
+        let payout = fees.payout_from_result(&value, price.0, &owner_id);
+        NearEvent::purchase(PurchaseData {
+            auction_id: None,
+            owner_id,
+            nft_contract_id,
+            token_id,
+            buyer_id: bid.owner_id,
+            ft_token_id: ft_token_id.clone(),
+            price,
+            payout: payout.clone(),
+        })
+        .emit();
+        self.internal_pay_out(&ft_token_id, payout);
+    }
+}
+
+impl Market {
+    pub(crate) fn internal_token_offer(
+        &mut self,
+        nft_contract_id: AccountId,
+        token_id: TokenId,
+        ft_token_id: FungibleTokenId,
+        buyer_id: AccountId,
+        deposit: Balance,
+        start: Option<U64>,
+        duration: Option<U64>,
+        origins: Option<Origins>,
+    ) {
+        require!(
+            self.non_fungible_token_account_ids
+                .contains(&nft_contract_id),
+            format!(
+                "NFT contract {} is not supported by this market",
+                nft_contract_id
+            )
+        );
+        require!(
+            self.market.ft_token_ids.contains(&ft_token_id),
+            format!("Token {} not supported by this market", ft_token_id)
+        );
+        require!(deposit > 0, "Attached deposit must be greater than 0");
+        let origins = origins.unwrap_or_default();
+        let total_origins = calculate_origins(&origins);
+        require!(total_origins < 4_700, "Max origins exceeded");
+        require!(
+            self.storage_covers_new_sale(&buyer_id, &buyer_id),
+            "User has more sales than storage paid"
+        );
+
+        let start = start.unwrap_or(env::block_timestamp().into());
+        let offer = TokenOffer {
+            bid: Bid {
+                owner_id: buyer_id,
+                price: U128(deposit),
+                start,
+                end: duration.map(|d| U64(d.0 + start.0)),
+                origins,
+            },
+            protocol_fee: self.market.protocol_fee,
+        };
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let mut offers = self
+            .market
+            .token_offers
+            .get(&contract_and_token_id)
+            .unwrap_or_default();
+        let offers_for_ft = offers.entry(ft_token_id.clone()).or_default();
+        // The offers are compared without fees, each one with its own protocol fee
+        if let Some(current) = offers_for_ft.last() {
+            let current_origins = calculate_origins(&current.bid.origins);
+            let current_amount =
+                calculate_actual_amount(current.bid.price.0, current_origins, current.protocol_fee);
+            require!(
+                calculate_actual_amount(deposit, total_origins, offer.protocol_fee)
+                    > current_amount,
+                format!(
+                    "Can't pay less than or equal to current offer price: {}",
+                    current.bid.price.0
+                )
+            );
+        }
+        offers_for_ft.push(offer.clone());
+        let early_offer = if offers_for_ft.len() > self.market.bid_history_length as usize {
+            Some(offers_for_ft.remove(0))
+        } else {
+            None
+        };
+        self.market
+            .token_offers
+            .insert(&contract_and_token_id, &offers);
+        self.internal_count_token_offer(&offer.bid.owner_id, true);
+        self.escrow_deposit(&ft_token_id, deposit);
+        NearEvent::token_offer_add(BidData {
+            nft_contract_id: nft_contract_id.clone(),
+            token_id: token_id.clone(),
+            ft_token_id: ft_token_id.clone(),
+            bid: offer.bid,
+        })
+        .emit();
+        if let Some(TokenOffer { bid: early_bid, .. }) = early_offer {
+            // Need to refund the earliest offer after removing it
+            self.internal_count_token_offer(&early_bid.owner_id, false);
+            self.refund_bid(
+                ft_token_id.clone(),
+                early_bid.owner_id.clone(),
+                early_bid.price,
+            );
+            NearEvent::token_offer_remove(BidData {
+                nft_contract_id,
+                token_id,
+                ft_token_id,
+                bid: early_bid,
+            })
+            .emit();
+        }
+    }
+
+    // Called in nft_on_approve to sell the token to the offer of `bid_owner_id` with `bid_price`
+    // or to the last (highest) offer in `ft_token_id`
+    pub(crate) fn accept_token_offer(
+        &mut self,
+        ft_token_id: FungibleTokenId,
+        bid_owner_id: Option<AccountId>,
+        bid_price: Option<U128>,
+        token_id: TokenId,
+        owner_id: AccountId,
+        approval_id: u64,
+        nft_contract_id: AccountId,
+    ) -> Promise {
+        let (bid_owner_id, bid_price) = match (bid_owner_id, bid_price) {
+            (Some(bid_owner_id), Some(bid_price)) => (bid_owner_id, bid_price),
+            (None, None) => {
+                let offers = self.get_token_offers(nft_contract_id.clone(), token_id.clone());
+                let offer = offers
+                    .get(&ft_token_id)
+                    .and_then(|offers| offers.last())
+                    .unwrap_or_else(|| env::panic_str("No offers"));
+                (offer.bid.owner_id.clone(), offer.bid.price)
+            }
+            _ => env::panic_str("Both bid_owner_id and bid_price should be specified"),
+        };
+        let offer = self
+            .internal_remove_token_offer(
+                &nft_contract_id,
+                &token_id,
+                &ft_token_id,
+                &bid_owner_id,
+                bid_price,
+            )
+            .expect("No such offer");
+        require!(offer.bid.in_limits(), "Out of time limit of the offer");
+        require!(
+            offer.bid.owner_id != owner_id,
+            "Cannot accept your own offer"
+        );
+        // The token isn't listed, so the seller has no origins
+        let fees = fee::Fees::new(
+            offer.bid.origins.clone(),
+            &Origins::new(),
+            &self.market.treasury_id,
+            offer.protocol_fee,
+        );
+        ext_contract::nft_transfer_payout(
+            offer.bid.owner_id.clone(),
+            token_id.clone(),
+            approval_id,
+            Some(near_sdk::serde_json::to_string(&fees).expect("Failed to serialize")),
+            offer.bid.price,
+            MAX_LEN_PAYOUT,
+            nft_contract_id.clone(),
+            1,
+            GAS_FOR_NFT_TRANSFER,
+        )
+        .then(ext_self::resolve_token_offer(
+            nft_contract_id,
+            token_id,
+            ft_token_id,
+            offer,
+            owner_id,
+            fees,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_ROYALTIES,
+        ))
+    }
+
+    pub(crate) fn internal_remove_token_offer(
+        &mut self,
+        nft_contract_id: &AccountId,
+        token_id: &TokenId,
+        ft_token_id: &FungibleTokenId,
+        owner_id: &AccountId,
+        price: U128,
+    ) -> Option<TokenOffer> {
+        let contract_and_token_id = format!("{}{}{}", nft_contract_id, DELIMETER, token_id);
+        let mut offers = self.market.token_offers.get(&contract_and_token_id)?;
+        let offers_for_ft = offers.get_mut(ft_token_id)?;
+        let index = offers_for_ft
+            .iter()
+            .position(|offer| &offer.bid.owner_id == owner_id && offer.bid.price == price)?;
+        let offer = offers_for_ft.remove(index);
+        if offers_for_ft.is_empty() {
+            offers.remove(ft_token_id);
+        }
+        if offers.is_empty() {
+            self.market.token_offers.remove(&contract_and_token_id);
+        } else {
+            self.market
+                .token_offers
+                .insert(&contract_and_token_id, &offers);
+        }
+        self.internal_count_token_offer(owner_id, false);
+        NearEvent::token_offer_remove(BidData {
+            nft_contract_id: nft_contract_id.clone(),
+            token_id: token_id.clone(),
+            ft_token_id: ft_token_id.clone(),
+            bid: offer.bid.clone(),
+        })
+        .emit();
+        Some(offer)
+    }
+
+    // Counts the open token offers of the owner for the storage, see `internal_listings_count`
+    fn internal_count_token_offer(&mut self, owner_id: &AccountId, added: bool) {
+        let count = self
+            .market
+            .token_offers_by_owner_id
+            .get(owner_id)
+            .unwrap_or(0);
+        let count = if added { count + 1 } else { count - 1 };
+        if count == 0 {
+            self.market.token_offers_by_owner_id.remove(owner_id);
+        } else {
+            self.market
+                .token_offers_by_owner_id
+                .insert(owner_id, &count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::market_core::NonFungibleTokenApprovalReceiver;
+    use crate::test_utils::{account, context, function_calls, new_market, with_storage};
+
+    fn accept_msg(bid: Option<(&str, u128)>) -> String {
+        near_sdk::serde_json::to_string(&ArgsKind::AcceptOffer(AcceptOfferArgs {
+            ft_token_id: account("near"),
+            bid_owner_id: bid.map(|(owner_id, _)| account(owner_id)),
+            bid_price: bid.map(|(_, price)| U128(price)),
+        }))
+        .unwrap()
+    }
+
+    // Market with the offers of bob and carol on the unlisted token "1:1"
+    fn market() -> Market {
+        let mut contract = new_market();
+        for (bidder, price, duration) in [("bob", 1000, Some(100)), ("carol", 2000, None)] {
+            with_storage(&mut contract, bidder);
+            context(bidder, bidder, price, 10, vec![]);
+            contract.token_offer(
+                account("nft"),
+                "1:1".to_string(),
+                account("near"),
+                None,
+                duration.map(U64),
+                None,
+            );
+        }
+        contract
+    }
+
+    fn payout_receiver() -> Vec<String> {
+        function_calls()
+            .into_iter()
+            .filter(|(_, function_name, _)| function_name == "nft_transfer_payout")
+            .map(|(_, _, args)| args["receiver_id"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    #[should_panic(expected = "Can't pay less than or equal to current offer price: 2000")]
+    fn token_offer_should_be_higher() {
+        let mut contract = market();
+        with_storage(&mut contract, "dave");
+        context("dave", "dave", 1500, 10, vec![]);
+        let token_id = "1:1".to_string();
+        contract.token_offer(account("nft"), token_id, account("near"), None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "User has more sales than storage paid")]
+    fn token_offer_takes_storage() {
+        let mut contract = market();
+        context("bob", "bob", 3000, 10, vec![]);
+        let token_id = "1:1".to_string();
+        contract.token_offer(account("nft"), token_id, account("near"), None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "User has more sales than storage paid")]
+    fn token_offer_takes_whole_storage() {
+        let mut contract = new_market();
+        context("dave", "dave", STORAGE_PER_SALE * 3 / 2, 10, vec![]);
+        contract.storage_deposit(None);
+        for token_id in ["1:1", "1:2"] {
+            context("dave", "dave", 1000, 10, vec![]);
+            let token_id = token_id.to_string();
+            contract.token_offer(account("nft"), token_id, account("near"), None, None, None);
+        }
+    }
+
+    #[test]
+    fn accept_with_protocol_fee_of_offer() {
+        let mut contract = market();
+        contract.market.protocol_fee = 500;
+        context("nft", "alice", 0, 20, vec![]);
+        contract.nft_on_approve("1:1".to_string(), account("alice"), 0, accept_msg(None));
+        let calls = function_calls();
+        assert_eq!(calls[1].1, "resolve_token_offer");
+        assert_eq!(calls[1].2["offer"]["protocol_fee"], 300);
+        assert_eq!(calls[1].2["fees"]["buyer"]["market"], 300);
+        assert_eq!(contract.internal_listings_count(&account("carol")), 0);
+    }
+
+    #[test]
+    fn accept_last_offer() {
+        let mut contract = market();
+        context("nft", "alice", 0, 20, vec![]);
+        contract.nft_on_approve("1:1".to_string(), account("alice"), 0, accept_msg(None));
+        assert_eq!(payout_receiver(), vec!["carol".to_string()]);
+        let offers = contract.get_token_offers(account("nft"), "1:1".to_string());
+        assert_eq!(offers[&account("near")].len(), 1);
+        assert_eq!(offers[&account("near")][0].bid.owner_id, account("bob"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Out of time limit of the offer")]
+    fn accept_ended_offer() {
+        let mut contract = market();
+        context("nft", "alice", 0, 200, vec![]);
+        let msg = accept_msg(Some(("bob", 1000)));
+        contract.nft_on_approve("1:1".to_string(), account("alice"), 0, msg);
+    }
+
+    #[test]
+    fn remove_ended_offer() {
+        let mut contract = market();
+        context("dave", "dave", 1, 200, vec![]);
+        contract.remove_token_offer(
+            account("nft"),
+            "1:1".to_string(),
+            account("near"),
+            account("bob"),
+            U128(1000),
+        );
+        let offers = contract.get_token_offers(account("nft"), "1:1".to_string());
+        assert_eq!(offers[&account("near")].len(), 1);
+        assert_eq!(contract.get_escrow_summary().escrow[&account("near")], U128(2000));
+    }
+}
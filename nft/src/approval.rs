@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+
+use near_contract_standards::non_fungible_token::approval::NonFungibleTokenApproval;
+use near_sdk::{ext_contract, Gas, Promise};
+
+use crate::*;
+
+pub const GAS_FOR_NFT_ON_REVOKE: Gas = Gas(10_000_000_000_000);
+// Kept for the rest of the call after the notifications, e.g. the result of `nft_transfer_payout`
+pub const GAS_AFTER_REVOKE: Gas = Gas(5_000_000_000_000);
+
+// Lets the approved accounts (e.g. markets) drop the listings of the token
+#[ext_contract(ext_revoke_receiver)]
+trait NonFungibleTokenRevokeReceiver {
+    fn nft_on_revoke(&mut self, token_id: TokenId, owner_id: AccountId, approval_id: u64);
+}
+
+#[near_bindgen]
+impl NonFungibleTokenApproval for Nft {
+    #[payable]
+    fn nft_approve(
+        &mut self,
+        token_id: TokenId,
+        account_id: AccountId,
+        msg: Option<String>,
+    ) -> Option<Promise> {
+        self.tokens.nft_approve(token_id, account_id, msg)
+    }
+
+    #[payable]
+    fn nft_revoke(&mut self, token_id: TokenId, account_id: AccountId) {
+        let approval_id = self
+            .approved_account_ids(&token_id)
+            .get(&account_id)
+            .copied();
+        self.tokens.nft_revoke(token_id.clone(), account_id.clone());
+        if let Some(approval_id) = approval_id {
+            notify_revoke(
+                token_id,
+                env::predecessor_account_id(),
+                HashMap::from([(account_id, approval_id)]),
+            );
+        }
+    }
+
+    #[payable]
+    fn nft_revoke_all(&mut self, token_id: TokenId) {
+        let approved_account_ids = self.approved_account_ids(&token_id);
+        self.tokens.nft_revoke_all(token_id.clone());
+        notify_revoke(
+            token_id,
+            env::predecessor_account_id(),
+            approved_account_ids,
+        );
+    }
+
+    fn nft_is_approved(
+        &self,
+        token_id: TokenId,
+        approved_account_id: AccountId,
+        approval_id: Option<u64>,
+    ) -> bool {
+        self.tokens
+            .nft_is_approved(token_id, approved_account_id, approval_id)
+    }
+}
+
+impl Nft {
+    pub(crate) fn approved_account_ids(&self, token_id: &TokenId) -> HashMap<AccountId, u64> {
+        self.tokens
+            .approvals_by_id
+            .as_ref()
+            .and_then(|by_id| by_id.get(token_id))
+            .unwrap_or_default()
+    }
+}
+
+// The approvals are gone after a revoke or a transfer,
+// the accounts get the revoked approval ids to drop only the listings made with them
+// The notifications take only the gas left by the caller, so a transfer never runs out of gas
+// because of them, the latest approvals are notified first
+// The accounts which aren't notified should check the owner of the token themselves
+pub(crate) fn notify_revoke(
+    token_id: TokenId,
+    owner_id: AccountId,
+    approved_account_ids: HashMap<AccountId, u64>,
+) {
+    let mut approved_account_ids: Vec<_> = approved_account_ids.into_iter().collect();
+    approved_account_ids.sort_by(|(_, a), (_, b)| b.cmp(a));
+    for (account_id, approval_id) in approved_account_ids {
+        if env::prepaid_gas() - env::used_gas() < GAS_FOR_NFT_ON_REVOKE + GAS_AFTER_REVOKE {
+            break;
+        }
+        ext_revoke_receiver::nft_on_revoke(
+            token_id.clone(),
+            owner_id.clone(),
+            approval_id,
+            account_id,
+            0,
+            GAS_FOR_NFT_ON_REVOKE,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::test_utils::{get_created_receipts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use super::*;
+
+    fn account(id: &str) -> AccountId {
+        AccountId::new_unchecked(id.to_string())
+    }
+
+    #[test]
+    fn notify_revoke_within_prepaid_gas() {
+        testing_env!(VMContextBuilder::new()
+            .prepaid_gas(Gas(30_000_000_000_000))
+            .build());
+        let approved_account_ids = HashMap::from([
+            (account("market_a"), 1),
+            (account("market_b"), 2),
+            (account("market_c"), 3),
+        ]);
+        notify_revoke("1:1".to_string(), account("alice"), approved_account_ids);
+        let receivers: Vec<String> = get_created_receipts()
+            .into_iter()
+            .map(|receipt| receipt.receiver_id.to_string())
+            .collect();
+        assert_eq!(receivers, vec!["market_c", "market_b"]);
+    }
+}
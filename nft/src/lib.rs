@@ -1,3 +1,4 @@
+mod approval;
 mod nft_core;
 mod token;
 
@@ -11,16 +12,19 @@ use common::*;
 mod token_series;
 use event::NearEvent;
 use near_contract_standards::non_fungible_token::refund_deposit_to_account;
-use near_sdk::{ext_contract, Promise};
+use near_sdk::{ext_contract, Gas, Promise};
 use permissions::PrivateMint;
-use token_series::{TokenSeries, TokenSeriesId, TokenSeriesSale, TOKEN_DELIMETER};
+use token_series::{TokenSeries, TokenSeriesId, TokenSeriesSale};
 
 mod payouts;
 use crate::{event::NftMintData, payouts::MAXIMUM_ROYALTY};
 
 use std::collections::HashMap;
 
-pub use token_series::TokenSeriesJson;
+pub const GAS_FOR_NFT_SERIES_APPROVE: Gas = Gas(10_000_000_000_000);
+
+pub use approval::GAS_FOR_NFT_ON_REVOKE;
+pub use token_series::{TokenSeriesJson, TOKEN_DELIMETER};
 pub use payouts::Payout;
 
 // Since Near doesn't support multitoken(yet) by default we need to create some workaround
@@ -47,6 +51,7 @@ enum StorageKey {
     TokensBySeriesInner { token_series: String },
     TokensPerOwner { account_hash: Vec<u8> },
     Minters,
+    ApprovedMarkets,
 }
 
 #[near_bindgen]
@@ -103,19 +108,19 @@ impl Nft {
         receiver_id: AccountId,
         refund_id: Option<AccountId>,
     ) -> TokenId {
-        self.private_mint
-            .panic_if_not_allowed(&env::predecessor_account_id());
-        let refund_id = refund_id.unwrap_or_else(env::predecessor_account_id);
+        let predecessor_id = env::predecessor_account_id();
+        let refund_id = refund_id.unwrap_or_else(|| predecessor_id.clone());
         let initial_storage_usage = env::storage_usage();
 
         let mut token_series = self
             .token_series_by_id
             .get(&token_series_id)
             .expect("Token series does not exist");
-        require!(
-            env::predecessor_account_id().eq(&token_series.owner_id),
-            "permission denied"
-        );
+        // The approved market mints the copies it sells on behalf of the series owner
+        if approved_markets().get(&token_series_id).as_ref() != Some(&predecessor_id) {
+            self.private_mint.panic_if_not_allowed(&predecessor_id);
+            require!(predecessor_id == token_series.owner_id, "permission denied");
+        }
         require!(
             token_series.tokens.len() < token_series.metadata.copies.unwrap_or(u64::MAX),
             "Max token minted"
@@ -224,48 +229,54 @@ impl Nft {
         token_series_id
     }
 
-    /* Lazy_mint if needed
-       #[payable]
-       pub fn nft_series_market_approve(
-           &mut self,
-           token_series_id: TokenId,
-           sale_conditions: token_series::SaleConditions,
-           copies: u64,
-           approved_market_id: AccountId,
-       ) -> Promise {
-           let initial_storage_usage = env::storage_usage();
-           let mut token_series = self
-               .token_series_by_id
-               .get(&token_series_id)
-               .expect("Series not found");
-           require!(
-               env::predecessor_account_id().eq(&token_series.owner_id),
-               "Not token owner"
-           );
-           require!(
-               token_series.metadata.copies.unwrap_or(u64::MAX) - token_series.tokens.len() >= copies,
-               "Too many copies"
-           );
-           token_series.approved_market_id = Some(approved_market_id.clone());
-           self.token_series_by_id
-               .insert(&token_series_id, &token_series);
-           refund_deposit(env::storage_usage() - initial_storage_usage);
-           ext_contract::nft_on_series_approve(
-               TokenSeriesSale {
-                   sale_conditions,
-                   series_id: token_series_id,
-                   owner_id: token_series.owner_id,
-                   copies,
-               },
-               approved_market_id,
-               0,
-               env::prepaid_gas() - GAS_FOR_NFT_APPROVE,
-           )
-       }
-    */
+    /// Lists `copies` of the series for `sale_conditions` on `approved_market_id`.
+    /// Only the series owner can call it, the attached deposit pays for the storage.
+    /// The market is allowed to mint the copies with `nft_mint` for the buyers and gets
+    /// the listing with `nft_on_series_approve`, its result is returned.
+    /// Approving another market replaces the previous one.
+    #[payable]
+    pub fn nft_series_market_approve(
+        &mut self,
+        token_series_id: TokenSeriesId,
+        sale_conditions: token_series::SaleConditions,
+        copies: u64,
+        approved_market_id: AccountId,
+    ) -> Promise {
+        let initial_storage_usage = env::storage_usage();
+        let token_series = self
+            .token_series_by_id
+            .get(&token_series_id)
+            .expect("Token series does not exist");
+        require!(
+            env::predecessor_account_id() == token_series.owner_id,
+            "permission denied"
+        );
+        require!(
+            token_series.metadata.copies.unwrap_or(u64::MAX) - token_series.tokens.len() >= copies,
+            "Too many copies"
+        );
+        approved_markets().insert(&token_series_id, &approved_market_id);
+        refund_deposit(env::storage_usage() - initial_storage_usage);
+        ext_contract::nft_on_series_approve(
+            TokenSeriesSale {
+                sale_conditions,
+                series_id: token_series_id,
+                owner_id: token_series.owner_id,
+                copies,
+            },
+            approved_market_id,
+            0,
+            env::prepaid_gas() - GAS_FOR_NFT_SERIES_APPROVE,
+        )
+    }
+}
+
+// The market approved to mint the copies of each series, see `nft_series_market_approve`
+// It isn't a field of `Nft` or `TokenSeries`, so the layout of their state doesn't change
+fn approved_markets() -> LookupMap<TokenSeriesId, AccountId> {
+    LookupMap::new(StorageKey::ApprovedMarkets)
 }
 
-near_contract_standards::impl_non_fungible_token_approval!(Nft, tokens);
 near_contract_standards::impl_non_fungible_token_enumeration!(Nft, tokens);
 
 #[ext_contract(ext_contract)]
@@ -1,4 +1,5 @@
 use crate::*;
+use crate::approval::notify_revoke;
 use crate::event::NftTransferData;
 use near_contract_standards::non_fungible_token::{core::NonFungibleTokenCore, Token};
 
@@ -11,15 +12,28 @@ impl NonFungibleTokenCore for Nft {
         approval_id: Option<u64>,
         memo: Option<String>,
     ) {
-        let old_owner_id =
-            self.tokens.owner_by_id.get(&token_id).unwrap_or_else(|| env::panic_str("Token not found"));
+        let old_owner_id = self
+            .tokens
+            .owner_by_id
+            .get(&token_id)
+            .unwrap_or_else(|| env::panic_str("Token not found"));
         let authorized_id = if old_owner_id != env::predecessor_account_id() {
             Some(env::predecessor_account_id())
         } else {
             None
         };
+        let approved_account_ids = self.approved_account_ids(&token_id);
         self.tokens
             .nft_transfer(receiver_id.clone(), token_id.clone(), approval_id, memo.clone());
+        // The account which made the transfer doesn't need to be notified
+        notify_revoke(
+            token_id.clone(),
+            old_owner_id.clone(),
+            approved_account_ids
+                .into_iter()
+                .filter(|(account_id, _)| Some(account_id) != authorized_id.as_ref())
+                .collect(),
+        );
         NearEvent::nft_transfer(vec![NftTransferData::new(
             &old_owner_id,
             &receiver_id,
@@ -37,8 +51,25 @@ impl NonFungibleTokenCore for Nft {
         memo: Option<String>,
         msg: String,
     ) -> near_sdk::PromiseOrValue<bool> {
-        self.tokens
-            .nft_transfer_call(receiver_id, token_id, approval_id, memo, msg)
+        let old_owner_id = self
+            .tokens
+            .owner_by_id
+            .get(&token_id)
+            .unwrap_or_else(|| env::panic_str("Token not found"));
+        let predecessor_id = env::predecessor_account_id();
+        let approved_account_ids = self.approved_account_ids(&token_id);
+        let result = self
+            .tokens
+            .nft_transfer_call(receiver_id, token_id.clone(), approval_id, memo, msg);
+        notify_revoke(
+            token_id,
+            old_owner_id,
+            approved_account_ids
+                .into_iter()
+                .filter(|(account_id, _)| account_id != &predecessor_id)
+                .collect(),
+        );
+        result
     }
 
     fn nft_token(
@@ -5,9 +5,15 @@ use std::{
 //use crate::utils::{init_market, init_nft, mint_token, check_outcome_success, check_outcome_fail};
 use near_units::{parse_gas, parse_near};
 use crate::utils::{init_market, init_nft, create_subaccount, create_series, deposit,
-    mint_token, check_outcome_success, check_outcome_fail
+    mint_token, check_outcome_success, check_outcome_fail, nft_approve, offer
 };
-use nft_bid_market::{ArgsKind, AuctionArgs, AuctionJson};
+use nft_bid_market::{
+    ArgsKind, AuctionArgs, AuctionExtension, AuctionJson, DutchAuctionArgs, SaleArgs, SaleJson,
+    SealedAuctionArgs, TokenListingJson,
+};
+use std::collections::HashMap;
+use near_contract_standards::non_fungible_token::Token;
+use nft_contract::common::U128;
 //use workspaces::{Contract, Account, Worker};
 
 const THIRTY_SECONDS: Duration = Duration::from_secs(30);
@@ -53,6 +59,8 @@ async fn nft_on_approve_auction_positive() -> anyhow::Result<()> {
                 start: None,
                 duration: 900000000000.into(),
                 buy_out_price: Some(10000000000.into()),
+                reserve_price: None,
+                extension: None,
                 origins: None,
             })).to_string()
         }))?
@@ -64,6 +72,120 @@ async fn nft_on_approve_auction_positive() -> anyhow::Result<()> {
     Ok(())
 }
 
+/*
+- The auction replaces the sale of the token, the bids of the sale are refunded
+- The owner can't replace the auction after the first bid
+*/
+#[tokio::test]
+async fn nft_on_approve_replaces_listing() -> anyhow::Result<()> {
+    let worker = workspaces::sandbox();
+    let owner = worker.root_account();
+    let nft = init_nft(&worker, owner.id()).await?;
+    let market = init_market(&worker, worker.root_account().id(), vec![nft.id()]).await?;
+
+    let user1 = create_subaccount(&worker, &owner, "user1").await?;
+    let user2 = create_subaccount(&worker, &owner, "user2").await?;
+
+    let series = create_series(&worker, nft.id().clone(), &user1, owner.id().clone()).await?;
+    let token1 = mint_token(&worker, nft.id().clone(), &user1, user1.id(), &series).await?;
+    deposit(&worker, market.id().clone(), &user1).await;
+    let sale_conditions = HashMap::from([("near".parse().unwrap(), 10000.into())]);
+    nft_approve(
+        &worker,
+        nft.id().clone(),
+        market.id().clone(),
+        &user1,
+        token1.clone(),
+        sale_conditions.clone(),
+        series.clone(),
+    )
+    .await;
+    offer(&worker, nft.id().clone(), market.id().clone(), &user2, token1.clone(), 900.into())
+        .await;
+
+    let outcome = user1
+        .call(&worker, nft.id().clone(), "nft_approve")
+        .args_json(serde_json::json!({
+            "token_id": token1,
+            "account_id": market.id(),
+            "msg": serde_json::json!(ArgsKind::Auction(AuctionArgs {
+                token_type: None,
+                minimal_step: 100.into(),
+                start_price: 10000.into(),
+                start: None,
+                duration: 900000000000.into(),
+                buy_out_price: None,
+                reserve_price: None,
+                extension: None,
+                origins: None,
+            })).to_string()
+        }))?
+        .deposit(parse_near!("1 N"))
+        .gas(parse_gas!("200 Tgas") as u64)
+        .transact()
+        .await?;
+    check_outcome_success(outcome.status).await;
+
+    let sale: Option<SaleJson> = market
+        .view(
+            &worker,
+            "get_sale",
+            serde_json::json!({ "nft_contract_id": nft.id(), "token_id": token1 })
+                .to_string()
+                .into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert!(sale.is_none(), "The sale wasn't replaced");
+    let listing: Option<TokenListingJson> = market
+        .view(
+            &worker,
+            "get_listing_for_token",
+            serde_json::json!({ "nft_contract_id": nft.id(), "token_id": token1 })
+                .to_string()
+                .into_bytes(),
+        )
+        .await?
+        .json()?;
+    match listing {
+        Some(TokenListingJson::Auction { auction_id, .. }) => assert_eq!(auction_id, U128(0)),
+        _ => panic!("The token isn't auctioned"),
+    }
+
+    let outcome = user2
+        .call(&worker, market.id().clone(), "auction_add_bid")
+        .args_json(serde_json::json!({
+            "auction_id": "0".to_string(),
+        }))?
+        .deposit(10300)
+        .transact()
+        .await?;
+    check_outcome_success(outcome.status).await;
+    let outcome = user1
+        .call(&worker, nft.id().clone(), "nft_approve")
+        .args_json(serde_json::json!({
+            "token_id": token1,
+            "account_id": market.id(),
+            "msg": serde_json::json!(ArgsKind::Sale(SaleArgs {
+                sale_conditions,
+                token_type: Some(series),
+                start: None,
+                end: None,
+                origins: None,
+            })).to_string()
+        }))?
+        .deposit(parse_near!("1 N"))
+        .gas(parse_gas!("200 Tgas") as u64)
+        .transact()
+        .await?;
+    check_outcome_fail(
+        outcome.status,
+        "The token is auctioned with bids, the auction should be finished first",
+    )
+    .await;
+    Ok(())
+}
+
 /*
     - Should panic if `ft_token_id` is not supported
     - TODO: Should panic if the auction is not in progress
@@ -113,6 +235,8 @@ async fn auction_add_bid_negative() -> anyhow::Result<()> {
                 start: None,
                 duration: 900000000000.into(),
                 buy_out_price: Some(10000000000.into()),
+                reserve_price: None,
+                extension: None,
                 origins: None,
             })).to_string()
         }))?
@@ -236,6 +360,8 @@ async fn auction_add_bid_positive() -> anyhow::Result<()> {
                 start: None,
                 duration: 900000000000.into(),
                 buy_out_price: Some(10000000000.into()),
+                reserve_price: None,
+                extension: None,
                 origins: None,
             })).to_string()
         }))?
@@ -375,6 +501,8 @@ async fn cancel_auction_negative() -> anyhow::Result<()> {
                 start: None,
                 duration: 900000000000.into(),
                 buy_out_price: Some(10000000000.into()),
+                reserve_price: None,
+                extension: None,
                 origins: None,
             })).to_string()
         }))?
@@ -496,6 +624,8 @@ async fn cancel_auction_positive() -> anyhow::Result<()> {
                 start: None,
                 duration: 900000000000.into(),
                 buy_out_price: Some(10000000000.into()),
+                reserve_price: None,
+                extension: None,
                 origins: None,
             })).to_string()
         }))?
@@ -575,6 +705,8 @@ async fn finish_auction_positive() -> anyhow::Result<()> {
                 start: None,
                 duration: 900000000000.into(),
                 buy_out_price: Some(10000000000.into()),
+                reserve_price: None,
+                extension: None,
                 origins: None,
             })).to_string()
         }))?
@@ -601,7 +733,7 @@ async fn finish_auction_positive() -> anyhow::Result<()> {
     - Panics if the auction is not active
     - Should panic if called before the auction ends
     - TODO: Panics if there is no bid
-    - TODO: panic if number of payouts plus number of bids exceeds 10
+    - TODO: at most 10 payout transfers are sent, further receivers are credited
 */
 #[tokio::test]
 async fn finish_auction_negative() -> anyhow::Result<()> {
@@ -644,6 +776,8 @@ async fn finish_auction_negative() -> anyhow::Result<()> {
                 start: None,
                 duration: 900000000000.into(),
                 buy_out_price: Some(10000000000.into()),
+                reserve_price: None,
+                extension: None,
                 origins: None,
             })).to_string()
         }))?
@@ -680,3 +814,564 @@ async fn finish_auction_negative() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/*
+    - Should panic if the floor price is not less than the start price
+    - Should panic if the deposit is less than the current price with fees
+    - The first bid covering the current price with fees buys the token, the change is refunded
+*/
+#[tokio::test]
+async fn dutch_auction_buy() -> anyhow::Result<()> {
+    let worker = workspaces::sandbox();
+    let owner = worker.root_account();
+    let nft = init_nft(&worker, owner.id()).await?;
+    let market = init_market(
+        &worker,
+        worker.root_account().id(),
+        vec![nft.id()]
+    ).await?;
+
+    let user1 = create_subaccount(&worker, &owner, "user1").await?;
+    let user2 = create_subaccount(&worker, &owner, "user2").await?;
+
+    let series = create_series(
+        &worker,
+        nft.id().clone(),
+        &user1,
+        owner.id().clone()
+    ).await?;
+    let token1 = mint_token(
+        &worker,
+        nft.id().clone(),
+        &user1,
+        user1.id(),
+        &series
+    ).await?;
+
+    deposit(&worker, market.id().clone(), &user1).await;
+
+    // Should panic if the floor price is not less than the start price
+    let outcome = user1
+        .call(&worker, nft.id().clone(), "nft_approve")
+        .args_json(serde_json::json!({
+            "token_id": token1,
+            "account_id": market.id(),
+            "msg": serde_json::json!(ArgsKind::DutchAuction(DutchAuctionArgs {
+                token_type: None,
+                start_price: 10000.into(),
+                floor_price: 10000.into(),
+                step: None,
+                start: None,
+                duration: 900000000000.into(),
+                origins: None,
+            })).to_string()
+        }))?
+        .deposit(parse_near!("1 N"))
+        .gas(parse_gas!("200 Tgas") as u64)
+        .transact()
+        .await?;
+    check_outcome_fail(outcome.status, "Floor price should be less than the start price").await;
+
+    user1
+        .call(&worker, nft.id().clone(), "nft_approve")
+        .args_json(serde_json::json!({
+            "token_id": token1,
+            "account_id": market.id(),
+            "msg": serde_json::json!(ArgsKind::DutchAuction(DutchAuctionArgs {
+                token_type: None,
+                start_price: 10000.into(),
+                floor_price: 5000.into(),
+                step: None,
+                start: None,
+                duration: 900000000000.into(),
+                origins: None,
+            })).to_string()
+        }))?
+        .deposit(parse_near!("1 N"))
+        .gas(parse_gas!("200 Tgas") as u64)
+        .transact()
+        .await?;
+    let price: U128 = market
+        .view(
+            &worker,
+            "get_current_price",
+            serde_json::json!({ "auction_id": "0" }).to_string().into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert!(price.0 <= 10000 && price.0 >= 5000);
+
+    // Should panic if the deposit is less than the current price with fees
+    let outcome = user2
+        .call(&worker, market.id().clone(), "auction_add_bid")
+        .args_json(serde_json::json!({
+            "auction_id": "0".to_string(),
+        }))?
+        .deposit(5000)
+        .transact()
+        .await?;
+    check_outcome_fail(outcome.status, "Should pay at least").await;
+
+    // The first bid covering the current price with fees buys the token
+    let outcome = user2
+        .call(&worker, market.id().clone(), "auction_add_bid")
+        .args_json(serde_json::json!({
+            "auction_id": "0".to_string(),
+        }))?
+        .deposit(20000)
+        .gas(parse_gas!("300 Tgas") as u64)
+        .transact()
+        .await?;
+    check_outcome_success(outcome.status).await;
+    let token_data: Token = nft
+        .view(
+            &worker,
+            "nft_token",
+            serde_json::json!({ "token_id": token1 }).to_string().into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert_eq!(token_data.owner_id.as_ref(), user2.id().as_ref());
+    let auction = market
+        .view(
+            &worker,
+            "get_auction",
+            serde_json::json!({ "auction_id": "0" }).to_string().into_bytes(),
+        )
+        .await;
+    assert!(auction.is_err());
+
+    Ok(())
+}
+
+/*
+    - Should panic if the bid is committed with a deposit less than the start price with fees
+    - Should panic if the bid is revealed before the auction end
+    - Should panic if the price and the salt don't match the hash
+    - Should panic if the auction is finished before the end of the reveal phase
+    - The bid is revealed with the correct price and salt
+*/
+#[tokio::test]
+async fn sealed_auction_commit_reveal() -> anyhow::Result<()> {
+    let worker = workspaces::sandbox();
+    let owner = worker.root_account();
+    let nft = init_nft(&worker, owner.id()).await?;
+    let market = init_market(
+        &worker,
+        worker.root_account().id(),
+        vec![nft.id()]
+    ).await?;
+
+    let user1 = create_subaccount(&worker, &owner, "user1").await?;
+    let user2 = create_subaccount(&worker, &owner, "user2").await?;
+
+    let series = create_series(
+        &worker,
+        nft.id().clone(),
+        &user1,
+        owner.id().clone()
+    ).await?;
+    let token1 = mint_token(
+        &worker,
+        nft.id().clone(),
+        &user1,
+        user1.id(),
+        &series
+    ).await?;
+
+    deposit(&worker, market.id().clone(), &user1).await;
+    user1
+        .call(&worker, nft.id().clone(), "nft_approve")
+        .args_json(serde_json::json!({
+            "token_id": token1,
+            "account_id": market.id(),
+            "msg": serde_json::json!(ArgsKind::SealedAuction(SealedAuctionArgs {
+                token_type: None,
+                start_price: 10000.into(),
+                second_price: true,
+                start: None,
+                duration: 900000000000.into(),
+                reveal_duration: 900000000000.into(),
+                origins: None,
+            })).to_string()
+        }))?
+        .deposit(parse_near!("1 N"))
+        .gas(parse_gas!("200 Tgas") as u64)
+        .transact()
+        .await?;
+
+    let hash: String = market
+        .view(
+            &worker,
+            "get_sealed_bid_hash",
+            serde_json::json!({
+                "owner_id": user2.id(),
+                "price": "15000",
+                "salt": "secret",
+            }).to_string().into_bytes(),
+        )
+        .await?
+        .json()?;
+
+    // Should panic if the deposit is less than the start price with fees
+    let outcome = user2
+        .call(&worker, market.id().clone(), "auction_commit_bid")
+        .args_json(serde_json::json!({
+            "auction_id": "0".to_string(),
+            "hash": hash,
+        }))?
+        .deposit(10000)
+        .transact()
+        .await?;
+    check_outcome_fail(outcome.status, "Should bid at least 10300").await;
+
+    let outcome = user2
+        .call(&worker, market.id().clone(), "auction_commit_bid")
+        .args_json(serde_json::json!({
+            "auction_id": "0".to_string(),
+            "hash": hash,
+        }))?
+        .deposit(20000)
+        .transact()
+        .await?;
+    check_outcome_success(outcome.status).await;
+
+    // Should panic if the bid is revealed before the auction end
+    let outcome = user2
+        .call(&worker, market.id().clone(), "auction_reveal_bid")
+        .args_json(serde_json::json!({
+            "auction_id": "0".to_string(),
+            "price": "15000",
+            "salt": "secret",
+        }))?
+        .transact()
+        .await?;
+    check_outcome_fail(outcome.status, "Bids can be revealed only during the reveal phase").await;
+
+    user1
+        .call(&worker, market.id().clone(), "hack_finish_auction")
+        .args_json(serde_json::json!({
+            "auction_id": "0".to_string(),
+        }))?
+        .transact()
+        .await?;
+
+    // Should panic if the price and the salt don't match the hash
+    let outcome = user2
+        .call(&worker, market.id().clone(), "auction_reveal_bid")
+        .args_json(serde_json::json!({
+            "auction_id": "0".to_string(),
+            "price": "16000",
+            "salt": "secret",
+        }))?
+        .transact()
+        .await?;
+    check_outcome_fail(outcome.status, "The price and the salt don't match the committed hash").await;
+
+    let outcome = user2
+        .call(&worker, market.id().clone(), "auction_reveal_bid")
+        .args_json(serde_json::json!({
+            "auction_id": "0".to_string(),
+            "price": "15000",
+            "salt": "secret",
+        }))?
+        .transact()
+        .await?;
+    check_outcome_success(outcome.status).await;
+
+    // Should panic if the auction is finished before the end of the reveal phase
+    let outcome = user1
+        .call(&worker, market.id().clone(), "finish_auction")
+        .args_json(serde_json::json!({
+            "auction_id": "0".to_string()
+        }))?
+        .gas(parse_gas!("200 Tgas") as u64)
+        .transact()
+        .await?;
+    check_outcome_fail(outcome.status, "Auction can be finalized only after the reveal phase").await;
+
+    Ok(())
+}
+
+/*
+    - Should panic if the reserve price is less than the start price
+    - Views show only whether the reserve price is met
+    - If the reserve price is not met, `finish_auction` refunds the bid and removes the auction
+*/
+#[tokio::test]
+async fn auction_reserve_price() -> anyhow::Result<()> {
+    let worker = workspaces::sandbox();
+    let owner = worker.root_account();
+    let nft = init_nft(&worker, owner.id()).await?;
+    let market = init_market(
+        &worker,
+        worker.root_account().id(),
+        vec![nft.id()]
+    ).await?;
+
+    let user1 = create_subaccount(&worker, &owner, "user1").await?;
+    let user2 = create_subaccount(&worker, &owner, "user2").await?;
+
+    let series = create_series(
+        &worker,
+        nft.id().clone(),
+        &user1,
+        owner.id().clone()
+    ).await?;
+    let token1 = mint_token(
+        &worker,
+        nft.id().clone(),
+        &user1,
+        user1.id(),
+        &series
+    ).await?;
+
+    deposit(&worker, market.id().clone(), &user1).await;
+
+    // Should panic if the reserve price is less than the start price
+    let outcome = user1
+        .call(&worker, nft.id().clone(), "nft_approve")
+        .args_json(serde_json::json!({
+            "token_id": token1,
+            "account_id": market.id(),
+            "msg": serde_json::json!(ArgsKind::Auction(AuctionArgs {
+                token_type: None,
+                minimal_step: 100.into(),
+                start_price: 10000.into(),
+                start: None,
+                duration: 900000000000.into(),
+                buy_out_price: None,
+                reserve_price: Some(5000.into()),
+                extension: None,
+                origins: None,
+            })).to_string()
+        }))?
+        .deposit(parse_near!("1 N"))
+        .gas(parse_gas!("200 Tgas") as u64)
+        .transact()
+        .await?;
+    check_outcome_fail(outcome.status, "Reserve price should not be less than the start price").await;
+
+    user1
+        .call(&worker, nft.id().clone(), "nft_approve")
+        .args_json(serde_json::json!({
+            "token_id": token1,
+            "account_id": market.id(),
+            "msg": serde_json::json!(ArgsKind::Auction(AuctionArgs {
+                token_type: None,
+                minimal_step: 100.into(),
+                start_price: 10000.into(),
+                start: None,
+                duration: 900000000000.into(),
+                buy_out_price: None,
+                reserve_price: Some(20000.into()),
+                extension: None,
+                origins: None,
+            })).to_string()
+        }))?
+        .deposit(parse_near!("1 N"))
+        .gas(parse_gas!("200 Tgas") as u64)
+        .transact()
+        .await?;
+    let auction: AuctionJson = market
+        .view(
+            &worker,
+            "get_auction",
+            serde_json::json!({ "auction_id": "0" }).to_string().into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert_eq!(auction.reserve_met, Some(false));
+
+    user2
+        .call(&worker, market.id().clone(), "auction_add_bid")
+        .args_json(serde_json::json!({
+            "auction_id": "0".to_string(),
+        }))?
+        .deposit(10300)
+        .transact()
+        .await?;
+    let auction: AuctionJson = market
+        .view(
+            &worker,
+            "get_auction",
+            serde_json::json!({ "auction_id": "0" }).to_string().into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert_eq!(auction.reserve_met, Some(false));
+
+    // If the reserve price is not met, the bid is refunded and the auction is removed
+    user1
+        .call(&worker, market.id().clone(), "hack_finish_auction")
+        .args_json(serde_json::json!({
+            "auction_id": "0".to_string(),
+        }))?
+        .transact()
+        .await?;
+    let outcome = user1
+        .call(&worker, market.id().clone(), "finish_auction")
+        .args_json(serde_json::json!({
+            "auction_id": "0".to_string()
+        }))?
+        .gas(parse_gas!("300 Tgas") as u64)
+        .transact()
+        .await?;
+    check_outcome_success(outcome.status).await;
+    let token_data: Token = nft
+        .view(
+            &worker,
+            "nft_token",
+            serde_json::json!({ "token_id": token1 }).to_string().into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert_eq!(token_data.owner_id.as_ref(), user1.id().as_ref());
+    let auction = market
+        .view(
+            &worker,
+            "get_auction",
+            serde_json::json!({ "auction_id": "0" }).to_string().into_bytes(),
+        )
+        .await;
+    assert!(auction.is_err());
+
+    Ok(())
+}
+
+/*
+    - Should panic if the extension window or duration is out of the owner-set bounds
+    - `set_extension_bounds` can only be called by the market owner
+    - A late bid extends the auction by the auction extension settings
+*/
+#[tokio::test]
+async fn auction_custom_extension() -> anyhow::Result<()> {
+    let worker = workspaces::sandbox();
+    let owner = worker.root_account();
+    let nft = init_nft(&worker, owner.id()).await?;
+    let market = init_market(
+        &worker,
+        worker.root_account().id(),
+        vec![nft.id()]
+    ).await?;
+
+    let user1 = create_subaccount(&worker, &owner, "user1").await?;
+    let user2 = create_subaccount(&worker, &owner, "user2").await?;
+
+    let series = create_series(
+        &worker,
+        nft.id().clone(),
+        &user1,
+        owner.id().clone()
+    ).await?;
+    let token1 = mint_token(
+        &worker,
+        nft.id().clone(),
+        &user1,
+        user1.id(),
+        &series
+    ).await?;
+
+    deposit(&worker, market.id().clone(), &user1).await;
+
+    // `set_extension_bounds` can only be called by the market owner
+    let outcome = user1
+        .call(&worker, market.id().clone(), "set_extension_bounds")
+        .args_json(serde_json::json!({
+            "min_duration": "60000000000",
+            "max_duration": "3600000000000",
+        }))?
+        .deposit(1)
+        .transact()
+        .await?;
+    check_outcome_fail(outcome.status, "Only the market owner can call this method").await;
+    let outcome = owner
+        .call(&worker, market.id().clone(), "set_extension_bounds")
+        .args_json(serde_json::json!({
+            "min_duration": "60000000000",
+            "max_duration": "3600000000000",
+        }))?
+        .deposit(1)
+        .transact()
+        .await?;
+    check_outcome_success(outcome.status).await;
+
+    // Should panic if the extension window or duration is out of bounds
+    let outcome = user1
+        .call(&worker, nft.id().clone(), "nft_approve")
+        .args_json(serde_json::json!({
+            "token_id": token1,
+            "account_id": market.id(),
+            "msg": serde_json::json!(ArgsKind::Auction(AuctionArgs {
+                token_type: None,
+                minimal_step: 100.into(),
+                start_price: 10000.into(),
+                start: None,
+                duration: 900000000000.into(),
+                buy_out_price: None,
+                reserve_price: None,
+                extension: Some(AuctionExtension {
+                    window: 900000000000.into(),
+                    duration: 7200000000000.into(),
+                    max_count: None,
+                    max_total: None,
+                }),
+                origins: None,
+            })).to_string()
+        }))?
+        .deposit(parse_near!("1 N"))
+        .gas(parse_gas!("200 Tgas") as u64)
+        .transact()
+        .await?;
+    check_outcome_fail(outcome.status, "Extension window and duration should be from").await;
+
+    // The window covers the whole auction, so the first bid extends it
+    user1
+        .call(&worker, nft.id().clone(), "nft_approve")
+        .args_json(serde_json::json!({
+            "token_id": token1,
+            "account_id": market.id(),
+            "msg": serde_json::json!(ArgsKind::Auction(AuctionArgs {
+                token_type: None,
+                minimal_step: 100.into(),
+                start_price: 10000.into(),
+                start: None,
+                duration: 900000000000.into(),
+                buy_out_price: None,
+                reserve_price: None,
+                extension: Some(AuctionExtension {
+                    window: 1800000000000.into(),
+                    duration: 1800000000000.into(),
+                    max_count: Some(1),
+                    max_total: None,
+                }),
+                origins: None,
+            })).to_string()
+        }))?
+        .deposit(parse_near!("1 N"))
+        .gas(parse_gas!("200 Tgas") as u64)
+        .transact()
+        .await?;
+    let outcome = user2
+        .call(&worker, market.id().clone(), "auction_add_bid")
+        .args_json(serde_json::json!({
+            "auction_id": "0".to_string(),
+        }))?
+        .deposit(10300)
+        .transact()
+        .await?;
+    check_outcome_success(outcome.status).await;
+    let auction: AuctionJson = market
+        .view(
+            &worker,
+            "get_auction",
+            serde_json::json!({ "auction_id": "0" }).to_string().into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert_eq!(auction.extension_count, 1);
+    assert!(auction.end.0 - auction.start.0 > 900000000000);
+    assert_eq!(auction.extension.max_count, Some(1));
+
+    Ok(())
+}
@@ -6,7 +6,7 @@ use near_units::{parse_gas, parse_near};
 use crate::utils::{init_market, init_nft, create_subaccount, create_series, deposit,
     mint_token, check_outcome_success
 };
-use nft_bid_market::{ArgsKind, AuctionArgs, AuctionJson};
+use nft_bid_market::{ArgsKind, AuctionArgs, AuctionJson, EscrowSummary};
 use nft_contract::common::AccountId;
 use nft_contract::common::{U64, U128};
 
@@ -50,6 +50,8 @@ async fn view_auction_get_auction() -> anyhow::Result<()> {
                 start: None,
                 duration: 900000000000.into(),
                 buy_out_price: Some(10000000000.into()),
+                reserve_price: None,
+                extension: None,
                 origins: None,
             })).to_string()
         }))?
@@ -159,6 +161,8 @@ async fn view_auction_get_auctions() -> anyhow::Result<()> {
                 start: None,
                 duration: 900000000000.into(),
                 buy_out_price: Some(10000000000.into()),
+                reserve_price: None,
+                extension: None,
                 origins: None,
             })).to_string()
         }))?
@@ -178,6 +182,8 @@ async fn view_auction_get_auctions() -> anyhow::Result<()> {
                 start: None,
                 duration: 900000000000.into(),
                 buy_out_price: Some(1000000000.into()),
+                reserve_price: None,
+                extension: None,
                 origins: None,
             })).to_string()
         }))?
@@ -258,6 +264,8 @@ async fn view_auction_get_current_buyer() -> anyhow::Result<()> {
                 start: None,
                 duration: 900000000000.into(),
                 buy_out_price: Some(10000000000.into()),
+                reserve_price: None,
+                extension: None,
                 origins: None,
             })).to_string()
         }))?
@@ -369,6 +377,8 @@ async fn view_auction_get_current_bid() -> anyhow::Result<()> {
                 start: None,
                 duration: 900000000000.into(),
                 buy_out_price: Some(10000000000.into()),
+                reserve_price: None,
+                extension: None,
                 origins: None,
             })).to_string()
         }))?
@@ -482,6 +492,8 @@ async fn view_auction_get_minimal_next_bid() -> anyhow::Result<()> {
                 start: None,
                 duration: 900000000000.into(),
                 buy_out_price: Some(10000000000.into()),
+                reserve_price: None,
+                extension: None,
                 origins: None,
             })).to_string()
         }))?
@@ -598,6 +610,8 @@ async fn view_auction_check_auction_in_progress() -> anyhow::Result<()> {
                 start: None,
                 duration: 900000000000.into(),
                 buy_out_price: Some(10000000000.into()),
+                reserve_price: None,
+                extension: None,
                 origins: None,
             })).to_string()
         }))?
@@ -657,6 +671,8 @@ async fn view_auction_check_auction_in_progress() -> anyhow::Result<()> {
                 start: Some(U64(epoch_plus_waiting_time as u64)),
                 duration: 900000000000.into(),
                 buy_out_price: Some(10000000000.into()),
+                reserve_price: None,
+                extension: None,
                 origins: None,
             })).to_string()
         }))?
@@ -679,4 +695,88 @@ async fn view_auction_check_auction_in_progress() -> anyhow::Result<()> {
     // TODO: check `check_auction_in_progress` if auction is ended
     
     Ok(())
-}
\ No newline at end of file
+}
+
+/*
+- The auctions are returned by their owner and by the NFT contract
+- The storage deposit of the auction stays on the market after `storage_withdraw`
+ */
+#[tokio::test]
+async fn view_auction_get_auctions_by_owner_id() -> anyhow::Result<()> {
+    let worker = workspaces::sandbox();
+    let owner = worker.root_account();
+    let nft = init_nft(&worker, owner.id()).await?;
+    let market = init_market(&worker, worker.root_account().id(), vec![nft.id()]).await?;
+
+    let user1 = create_subaccount(&worker, &owner, "user1").await?;
+    let user2 = create_subaccount(&worker, &owner, "user2").await?;
+    deposit(&worker, market.id().clone(), &user1).await;
+    deposit(&worker, market.id().clone(), &user2).await;
+
+    for user in [&user1, &user2] {
+        let series = create_series(&worker, nft.id().clone(), user, owner.id().clone()).await?;
+        let token = mint_token(&worker, nft.id().clone(), user, user.id(), &series).await?;
+        let outcome = user
+            .call(&worker, nft.id().clone(), "nft_approve")
+            .args_json(serde_json::json!({
+                "token_id": token,
+                "account_id": market.id(),
+                "msg": serde_json::json!(ArgsKind::Auction(AuctionArgs {
+                    token_type: None,
+                    minimal_step: 100.into(),
+                    start_price: 10000.into(),
+                    start: None,
+                    duration: 900000000000.into(),
+                    buy_out_price: None,
+                    reserve_price: None,
+                    extension: None,
+                    origins: None,
+                })).to_string()
+            }))?
+            .deposit(parse_near!("1 N"))
+            .gas(parse_gas!("200 Tgas") as u64)
+            .transact()
+            .await?;
+        check_outcome_success(outcome.status).await;
+    }
+
+    let auctions: Vec<AuctionJson> = market
+        .view(
+            &worker,
+            "get_auctions_by_owner_id",
+            serde_json::json!({ "account_id": user1.id(), "from_index": "0", "limit": 10 })
+                .to_string()
+                .into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert_eq!(auctions.len(), 1);
+    assert_eq!(auctions[0].owner_id.as_str(), user1.id().as_ref());
+    let auctions: Vec<AuctionJson> = market
+        .view(
+            &worker,
+            "get_auctions_by_nft_contract_id",
+            serde_json::json!({ "nft_contract_id": nft.id(), "from_index": "0", "limit": 10 })
+                .to_string()
+                .into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert_eq!(auctions.len(), 2);
+
+    let outcome = user1
+        .call(&worker, market.id().clone(), "storage_withdraw")
+        .deposit(1)
+        .transact()
+        .await?;
+    check_outcome_success(outcome.status).await;
+    let summary: EscrowSummary = market
+        .view(&worker, "get_escrow_summary", Vec::new())
+        .await?
+        .json()?;
+    assert_eq!(
+        summary.storage_deposits.0,
+        parse_near!("1 N") + parse_near!("0.01 N")
+    );
+    Ok(())
+}
@@ -5,7 +5,7 @@ use crate::utils::{
     init_market, init_nft, mint_token, nft_approve, offer, offer_with_duration,
 };
 use near_units::parse_gas;
-use nft_bid_market::SaleJson;
+use nft_bid_market::{BidListing, BidderBidJson, SaleJson};
 use nft_contract::common::{AccountId, U128, U64};
 
 /*
@@ -108,6 +108,100 @@ async fn remove_bid_positive() -> anyhow::Result<()> {
     Ok(())
 }
 
+/*
+- Lists the open bids of the bidder with the sale and whether the bid is leading
+- The bid is removed from the list after `remove_bid`
+*/
+#[tokio::test]
+async fn get_bids_by_bidder() -> anyhow::Result<()> {
+    let worker = workspaces::sandbox();
+    let owner = worker.root_account();
+    let nft = init_nft(&worker, owner.id()).await?;
+    let market = init_market(&worker, worker.root_account().id(), vec![nft.id()]).await?;
+
+    let user1 = create_subaccount(&worker, &owner, "user1").await?;
+    let user2 = create_subaccount(&worker, &owner, "user2").await?;
+
+    let series = create_series(&worker, nft.id().clone(), &user1, owner.id().clone()).await?;
+    let token1 = mint_token(&worker, nft.id().clone(), &user1, user1.id(), &series).await?;
+    deposit(&worker, market.id().clone(), &user1).await;
+    let sale_conditions = HashMap::from([("near".parse().unwrap(), 10000.into())]);
+    nft_approve(
+        &worker,
+        nft.id().clone(),
+        market.id().clone(),
+        &user1,
+        token1.clone(),
+        sale_conditions.clone(),
+        series.clone(),
+    )
+    .await;
+    let price: U128 = 900.into();
+    offer(
+        &worker,
+        nft.id().clone(),
+        market.id().clone(),
+        &user2,
+        token1.clone(),
+        price,
+    )
+    .await;
+
+    let bids: Vec<BidderBidJson> = market
+        .view(
+            &worker,
+            "get_bids_by_bidder",
+            serde_json::json!({
+                "account_id": user2.id(),
+                "from_index": "0",
+                "limit": 10
+            })
+            .to_string()
+            .into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert_eq!(bids.len(), 1);
+    assert!(bids[0].leading, "The only bid should be leading");
+    assert_eq!(bids[0].bid.price, price);
+    match &bids[0].listing {
+        BidListing::Sale { token_id, .. } => assert_eq!(token_id, &token1),
+        BidListing::Auction { .. } => panic!("The bid was placed on a sale"),
+    }
+
+    let outcome = user2
+        .call(&worker, market.id().clone(), "remove_bid")
+        .args_json(serde_json::json!({
+            "nft_contract_id": nft.id().clone(),
+            "token_id": token1.clone(),
+            "ft_token_id": "near",
+            "price": price,
+        }))?
+        .deposit(1)
+        .gas(parse_gas!("300 Tgas") as u64)
+        .transact()
+        .await?;
+    check_outcome_success(outcome.status).await;
+
+    let bids: Vec<BidderBidJson> = market
+        .view(
+            &worker,
+            "get_bids_by_bidder",
+            serde_json::json!({
+                "account_id": user2.id(),
+                "from_index": "0",
+                "limit": 10
+            })
+            .to_string()
+            .into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert!(bids.is_empty(), "The removed bid is still listed");
+
+    Ok(())
+}
+
 /*
 - Should panic unless 1 yoctoNEAR is attached
 - Should panic if there is no sale with the given `nft_contract_id` and `token_id`
@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+
+use crate::utils::{
+    check_outcome_fail, check_outcome_success, create_series_raw, create_subaccount, deposit,
+    init_market, init_nft, mint_token,
+};
+use near_contract_standards::non_fungible_token::Token;
+use near_units::{parse_gas, parse_near};
+use nft_bid_market::{BundleArgs, BundleJson, BundleTokenArgs, ClaimableToken, EscrowSummary};
+use nft_contract::common::{AccountId, U128};
+
+/*
+- Panics if a token of the bundle is not approved for the market
+- The owner lists two approved tokens as one bundle
+- Panics if the deposit is not equal to the price with fees
+- The buyer buys the bundle, both tokens are transferred to the buyer
+- The bundle is removed, the escrow is empty, no token is left to claim
+ */
+#[tokio::test]
+async fn bundle_buy() -> anyhow::Result<()> {
+    let worker = workspaces::sandbox();
+    let owner = worker.root_account();
+    let nft = init_nft(&worker, owner.id()).await?;
+    let market = init_market(&worker, worker.root_account().id(), vec![nft.id()]).await?;
+
+    let user1 = create_subaccount(&worker, &owner, "user1").await?;
+    let user2 = create_subaccount(&worker, &owner, "user2").await?;
+
+    let series = create_series_raw(
+        &worker,
+        nft.id().clone(),
+        &user1,
+        Some(4),
+        HashMap::from([(user1.id(), 500)]),
+    )
+    .await?;
+    let token1 = mint_token(&worker, nft.id().clone(), &user1, user1.id(), &series).await?;
+    let token2 = mint_token(&worker, nft.id().clone(), &user1, user1.id(), &series).await?;
+    deposit(&worker, market.id().clone(), &user1).await;
+
+    let near: AccountId = "near".parse().unwrap();
+    let args = BundleArgs {
+        tokens: [&token1, &token2]
+            .iter()
+            .map(|token_id| BundleTokenArgs {
+                nft_contract_id: nft.id().as_ref().parse().unwrap(),
+                token_id: token_id.to_string(),
+            })
+            .collect(),
+        sale_conditions: HashMap::from([(near.clone(), U128(parse_near!("2 N")))]),
+        minimal_step: None,
+        start: None,
+        duration: None,
+        origins: None,
+    };
+    let create_bundle = || {
+        user1
+            .call(&worker, market.id().clone(), "create_bundle")
+            .args_json(serde_json::json!({ "args": args }))
+            .unwrap()
+            .gas(parse_gas!("100 Tgas") as u64)
+            .transact()
+    };
+    let outcome = user1
+        .call(&worker, nft.id().clone(), "nft_approve")
+        .args_json(serde_json::json!({
+            "token_id": token1,
+            "account_id": market.id(),
+        }))?
+        .deposit(parse_near!("0.01 N"))
+        .transact()
+        .await?;
+    check_outcome_success(outcome.status).await;
+    let outcome = create_bundle().await?;
+    check_outcome_fail(outcome.status, "is not approved for the market by the owner").await;
+    let outcome = user1
+        .call(&worker, nft.id().clone(), "nft_approve")
+        .args_json(serde_json::json!({
+            "token_id": token2,
+            "account_id": market.id(),
+        }))?
+        .deposit(parse_near!("0.01 N"))
+        .transact()
+        .await?;
+    check_outcome_success(outcome.status).await;
+    let outcome = create_bundle().await?;
+    check_outcome_success(outcome.status).await;
+
+    let bundles: Vec<BundleJson> = market
+        .view(
+            &worker,
+            "get_bundles_by_owner_id",
+            serde_json::json!({ "account_id": user1.id() })
+                .to_string()
+                .into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert_eq!(bundles.len(), 1);
+    let bundle_id = bundles[0].bundle_id;
+
+    let price: U128 = market
+        .view(
+            &worker,
+            "price_with_fees",
+            serde_json::json!({ "price": U128(parse_near!("2 N")) })
+                .to_string()
+                .into_bytes(),
+        )
+        .await?
+        .json()?;
+    let buy = |amount: u128| {
+        user2
+            .call(&worker, market.id().clone(), "buy_bundle")
+            .args_json(serde_json::json!({
+                "bundle_id": bundle_id,
+                "ft_token_id": "near",
+            }))
+            .unwrap()
+            .deposit(amount)
+            .gas(parse_gas!("300 Tgas") as u64)
+            .transact()
+    };
+    let outcome = buy(parse_near!("2 N")).await?;
+    check_outcome_fail(outcome.status, "Deposit should be equal to the price with fees").await;
+    let outcome = buy(price.0).await?;
+    check_outcome_success(outcome.status).await;
+
+    let tokens: Vec<Token> = nft
+        .view(
+            &worker,
+            "nft_tokens_for_owner",
+            serde_json::json!({ "account_id": user2.id() })
+                .to_string()
+                .into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert_eq!(tokens.len(), 2);
+    let bundle: Option<BundleJson> = market
+        .view(
+            &worker,
+            "get_bundle",
+            serde_json::json!({ "bundle_id": bundle_id })
+                .to_string()
+                .into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert!(bundle.is_none());
+    let claimable: Vec<ClaimableToken> = market
+        .view(
+            &worker,
+            "get_claimable_tokens",
+            serde_json::json!({ "account_id": user2.id() })
+                .to_string()
+                .into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert!(claimable.is_empty());
+    let summary: EscrowSummary = market
+        .view(&worker, "get_escrow_summary", Vec::new())
+        .await?
+        .json()?;
+    assert!(summary.escrow.is_empty());
+    assert!(summary.protocol_fees[&near].0 > 0);
+    Ok(())
+}
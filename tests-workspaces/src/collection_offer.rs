@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+
+use crate::utils::{
+    check_outcome_fail, check_outcome_success, create_series_raw, create_subaccount, deposit,
+    init_market, init_nft, mint_token,
+};
+use near_contract_standards::non_fungible_token::Token;
+use near_units::{parse_gas, parse_near};
+use nft_bid_market::{AcceptCollectionOfferArgs, ArgsKind, CollectionOfferJson, EscrowSummary};
+use nft_contract::common::{AccountId, U128};
+
+/*
+- Panics if the NFT contract is not supported
+- Panics if the deposit is zero
+- Panics if the owner of the offer didn't pay for the storage of one more sale
+- Creates an offer and returns its id
+- The offer can be found by the NFT contract and the series
+- Only the owner can remove the offer before its end, the deposit is refunded
+ */
+#[tokio::test]
+async fn collection_offer_positive_and_negative() -> anyhow::Result<()> {
+    let worker = workspaces::sandbox();
+    let owner = worker.root_account();
+    let nft = init_nft(&worker, owner.id()).await?;
+    let market = init_market(&worker, worker.root_account().id(), vec![nft.id()]).await?;
+
+    let user1 = create_subaccount(&worker, &owner, "user1").await?;
+    let user2 = create_subaccount(&worker, &owner, "user2").await?;
+
+    let outcome = user2
+        .call(&worker, market.id().clone(), "collection_offer")
+        .args_json(serde_json::json!({
+            "nft_contract_id": user1.id(),
+            "token_type": null,
+        }))?
+        .deposit(1000)
+        .transact()
+        .await?;
+    check_outcome_fail(outcome.status, "is not supported by this market").await;
+
+    let outcome = user2
+        .call(&worker, market.id().clone(), "collection_offer")
+        .args_json(serde_json::json!({
+            "nft_contract_id": nft.id(),
+            "token_type": null,
+        }))?
+        .transact()
+        .await?;
+    check_outcome_fail(outcome.status, "Attached deposit must be greater than 0").await;
+
+    let outcome = user2
+        .call(&worker, market.id().clone(), "collection_offer")
+        .args_json(serde_json::json!({
+            "nft_contract_id": nft.id(),
+            "token_type": null,
+        }))?
+        .deposit(1000)
+        .transact()
+        .await?;
+    check_outcome_fail(outcome.status, "User has more sales than storage paid").await;
+
+    deposit(&worker, market.id().clone(), &user2).await;
+
+    let offer_id: U128 = user2
+        .call(&worker, market.id().clone(), "collection_offer")
+        .args_json(serde_json::json!({
+            "nft_contract_id": nft.id(),
+            "token_type": "1",
+        }))?
+        .deposit(1000)
+        .transact()
+        .await?
+        .json()?;
+    let offers: Vec<CollectionOfferJson> = market
+        .view(
+            &worker,
+            "get_collection_offers",
+            serde_json::json!({
+                "nft_contract_id": nft.id(),
+                "token_type": "1",
+            })
+            .to_string()
+            .into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert_eq!(offers.len(), 1);
+    assert_eq!(offers[0].offer_id, offer_id);
+    assert_eq!(offers[0].offer.price, U128(1000));
+    let offers: Vec<CollectionOfferJson> = market
+        .view(
+            &worker,
+            "get_collection_offers",
+            serde_json::json!({
+                "nft_contract_id": nft.id(),
+                "token_type": "2",
+            })
+            .to_string()
+            .into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert!(offers.is_empty());
+
+    let outcome = user1
+        .call(&worker, market.id().clone(), "remove_collection_offer")
+        .args_json(serde_json::json!({ "offer_id": offer_id }))?
+        .deposit(1)
+        .transact()
+        .await?;
+    check_outcome_fail(
+        outcome.status,
+        "Until the offer has ended, it can only be removed by its owner",
+    )
+    .await;
+    let outcome = user2
+        .call(&worker, market.id().clone(), "remove_collection_offer")
+        .args_json(serde_json::json!({ "offer_id": offer_id }))?
+        .deposit(1)
+        .transact()
+        .await?;
+    check_outcome_success(outcome.status).await;
+    let offer: Option<CollectionOfferJson> = market
+        .view(
+            &worker,
+            "get_collection_offer",
+            serde_json::json!({ "offer_id": offer_id })
+                .to_string()
+                .into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert!(offer.is_none());
+    let summary: EscrowSummary = market
+        .view(&worker, "get_escrow_summary", Vec::new())
+        .await?
+        .json()?;
+    assert!(summary.escrow.is_empty());
+    Ok(())
+}
+
+/*
+- Panics if the token doesn't belong to the series of the offer
+- Panics if the offer owner accepts its own offer
+- The holder sells the token to the offer without the storage deposit
+- The offer is removed and paid out
+ */
+#[tokio::test]
+async fn accept_collection_offer() -> anyhow::Result<()> {
+    let worker = workspaces::sandbox();
+    let owner = worker.root_account();
+    let nft = init_nft(&worker, owner.id()).await?;
+    let market = init_market(&worker, worker.root_account().id(), vec![nft.id()]).await?;
+
+    let user1 = create_subaccount(&worker, &owner, "user1").await?;
+    let user2 = create_subaccount(&worker, &owner, "user2").await?;
+
+    let series1 = create_series_raw(
+        &worker,
+        nft.id().clone(),
+        &user1,
+        Some(4),
+        HashMap::from([(user1.id(), 500)]),
+    )
+    .await?;
+    let series2 = create_series_raw(
+        &worker,
+        nft.id().clone(),
+        &user1,
+        Some(4),
+        HashMap::from([(user1.id(), 500)]),
+    )
+    .await?;
+    let token1 = mint_token(&worker, nft.id().clone(), &user1, user1.id(), &series1).await?;
+    let token2 = mint_token(&worker, nft.id().clone(), &user1, user1.id(), &series2).await?;
+    let token3 = mint_token(&worker, nft.id().clone(), &user1, user2.id(), &series1).await?;
+
+    deposit(&worker, market.id().clone(), &user2).await;
+    let offer_id: U128 = user2
+        .call(&worker, market.id().clone(), "collection_offer")
+        .args_json(serde_json::json!({
+            "nft_contract_id": nft.id(),
+            "token_type": series1,
+        }))?
+        .deposit(parse_near!("1 N"))
+        .transact()
+        .await?
+        .json()?;
+    let msg = serde_json::json!(ArgsKind::AcceptCollectionOffer(AcceptCollectionOfferArgs {
+        offer_id
+    }))
+    .to_string();
+
+    let outcome = user1
+        .call(&worker, nft.id().clone(), "nft_approve")
+        .args_json(serde_json::json!({
+            "token_id": token2,
+            "account_id": market.id(),
+            "msg": msg,
+        }))?
+        .deposit(parse_near!("1 N"))
+        .gas(parse_gas!("300 Tgas") as u64)
+        .transact()
+        .await?;
+    check_outcome_fail(outcome.status, "The offer doesn't match the token").await;
+    let outcome = user2
+        .call(&worker, nft.id().clone(), "nft_approve")
+        .args_json(serde_json::json!({
+            "token_id": token3,
+            "account_id": market.id(),
+            "msg": msg,
+        }))?
+        .deposit(parse_near!("1 N"))
+        .gas(parse_gas!("300 Tgas") as u64)
+        .transact()
+        .await?;
+    check_outcome_fail(outcome.status, "Cannot accept your own offer").await;
+
+    let outcome = user1
+        .call(&worker, nft.id().clone(), "nft_approve")
+        .args_json(serde_json::json!({
+            "token_id": token1,
+            "account_id": market.id(),
+            "msg": msg,
+        }))?
+        .deposit(parse_near!("1 N"))
+        .gas(parse_gas!("300 Tgas") as u64)
+        .transact()
+        .await?;
+    check_outcome_success(outcome.status).await;
+
+    let token: Token = nft
+        .view(
+            &worker,
+            "nft_token",
+            serde_json::json!({ "token_id": token1 })
+                .to_string()
+                .into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert_eq!(token.owner_id.as_ref(), user2.id().as_ref());
+    let offer: Option<CollectionOfferJson> = market
+        .view(
+            &worker,
+            "get_collection_offer",
+            serde_json::json!({ "offer_id": offer_id })
+                .to_string()
+                .into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert!(offer.is_none());
+    let summary: EscrowSummary = market
+        .view(&worker, "get_escrow_summary", Vec::new())
+        .await?
+        .json()?;
+    assert!(summary.escrow.is_empty());
+    let near: AccountId = "near".parse().unwrap();
+    assert!(summary.protocol_fees[&near].0 > 0);
+    Ok(())
+}
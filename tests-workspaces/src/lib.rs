@@ -11,3 +11,8 @@ mod auction_views;
 mod sale_views;
 mod series_views;
 mod fee;
+mod collection_offer;
+mod token_offer;
+mod series_sale;
+mod bundle;
+mod swap;
@@ -1,11 +1,13 @@
 use std::collections::HashMap;
 
-use near_units::parse_near;
+use near_units::{parse_gas, parse_near};
 
 use crate::utils::{
-    check_outcome_fail, check_outcome_success, create_series_raw, init_market, init_nft,
-    mint_token, nft_approve,
+    check_outcome_fail, check_outcome_success, create_series_raw, create_subaccount, deposit,
+    init_market, init_nft, mint_token, nft_approve, offer,
 };
+use nft_bid_market::{ArgsKind, SaleArgs, SaleJson, MAX_PROTOCOL_FEE, PROTOCOL_FEE};
+use nft_contract::common::{AccountId, U128};
 
 #[tokio::test]
 async fn storage_deposit() -> anyhow::Result<()> {
@@ -103,3 +105,432 @@ async fn storage_withdraw() -> anyhow::Result<()> {
     // TODO: check balances
     Ok(())
 }
+
+/*
+- Can only be called by the market owner
+- Requires 1 yoctoNEAR
+- Adds/removes the token and returns whether the list has changed
+- New offers in a removed token are rejected
+ */
+#[tokio::test]
+async fn add_remove_ft_token() -> anyhow::Result<()> {
+    let worker = workspaces::sandbox();
+    let owner = worker.root_account();
+    let nft = init_nft(&worker, owner.id()).await?;
+    let market = init_market(&worker, worker.root_account().id(), vec![nft.id()]).await?;
+
+    let user1 = create_subaccount(&worker, &owner, "user1").await?;
+    let user2 = create_subaccount(&worker, &owner, "user2").await?;
+
+    // Negative
+    let outcome = user1
+        .call(&worker, market.id().clone(), "add_ft_token")
+        .args_json(serde_json::json!({ "ft_token_id": "ft.near" }))?
+        .deposit(1)
+        .transact()
+        .await?;
+    check_outcome_fail(outcome.status, "Only the market owner can call this method").await;
+    let outcome = owner
+        .call(&worker, market.id().clone(), "add_ft_token")
+        .args_json(serde_json::json!({ "ft_token_id": "ft.near" }))?
+        .transact()
+        .await?;
+    check_outcome_fail(
+        outcome.status,
+        "Requires attached deposit of exactly 1 yoctoNEAR",
+    )
+    .await;
+
+    // Positive
+    let added: bool = owner
+        .call(&worker, market.id().clone(), "add_ft_token")
+        .args_json(serde_json::json!({ "ft_token_id": "ft.near" }))?
+        .deposit(1)
+        .transact()
+        .await?
+        .json()?;
+    assert!(added);
+    let tokens: Vec<AccountId> = market
+        .view(&worker, "get_supported_ft_tokens", Vec::new())
+        .await?
+        .json()?;
+    assert_eq!(
+        tokens,
+        vec!["near".parse().unwrap(), "ft.near".parse().unwrap()]
+    );
+
+    // Sale in NEAR doesn't accept offers after NEAR is removed
+    let series = create_series_raw(
+        &worker,
+        nft.id().clone(),
+        &user1,
+        Some(4),
+        HashMap::from([(user1.id(), 500)]),
+    )
+    .await?;
+    let token = mint_token(&worker, nft.id().clone(), &user1, user1.id(), &series).await?;
+    deposit(&worker, market.id().clone(), &user1).await;
+    let sale_conditions = HashMap::from([("near".parse().unwrap(), 42000.into())]);
+    nft_approve(
+        &worker,
+        nft.id().clone(),
+        market.id().clone(),
+        &user1,
+        token.clone(),
+        sale_conditions,
+        series,
+    )
+    .await;
+    let removed: bool = owner
+        .call(&worker, market.id().clone(), "remove_ft_token")
+        .args_json(serde_json::json!({ "ft_token_id": "near" }))?
+        .deposit(1)
+        .transact()
+        .await?
+        .json()?;
+    assert!(removed);
+    let outcome = user2
+        .call(&worker, market.id().clone(), "offer")
+        .args_json(serde_json::json!({
+            "nft_contract_id": nft.id(),
+            "token_id": token,
+            "ft_token_id": "near",
+        }))?
+        .deposit(10000)
+        .transact()
+        .await?;
+    check_outcome_fail(outcome.status, "Token near not supported by this market").await;
+    Ok(())
+}
+
+/*
+- Tokens of NFT contracts which are not in the allowlist can't be listed
+- Only the market owner can change the allowlist
+- Returns the allowlist with pagination
+ */
+#[tokio::test]
+async fn add_remove_nft_contract() -> anyhow::Result<()> {
+    let worker = workspaces::sandbox();
+    let owner = worker.root_account();
+    let nft = init_nft(&worker, owner.id()).await?;
+    let nft2 = init_nft(&worker, owner.id()).await?;
+    let market = init_market(&worker, worker.root_account().id(), vec![nft.id()]).await?;
+
+    let user1 = create_subaccount(&worker, &owner, "user1").await?;
+
+    let series = create_series_raw(
+        &worker,
+        nft2.id().clone(),
+        &user1,
+        Some(4),
+        HashMap::from([(user1.id(), 500)]),
+    )
+    .await?;
+    let token = mint_token(&worker, nft2.id().clone(), &user1, user1.id(), &series).await?;
+    deposit(&worker, market.id().clone(), &user1).await;
+    let sale_conditions: HashMap<AccountId, U128> =
+        HashMap::from([("near".parse().unwrap(), 42000.into())]);
+    let msg = serde_json::json!(ArgsKind::Sale(SaleArgs {
+        sale_conditions,
+        token_type: Some(series),
+        start: None,
+        end: None,
+        origins: None,
+    }))
+    .to_string();
+
+    // Negative
+    let outcome = user1
+        .call(&worker, nft2.id().clone(), "nft_approve")
+        .args_json(serde_json::json!({
+            "token_id": token,
+            "account_id": market.id(),
+            "msg": msg
+        }))?
+        .deposit(parse_near!("1 N"))
+        .gas(parse_gas!("200 Tgas") as u64)
+        .transact()
+        .await?;
+    check_outcome_fail(outcome.status, "is not supported by this market").await;
+    let outcome = user1
+        .call(&worker, market.id().clone(), "add_nft_contract")
+        .args_json(serde_json::json!({ "nft_contract_id": nft2.id() }))?
+        .deposit(1)
+        .transact()
+        .await?;
+    check_outcome_fail(outcome.status, "Only the market owner can call this method").await;
+
+    // Positive
+    let outcome = owner
+        .call(&worker, market.id().clone(), "add_nft_contract")
+        .args_json(serde_json::json!({ "nft_contract_id": nft2.id() }))?
+        .deposit(1)
+        .transact()
+        .await?;
+    check_outcome_success(outcome.status).await;
+    let contracts: Vec<AccountId> = market
+        .view(
+            &worker,
+            "get_supported_nft_contracts",
+            serde_json::json!({ "from_index": "1", "limit": 1 })
+                .to_string()
+                .into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert_eq!(contracts, vec![nft2.id().as_ref().parse().unwrap()]);
+    let outcome = user1
+        .call(&worker, nft2.id().clone(), "nft_approve")
+        .args_json(serde_json::json!({
+            "token_id": token,
+            "account_id": market.id(),
+            "msg": msg
+        }))?
+        .deposit(parse_near!("1 N"))
+        .gas(parse_gas!("200 Tgas") as u64)
+        .transact()
+        .await?;
+    check_outcome_success(outcome.status).await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn set_price_oracle() -> anyhow::Result<()> {
+    let worker = workspaces::sandbox();
+    let owner = worker.root_account();
+    let nft = init_nft(&worker, owner.id()).await?;
+    let market = init_market(&worker, worker.root_account().id(), vec![nft.id()]).await?;
+
+    let user1 = create_subaccount(&worker, &owner, "user1").await?;
+    let oracle: AccountId = "oracle.near".parse().unwrap();
+
+    // Negative
+    let outcome = user1
+        .call(&worker, market.id().clone(), "set_price_oracle")
+        .args_json(serde_json::json!({ "price_oracle_id": oracle }))?
+        .deposit(1)
+        .transact()
+        .await?;
+    check_outcome_fail(outcome.status, "Only the market owner can call this method").await;
+
+    // Positive
+    let outcome = owner
+        .call(&worker, market.id().clone(), "set_price_oracle")
+        .args_json(serde_json::json!({ "price_oracle_id": oracle }))?
+        .deposit(1)
+        .transact()
+        .await?;
+    check_outcome_success(outcome.status).await;
+    let price_oracle_id: Option<AccountId> = market
+        .view(&worker, "get_price_oracle", Vec::new())
+        .await?
+        .json()?;
+    assert_eq!(price_oracle_id, Some(oracle));
+    Ok(())
+}
+
+#[tokio::test]
+async fn set_protocol_fee() -> anyhow::Result<()> {
+    let worker = workspaces::sandbox();
+    let owner = worker.root_account();
+    let nft = init_nft(&worker, owner.id()).await?;
+    let market = init_market(&worker, worker.root_account().id(), vec![nft.id()]).await?;
+
+    let user1 = create_subaccount(&worker, &owner, "user1").await?;
+    let treasury: AccountId = "treasury.near".parse().unwrap();
+
+    // The sale listed before the change keeps the old fee
+    let series = create_series_raw(
+        &worker,
+        nft.id().clone(),
+        &user1,
+        Some(4),
+        HashMap::from([(user1.id(), 500)]),
+    )
+    .await?;
+    let token = mint_token(&worker, nft.id().clone(), &user1, user1.id(), &series).await?;
+    deposit(&worker, market.id().clone(), &user1).await;
+    let sale_conditions = HashMap::from([("near".parse().unwrap(), 10000.into())]);
+    nft_approve(
+        &worker,
+        nft.id().clone(),
+        market.id().clone(),
+        &user1,
+        token.clone(),
+        sale_conditions,
+        series,
+    )
+    .await;
+
+    // Negative
+    let outcome = user1
+        .call(&worker, market.id().clone(), "set_protocol_fee")
+        .args_json(serde_json::json!({ "protocol_fee": 500 }))?
+        .deposit(1)
+        .transact()
+        .await?;
+    check_outcome_fail(outcome.status, "Only the market owner can call this method").await;
+    let outcome = owner
+        .call(&worker, market.id().clone(), "set_protocol_fee")
+        .args_json(serde_json::json!({ "protocol_fee": MAX_PROTOCOL_FEE + 1 }))?
+        .deposit(1)
+        .transact()
+        .await?;
+    check_outcome_fail(
+        outcome.status,
+        &format!("Protocol fee should not be bigger than {}", MAX_PROTOCOL_FEE),
+    )
+    .await;
+    let outcome = user1
+        .call(&worker, market.id().clone(), "set_treasury")
+        .args_json(serde_json::json!({ "treasury_id": treasury }))?
+        .deposit(1)
+        .transact()
+        .await?;
+    check_outcome_fail(outcome.status, "Only the market owner can call this method").await;
+
+    // Positive
+    let outcome = owner
+        .call(&worker, market.id().clone(), "set_protocol_fee")
+        .args_json(serde_json::json!({ "protocol_fee": 500 }))?
+        .deposit(1)
+        .transact()
+        .await?;
+    check_outcome_success(outcome.status).await;
+    let protocol_fee: u32 = market
+        .view(&worker, "get_protocol_fee", Vec::new())
+        .await?
+        .json()?;
+    assert_eq!(protocol_fee, 500);
+    let outcome = owner
+        .call(&worker, market.id().clone(), "set_treasury")
+        .args_json(serde_json::json!({ "treasury_id": treasury }))?
+        .deposit(1)
+        .transact()
+        .await?;
+    check_outcome_success(outcome.status).await;
+    let treasury_id: AccountId = market
+        .view(&worker, "get_treasury", Vec::new())
+        .await?
+        .json()?;
+    assert_eq!(treasury_id, treasury);
+
+    let price_with_fees: U128 = market
+        .view(
+            &worker,
+            "price_with_fees",
+            serde_json::json!({ "price": U128(10000) })
+                .to_string()
+                .into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert_eq!(price_with_fees, U128(10500));
+    let sale: SaleJson = market
+        .view(
+            &worker,
+            "get_sale",
+            serde_json::json!({
+                "nft_contract_id": nft.id(),
+                "token_id": token
+            })
+            .to_string()
+            .into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert_eq!(sale.protocol_fee, PROTOCOL_FEE as u32);
+    Ok(())
+}
+
+#[tokio::test]
+async fn withdraw_protocol_fees() -> anyhow::Result<()> {
+    let worker = workspaces::sandbox();
+    let owner = worker.root_account();
+    let nft = init_nft(&worker, owner.id()).await?;
+    let market = init_market(&worker, worker.root_account().id(), vec![nft.id()]).await?;
+
+    let user1 = create_subaccount(&worker, &owner, "user1").await?;
+    let user2 = create_subaccount(&worker, &owner, "user2").await?;
+
+    let series = create_series_raw(
+        &worker,
+        nft.id().clone(),
+        &user1,
+        Some(4),
+        HashMap::from([(user1.id(), 500)]),
+    )
+    .await?;
+    let token = mint_token(&worker, nft.id().clone(), &user1, user1.id(), &series).await?;
+    deposit(&worker, market.id().clone(), &user1).await;
+    let sale_conditions = HashMap::from([("near".parse().unwrap(), 10000.into())]);
+    nft_approve(
+        &worker,
+        nft.id().clone(),
+        market.id().clone(),
+        &user1,
+        token.clone(),
+        sale_conditions,
+        series,
+    )
+    .await;
+    // 3% from the buyer and 3% from the seller
+    offer(
+        &worker,
+        nft.id().clone(),
+        market.id().clone(),
+        &user2,
+        token,
+        10300.into(),
+    )
+    .await;
+    let protocol_fees: HashMap<AccountId, U128> = market
+        .view(&worker, "get_protocol_fees", Vec::new())
+        .await?
+        .json()?;
+    let near: AccountId = "near".parse().unwrap();
+    assert_eq!(protocol_fees, HashMap::from([(near.clone(), U128(600))]));
+
+    // Negative
+    let outcome = user1
+        .call(&worker, market.id().clone(), "withdraw_protocol_fees")
+        .args_json(serde_json::json!({
+            "ft_token_id": near,
+            "amount": U128(600),
+            "receiver_id": user1.id(),
+        }))?
+        .deposit(1)
+        .transact()
+        .await?;
+    check_outcome_fail(outcome.status, "Only the market owner can call this method").await;
+    let outcome = owner
+        .call(&worker, market.id().clone(), "withdraw_protocol_fees")
+        .args_json(serde_json::json!({
+            "ft_token_id": near,
+            "amount": U128(601),
+            "receiver_id": user1.id(),
+        }))?
+        .deposit(1)
+        .transact()
+        .await?;
+    check_outcome_fail(outcome.status, "Can withdraw from 1 to 600 of near").await;
+
+    // Positive
+    let outcome = owner
+        .call(&worker, market.id().clone(), "withdraw_protocol_fees")
+        .args_json(serde_json::json!({
+            "ft_token_id": near,
+            "amount": U128(600),
+            "receiver_id": user1.id(),
+        }))?
+        .deposit(1)
+        .transact()
+        .await?;
+    check_outcome_success(outcome.status).await;
+    let protocol_fees: HashMap<AccountId, U128> = market
+        .view(&worker, "get_protocol_fees", Vec::new())
+        .await?
+        .json()?;
+    assert!(protocol_fees.is_empty());
+    Ok(())
+}
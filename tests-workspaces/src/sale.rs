@@ -4,12 +4,16 @@ use std::{
 };
 
 use crate::utils::{
-    check_outcome_fail, create_series, create_series_raw, deposit, init_market, init_nft,
-    mint_token, nft_approve, offer,
+    check_outcome_fail, check_outcome_success, create_series, create_series_raw,
+    create_subaccount, deposit, init_market, init_nft, mint_token, nft_approve, offer,
+    price_with_fees,
 };
 use near_contract_standards::non_fungible_token::Token;
 use near_units::{parse_gas, parse_near};
-use nft_bid_market::{ArgsKind, SaleArgs, SaleJson, BID_HISTORY_LENGTH_DEFAULT};
+use nft_bid_market::{
+    ArgsKind, AuctionBidArgs, EscrowSummary, FtArgsKind, SaleArgs, SaleJson,
+    BID_HISTORY_LENGTH_DEFAULT, MAX_LEN_PAYOUT,
+};
 use nft_contract::common::{AccountId, U128, U64};
 
 /*
@@ -221,7 +225,7 @@ async fn nft_on_approve_positive() -> anyhow::Result<()> {
     - Should panic if the deposit equal to 0
     - Should panic if the NFT can't be bought by `ft_token_id`
 - If the `attached_deposit` is equal to the price + fees
-  -  panics if number of payouts plus number of bids exceeds 10
+  -  panics if the payout has more than 10 receivers
 - If the `attached_deposit` is not equal to the price + fees
   - should panic if `ft_token_id` is not supported
   - panics if the bid smaller or equal to the previous one
@@ -417,7 +421,7 @@ async fn offer_negative() -> anyhow::Result<()> {
         .await?;
     check_outcome_fail(outcome.status, "Max origins exceeded").await;
 
-    // number of payouts plus number of bids exceeds 10
+    // the payout has more than 10 receivers
     let too_much_origins: HashMap<AccountId, u32> = HashMap::from([
         ("acc1.near".parse().unwrap(), 100),
         ("acc2.near".parse().unwrap(), 100),
@@ -475,7 +479,7 @@ async fn offer_negative() -> anyhow::Result<()> {
     -  ft transferred to the previous owner
     -  protocol, royalty and origin fees are paid
     -  royalty paid from seller side
-    -  previous bids refunded
+    -  previous bids credited to the claimable balances of the bidders
 - If the `attached_deposit` is not equal to the price + fees
   - a new bid should be added
   - if the number of stored bids exceeds `bid_history_length`, the earliest bid is removed and refunded
@@ -901,6 +905,265 @@ async fn accept_offer_positive() -> anyhow::Result<()> {
     Ok(())
 }
 
+/*
+- Should panic unless called by the sale owner
+- Should panic if only one of `bid_owner_id` and `bid_price` is given
+- Should panic if there is no bid with given `bid_owner_id` and `bid_price`
+- Nft transfered to the owner of the chosen bid, other bids are credited to the claimable balances of the bidders
+ */
+#[tokio::test]
+async fn accept_offer_specific_bid() -> anyhow::Result<()> {
+    let worker = workspaces::sandbox();
+    let owner = worker.root_account();
+    let nft = init_nft(&worker, owner.id()).await?;
+    let market = init_market(&worker, worker.root_account().id(), vec![nft.id()]).await?;
+
+    let user1 = create_subaccount(&worker, &owner, "user1").await?;
+    let user2 = create_subaccount(&worker, &owner, "user2").await?;
+    let user3 = create_subaccount(&worker, &owner, "user3").await?;
+    let series = create_series_raw(
+        &worker,
+        nft.id().clone(),
+        &user1,
+        Some(4),
+        HashMap::from([(user1.id(), 500)]),
+    )
+    .await?;
+    let token1 = mint_token(&worker, nft.id().clone(), &user1, user1.id(), &series).await?;
+    deposit(&worker, market.id().clone(), &user1).await;
+    let sale_conditions = HashMap::from([("near".parse().unwrap(), 42000.into())]);
+    nft_approve(
+        &worker,
+        nft.id().clone(),
+        market.id().clone(),
+        &user1,
+        token1.clone(),
+        sale_conditions,
+        series,
+    )
+    .await;
+    offer(
+        &worker,
+        nft.id().clone(),
+        market.id().clone(),
+        &user2,
+        token1.clone(),
+        200.into(),
+    )
+    .await;
+    offer(
+        &worker,
+        nft.id().clone(),
+        market.id().clone(),
+        &user3,
+        token1.clone(),
+        300.into(),
+    )
+    .await;
+
+    // Negative
+    let outcome = user2
+        .call(&worker, market.id().clone(), "accept_offer")
+        .args_json(serde_json::json!({
+            "nft_contract_id": nft.id(),
+            "token_id": token1,
+            "ft_token_id": "near",
+        }))?
+        .gas(parse_gas!("300 Tgas") as u64)
+        .transact()
+        .await?;
+    check_outcome_fail(outcome.status, "Only the sale owner can accept an offer").await;
+    let outcome = user1
+        .call(&worker, market.id().clone(), "accept_offer")
+        .args_json(serde_json::json!({
+            "nft_contract_id": nft.id(),
+            "token_id": token1,
+            "ft_token_id": "near",
+            "bid_owner_id": user2.id(),
+        }))?
+        .gas(parse_gas!("300 Tgas") as u64)
+        .transact()
+        .await?;
+    check_outcome_fail(
+        outcome.status,
+        "Both bid_owner_id and bid_price should be specified",
+    )
+    .await;
+    let outcome = user1
+        .call(&worker, market.id().clone(), "accept_offer")
+        .args_json(serde_json::json!({
+            "nft_contract_id": nft.id(),
+            "token_id": token1,
+            "ft_token_id": "near",
+            "bid_owner_id": user2.id(),
+            "bid_price": "300",
+        }))?
+        .gas(parse_gas!("300 Tgas") as u64)
+        .transact()
+        .await?;
+    check_outcome_fail(outcome.status, "No such bid").await;
+
+    // Positive
+    let outcome = user1
+        .call(&worker, market.id().clone(), "accept_offer")
+        .args_json(serde_json::json!({
+            "nft_contract_id": nft.id(),
+            "token_id": token1,
+            "ft_token_id": "near",
+            "bid_owner_id": user2.id(),
+            "bid_price": "200",
+        }))?
+        .gas(parse_gas!("300 Tgas") as u64)
+        .transact()
+        .await?;
+    check_outcome_success(outcome.status).await;
+    let token_data: Token = nft
+        .view(
+            &worker,
+            "nft_token",
+            serde_json::json!({ "token_id": token1 })
+                .to_string()
+                .into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert_eq!(token_data.owner_id.as_ref(), user2.id().as_ref());
+    let sale_json: Option<SaleJson> = market
+        .view(
+            &worker,
+            "get_sale",
+            serde_json::json!({
+               "nft_contract_id": nft.id(),
+               "token_id": token1
+            })
+            .to_string()
+            .into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert!(sale_json.is_none());
+    Ok(())
+}
+
+/*
+- Should panic if `ft_token_id` is not given together with `bid_owner_id` and `bid_price`
+- Should panic if bids in several currencies should be compared, but the price oracle is not set
+- Without `ft_token_id` the last bid is accepted when all the bids are in one currency
+*/
+#[tokio::test]
+async fn accept_offer_best_bid() -> anyhow::Result<()> {
+    let worker = workspaces::sandbox();
+    let owner = worker.root_account();
+    let nft = init_nft(&worker, owner.id()).await?;
+    let market = init_market(&worker, worker.root_account().id(), vec![nft.id()]).await?;
+
+    let user1 = create_subaccount(&worker, &owner, "user1").await?;
+    let user2 = create_subaccount(&worker, &owner, "user2").await?;
+    let user3 = create_subaccount(&worker, &owner, "user3").await?;
+    let series = create_series_raw(
+        &worker,
+        nft.id().clone(),
+        &user1,
+        Some(4),
+        HashMap::from([(user1.id(), 500)]),
+    )
+    .await?;
+    let token1 = mint_token(&worker, nft.id().clone(), &user1, user1.id(), &series).await?;
+    deposit(&worker, market.id().clone(), &user1).await;
+    let sale_conditions = HashMap::from([("near".parse().unwrap(), 42000.into())]);
+    nft_approve(
+        &worker,
+        nft.id().clone(),
+        market.id().clone(),
+        &user1,
+        token1.clone(),
+        sale_conditions,
+        series,
+    )
+    .await;
+    offer(
+        &worker,
+        nft.id().clone(),
+        market.id().clone(),
+        &user2,
+        token1.clone(),
+        200.into(),
+    )
+    .await;
+    offer(
+        &worker,
+        nft.id().clone(),
+        market.id().clone(),
+        &user3,
+        token1.clone(),
+        300.into(),
+    )
+    .await;
+
+    // Negative
+    let outcome = user1
+        .call(&worker, market.id().clone(), "accept_offer")
+        .args_json(serde_json::json!({
+            "nft_contract_id": nft.id(),
+            "token_id": token1,
+            "bid_owner_id": user2.id(),
+            "bid_price": "200",
+        }))?
+        .gas(parse_gas!("300 Tgas") as u64)
+        .transact()
+        .await?;
+    check_outcome_fail(
+        outcome.status,
+        "ft_token_id should be specified to accept a specific bid",
+    )
+    .await;
+    let outcome = user2
+        .call(&worker, market.id().clone(), "update_prices")
+        .gas(parse_gas!("100 Tgas") as u64)
+        .transact()
+        .await?;
+    check_outcome_fail(outcome.status, "Price oracle is not set").await;
+    // Without the cached prices there is nothing to compare
+    let best_offer: Option<serde_json::Value> = market
+        .view(
+            &worker,
+            "get_best_offer",
+            serde_json::json!({
+                "nft_contract_id": nft.id(),
+                "token_id": token1,
+            })
+            .to_string()
+            .into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert!(best_offer.is_none());
+
+    // Positive
+    let outcome = user1
+        .call(&worker, market.id().clone(), "accept_offer")
+        .args_json(serde_json::json!({
+            "nft_contract_id": nft.id(),
+            "token_id": token1,
+        }))?
+        .gas(parse_gas!("300 Tgas") as u64)
+        .transact()
+        .await?;
+    check_outcome_success(outcome.status).await;
+    let token_data: Token = nft
+        .view(
+            &worker,
+            "nft_token",
+            serde_json::json!({ "token_id": token1 })
+                .to_string()
+                .into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert_eq!(token_data.owner_id.as_ref(), user3.id().as_ref());
+    Ok(())
+}
+
 /*
 - Should panic unless 1 yoctoNEAR is attached
 - Should panic if there is no sale with the given `nft_contract_id` and `token_id`
@@ -1238,3 +1501,403 @@ async fn remove_sale_positive() -> anyhow::Result<()> {
     assert!(sale_json.is_none());
     Ok(())
 }
+
+/*
+- Panics if the predecessor is not a supported FT contract
+- Panics if `msg` doesn't contain valid parameters for offer, purchase or auction bid
+ */
+#[tokio::test]
+async fn ft_on_transfer_negative() -> anyhow::Result<()> {
+    let worker = workspaces::sandbox();
+    let owner = worker.root_account();
+    let nft = init_nft(&worker, owner.id()).await?;
+    let market = init_market(&worker, worker.root_account().id(), vec![nft.id()]).await?;
+
+    let user1 = owner
+        .create_subaccount(&worker, "user1")
+        .initial_balance(parse_near!("10 N"))
+        .transact()
+        .await?
+        .unwrap();
+
+    // try to call ft_on_transfer not from FT contract
+    let outcome = user1
+        .call(&worker, market.id().clone(), "ft_on_transfer")
+        .args_json(serde_json::json!({
+            "sender_id": user1.id(),
+            "amount": "10000",
+            "msg": serde_json::json!(FtArgsKind::AuctionAddBid(AuctionBidArgs {
+                auction_id: 0.into(),
+                origins: None,
+            })).to_string()
+        }))?
+        .transact()
+        .await?;
+    check_outcome_fail(
+        outcome.status,
+        &format!("Token {} not supported by this market", user1.id()),
+    )
+    .await;
+    Ok(())
+}
+
+/*
+- The sale is removed when the owner revokes the approval or transfers the token
+- The bids are credited to the claimable balances of the bidders
+ */
+#[tokio::test]
+async fn nft_on_revoke() -> anyhow::Result<()> {
+    let worker = workspaces::sandbox();
+    let owner = worker.root_account();
+    let nft = init_nft(&worker, owner.id()).await?;
+    let market = init_market(&worker, worker.root_account().id(), vec![nft.id()]).await?;
+
+    let user1 = create_subaccount(&worker, &owner, "user1").await?;
+    let user2 = create_subaccount(&worker, &owner, "user2").await?;
+    let user3 = create_subaccount(&worker, &owner, "user3").await?;
+
+    let series = create_series_raw(
+        &worker,
+        nft.id().clone(),
+        &user1,
+        Some(4),
+        HashMap::from([(user1.id(), 500)]),
+    )
+    .await?;
+    let token1 = mint_token(&worker, nft.id().clone(), &user1, user1.id(), &series).await?;
+    let token2 = mint_token(&worker, nft.id().clone(), &user1, user1.id(), &series).await?;
+    deposit(&worker, market.id().clone(), &user1).await;
+    let sale_conditions = HashMap::from([("near".parse().unwrap(), 42000.into())]);
+    for token in [&token1, &token2] {
+        nft_approve(
+            &worker,
+            nft.id().clone(),
+            market.id().clone(),
+            &user1,
+            token.clone(),
+            sale_conditions.clone(),
+            series.clone(),
+        )
+        .await;
+    }
+    offer(&worker, nft.id().clone(), market.id().clone(), &user2, token1.clone(), 200.into()).await;
+    offer(&worker, nft.id().clone(), market.id().clone(), &user3, token2.clone(), 300.into()).await;
+
+    let outcome = user1
+        .call(&worker, nft.id().clone(), "nft_revoke")
+        .args_json(serde_json::json!({
+            "token_id": token1,
+            "account_id": market.id(),
+        }))?
+        .deposit(1)
+        .gas(parse_gas!("100 Tgas") as u64)
+        .transact()
+        .await?;
+    check_outcome_success(outcome.status).await;
+    let outcome = user1
+        .call(&worker, nft.id().clone(), "nft_transfer")
+        .args_json(serde_json::json!({
+            "receiver_id": user3.id(),
+            "token_id": token2,
+        }))?
+        .deposit(1)
+        .gas(parse_gas!("100 Tgas") as u64)
+        .transact()
+        .await?;
+    check_outcome_success(outcome.status).await;
+
+    for token in [&token1, &token2] {
+        let sale_json: Option<SaleJson> = market
+            .view(
+                &worker,
+                "get_sale",
+                serde_json::json!({
+                   "nft_contract_id": nft.id(),
+                   "token_id": token
+                })
+                .to_string()
+                .into_bytes(),
+            )
+            .await?
+            .json()?;
+        assert!(sale_json.is_none());
+    }
+    let outcome = user1
+        .call(&worker, market.id().clone(), "accept_offer")
+        .args_json(serde_json::json!({
+            "nft_contract_id": nft.id(),
+            "token_id": token1,
+            "ft_token_id": "near",
+        }))?
+        .gas(parse_gas!("300 Tgas") as u64)
+        .transact()
+        .await?;
+    check_outcome_fail(outcome.status, "No sale").await;
+
+    let near: AccountId = "near".parse().unwrap();
+    for (user, price) in [(&user2, 200), (&user3, 300)] {
+        let claimable: HashMap<AccountId, U128> = market
+            .view(
+                &worker,
+                "get_claimable",
+                serde_json::json!({ "account_id": user.id() })
+                    .to_string()
+                    .into_bytes(),
+            )
+            .await?
+            .json()?;
+        assert_eq!(claimable, HashMap::from([(near.clone(), U128(price))]));
+        let outcome = user
+            .call(&worker, market.id().clone(), "claim")
+            .args_json(serde_json::json!({ "ft_token_id": near }))?
+            .deposit(1)
+            .gas(parse_gas!("50 Tgas") as u64)
+            .transact()
+            .await?;
+        check_outcome_success(outcome.status).await;
+    }
+    let summary: EscrowSummary = market
+        .view(&worker, "get_escrow_summary", Vec::new())
+        .await?
+        .json()?;
+    assert!(summary.escrow.is_empty());
+    Ok(())
+}
+
+/*
+- Panics if there is no sale
+- Keeps the sale while the owner has the token and the market is approved
+ */
+#[tokio::test]
+async fn prune_stale_listing() -> anyhow::Result<()> {
+    let worker = workspaces::sandbox();
+    let owner = worker.root_account();
+    let nft = init_nft(&worker, owner.id()).await?;
+    let market = init_market(&worker, worker.root_account().id(), vec![nft.id()]).await?;
+
+    let user1 = create_subaccount(&worker, &owner, "user1").await?;
+    let user2 = create_subaccount(&worker, &owner, "user2").await?;
+
+    let series = create_series_raw(
+        &worker,
+        nft.id().clone(),
+        &user1,
+        Some(4),
+        HashMap::from([(user1.id(), 500)]),
+    )
+    .await?;
+    let token1 = mint_token(&worker, nft.id().clone(), &user1, user1.id(), &series).await?;
+    deposit(&worker, market.id().clone(), &user1).await;
+    let sale_conditions = HashMap::from([("near".parse().unwrap(), 42000.into())]);
+    nft_approve(
+        &worker,
+        nft.id().clone(),
+        market.id().clone(),
+        &user1,
+        token1.clone(),
+        sale_conditions,
+        series,
+    )
+    .await;
+
+    let outcome = user2
+        .call(&worker, market.id().clone(), "prune_stale_listing")
+        .args_json(serde_json::json!({
+            "nft_contract_id": nft.id(),
+            "token_id": "1:2",
+        }))?
+        .gas(parse_gas!("100 Tgas") as u64)
+        .transact()
+        .await?;
+    check_outcome_fail(outcome.status, "No sale").await;
+
+    let removed: bool = user2
+        .call(&worker, market.id().clone(), "prune_stale_listing")
+        .args_json(serde_json::json!({
+            "nft_contract_id": nft.id(),
+            "token_id": token1,
+        }))?
+        .gas(parse_gas!("100 Tgas") as u64)
+        .transact()
+        .await?
+        .json()?;
+    assert!(!removed);
+    let sale_json: Option<SaleJson> = market
+        .view(
+            &worker,
+            "get_sale",
+            serde_json::json!({
+               "nft_contract_id": nft.id(),
+               "token_id": token1
+            })
+            .to_string()
+            .into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert!(sale_json.is_some());
+    Ok(())
+}
+
+/*
+- If the nft contract can't pay out to all the receivers, the buyer is refunded
+- The sale is restored with its bids, because the token is still approved
+ */
+#[tokio::test]
+async fn purchase_too_many_receivers() -> anyhow::Result<()> {
+    let worker = workspaces::sandbox();
+    let owner = worker.root_account();
+    let nft = init_nft(&worker, owner.id()).await?;
+    let market = init_market(&worker, worker.root_account().id(), vec![nft.id()]).await?;
+
+    let user1 = create_subaccount(&worker, &owner, "user1").await?;
+    let user2 = create_subaccount(&worker, &owner, "user2").await?;
+    let user3 = create_subaccount(&worker, &owner, "user3").await?;
+
+    let receivers: Vec<workspaces::AccountId> = (0..=MAX_LEN_PAYOUT)
+        .map(|i| format!("receiver{}.test.near", i).parse().unwrap())
+        .collect();
+    let series = create_series_raw(
+        &worker,
+        nft.id().clone(),
+        &user1,
+        Some(4),
+        receivers.iter().map(|receiver| (receiver, 100)).collect(),
+    )
+    .await?;
+    let token1 = mint_token(&worker, nft.id().clone(), &user1, user1.id(), &series).await?;
+    deposit(&worker, market.id().clone(), &user1).await;
+    let sale_conditions = HashMap::from([("near".parse().unwrap(), 10000.into())]);
+    nft_approve(
+        &worker,
+        nft.id().clone(),
+        market.id().clone(),
+        &user1,
+        token1.clone(),
+        sale_conditions,
+        series,
+    )
+    .await;
+    offer(&worker, nft.id().clone(), market.id().clone(), &user3, token1.clone(), 500.into()).await;
+    offer(&worker, nft.id().clone(), market.id().clone(), &user2, token1.clone(), 10300.into())
+        .await;
+
+    let token_data: Token = nft
+        .view(
+            &worker,
+            "nft_token",
+            serde_json::json!({ "token_id": token1 })
+                .to_string()
+                .into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert_eq!(token_data.owner_id.as_ref(), user1.id().as_ref());
+    let sale_json: Option<SaleJson> = market
+        .view(
+            &worker,
+            "get_sale",
+            serde_json::json!({
+               "nft_contract_id": nft.id(),
+               "token_id": token1
+            })
+            .to_string()
+            .into_bytes(),
+        )
+        .await?
+        .json()?;
+    let bids = sale_json.unwrap().bids;
+    let near: AccountId = "near".parse().unwrap();
+    assert_eq!(bids[&near].len(), 1);
+    assert_eq!(bids[&near][0].owner_id.as_ref(), user3.id().as_ref());
+    // Only the bid of user3 is escrowed
+    let summary: EscrowSummary = market
+        .view(&worker, "get_escrow_summary", Vec::new())
+        .await?
+        .json()?;
+    assert_eq!(summary.escrow, HashMap::from([(near, U128(500))]));
+    assert!(summary.protocol_fees.is_empty());
+    Ok(())
+}
+
+/*
+- The token approved to the other accounts is bought, not all of them are notified
+ */
+#[tokio::test]
+async fn purchase_with_many_approvals() -> anyhow::Result<()> {
+    let worker = workspaces::sandbox();
+    let owner = worker.root_account();
+    let nft = init_nft(&worker, owner.id()).await?;
+    let market = init_market(&worker, worker.root_account().id(), vec![nft.id()]).await?;
+
+    let user1 = create_subaccount(&worker, &owner, "user1").await?;
+    let user2 = create_subaccount(&worker, &owner, "user2").await?;
+
+    let series = create_series(&worker, nft.id().clone(), &user1, owner.id().clone()).await?;
+    let token1 = mint_token(&worker, nft.id().clone(), &user1, user1.id(), &series).await?;
+    for i in 0..3 {
+        let outcome = user1
+            .call(&worker, nft.id().clone(), "nft_approve")
+            .args_json(serde_json::json!({
+                "token_id": token1,
+                "account_id": format!("approved{}.test.near", i),
+            }))?
+            .deposit(parse_near!("1 N"))
+            .gas(parse_gas!("200 Tgas") as u64)
+            .transact()
+            .await?;
+        check_outcome_success(outcome.status).await;
+    }
+    deposit(&worker, market.id().clone(), &user1).await;
+    let sale_conditions = HashMap::from([("near".parse().unwrap(), 10000.into())]);
+    nft_approve(
+        &worker,
+        nft.id().clone(),
+        market.id().clone(),
+        &user1,
+        token1.clone(),
+        sale_conditions.clone(),
+        series,
+    )
+    .await;
+    let price = price_with_fees(&worker, &market, sale_conditions).await?;
+    let outcome = user2
+        .call(&worker, market.id().clone(), "offer")
+        .args_json(serde_json::json!({
+            "nft_contract_id": nft.id(),
+            "token_id": token1,
+            "ft_token_id": "near",
+        }))?
+        .deposit(price.into())
+        .gas(parse_gas!("300 Tgas") as u64)
+        .transact()
+        .await?;
+    check_outcome_success(outcome.status).await;
+
+    let token_data: Token = nft
+        .view(
+            &worker,
+            "nft_token",
+            serde_json::json!({ "token_id": token1 })
+                .to_string()
+                .into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert_eq!(token_data.owner_id.as_ref(), user2.id().as_ref());
+    let sale_json: Option<SaleJson> = market
+        .view(
+            &worker,
+            "get_sale",
+            serde_json::json!({
+               "nft_contract_id": nft.id(),
+               "token_id": token1
+            })
+            .to_string()
+            .into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert!(sale_json.is_none());
+    Ok(())
+}
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+
+use crate::utils::{
+    check_outcome_fail, check_outcome_success, create_series_raw, create_subaccount, deposit,
+    init_market, init_nft,
+};
+use near_contract_standards::non_fungible_token::Token;
+use near_units::{parse_gas, parse_near};
+use nft_bid_market::{EscrowSummary, SeriesSale, STORAGE_FOR_MINT};
+use nft_contract::common::{AccountId, U128};
+
+/*
+- Panics if the series owner has no storage deposit on the market
+- The series owner lists 2 copies with `nft_series_market_approve`
+- Panics if more copies than listed are bought
+- Panics if the deposit doesn't include the storage of the mint
+- The buyer buys the copies one by one, they are minted for the buyer
+- The sale is removed after the last copy, the escrow is empty
+ */
+#[tokio::test]
+async fn series_sale_partial_fills() -> anyhow::Result<()> {
+    let worker = workspaces::sandbox();
+    let owner = worker.root_account();
+    let nft = init_nft(&worker, owner.id()).await?;
+    let market = init_market(&worker, worker.root_account().id(), vec![nft.id()]).await?;
+
+    let user1 = create_subaccount(&worker, &owner, "user1").await?;
+    let user2 = create_subaccount(&worker, &owner, "user2").await?;
+
+    let series = create_series_raw(
+        &worker,
+        nft.id().clone(),
+        &user1,
+        Some(4),
+        HashMap::from([(user1.id(), 500)]),
+    )
+    .await?;
+    let approve = || {
+        user1
+            .call(&worker, nft.id().clone(), "nft_series_market_approve")
+            .args_json(serde_json::json!({
+                "token_series_id": series,
+                "sale_conditions": { "near": U128(parse_near!("1 N")) },
+                "copies": 2,
+                "approved_market_id": market.id(),
+            }))
+            .unwrap()
+            .deposit(parse_near!("0.01 N"))
+            .gas(parse_gas!("100 Tgas") as u64)
+            .transact()
+    };
+    let outcome = approve().await?;
+    check_outcome_fail(outcome.status, "User has more sales than storage paid").await;
+    deposit(&worker, market.id().clone(), &user1).await;
+    let outcome = approve().await?;
+    check_outcome_success(outcome.status).await;
+
+    let series_sale: Option<SeriesSale> = market
+        .view(
+            &worker,
+            "get_series_sale",
+            serde_json::json!({
+                "nft_contract_id": nft.id(),
+                "series_id": series,
+            })
+            .to_string()
+            .into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert_eq!(series_sale.unwrap().copies, 2);
+
+    let price: U128 = market
+        .view(
+            &worker,
+            "price_with_fees",
+            serde_json::json!({ "price": U128(parse_near!("1 N")) })
+                .to_string()
+                .into_bytes(),
+        )
+        .await?
+        .json()?;
+    let buy = |copies: u64, amount: u128| {
+        user2
+            .call(&worker, market.id().clone(), "buy_series")
+            .args_json(serde_json::json!({
+                "nft_contract_id": nft.id(),
+                "series_id": series,
+                "copies": copies,
+            }))
+            .unwrap()
+            .deposit(amount)
+            .gas(parse_gas!("300 Tgas") as u64)
+            .transact()
+    };
+    let outcome = buy(3, (price.0 + STORAGE_FOR_MINT) * 3).await?;
+    check_outcome_fail(outcome.status, "Only 2 copies left").await;
+    let outcome = buy(1, price.0).await?;
+    check_outcome_fail(outcome.status, "Attached deposit should be").await;
+    for _ in 0..2 {
+        let outcome = buy(1, price.0 + STORAGE_FOR_MINT).await?;
+        check_outcome_success(outcome.status).await;
+    }
+
+    let tokens: Vec<Token> = nft
+        .view(
+            &worker,
+            "nft_tokens_for_owner",
+            serde_json::json!({ "account_id": user2.id() })
+                .to_string()
+                .into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert_eq!(tokens.len(), 2);
+    let series_sale: Option<SeriesSale> = market
+        .view(
+            &worker,
+            "get_series_sale",
+            serde_json::json!({
+                "nft_contract_id": nft.id(),
+                "series_id": series,
+            })
+            .to_string()
+            .into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert!(series_sale.is_none());
+    let summary: EscrowSummary = market
+        .view(&worker, "get_escrow_summary", Vec::new())
+        .await?
+        .json()?;
+    assert!(summary.escrow.is_empty());
+    let near: AccountId = "near".parse().unwrap();
+    assert!(summary.protocol_fees[&near].0 > 0);
+    Ok(())
+}
@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+
+use crate::utils::{
+    check_outcome_fail, check_outcome_success, create_series_raw, create_subaccount, deposit,
+    init_market, init_nft, mint_token,
+};
+use near_contract_standards::non_fungible_token::Token;
+use near_units::{parse_gas, parse_near};
+use nft_bid_market::{
+    AcceptSwapArgs, ArgsKind, BundleTokenArgs, ClaimableToken, EscrowSummary, SwapOfferArgs,
+    SwapOfferJson,
+};
+use nft_contract::common::U128;
+
+/*
+- user1 offers the approved token1 and 1 NEAR for token2 of user2
+- Panics if the offer is accepted with another token
+- user2 accepts the offer with `nft_approve` of token2
+- The tokens are swapped, the offer is removed and the escrow is empty, no token is left to claim
+ */
+#[tokio::test]
+async fn swap_offer_accept() -> anyhow::Result<()> {
+    let worker = workspaces::sandbox();
+    let owner = worker.root_account();
+    let nft = init_nft(&worker, owner.id()).await?;
+    let market = init_market(&worker, worker.root_account().id(), vec![nft.id()]).await?;
+
+    let user1 = create_subaccount(&worker, &owner, "user1").await?;
+    let user2 = create_subaccount(&worker, &owner, "user2").await?;
+
+    let series = create_series_raw(
+        &worker,
+        nft.id().clone(),
+        &user1,
+        Some(4),
+        HashMap::from([(user1.id(), 500)]),
+    )
+    .await?;
+    let token1 = mint_token(&worker, nft.id().clone(), &user1, user1.id(), &series).await?;
+    let token2 = mint_token(&worker, nft.id().clone(), &user1, user2.id(), &series).await?;
+    let token3 = mint_token(&worker, nft.id().clone(), &user1, user2.id(), &series).await?;
+    deposit(&worker, market.id().clone(), &user1).await;
+
+    let outcome = user1
+        .call(&worker, nft.id().clone(), "nft_approve")
+        .args_json(serde_json::json!({
+            "token_id": token1,
+            "account_id": market.id(),
+        }))?
+        .deposit(parse_near!("0.01 N"))
+        .transact()
+        .await?;
+    check_outcome_success(outcome.status).await;
+    let outcome = user1
+        .call(&worker, market.id().clone(), "swap_offer")
+        .args_json(serde_json::json!({
+            "args": SwapOfferArgs {
+                tokens: vec![BundleTokenArgs {
+                    nft_contract_id: nft.id().as_ref().parse().unwrap(),
+                    token_id: token1.clone(),
+                }],
+                nft_contract_id: nft.id().as_ref().parse().unwrap(),
+                token_id: token2.clone(),
+                start: None,
+                duration: None,
+            }
+        }))?
+        .deposit(parse_near!("1 N"))
+        .gas(parse_gas!("100 Tgas") as u64)
+        .transact()
+        .await?;
+    check_outcome_success(outcome.status).await;
+    let offers: Vec<SwapOfferJson> = market
+        .view(
+            &worker,
+            "get_swap_offers_for_token",
+            serde_json::json!({
+                "nft_contract_id": nft.id(),
+                "token_id": token2,
+            })
+            .to_string()
+            .into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert_eq!(offers.len(), 1);
+    assert_eq!(offers[0].offer.top_up, U128(parse_near!("1 N")));
+
+    let accept = |token_id: &str| {
+        user2
+            .call(&worker, nft.id().clone(), "nft_approve")
+            .args_json(serde_json::json!({
+                "token_id": token_id,
+                "account_id": market.id(),
+                "msg": serde_json::json!(ArgsKind::Swap(AcceptSwapArgs {
+                    swap_id: offers[0].swap_id,
+                }))
+                .to_string()
+            }))
+            .unwrap()
+            .deposit(parse_near!("0.01 N"))
+            .gas(parse_gas!("300 Tgas") as u64)
+            .transact()
+    };
+    let outcome = accept(&token3).await?;
+    check_outcome_fail(outcome.status, "The offer doesn't match the token").await;
+    let outcome = accept(&token2).await?;
+    check_outcome_success(outcome.status).await;
+
+    for (user, token_id) in [(&user1, &token2), (&user2, &token1)] {
+        let tokens: Vec<Token> = nft
+            .view(
+                &worker,
+                "nft_tokens_for_owner",
+                serde_json::json!({ "account_id": user.id() })
+                    .to_string()
+                    .into_bytes(),
+            )
+            .await?
+            .json()?;
+        assert!(tokens.iter().any(|token| &token.token_id == token_id));
+        let claimable: Vec<ClaimableToken> = market
+            .view(
+                &worker,
+                "get_claimable_tokens",
+                serde_json::json!({ "account_id": user.id() })
+                    .to_string()
+                    .into_bytes(),
+            )
+            .await?
+            .json()?;
+        assert!(claimable.is_empty());
+    }
+    let offers: Vec<SwapOfferJson> = market
+        .view(
+            &worker,
+            "get_swap_offers_by_owner_id",
+            serde_json::json!({ "account_id": user1.id() })
+                .to_string()
+                .into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert!(offers.is_empty());
+    let summary: EscrowSummary = market
+        .view(&worker, "get_escrow_summary", Vec::new())
+        .await?
+        .json()?;
+    assert!(summary.escrow.is_empty());
+    Ok(())
+}
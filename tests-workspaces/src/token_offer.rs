@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+
+use crate::utils::{
+    check_outcome_fail, check_outcome_success, create_series_raw, create_subaccount, deposit,
+    init_market, init_nft, mint_token,
+};
+use near_contract_standards::non_fungible_token::Token;
+use near_units::{parse_gas, parse_near};
+use nft_bid_market::{AcceptOfferArgs, ArgsKind, EscrowSummary};
+use nft_contract::common::{AccountId, U128};
+
+/*
+- Panics if the NFT contract is not supported
+- Panics if the owner of the offer didn't pay for the storage of one more sale
+- Panics if the offer is not higher than the last one
+- The offer is added to the unlisted token with the current protocol fee
+- The owner sells the token to the last offer with `nft_approve` without the storage deposit
+- Other offers stay on the token and can be removed by their owners
+ */
+#[tokio::test]
+async fn token_offer_accept() -> anyhow::Result<()> {
+    let worker = workspaces::sandbox();
+    let owner = worker.root_account();
+    let nft = init_nft(&worker, owner.id()).await?;
+    let market = init_market(&worker, worker.root_account().id(), vec![nft.id()]).await?;
+
+    let user1 = create_subaccount(&worker, &owner, "user1").await?;
+    let user2 = create_subaccount(&worker, &owner, "user2").await?;
+    let user3 = create_subaccount(&worker, &owner, "user3").await?;
+
+    let series = create_series_raw(
+        &worker,
+        nft.id().clone(),
+        &user1,
+        Some(4),
+        HashMap::from([(user1.id(), 500)]),
+    )
+    .await?;
+    let token1 = mint_token(&worker, nft.id().clone(), &user1, user1.id(), &series).await?;
+
+    let outcome = user2
+        .call(&worker, market.id().clone(), "token_offer")
+        .args_json(serde_json::json!({
+            "nft_contract_id": user1.id(),
+            "token_id": token1,
+            "ft_token_id": "near",
+        }))?
+        .deposit(1000)
+        .transact()
+        .await?;
+    check_outcome_fail(outcome.status, "is not supported by this market").await;
+
+    let outcome = user2
+        .call(&worker, market.id().clone(), "token_offer")
+        .args_json(serde_json::json!({
+            "nft_contract_id": nft.id(),
+            "token_id": token1,
+            "ft_token_id": "near",
+        }))?
+        .deposit(1000)
+        .transact()
+        .await?;
+    check_outcome_fail(outcome.status, "User has more sales than storage paid").await;
+
+    deposit(&worker, market.id().clone(), &user2).await;
+    deposit(&worker, market.id().clone(), &user3).await;
+    for (user, price) in [(&user2, parse_near!("1 N")), (&user3, parse_near!("2 N"))] {
+        let outcome = user
+            .call(&worker, market.id().clone(), "token_offer")
+            .args_json(serde_json::json!({
+                "nft_contract_id": nft.id(),
+                "token_id": token1,
+                "ft_token_id": "near",
+            }))?
+            .deposit(price)
+            .transact()
+            .await?;
+        check_outcome_success(outcome.status).await;
+    }
+    let outcome = user2
+        .call(&worker, market.id().clone(), "token_offer")
+        .args_json(serde_json::json!({
+            "nft_contract_id": nft.id(),
+            "token_id": token1,
+            "ft_token_id": "near",
+        }))?
+        .deposit(parse_near!("2 N"))
+        .transact()
+        .await?;
+    check_outcome_fail(outcome.status, "Can't pay less than or equal to current offer price").await;
+
+    let outcome = user1
+        .call(&worker, nft.id().clone(), "nft_approve")
+        .args_json(serde_json::json!({
+            "token_id": token1,
+            "account_id": market.id(),
+            "msg": serde_json::json!(ArgsKind::AcceptOffer(AcceptOfferArgs {
+                ft_token_id: "near".parse().unwrap(),
+                bid_owner_id: None,
+                bid_price: None,
+            }))
+            .to_string(),
+        }))?
+        .deposit(parse_near!("1 N"))
+        .gas(parse_gas!("300 Tgas") as u64)
+        .transact()
+        .await?;
+    check_outcome_success(outcome.status).await;
+
+    let token: Token = nft
+        .view(
+            &worker,
+            "nft_token",
+            serde_json::json!({ "token_id": token1 })
+                .to_string()
+                .into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert_eq!(token.owner_id.as_ref(), user3.id().as_ref());
+
+    let near: AccountId = "near".parse().unwrap();
+    let offers: HashMap<AccountId, Vec<serde_json::Value>> = market
+        .view(
+            &worker,
+            "get_token_offers",
+            serde_json::json!({
+                "nft_contract_id": nft.id(),
+                "token_id": token1,
+            })
+            .to_string()
+            .into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert_eq!(offers[&near].len(), 1);
+    assert_eq!(offers[&near][0]["owner_id"], user2.id().as_ref());
+    assert_eq!(offers[&near][0]["protocol_fee"], 300);
+
+    let outcome = user3
+        .call(&worker, market.id().clone(), "remove_token_offer")
+        .args_json(serde_json::json!({
+            "nft_contract_id": nft.id(),
+            "token_id": token1,
+            "ft_token_id": "near",
+            "owner_id": user2.id(),
+            "price": U128(parse_near!("1 N")),
+        }))?
+        .deposit(1)
+        .transact()
+        .await?;
+    check_outcome_fail(
+        outcome.status,
+        "Until the offer has ended, it can only be removed by its owner",
+    )
+    .await;
+    let outcome = user2
+        .call(&worker, market.id().clone(), "remove_token_offer")
+        .args_json(serde_json::json!({
+            "nft_contract_id": nft.id(),
+            "token_id": token1,
+            "ft_token_id": "near",
+            "owner_id": user2.id(),
+            "price": U128(parse_near!("1 N")),
+        }))?
+        .deposit(1)
+        .transact()
+        .await?;
+    check_outcome_success(outcome.status).await;
+    let summary: EscrowSummary = market
+        .view(&worker, "get_escrow_summary", Vec::new())
+        .await?
+        .json()?;
+    assert!(summary.escrow.is_empty());
+    Ok(())
+}